@@ -53,10 +53,22 @@ class CounterExample(FileProcess):
         self._counter = 0
 
         # Register command handlers
-        self.register_command("increment", self._on_increment)
-        self.register_command("decrement", self._on_decrement)
-        self.register_command("reset", self._on_reset)
-        self.register_command("get", self._on_get)
+        amount_schema = {
+            "type": "object",
+            "properties": {"amount": {"type": "integer"}},
+        }
+        self.register_command(
+            "increment", self._on_increment,
+            description="Add amount (default 1) to the counter",
+            schema=amount_schema,
+        )
+        self.register_command(
+            "decrement", self._on_decrement,
+            description="Subtract amount (default 1) from the counter",
+            schema=amount_schema,
+        )
+        self.register_command("reset", self._on_reset, description="Set the counter to 0")
+        self.register_command("get", self._on_get, description="Return the current value")
 
     def start(self, blocking: bool = True) -> None:
         """Start the counter process, syncing state from any existing document."""
@@ -150,7 +162,7 @@ class CounterExample(FileProcess):
             "delta": -old_value,
         })
 
-    def _on_get(self, payload: dict) -> None:
+    def _on_get(self, payload: dict) -> dict:
         """Handle get command - broadcasts current value without changing it."""
         print(f"[{self.path}] Get request, current value: {self._counter}")
 
@@ -158,6 +170,7 @@ class CounterExample(FileProcess):
         self.broadcast_event("current-value", {
             "value": self._counter,
         })
+        return {"value": self._counter}
 
 
 def main():
@@ -22,6 +22,7 @@ import queue
 from typing import Callable, Optional
 from dataclasses import dataclass, field
 
+import jsonschema
 import paho.mqtt.client as mqtt
 import y_py as Y
 
@@ -42,6 +43,11 @@ def events_topic(path: str, event_name: str) -> str:
     return f"{path}/events/{event_name}"
 
 
+def manifest_topic(path: str) -> str:
+    """Retained manifest of the commands this path accepts."""
+    return f"{path}/events/manifest"
+
+
 def sync_topic(path: str, client_id: str) -> str:
     """Topic for sync protocol (cyan port)."""
     return f"{path}/sync/{client_id}"
@@ -68,8 +74,10 @@ class FileProcess:
                 self.register_command("do-thing", self.on_do_thing)
 
             def on_do_thing(self, payload: dict):
-                # Handle the command
+                # Handle the command; the return value is sent as the
+                # reply when the sender asked for one (reply_to)
                 self.broadcast_event("thing-done", {"result": "success"})
+                return {"result": "success"}
     """
 
     def __init__(
@@ -105,7 +113,8 @@ class FileProcess:
         # State
         self._ydoc = Y.YDoc()
         self._current_head: Optional[str] = None
-        self._command_handlers: dict[str, Callable[[dict], None]] = {}
+        self._command_handlers: dict[str, Callable[[dict], object]] = {}
+        self._command_specs: dict[str, dict] = {}
         self._sync_state = SyncState()
         self._connected = threading.Event()
         self._ready = threading.Event()
@@ -116,15 +125,35 @@ class FileProcess:
         # Commands are queued here and processed in the main thread.
         self._work_queue: queue.Queue = queue.Queue()
 
-    def register_command(self, verb: str, handler: Callable[[dict], None]) -> None:
+    def register_command(
+        self,
+        verb: str,
+        handler: Callable[[dict], object],
+        description: Optional[str] = None,
+        schema: Optional[dict] = None,
+    ) -> None:
         """
         Register a handler for a command verb.
 
+        If the command carries a `reply_to` topic, the handler's return value
+        is published there as the result (or the exception as the error).
+        Registered verbs are published as a retained manifest on start.
+        Payloads that don't match `schema` are rejected without calling the
+        handler: the store's check of the manifest is only advisory.
+
         Args:
             verb: The command verb (e.g., "increment", "reset")
             handler: Function that takes the command payload dict
+            description: What the command does
+            schema: JSON Schema for the payload
         """
         self._command_handlers[verb] = handler
+        spec = {}
+        if description is not None:
+            spec["description"] = description
+        if schema is not None:
+            spec["schema"] = schema
+        self._command_specs[verb] = spec
 
     def start(self, blocking: bool = True) -> None:
         """
@@ -186,12 +215,23 @@ class FileProcess:
         item_type, *args = item
 
         if item_type == "command":
-            verb, payload, source = args
-            if verb in self._command_handlers:
+            verb, payload, source, reply_to, req = args
+            error = self._validate_command(verb, payload)
+            if error is not None:
+                print(f"[{self.path}] Rejected command {verb}: {error}")
+                self._reply(reply_to, req, error=error)
+            elif verb in self._command_handlers:
                 print(f"[{self.path}] Received command: {verb} from {source or 'unknown'}")
-                self._command_handlers[verb](payload)
+                try:
+                    result = self._command_handlers[verb](payload)
+                except Exception as e:
+                    print(f"[{self.path}] Command {verb} failed: {e}")
+                    self._reply(reply_to, req, error=str(e))
+                else:
+                    self._reply(reply_to, req, result=result)
             else:
                 print(f"[{self.path}] Unknown command: {verb}")
+                self._reply(reply_to, req, error=f"Unknown command: {verb}")
 
         elif item_type == "edit":
             # External edit from another client
@@ -199,6 +239,20 @@ class FileProcess:
             Y.apply_update(self._ydoc, update_bytes)
             print(f"[{self.path}] Applied edit from {author}")
 
+    def _validate_command(self, verb: str, payload: object) -> Optional[str]:
+        """Check a command's payload against its registered schema.
+
+        Returns the validation error, or None if the payload is accepted.
+        """
+        schema = self._command_specs.get(verb, {}).get("schema")
+        if schema is None:
+            return None
+        try:
+            jsonschema.validate(payload, schema)
+        except jsonschema.ValidationError as e:
+            return f"Invalid payload for '{verb}': {e.message}"
+        return None
+
     def stop(self) -> None:
         """Stop the file process and disconnect from MQTT."""
         self._shutdown.set()
@@ -219,6 +273,13 @@ class FileProcess:
         self._mqtt.subscribe(sync_topic(self.path, self.client_id), qos=0)
         print(f"[{self.path}] Subscribed to sync channel")
 
+        # Announce the commands we accept
+        manifest = {"commands": self._command_specs, "source": self.client_id}
+        self._mqtt.publish(
+            manifest_topic(self.path), json.dumps(manifest).encode(), qos=1, retain=True
+        )
+        print(f"[{self.path}] Published command manifest")
+
     def _sync_history(self) -> None:
         """Request HEAD to sync document history."""
         req_id = f"head-{uuid.uuid4()}"
@@ -268,10 +329,12 @@ class FileProcess:
             message = json.loads(payload)
             data = message.get("payload", {})
             source = message.get("source")
+            reply_to = message.get("reply_to")
+            req = message.get("req")
 
             # Queue the command for main thread processing
             # (YDoc operations must happen in main thread)
-            self._work_queue.put(("command", verb, data, source))
+            self._work_queue.put(("command", verb, data, source, reply_to, req))
 
         except json.JSONDecodeError as e:
             print(f"[{self.path}] Invalid command JSON: {e}")
@@ -413,6 +476,19 @@ class FileProcess:
 
         print(f"[{self.path}] Broadcast event: {event_name}")
 
+    def _reply(self, reply_to: Optional[str], req: Optional[str], result=None, error=None) -> None:
+        """Publish a command reply if the sender asked for one."""
+        if not reply_to or not req:
+            return
+
+        message = {"req": req, "source": self.client_id}
+        if error is not None:
+            message["error"] = error
+        else:
+            message["result"] = result
+
+        self._mqtt.publish(reply_to, json.dumps(message).encode(), qos=1)
+
     def get_content(self) -> dict:
         """
         Get the current document content.
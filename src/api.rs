@@ -3,14 +3,15 @@ use axum::{
     http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::{delete, get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+use crate::auth::{effective_author, may_override_owner, Principal};
 use crate::document::{ContentType, DocumentStore};
 use crate::events::CommitBroadcaster;
-use crate::services::{DocumentService, ServiceError};
+use crate::services::{DocumentService, PushedCommit, ServiceError};
 use crate::store::CommitStore;
 
 #[derive(Clone)]
@@ -31,6 +32,7 @@ impl ServiceError {
             ServiceError::InvalidInput(_) => StatusCode::BAD_REQUEST,
             ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ServiceError::Conflict => StatusCode::CONFLICT,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
         }
     }
 }
@@ -66,6 +68,7 @@ pub fn router(
         .route("/docs/:id/head", get(get_doc_head))
         .route("/docs/:id/edit", post(edit_doc))
         .route("/docs/:id/replace", post(replace_doc))
+        .route("/docs/:id/push", post(push_doc))
         .route("/docs/:id/fork", post(fork_doc))
         // fs-root discovery endpoint
         .route("/fs-root", get(get_fs_root))
@@ -178,11 +181,21 @@ async fn get_doc_content(
 async fn delete_doc(
     State(state): State<ApiState>,
     Path(id): Path<String>,
-) -> Result<StatusCode, StatusCode> {
-    if state.doc_store.delete_document(&id).await {
+    Query(owner): Query<OwnerParams>,
+    principal: Option<Extension<Principal>>,
+) -> Result<StatusCode, ServiceError> {
+    state
+        .service
+        .check_owner(
+            &id,
+            effective_author(principal.as_deref(), None).as_deref(),
+            owner.override_owner && may_override_owner(principal.as_deref()),
+        )
+        .await?;
+    if state.service.delete_document(&id).await {
         Ok(StatusCode::NO_CONTENT)
     } else {
-        Err(StatusCode::NOT_FOUND)
+        Err(ServiceError::NotFound)
     }
 }
 
@@ -199,6 +212,14 @@ struct CreateCommitRequest {
     parent_cid: Option<String>,
 }
 
+/// Query parameters for write endpoints that take a JSON body.
+#[derive(Deserialize)]
+struct OwnerParams {
+    /// Admin override of single-writer file ownership
+    #[serde(default)]
+    override_owner: bool,
+}
+
 #[derive(Serialize)]
 struct CreateCommitResponse {
     cid: String,
@@ -209,6 +230,8 @@ struct CreateCommitResponse {
 async fn create_commit(
     State(state): State<ApiState>,
     Path(doc_id): Path<String>,
+    Query(owner): Query<OwnerParams>,
+    principal: Option<Extension<Principal>>,
     Json(req): Json<CreateCommitRequest>,
 ) -> Result<Json<CreateCommitResponse>, ServiceError> {
     // Only support "update" verb for now
@@ -218,9 +241,18 @@ async fn create_commit(
         ));
     }
 
+    let author = effective_author(principal.as_deref(), Some(req.author)).unwrap_or_default();
+    state
+        .service
+        .check_owner(
+            &doc_id,
+            Some(&author),
+            owner.override_owner && may_override_owner(principal.as_deref()),
+        )
+        .await?;
     let result = state
         .service
-        .create_commit(&doc_id, &req.value, req.author, req.message, req.parent_cid)
+        .create_commit(&doc_id, &req.value, author, req.message, req.parent_cid)
         .await?;
 
     Ok(Json(CreateCommitResponse {
@@ -303,11 +335,22 @@ struct DocEditResponse {
 async fn edit_doc(
     State(state): State<ApiState>,
     Path(id): Path<String>,
+    Query(owner): Query<OwnerParams>,
+    principal: Option<Extension<Principal>>,
     Json(req): Json<DocEditRequest>,
 ) -> Result<Json<DocEditResponse>, ServiceError> {
+    let author = effective_author(principal.as_deref(), req.author);
+    state
+        .service
+        .check_owner(
+            &id,
+            author.as_deref(),
+            owner.override_owner && may_override_owner(principal.as_deref()),
+        )
+        .await?;
     let result = state
         .service
-        .edit_document(&id, &req.update, req.author, req.message)
+        .edit_document(&id, &req.update, author, req.message)
         .await?;
 
     Ok(Json(DocEditResponse { cid: result.cid }))
@@ -318,6 +361,9 @@ struct ReplaceParams {
     parent_cid: Option<String>,
     #[serde(default)]
     author: Option<String>,
+    /// Admin override of single-writer file ownership
+    #[serde(default)]
+    override_owner: bool,
 }
 
 #[derive(Serialize)]
@@ -338,11 +384,21 @@ async fn replace_doc(
     State(state): State<ApiState>,
     Path(id): Path<String>,
     Query(params): Query<ReplaceParams>,
+    principal: Option<Extension<Principal>>,
     body: String,
 ) -> Result<Json<ReplaceResponse>, ServiceError> {
+    let author = effective_author(principal.as_deref(), params.author);
+    state
+        .service
+        .check_owner(
+            &id,
+            author.as_deref(),
+            params.override_owner && may_override_owner(principal.as_deref()),
+        )
+        .await?;
     let result = state
         .service
-        .replace_content(&id, &body, params.parent_cid, params.author)
+        .replace_content(&id, &body, params.parent_cid, author)
         .await?;
 
     Ok(Json(ReplaceResponse {
@@ -356,6 +412,75 @@ async fn replace_doc(
     }))
 }
 
+#[derive(Deserialize)]
+struct PushParams {
+    #[serde(default)]
+    author: Option<String>,
+    /// Admin override of single-writer file ownership
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct DocPushRequest {
+    base: String,
+    commits: Vec<DocPushCommit>,
+}
+
+#[derive(Deserialize)]
+struct DocPushCommit {
+    id: String,
+    parent: String,
+    content: String,
+    timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct DocPushResponse {
+    cid: String,
+    commits: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merge_cid: Option<String>,
+}
+
+async fn push_doc(
+    State(state): State<ApiState>,
+    Path(id): Path<String>,
+    Query(params): Query<PushParams>,
+    principal: Option<Extension<Principal>>,
+    Json(req): Json<DocPushRequest>,
+) -> Result<Json<DocPushResponse>, ServiceError> {
+    let author = effective_author(principal.as_deref(), params.author);
+    state
+        .service
+        .check_owner(
+            &id,
+            author.as_deref(),
+            params.override_owner && may_override_owner(principal.as_deref()),
+        )
+        .await?;
+    let commits = req
+        .commits
+        .into_iter()
+        .map(|c| PushedCommit {
+            id: c.id,
+            parent: c.parent,
+            content: c.content,
+            timestamp: c.timestamp,
+        })
+        .collect();
+    let result = state
+        .service
+        .push_commits(&id, &req.base, commits, author)
+        .await?;
+
+    Ok(Json(DocPushResponse {
+        cid: result.cid,
+        commits: result.commits,
+        merge_cid: result.merge_cid,
+    }))
+}
+
 #[derive(Deserialize)]
 struct ForkParams {
     at_commit: Option<String>,
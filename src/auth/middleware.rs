@@ -0,0 +1,477 @@
+//! Axum middleware enforcing bearer-token authorization.
+//!
+//! The middleware authenticates the request, works out which resources the
+//! route touches and whether it reads or writes them, and stores the
+//! [`Principal`] in the request extensions so handlers can record it as the
+//! commit author. WebSocket upgrades also get a [`WriteAccess`] extension, as
+//! the connection can push updates after the read-only upgrade.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+use super::{file_scope, Access, AuthError, Authenticator, Principal};
+use crate::document::DocumentStore;
+use crate::fs::find_doc_paths;
+
+/// Query parameter accepted as an alternative to the `Authorization` header.
+pub const ACCESS_TOKEN_PARAM: &str = "access_token";
+
+/// Whether the principal may write the resources of a read request, decided
+/// the same way as for write requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteAccess(pub bool);
+
+/// The bearer token a request was authenticated with, for handlers that pass
+/// it on to another service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BearerToken(pub String);
+
+/// Looks up the fs-root paths a document is mounted at. Called with the
+/// request's token and the document ID.
+pub type DocPathResolver =
+    Arc<dyn Fn(String, String) -> BoxFuture<'static, Vec<String>> + Send + Sync>;
+
+/// Why a request was refused.
+#[derive(Debug)]
+pub enum Denied {
+    /// The token is missing or invalid
+    Unauthenticated(AuthError),
+    /// The principal lacks a capability for one of the resources
+    Forbidden { access: Access, resource: String },
+}
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denied::Unauthenticated(e) => write!(f, "{}", e),
+            Denied::Forbidden { access, resource } => {
+                write!(f, "Not authorized to {:?} {}", access, resource)
+            }
+        }
+    }
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        match self {
+            Denied::Unauthenticated(e) => unauthorized(e),
+            forbidden => (StatusCode::FORBIDDEN, forbidden.to_string()).into_response(),
+        }
+    }
+}
+
+/// State for the auth middleware.
+#[derive(Clone)]
+pub struct AuthState {
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Maps document IDs to paths so path-scoped principals can use the
+    /// ID-based routes
+    doc_paths: Option<DocPathResolver>,
+}
+
+impl AuthState {
+    pub fn new(authenticator: Arc<dyn Authenticator>) -> Self {
+        Self {
+            authenticator,
+            doc_paths: None,
+        }
+    }
+
+    /// Resolve `docs/<id>` resources through the fs-root schema.
+    pub fn with_fs_root(self, doc_store: Arc<DocumentStore>, fs_root: String) -> Self {
+        self.with_doc_paths(Arc::new(move |_token, doc_id| {
+            let doc_store = doc_store.clone();
+            let fs_root = fs_root.clone();
+            Box::pin(async move { find_doc_paths(&doc_store, &fs_root, &doc_id).await })
+        }))
+    }
+
+    /// Resolve `docs/<id>` resources with `resolver`.
+    pub fn with_doc_paths(mut self, resolver: DocPathResolver) -> Self {
+        self.doc_paths = Some(resolver);
+        self
+    }
+
+    /// Authenticate `token` and check it grants `access` to every resource.
+    ///
+    /// A request that names no resources (an unknown route, or a change
+    /// feed without document IDs) is only allowed with the `*` scope.
+    pub async fn authorize(
+        &self,
+        token: Option<&str>,
+        access: Access,
+        resources: &[String],
+    ) -> Result<Principal, Denied> {
+        let token = token.ok_or(Denied::Unauthenticated(AuthError::MissingToken))?;
+        let principal = self
+            .authenticator
+            .authenticate(token)
+            .map_err(Denied::Unauthenticated)?;
+
+        if resources.is_empty() && !principal.allows(access, "*") {
+            tracing::debug!(
+                "Denied {:?} on an unscoped request for principal {}",
+                access,
+                principal.name
+            );
+            return Err(Denied::Forbidden {
+                access,
+                resource: "*".to_string(),
+            });
+        }
+        for resource in resources {
+            if !self.allows(token, &principal, access, resource).await {
+                tracing::debug!(
+                    "Denied {:?} on {} for principal {}",
+                    access,
+                    resource,
+                    principal.name
+                );
+                return Err(Denied::Forbidden {
+                    access,
+                    resource: resource.clone(),
+                });
+            }
+        }
+        Ok(principal)
+    }
+
+    /// Check access to a resource, falling back to the paths a document is
+    /// mounted at when the principal has no direct `docs/<id>` scope.
+    async fn allows(
+        &self,
+        token: &str,
+        principal: &Principal,
+        access: Access,
+        resource: &str,
+    ) -> bool {
+        // Any authenticated principal may discover the fs-root ID
+        if resource == "fs-root" && access == Access::Read {
+            return true;
+        }
+        if principal.allows(access, resource) {
+            return true;
+        }
+
+        let (Some(doc_paths), Some(doc_id)) =
+            (self.doc_paths.as_ref(), resource.strip_prefix("docs/"))
+        else {
+            return false;
+        };
+
+        doc_paths(token.to_string(), doc_id.to_string())
+            .await
+            .iter()
+            .any(|path| principal.allows(access, &file_scope(path)))
+    }
+}
+
+/// Authenticate and authorize a request before it reaches the handler.
+///
+/// Responds with 401 when the token is missing or invalid and 403 when the
+/// principal lacks a capability for one of the resources the route touches.
+pub async fn require_auth(
+    State(state): State<AuthState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let token = extract_token(req.headers(), req.uri().query());
+    let (access, resources) = required_access(req.method(), req.uri().path(), req.uri().query());
+
+    let principal = match state.authorize(token.as_deref(), access, &resources).await {
+        Ok(principal) => principal,
+        Err(denied) => return denied.into_response(),
+    };
+    // authorize() fails without a token
+    let token = token.unwrap_or_default();
+
+    // A WebSocket may push updates over the connection the GET opened
+    if access == Access::Read && req.uri().path().starts_with("/ws/") {
+        let mut can_write = true;
+        for resource in &resources {
+            if !state
+                .allows(&token, &principal, Access::Write, resource)
+                .await
+            {
+                can_write = false;
+                break;
+            }
+        }
+        req.extensions_mut().insert(WriteAccess(can_write));
+    }
+
+    req.extensions_mut().insert(principal);
+    req.extensions_mut().insert(BearerToken(token));
+    next.run(req).await
+}
+
+fn unauthorized(err: AuthError) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        err.to_string(),
+    )
+        .into_response()
+}
+
+/// Extract a bearer token from the `Authorization` header or query string.
+fn extract_token(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    if let Some(value) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        if let Some(token) = value.strip_prefix("Bearer ") {
+            return Some(token.trim().to_string());
+        }
+    }
+
+    query_param(query, ACCESS_TOKEN_PARAM)
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        if key == name {
+            urlencoding::decode(value).ok().map(|v| v.into_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Work out the access level and resources a request touches.
+///
+/// Safe methods need read access; everything else needs write access.
+/// WebSocket upgrades are GETs, so they only need read access to connect;
+/// the WebSocket handler checks write access before applying updates.
+/// Routes that don't name a resource get none, which only `*` may use.
+pub fn required_access(method: &Method, path: &str, query: Option<&str>) -> (Access, Vec<String>) {
+    let access = if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        Access::Read
+    } else {
+        Access::Write
+    };
+
+    let decoded = urlencoding::decode(path)
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|_| path.to_string());
+    let segments: Vec<&str> = decoded.split('/').filter(|s| !s.is_empty()).collect();
+
+    let resources = match segments.as_slice() {
+        ["files", rest @ ..] => vec![file_resource(method, rest)],
+        ["sse", "files", rest @ ..] => vec![file_scope(&rest.join("/"))],
+        ["fs-root"] => vec!["fs-root".to_string()],
+        ["docs"] => vec!["create".to_string()],
+        ["docs", id, ..] | ["sse", "docs", id, ..] | ["ws", "docs", id, ..] => {
+            vec![format!("docs/{}", id)]
+        }
+        ["documents", "changes"] | ["documents", "stream"] => query_param(query, "doc_ids")
+            .map(|ids| {
+                ids.split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .map(|id| format!("docs/{}", id))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        ["documents", id, ..] => vec![format!("docs/{}", id)],
+        _ => Vec::new(),
+    };
+
+    (access, resources)
+}
+
+/// Build the `files/<path>` resource of a `/files/*` request, dropping the
+/// operation suffix of the routes that take one (`GET .../head`,
+/// `POST .../edit` and `POST .../replace`).
+fn file_resource(method: &Method, segments: &[&str]) -> String {
+    let operation = matches!(
+        (method, segments.last()),
+        (&Method::GET | &Method::HEAD, Some(&"head"))
+            | (&Method::POST, Some(&"edit") | Some(&"replace"))
+    );
+    let segments = if operation {
+        &segments[..segments.len() - 1]
+    } else {
+        segments
+    };
+    file_scope(&segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resources(method: Method, path: &str, query: Option<&str>) -> (Access, Vec<String>) {
+        required_access(&method, path, query)
+    }
+
+    #[test]
+    fn test_doc_routes() {
+        use Access::{Read, Write};
+        let doc = || vec!["docs/abc".to_string()];
+        let cases = [
+            (Method::POST, "/docs", Write, vec!["create".to_string()]),
+            (Method::GET, "/docs/abc", Read, doc()),
+            (Method::DELETE, "/docs/abc", Write, doc()),
+            (Method::POST, "/docs/abc/commit", Write, doc()),
+            (Method::GET, "/docs/abc/info", Read, doc()),
+            (Method::GET, "/docs/abc/head", Read, doc()),
+            (Method::POST, "/docs/abc/edit", Write, doc()),
+            (Method::POST, "/docs/abc/replace", Write, doc()),
+            (Method::POST, "/docs/abc/push", Write, doc()),
+            (Method::POST, "/docs/abc/fork", Write, doc()),
+            (Method::POST, "/docs/abc/event", Write, doc()),
+            (Method::GET, "/sse/docs/abc", Read, doc()),
+            (Method::GET, "/ws/docs/abc", Read, doc()),
+            (Method::GET, "/fs-root", Read, vec!["fs-root".to_string()]),
+        ];
+        for (method, path, access, expected) in cases {
+            assert_eq!(
+                resources(method, path, None),
+                (access, expected),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_routes_strip_operation_suffix() {
+        use Access::{Read, Write};
+        let file = |path: &str| vec![format!("files/{}", path)];
+        let cases = [
+            (
+                Method::GET,
+                "/files/notes/todo.txt",
+                Read,
+                file("notes/todo.txt"),
+            ),
+            (
+                Method::GET,
+                "/files/notes/todo.txt/head",
+                Read,
+                file("notes/todo.txt"),
+            ),
+            (
+                Method::POST,
+                "/files/notes/todo.txt/edit",
+                Write,
+                file("notes/todo.txt"),
+            ),
+            (
+                Method::POST,
+                "/files/notes/todo.txt/replace",
+                Write,
+                file("notes/todo.txt"),
+            ),
+            (
+                Method::DELETE,
+                "/files/notes/todo.txt",
+                Write,
+                file("notes/todo.txt"),
+            ),
+            (Method::GET, "/sse/files/a%20b.txt", Read, file("a b.txt")),
+        ];
+        for (method, path, access, expected) in cases {
+            assert_eq!(
+                resources(method, path, None),
+                (access, expected),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_suffix_kept_on_other_routes() {
+        // Files that happen to be named like an operation
+        assert_eq!(
+            resources(Method::GET, "/files/notes/edit", None).1,
+            vec!["files/notes/edit"]
+        );
+        assert_eq!(
+            resources(Method::DELETE, "/files/notes/replace", None).1,
+            vec!["files/notes/replace"]
+        );
+        assert_eq!(
+            resources(Method::POST, "/files/notes/head", None).1,
+            vec!["files/notes/head"]
+        );
+        assert_eq!(
+            resources(Method::GET, "/sse/files/notes/head", None).1,
+            vec!["files/notes/head"]
+        );
+    }
+
+    #[test]
+    fn test_change_feed_routes() {
+        for path in ["/documents/abc/changes", "/documents/abc/stream"] {
+            assert_eq!(
+                resources(Method::GET, path, Some("since=1")),
+                (Access::Read, vec!["docs/abc".to_string()])
+            );
+        }
+        for path in ["/documents/changes", "/documents/stream"] {
+            assert_eq!(
+                resources(Method::GET, path, Some("doc_ids=a,%20b")).1,
+                vec!["docs/a", "docs/b"]
+            );
+            assert!(resources(Method::GET, path, None).1.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_unknown_routes_name_no_resource() {
+        assert!(resources(Method::GET, "/viewer/index.html", None)
+            .1
+            .is_empty());
+        assert!(resources(Method::POST, "/", None).1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unscoped_requests_need_wildcard() {
+        let reader = |name: &str, scope: &str| Principal {
+            name: name.to_string(),
+            read: vec![scope.to_string()],
+            write: vec![],
+            admin: false,
+        };
+        let mut tokens = crate::auth::StaticTokens::new();
+        tokens.insert("scoped", reader("scoped", "docs"));
+        tokens.insert("root", reader("root", "*"));
+        let state = AuthState::new(Arc::new(tokens));
+
+        let denied = state.authorize(Some("scoped"), Access::Read, &[]).await;
+        assert!(matches!(denied, Err(Denied::Forbidden { .. })));
+        let scoped = ["docs/abc".to_string()];
+        assert!(state
+            .authorize(Some("scoped"), Access::Read, &scoped)
+            .await
+            .is_ok());
+        assert!(state
+            .authorize(Some("root"), Access::Read, &[])
+            .await
+            .is_ok());
+        assert!(state
+            .authorize(Some("root"), Access::Write, &[])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_extract_token_from_query() {
+        let headers = HeaderMap::new();
+        assert_eq!(
+            extract_token(&headers, Some("since=1&access_token=abc%3D")),
+            Some("abc=".to_string())
+        );
+        assert_eq!(extract_token(&headers, None), None);
+    }
+}
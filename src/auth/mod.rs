@@ -0,0 +1,356 @@
+//! HTTP authentication and authorization.
+//!
+//! Clients present a bearer token, either in an `Authorization: Bearer <token>`
+//! header or (for EventSource/WebSocket clients that cannot set headers) in an
+//! `access_token` query parameter. An [`Authenticator`] maps the token to a
+//! [`Principal`], which carries path-scoped read and write capabilities.
+//!
+//! Scopes are resource prefixes:
+//! - `files/<path>` for path-based routes (`/files/*`, `/sse/files/*`)
+//! - `docs/<id>` for ID-based routes (`/docs/:id`, `/sse/docs/:id`, `/ws/docs/:id`)
+//! - `create` for creating new documents, `fs-root` for fs-root discovery
+//! - `*` for everything, and for requests that name no resource
+//!
+//! A scope matches a resource if it is equal to it or is a parent of it at a
+//! `/` boundary, so `files/notes` grants `files/notes/todo.txt`. Read access
+//! to a `files/` scope also allows reading the directories above it, so a
+//! client can walk the schema from fs-root down to its subtree.
+
+pub mod middleware;
+pub mod token;
+
+pub use middleware::{require_auth, AuthState, BearerToken, Denied, DocPathResolver, WriteAccess};
+pub use token::SigningKey;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Environment variable carrying a bearer token for child processes and CLIs.
+pub const TOKEN_ENV: &str = "COMMONPLACE_TOKEN";
+
+/// Errors that can occur while authenticating a request.
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+
+    #[error("Invalid token")]
+    InvalidToken,
+
+    #[error("Token expired")]
+    Expired,
+
+    #[error("Failed to load credentials: {0}")]
+    Config(String),
+}
+
+/// Kind of access a request needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// An authenticated identity and the scopes it may read and write.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Principal {
+    /// Identity recorded as the author of commits made by this principal
+    pub name: String,
+    /// Scopes this principal may read
+    #[serde(default)]
+    pub read: Vec<String>,
+    /// Scopes this principal may write (write implies read)
+    #[serde(default)]
+    pub write: Vec<String>,
+    /// Whether this principal may override single-writer ownership of files
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub admin: bool,
+}
+
+impl Principal {
+    /// A principal that may write `files/<path>` and everything below it.
+    pub fn for_path(name: impl Into<String>, path: &str) -> Self {
+        Self {
+            name: name.into(),
+            read: vec![],
+            write: vec![file_scope(path)],
+            admin: false,
+        }
+    }
+
+    /// Check whether this principal may perform `access` on `resource`.
+    pub fn allows(&self, access: Access, resource: &str) -> bool {
+        let matches = |scopes: &[String]| scopes.iter().any(|s| scope_matches(s, resource));
+        match access {
+            Access::Read => {
+                matches(&self.read)
+                    || matches(&self.write)
+                    || self
+                        .read
+                        .iter()
+                        .chain(self.write.iter())
+                        .any(|s| is_file_ancestor(resource, s))
+            }
+            Access::Write => matches(&self.write),
+        }
+    }
+
+    /// Check whether this principal may write `resource`.
+    pub fn can_write(&self, resource: &str) -> bool {
+        self.allows(Access::Write, resource)
+    }
+}
+
+/// Check whether a capability scope covers a resource.
+pub fn scope_matches(scope: &str, resource: &str) -> bool {
+    let scope = scope.trim_end_matches('/');
+    if scope == "*" {
+        return true;
+    }
+    match resource.strip_prefix(scope) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Build the `files/<path>` scope for a document path.
+pub fn file_scope(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        "files".to_string()
+    } else {
+        format!("files/{}", path)
+    }
+}
+
+/// Whether `resource` is a `files/` directory above `scope`.
+fn is_file_ancestor(resource: &str, scope: &str) -> bool {
+    (resource == "files" || resource.starts_with("files/"))
+        && scope_matches(resource, scope)
+        && resource != scope
+}
+
+/// Whether a request may override file ownership.
+///
+/// Only authenticated admins may; an unauthenticated request can't prove it
+/// is allowed to.
+pub fn may_override_owner(principal: Option<&Principal>) -> bool {
+    principal.is_some_and(|p| p.admin)
+}
+
+/// Pick the commit author for a request.
+///
+/// When the request was authenticated, the principal's name wins over
+/// whatever author the client claimed in the request body or query.
+pub fn effective_author(principal: Option<&Principal>, claimed: Option<String>) -> Option<String> {
+    match principal {
+        Some(p) => Some(p.name.clone()),
+        None => claimed,
+    }
+}
+
+/// Pluggable token verification.
+pub trait Authenticator: Send + Sync {
+    /// Resolve a bearer token to the principal it identifies.
+    fn authenticate(&self, token: &str) -> Result<Principal, AuthError>;
+}
+
+/// Authenticator backed by a fixed table of bearer tokens.
+///
+/// The tokens file is JSON mapping each token to a principal:
+///
+/// ```json
+/// {
+///   "tokens": {
+///     "s3cret": { "name": "alice", "read": ["*"], "write": ["files/notes"], "admin": true }
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StaticTokens {
+    tokens: HashMap<String, Principal>,
+}
+
+impl StaticTokens {
+    /// Create an empty token table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a token for a principal.
+    pub fn insert(&mut self, token: impl Into<String>, principal: Principal) {
+        self.tokens.insert(token.into(), principal);
+    }
+
+    /// Load a token table from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| AuthError::Config(e.to_string()))?;
+        serde_json::from_str(&content).map_err(|e| AuthError::Config(e.to_string()))
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, token: &str) -> Result<Principal, AuthError> {
+        self.tokens
+            .get(token)
+            .cloned()
+            .ok_or(AuthError::InvalidToken)
+    }
+}
+
+/// Authenticator that tries each inner authenticator in turn.
+#[derive(Default)]
+pub struct AuthChain {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+}
+
+impl AuthChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an authenticator to the end of the chain.
+    pub fn push(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.authenticators.push(authenticator);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.authenticators.is_empty()
+    }
+}
+
+impl Authenticator for AuthChain {
+    fn authenticate(&self, token: &str) -> Result<Principal, AuthError> {
+        let mut last_err = AuthError::InvalidToken;
+        for authenticator in &self.authenticators {
+            match authenticator.authenticate(token) {
+                Ok(principal) => return Ok(principal),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+/// Build an HTTP client that sends `token` as a bearer token on every request.
+pub fn bearer_client(token: Option<&str>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(token) = token {
+        let mut headers = reqwest::header::HeaderMap::new();
+        match reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token)) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                headers.insert(reqwest::header::AUTHORIZATION, value);
+                builder = builder.default_headers(headers);
+            }
+            Err(e) => tracing::warn!("Ignoring malformed bearer token: {}", e),
+        }
+    }
+    builder.build().unwrap_or_else(|_| reqwest::Client::new())
+}
+
+/// Build an HTTP client using the token in `COMMONPLACE_TOKEN`, if set.
+pub fn client_from_env() -> reqwest::Client {
+    bearer_client(std::env::var(TOKEN_ENV).ok().as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(read: &[&str], write: &[&str]) -> Principal {
+        Principal {
+            name: "alice".to_string(),
+            read: read.iter().map(|s| s.to_string()).collect(),
+            write: write.iter().map(|s| s.to_string()).collect(),
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn test_scope_matches_at_path_boundary() {
+        assert!(scope_matches("files/notes", "files/notes"));
+        assert!(scope_matches("files/notes", "files/notes/todo.txt"));
+        assert!(scope_matches("files/notes/", "files/notes/todo.txt"));
+        assert!(!scope_matches("files/notes", "files/notes-archive/a.txt"));
+        assert!(!scope_matches("files/notes", "files"));
+        assert!(scope_matches("*", "docs/abc"));
+    }
+
+    #[test]
+    fn test_write_implies_read() {
+        let p = principal(&[], &["files/notes"]);
+        assert!(p.allows(Access::Read, "files/notes/a.txt"));
+        assert!(p.allows(Access::Write, "files/notes/a.txt"));
+        assert!(!p.allows(Access::Read, "files/other.txt"));
+    }
+
+    #[test]
+    fn test_read_only_cannot_write() {
+        let p = principal(&["*"], &[]);
+        assert!(p.allows(Access::Read, "docs/abc"));
+        assert!(!p.can_write("docs/abc"));
+    }
+
+    #[test]
+    fn test_path_scope_can_traverse_ancestors() {
+        let p = Principal::for_path("proc", "/examples/counter.json");
+        assert!(p.can_write("files/examples/counter.json"));
+        assert!(p.allows(Access::Read, "files/examples"));
+        assert!(p.allows(Access::Read, "files"));
+        assert!(!p.can_write("files/examples"));
+        assert!(!p.allows(Access::Read, "files/examples/other.json"));
+    }
+
+    #[test]
+    fn test_auth_chain_tries_each() {
+        let mut first = StaticTokens::new();
+        first.insert("a", principal(&["*"], &[]));
+        let mut second = StaticTokens::new();
+        second.insert("b", principal(&[], &["*"]));
+
+        let mut chain = AuthChain::new();
+        chain.push(Arc::new(first));
+        chain.push(Arc::new(second));
+        assert!(chain.authenticate("a").is_ok());
+        assert!(chain.authenticate("b").is_ok());
+        assert!(chain.authenticate("c").is_err());
+    }
+
+    #[test]
+    fn test_effective_author_prefers_principal() {
+        let p = principal(&["*"], &[]);
+        assert_eq!(
+            effective_author(Some(&p), Some("mallory".to_string())),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            effective_author(None, Some("bob".to_string())),
+            Some("bob".to_string())
+        );
+    }
+
+    #[test]
+    fn test_only_admins_override_owner() {
+        let mut p = principal(&["*"], &[]);
+        assert!(!may_override_owner(Some(&p)));
+        p.admin = true;
+        assert!(may_override_owner(Some(&p)));
+        assert!(!may_override_owner(None));
+    }
+
+    #[test]
+    fn test_static_tokens_parse() {
+        let tokens: StaticTokens = serde_json::from_str(
+            r#"{"tokens": {"t1": {"name": "alice", "read": ["*"], "write": ["files/notes"]}}}"#,
+        )
+        .unwrap();
+        let p = tokens.authenticate("t1").unwrap();
+        assert_eq!(p.name, "alice");
+        assert!(tokens.authenticate("nope").is_err());
+    }
+}
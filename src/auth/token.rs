@@ -0,0 +1,191 @@
+//! Signed, self-describing bearer tokens.
+//!
+//! A signed token carries its principal (name and scopes) and an expiry,
+//! authenticated with HMAC-SHA256 under a shared secret. Anyone holding the
+//! secret (e.g. the orchestrator) can mint tokens; the server verifies them
+//! offline without a token table.
+//!
+//! Wire format: `cp1.<base64url(claims json)>.<base64url(hmac)>`.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{AuthError, Authenticator, Principal};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Prefix identifying the token format version.
+const TOKEN_PREFIX: &str = "cp1";
+
+/// Claims embedded in a signed token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenClaims {
+    #[serde(flatten)]
+    principal: Principal,
+    /// Expiry as unix seconds
+    exp: u64,
+}
+
+/// Secret used to mint and verify signed tokens.
+#[derive(Clone)]
+pub struct SigningKey {
+    secret: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Create a key from raw secret bytes.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Load a key from a file. Surrounding whitespace is ignored.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| AuthError::Config(e.to_string()))?;
+        let secret = content.trim();
+        if secret.is_empty() {
+            return Err(AuthError::Config(format!(
+                "Signing key file {} is empty",
+                path.display()
+            )));
+        }
+        Ok(Self::new(secret.as_bytes()))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length")
+    }
+
+    /// Mint a token for `principal` that expires after `ttl`.
+    pub fn mint(&self, principal: &Principal, ttl: Duration) -> String {
+        let exp = unix_now() + ttl.as_secs();
+        let claims = TokenClaims {
+            principal: principal.clone(),
+            exp,
+        };
+        let payload =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize to JSON"));
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        format!("{}.{}.{}", TOKEN_PREFIX, payload, signature)
+    }
+
+    /// Verify a token and return its principal.
+    pub fn verify(&self, token: &str) -> Result<Principal, AuthError> {
+        let mut parts = token.splitn(3, '.');
+        let (prefix, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(prefix), Some(payload), Some(signature)) => (prefix, payload, signature),
+            _ => return Err(AuthError::InvalidToken),
+        };
+        if prefix != TOKEN_PREFIX {
+            return Err(AuthError::InvalidToken);
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let claims_json = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| AuthError::InvalidToken)?;
+        let claims: TokenClaims =
+            serde_json::from_slice(&claims_json).map_err(|_| AuthError::InvalidToken)?;
+
+        if unix_now() > claims.exp {
+            return Err(AuthError::Expired);
+        }
+
+        Ok(claims.principal)
+    }
+}
+
+impl Authenticator for SigningKey {
+    fn authenticate(&self, token: &str) -> Result<Principal, AuthError> {
+        self.verify(token)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal() -> Principal {
+        Principal {
+            name: "counter".to_string(),
+            read: vec!["files/examples".to_string()],
+            write: vec!["files/examples/counter.json".to_string()],
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn test_mint_and_verify_roundtrip() {
+        let key = SigningKey::new("secret");
+        let token = key.mint(&principal(), Duration::from_secs(60));
+        assert!(token.starts_with("cp1."));
+        assert_eq!(key.verify(&token).unwrap(), principal());
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        let token = SigningKey::new("secret").mint(&principal(), Duration::from_secs(60));
+        assert!(matches!(
+            SigningKey::new("other").verify(&token),
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_claims_rejected() {
+        let key = SigningKey::new("secret");
+        let token = key.mint(&principal(), Duration::from_secs(60));
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let mut widened = principal();
+        widened.write = vec!["*".to_string()];
+        let forged_payload = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&TokenClaims {
+                principal: widened,
+                exp: u64::MAX,
+            })
+            .unwrap(),
+        );
+        let forged = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+        assert!(key.verify(&forged).is_err());
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let key = SigningKey::new("secret");
+        let claims = TokenClaims {
+            principal: principal(),
+            exp: 1,
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let mut mac = key.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        let token = format!("cp1.{}.{}", payload, signature);
+        assert!(matches!(key.verify(&token), Err(AuthError::Expired)));
+    }
+}
@@ -5,6 +5,12 @@
 //!   commonplace-cmd examples/counter.json increment
 //!   commonplace-cmd examples/counter.json increment --payload '{"amount": 5}'
 //!
+//!   # Wait for the handler's reply and print its result
+//!   commonplace-cmd examples/counter.json get --wait --timeout 5
+//!
+//!   # List the verbs the path accepts (from its retained manifest)
+//!   commonplace-cmd examples/counter.json --list
+//!
 //!   # Relative path inside synced directory (uses state file to resolve)
 //!   cd /tmp/my-sync-dir && commonplace-cmd ./counter.json increment
 //!
@@ -14,7 +20,7 @@
 use clap::Parser;
 use commonplace_doc::{
     cli::CmdArgs,
-    mqtt::{CommandMessage, MqttClient, MqttConfig, Topic},
+    mqtt::{fetch_manifest, request_command, CommandMessage, MqttClient, Topic},
     sync::state_file::SyncStateFile,
 };
 use rumqttc::QoS;
@@ -24,6 +30,9 @@ use std::time::Duration;
 /// Schema filename used to identify synced directories
 const SCHEMA_FILENAME: &str = ".commonplace.json";
 
+/// How long to wait for a retained manifest with --list
+const MANIFEST_WAIT: Duration = Duration::from_secs(2);
+
 /// Resolve a path argument to a commonplace document path.
 ///
 /// Resolution order:
@@ -136,6 +145,35 @@ fn find_sync_root(start: &Path) -> Result<Option<(PathBuf, PathBuf)>, Box<dyn st
     Ok(None)
 }
 
+/// Print the verbs in a path's retained command manifest.
+async fn list_commands(args: &CmdArgs, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = args.mqtt_connect.config(
+        &args.mqtt_broker,
+        &format!("commonplace-cmd-{}", uuid::Uuid::new_v4()),
+    );
+    let client = std::sync::Arc::new(MqttClient::connect(config).await?);
+    let client_for_loop = client.clone();
+    let loop_handle = tokio::spawn(async move { client_for_loop.run_event_loop().await });
+
+    let manifest = fetch_manifest(&client, path, MANIFEST_WAIT).await;
+    let _ = client.disconnect().await;
+    loop_handle.abort();
+
+    let Some(manifest) = manifest? else {
+        return Err(format!("No command manifest published for {}", path).into());
+    };
+    for (verb, spec) in &manifest.commands {
+        match &spec.description {
+            Some(description) => println!("{}\t{}", verb, description),
+            None => println!("{}", verb),
+        }
+        if let Some(schema) = &spec.schema {
+            println!("    payload: {}", serde_json::to_string(schema)?);
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = CmdArgs::parse();
@@ -143,6 +181,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Resolve the path (handles relative paths in synced directories)
     let resolved_path = resolve_path(&args.path).await?;
 
+    if args.list {
+        return list_commands(&args, &resolved_path).await;
+    }
+    let verb = args.verb.clone().unwrap_or_default();
+
     // Parse the payload JSON
     let payload: serde_json::Value =
         serde_json::from_str(&args.payload).map_err(|e| format!("Invalid JSON payload: {}", e))?;
@@ -151,21 +194,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let message = CommandMessage {
         payload,
         source: Some(args.source.clone()),
+        reply_to: None,
+        req: None,
+        token: None,
     };
 
     // Build the topic
-    let topic = Topic::commands(&resolved_path, &args.verb);
+    let topic = Topic::commands(&resolved_path, &verb);
     let topic_str = topic.to_topic_string();
 
     // Connect to MQTT
-    let config = MqttConfig {
-        broker_url: args.mqtt_broker.clone(),
-        client_id: format!("commonplace-cmd-{}", uuid::Uuid::new_v4()),
-        ..Default::default()
-    };
+    let config = args.mqtt_connect.config(
+        &args.mqtt_broker,
+        &format!("commonplace-cmd-{}", uuid::Uuid::new_v4()),
+    );
 
     let client = MqttClient::connect(config).await?;
 
+    if args.wait {
+        let client = std::sync::Arc::new(client);
+        let client_for_loop = client.clone();
+        let loop_handle = tokio::spawn(async move { client_for_loop.run_event_loop().await });
+
+        let result = request_command(
+            &client,
+            &resolved_path,
+            &verb,
+            message,
+            Duration::from_secs(args.timeout),
+        )
+        .await;
+        let _ = client.disconnect().await;
+        loop_handle.abort();
+
+        let reply = result?;
+        if let Some(error) = reply.error {
+            return Err(format!("{} failed: {}", verb, error).into());
+        }
+        let result = reply.result.unwrap_or(serde_json::Value::Null);
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+
     // Need to poll the event loop once to establish connection
     // Spawn the event loop briefly
     let client_for_loop = std::sync::Arc::new(client);
@@ -189,10 +259,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if resolved_path != args.path {
         println!(
             "Sent {} to {} (resolved from {})",
-            args.verb, resolved_path, args.path
+            verb, resolved_path, args.path
         );
     } else {
-        println!("Sent {} to {}", args.verb, resolved_path);
+        println!("Sent {} to {}", verb, resolved_path);
     }
 
     // Wait for PUBACK confirmation (500ms for QoS1 delivery)
@@ -13,6 +13,7 @@ use std::process::Command;
 const SUBCOMMANDS: &[(&str, &str)] = &[
     ("server", "Document server with Yjs commit history"),
     ("sync", "Sync files with commonplace server"),
+    ("syncd", "Sync many files and directories in one process"),
     (
         "orchestrator",
         "Process supervisor for commonplace services",
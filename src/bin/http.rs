@@ -6,9 +6,10 @@
 
 use axum::{routing::get, Router};
 use clap::Parser;
-use commonplace_doc::{cli::HttpArgs, http_gateway, mqtt::MqttConfig};
+use commonplace_doc::{cli::HttpArgs, http_gateway};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -33,11 +34,9 @@ async fn main() {
     tracing::info!("Starting commonplace-http gateway");
 
     // Create MQTT config
-    let mqtt_config = MqttConfig {
-        broker_url: args.mqtt_broker.clone(),
-        client_id: args.mqtt_client_id.clone(),
-        ..Default::default()
-    };
+    let mqtt_config = args
+        .mqtt_connect
+        .config(&args.mqtt_broker, &args.mqtt_client_id);
 
     tracing::info!(
         "Connecting to MQTT broker: {} (client: {})",
@@ -49,7 +48,7 @@ async fn main() {
     let gateway = match http_gateway::HttpGateway::new(mqtt_config).await {
         Ok(gw) => {
             tracing::info!("HTTP gateway connected to MQTT");
-            Arc::new(gw)
+            Arc::new(gw.with_request_timeout(Duration::from_secs(args.store_timeout)))
         }
         Err(e) => {
             tracing::error!("Failed to create HTTP gateway: {}", e);
@@ -57,10 +56,21 @@ async fn main() {
         }
     };
 
-    // Build the router
+    // Check tokens here and forward them to the store
+    let auth = args.auth.authenticator().unwrap_or_else(|e| {
+        tracing::error!("Failed to load auth credentials: {}", e);
+        std::process::exit(1);
+    });
+    if auth.is_none() {
+        tracing::warn!(
+            "No --auth-tokens or --auth-key specified - HTTP routes are unauthenticated"
+        );
+    }
+
+    // Build the router; health stays open so load balancers can probe
     let app = Router::new()
         .route("/health", get(health_check))
-        .merge(http_gateway::router(gateway))
+        .merge(http_gateway::router(gateway, auth))
         .layer(CorsLayer::permissive());
 
     // Run the server
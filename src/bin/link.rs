@@ -15,7 +15,6 @@ use commonplace_doc::{
     fs::{DocEntry, Entry, FsSchema},
     sync::client::push_schema_to_server,
 };
-use reqwest::Client;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -101,7 +100,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     // Push schemas to server
-    let client = Client::new();
+    let client = commonplace_doc::auth::client_from_env();
     for (doc_id, schema_json) in &schemas_to_push {
         match push_schema_to_server(&client, &args.server, doc_id, schema_json).await {
             Ok(()) => println!("Pushed schema to server ({})", doc_id),
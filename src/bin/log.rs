@@ -82,7 +82,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Resolve file path to UUID
     let (uuid, _workspace_root, rel_path) = resolve_path_to_uuid(&args.path)?;
 
-    let client = Client::new();
+    let client = commonplace_doc::auth::client_from_env();
 
     // Fetch commit history
     let url = format!("{}/documents/{}/changes", args.server, uuid);
@@ -1,9 +1,15 @@
 //! commonplace-mcp: MCP server for firing commands to commonplace paths
 //!
 //! This MCP server exposes a `fire_command` tool that sends commands
-//! to document paths via MQTT, bridging LLM tool-use with commonplace.
-
-use commonplace_doc::mqtt::{CommandMessage, MqttConfig, Topic};
+//! to document paths via MQTT, bridging LLM tool-use with commonplace,
+//! and a `list_commands` tool that reads a path's command manifest.
+
+use clap::Parser;
+use commonplace_doc::cli::McpArgs;
+use commonplace_doc::mqtt::{
+    fetch_manifest, mqtt_options, request_command, CommandMessage, MqttClient, MqttConfig,
+    MqttError, Topic,
+};
 use rmcp::{
     handler::server::ServerHandler,
     model::{
@@ -14,10 +20,12 @@ use rmcp::{
     transport::io::stdio,
     ServiceExt,
 };
-use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinHandle;
 
 /// Parameters for the fire_command tool
 #[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
@@ -29,49 +37,67 @@ struct FireCommandParams {
     /// Optional JSON payload for the command
     #[serde(default)]
     payload: Option<serde_json::Value>,
+    /// Wait for the handler's reply and return its result
+    #[serde(default)]
+    wait: bool,
+    /// Seconds to wait for a reply when `wait` is set (default 10)
+    #[serde(default)]
+    timeout_secs: Option<u64>,
 }
 
+/// Parameters for the list_commands tool
+#[derive(Debug, Deserialize, Serialize, schemars::JsonSchema)]
+struct ListCommandsParams {
+    /// The document path (e.g., "examples/counter.json")
+    path: String,
+}
+
+/// How long to wait for a retained command manifest
+const MANIFEST_WAIT: Duration = Duration::from_secs(2);
+
+/// Reply timeout used when the caller doesn't give one
+const DEFAULT_REPLY_TIMEOUT_SECS: u64 = 10;
+
 /// The MCP server for commonplace commands
 #[derive(Clone)]
 struct CommonplaceMcp {
-    mqtt_broker: String,
+    /// Connection settings; each connection gets its own client ID
+    mqtt_config: MqttConfig,
 }
 
 impl CommonplaceMcp {
-    fn new(mqtt_broker: String) -> Self {
-        Self { mqtt_broker }
+    fn new(mqtt_config: MqttConfig) -> Self {
+        Self { mqtt_config }
+    }
+
+    /// Connection config with a fresh client ID.
+    fn mqtt_config(&self) -> MqttConfig {
+        MqttConfig {
+            client_id: format!("commonplace-mcp-{}", uuid::Uuid::new_v4()),
+            ..self.mqtt_config.clone()
+        }
     }
 
     async fn fire_command(&self, params: FireCommandParams) -> Result<String, String> {
-        let payload = params.payload.unwrap_or(serde_json::json!({}));
+        let payload = params.payload.clone().unwrap_or(serde_json::json!({}));
 
         let message = CommandMessage {
             payload,
             source: Some("commonplace-mcp".to_string()),
+            reply_to: None,
+            req: None,
+            token: None,
         };
 
+        if params.wait {
+            return self.request_command(&params, message).await;
+        }
+
         let topic = Topic::commands(&params.path, &params.verb);
         let topic_str = topic.to_topic_string();
 
-        let config = MqttConfig {
-            broker_url: self.mqtt_broker.clone(),
-            client_id: format!("commonplace-mcp-{}", uuid::Uuid::new_v4()),
-            ..Default::default()
-        };
-
-        // Parse broker URL
-        let broker_url = &config.broker_url;
-        let stripped = broker_url
-            .strip_prefix("mqtt://")
-            .or_else(|| broker_url.strip_prefix("tcp://"))
-            .unwrap_or(broker_url);
-        let parts: Vec<&str> = stripped.split(':').collect();
-        let host = parts.first().unwrap_or(&"localhost").to_string();
-        let port: u16 = parts.get(1).and_then(|p| p.parse().ok()).unwrap_or(1883);
-
-        let mut options = MqttOptions::new(&config.client_id, host, port);
-        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
-        options.set_clean_session(config.clean_session);
+        let options = mqtt_options(&self.mqtt_config())
+            .map_err(|e| format!("MQTT configuration error: {}", e))?;
 
         let (client, mut event_loop) = AsyncClient::new(options, 256);
 
@@ -135,6 +161,80 @@ impl CommonplaceMcp {
 
         Ok(format!("Sent {} to {}", params.verb, params.path))
     }
+
+    /// Send a command and wait for the handler's reply.
+    async fn request_command(
+        &self,
+        params: &FireCommandParams,
+        message: CommandMessage,
+    ) -> Result<String, String> {
+        let (client, loop_handle) = self.connect().await?;
+        let timeout =
+            Duration::from_secs(params.timeout_secs.unwrap_or(DEFAULT_REPLY_TIMEOUT_SECS));
+        let result = request_command(&client, &params.path, &params.verb, message, timeout).await;
+        let _ = client.disconnect().await;
+        loop_handle.abort();
+
+        let reply = result.map_err(|e| e.to_string())?;
+        if let Some(error) = reply.error {
+            return Err(format!("{} failed: {}", params.verb, error));
+        }
+        let result = reply.result.unwrap_or(serde_json::Value::Null);
+        serde_json::to_string_pretty(&result).map_err(|e| e.to_string())
+    }
+
+    /// Read a path's retained command manifest.
+    async fn list_commands(&self, params: ListCommandsParams) -> Result<String, String> {
+        let (client, loop_handle) = self.connect().await?;
+        let manifest = fetch_manifest(&client, &params.path, MANIFEST_WAIT).await;
+        let _ = client.disconnect().await;
+        loop_handle.abort();
+
+        match manifest.map_err(|e| e.to_string())? {
+            Some(manifest) => {
+                serde_json::to_string_pretty(&manifest.commands).map_err(|e| e.to_string())
+            }
+            None => Err(format!("No command manifest published for {}", params.path)),
+        }
+    }
+
+    /// Connect a client and run its event loop in the background.
+    async fn connect(
+        &self,
+    ) -> Result<(Arc<MqttClient>, JoinHandle<Result<(), MqttError>>), String> {
+        let client = Arc::new(
+            MqttClient::connect(self.mqtt_config())
+                .await
+                .map_err(|e| format!("MQTT connection error: {}", e))?,
+        );
+        let client_for_loop = client.clone();
+        let loop_handle = tokio::spawn(async move { client_for_loop.run_event_loop().await });
+        Ok((client, loop_handle))
+    }
+}
+
+/// Build a tool description from its parameter schema.
+fn tool<T: schemars::JsonSchema>(name: &'static str, description: &'static str) -> Tool {
+    let schema = schemars::schema_for!(T);
+    let schema_value = serde_json::to_value(schema).unwrap_or_default();
+    Tool {
+        name: name.into(),
+        description: Some(description.into()),
+        input_schema: serde_json::from_value(schema_value).unwrap_or_default(),
+        annotations: None,
+        icons: None,
+        meta: None,
+        output_schema: None,
+        title: None,
+    }
+}
+
+fn invalid_params(e: serde_json::Error) -> ErrorData {
+    ErrorData {
+        code: ErrorCode::INVALID_PARAMS,
+        message: Cow::Owned(format!("Invalid parameters: {}", e)),
+        data: None,
+    }
 }
 
 impl ServerHandler for CommonplaceMcp {
@@ -153,7 +253,9 @@ impl ServerHandler for CommonplaceMcp {
                 icons: None,
             },
             instructions: Some(
-                "Use the fire_command tool to send commands to commonplace document paths.".into(),
+                "Use list_commands to discover the commands a commonplace document path \
+                 accepts, then fire_command to send them."
+                    .into(),
             ),
         }
     }
@@ -171,20 +273,19 @@ impl ServerHandler for CommonplaceMcp {
         _request: Option<rmcp::model::PaginatedRequestParam>,
         _context: service::RequestContext<service::RoleServer>,
     ) -> Result<ListToolsResult, ErrorData> {
-        let schema = schemars::schema_for!(FireCommandParams);
-        let schema_value = serde_json::to_value(schema).unwrap_or_default();
-
         Ok(ListToolsResult {
-            tools: vec![Tool {
-                name: "fire_command".into(),
-                description: Some("Send a command to a commonplace document path via MQTT".into()),
-                input_schema: serde_json::from_value(schema_value).unwrap_or_default(),
-                annotations: None,
-                icons: None,
-                meta: None,
-                output_schema: None,
-                title: None,
-            }],
+            tools: vec![
+                tool::<FireCommandParams>(
+                    "fire_command",
+                    "Send a command to a commonplace document path via MQTT. \
+                     Set wait to return the handler's reply.",
+                ),
+                tool::<ListCommandsParams>(
+                    "list_commands",
+                    "List the commands a commonplace document path accepts, \
+                     with descriptions and JSON Schemas for their payloads.",
+                ),
+            ],
             next_cursor: None,
             meta: None,
         })
@@ -195,22 +296,26 @@ impl ServerHandler for CommonplaceMcp {
         request: rmcp::model::CallToolRequestParam,
         _context: service::RequestContext<service::RoleServer>,
     ) -> Result<CallToolResult, ErrorData> {
-        if request.name != "fire_command" {
-            return Err(ErrorData {
-                code: ErrorCode::METHOD_NOT_FOUND,
-                message: Cow::Owned(format!("Unknown tool: {}", request.name)),
-                data: None,
-            });
-        }
-
         let args = serde_json::Value::Object(request.arguments.unwrap_or_default());
-        let params: FireCommandParams = serde_json::from_value(args).map_err(|e| ErrorData {
-            code: ErrorCode::INVALID_PARAMS,
-            message: Cow::Owned(format!("Invalid parameters: {}", e)),
-            data: None,
-        })?;
+        let result = match request.name.as_ref() {
+            "fire_command" => {
+                let params = serde_json::from_value(args).map_err(invalid_params)?;
+                self.fire_command(params).await
+            }
+            "list_commands" => {
+                let params = serde_json::from_value(args).map_err(invalid_params)?;
+                self.list_commands(params).await
+            }
+            _ => {
+                return Err(ErrorData {
+                    code: ErrorCode::METHOD_NOT_FOUND,
+                    message: Cow::Owned(format!("Unknown tool: {}", request.name)),
+                    data: None,
+                })
+            }
+        };
 
-        match self.fire_command(params).await {
+        match result {
             Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
             Err(e) => Ok(CallToolResult::error(vec![Content::text(e)])),
         }
@@ -219,10 +324,12 @@ impl ServerHandler for CommonplaceMcp {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mqtt_broker =
-        std::env::var("MQTT_BROKER").unwrap_or_else(|_| "mqtt://localhost:1883".to_string());
+    let args = McpArgs::parse();
+    let mqtt_config = args
+        .mqtt_connect
+        .config(&args.mqtt_broker, "commonplace-mcp");
 
-    let server = CommonplaceMcp::new(mqtt_broker);
+    let server = CommonplaceMcp::new(mqtt_config);
 
     // Start the MCP server on stdio
     let transport = stdio();
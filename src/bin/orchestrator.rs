@@ -4,8 +4,11 @@
 //! processes.json files and manages discovered processes with automatic restart.
 
 use clap::Parser;
+use commonplace_doc::auth::bearer_client;
 use commonplace_doc::cli::OrchestratorArgs;
-use commonplace_doc::orchestrator::{DiscoveredProcessManager, OrchestratorConfig, ProcessManager};
+use commonplace_doc::orchestrator::{
+    CredentialMinter, DiscoveredProcessManager, OrchestratorConfig, ProcessManager,
+};
 use fs2::FileExt;
 use std::fs::File;
 use std::net::TcpStream;
@@ -247,6 +250,24 @@ async fn main() {
     // Start server and sync from commonplace.json, then discover processes recursively
     tracing::info!("[orchestrator] Server: {}", args.server);
 
+    // Load the signing key for per-process credentials if auth is enabled
+    let credentials = args
+        .auth_key
+        .as_ref()
+        .map(|path| match CredentialMinter::load(path) {
+            Ok(minter) => {
+                tracing::info!(
+                    "[orchestrator] Minting per-process credentials (valid for {}s)",
+                    args.token_ttl
+                );
+                minter.with_ttl(Duration::from_secs(args.token_ttl))
+            }
+            Err(e) => {
+                tracing::error!("[orchestrator] Failed to load auth key: {}", e);
+                std::process::exit(1);
+            }
+        });
+
     // First, start server and sync from commonplace.json using ProcessManager
     // This ensures the server is running before we try to discover processes
     let mut base_manager = ProcessManager::new(
@@ -254,6 +275,9 @@ async fn main() {
         args.mqtt_broker.clone(),
         args.disable.clone(),
     );
+    if let Some(ref minter) = credentials {
+        base_manager.set_credentials(minter.clone());
+    }
 
     // Handle --only mode: just run a single base process from config
     if let Some(only) = &args.only {
@@ -290,7 +314,8 @@ async fn main() {
     }
 
     // Wait for server to be healthy
-    let client = reqwest::Client::new();
+    let orchestrator_token = credentials.as_ref().map(|m| m.orchestrator_token());
+    let client = bearer_client(orchestrator_token.as_deref());
     let health_url = format!("{}/health", args.server);
     tracing::info!("[orchestrator] Waiting for server to be healthy...");
     let mut attempts = 0;
@@ -395,6 +420,9 @@ async fn main() {
     // Now we can start recursive discovery
     let mut discovered_manager =
         DiscoveredProcessManager::new(broker_raw.to_string(), args.server.clone());
+    if let Some(minter) = credentials {
+        discovered_manager.set_credentials(minter);
+    }
 
     tracing::info!(
         "[orchestrator] Starting recursive discovery with fs-root: {}",
@@ -8,7 +8,6 @@
 use clap::Parser;
 use commonplace_doc::cli::ReplayArgs;
 use commonplace_doc::fs::{Entry, FsSchema};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -76,7 +75,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (dirs, filename) = split_path(&rel_path)?;
     let uuid = resolve_uuid(&workspace_root, &dirs, &filename)?;
 
-    let client = Client::new();
+    let client = commonplace_doc::auth::client_from_env();
 
     if args.list {
         // Fetch commit history
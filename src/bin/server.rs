@@ -1,8 +1,13 @@
 use clap::Parser;
 use commonplace_doc::{
-    cli::Args, create_router_with_config, mqtt::MqttConfig, store::CommitStore, RouterConfig,
+    cli::{event_log_config, Args},
+    create_router_with_config,
+    mqtt::{AllowAll, EmbeddedBroker},
+    store::CommitStore,
+    RouterConfig,
 };
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -35,18 +40,30 @@ async fn main() {
         tracing::info!("Filesystem root: {}", fs_root);
     }
 
-    // Create MQTT config if broker URL is specified
-    let mqtt_config = args.mqtt_broker.as_ref().map(|broker_url| {
+    // Start the embedded broker if requested; it lives until the process exits
+    let embedded_broker = match args.embedded_broker {
+        Some(addr) => match EmbeddedBroker::start(addr, Arc::new(AllowAll)).await {
+            Ok(broker) => Some(broker),
+            Err(e) => {
+                tracing::error!("Failed to start embedded MQTT broker: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Create MQTT config if a broker URL is specified (or the broker is embedded)
+    let broker_url = args
+        .mqtt_broker
+        .clone()
+        .or_else(|| embedded_broker.as_ref().map(|b| b.url()));
+    let mqtt_config = broker_url.as_ref().map(|broker_url| {
         let client_id = args
             .mqtt_client_id
             .clone()
             .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
         tracing::info!("MQTT broker: {} (client: {})", broker_url, client_id);
-        MqttConfig {
-            broker_url: broker_url.clone(),
-            client_id,
-            ..Default::default()
-        }
+        args.mqtt_connect.config(broker_url, &client_id)
     });
 
     // Log MQTT subscriptions if configured
@@ -54,12 +71,37 @@ async fn main() {
         tracing::info!("MQTT subscribe: {}", path);
     }
 
+    // Load credentials if authentication is enabled
+    let auth = args
+        .auth
+        .authenticator()
+        .expect("Failed to load auth credentials");
+    if auth.is_none() {
+        tracing::warn!(
+            "No --auth-tokens or --auth-key specified - HTTP routes are unauthenticated"
+        );
+    }
+    if let Err(e) = args
+        .owns_policy
+        .check_enforceable(auth.is_some(), mqtt_config.is_some())
+    {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
     // Build our application with routes
     let app = create_router_with_config(RouterConfig {
         commit_store,
         fs_root: args.fs_root,
         mqtt: mqtt_config,
         mqtt_subscribe: args.mqtt_subscribe,
+        auth,
+        ownership: args.owns_policy,
+        event_log: event_log_config(
+            &args.record_events,
+            args.events_max_entries,
+            args.events_max_age,
+        ),
     })
     .await;
 
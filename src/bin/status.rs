@@ -0,0 +1,59 @@
+//! commonplace-status: Show the state of a synced checkout (like git status)
+//!
+//! Usage:
+//!   commonplace-status                  # Status of the checkout containing .
+//!   commonplace-status notes/           # Status of a synced directory
+//!   commonplace-status --offline        # Local changes only
+//!   commonplace-status --json           # JSON output
+//!
+//! Exits 0 when everything is in sync, 1 when something isn't, 2 on error.
+
+use clap::Parser;
+use commonplace_doc::cli::StatusArgs;
+use commonplace_doc::sync::{collect_status, find_sync_target, StatusOptions};
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = StatusArgs::parse();
+
+    let Some(target) = find_sync_target(&args.path) else {
+        eprintln!(
+            "{} is not in a synced checkout (no .commonplace-sync.json state file found)",
+            args.path.display()
+        );
+        return ExitCode::from(2);
+    };
+
+    let client = commonplace_doc::auth::client_from_env();
+    let options = StatusOptions {
+        server: args.server,
+        offline: args.offline,
+        ignore_patterns: args.ignore,
+    };
+    let report = match collect_status(&client, &target, &options).await {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    if args.json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Failed to serialize status: {}", e);
+                return ExitCode::from(2);
+            }
+        }
+    } else {
+        print!("{}", report);
+    }
+
+    if report.is_clean() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
+}
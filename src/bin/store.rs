@@ -5,10 +5,15 @@
 
 use clap::Parser;
 use commonplace_doc::{
-    cli::StoreArgs,
+    cli::{event_log_config, StoreArgs},
     document::{ContentType, DocumentStore},
-    fs::FilesystemReconciler,
-    mqtt::{topics::validate_extension, MqttConfig, MqttService},
+    events::CommitBroadcaster,
+    fs::{FilesystemReconciler, OwnershipGuard, OwnershipPolicy},
+    mqtt::{
+        topics::validate_extension, AllowAll, CommitFeed, EmbeddedBroker, EventLog, HeadAnnouncer,
+        MqttService,
+    },
+    services::DocumentService,
     store::CommitStore,
 };
 use std::sync::Arc;
@@ -41,6 +46,21 @@ async fn main() {
         std::process::exit(1);
     }
 
+    // Load credentials for store API requests
+    let auth = args.auth.authenticator().unwrap_or_else(|e| {
+        tracing::error!("Failed to load auth credentials: {}", e);
+        std::process::exit(1);
+    });
+    if auth.is_none() {
+        tracing::warn!(
+            "No --auth-tokens or --auth-key specified - store API requests are unauthenticated"
+        );
+    }
+    if let Err(e) = args.owns_policy.check_enforceable(auth.is_some(), true) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
     // Create commit store (required for store binary)
     tracing::info!("Using database at: {}", args.database.display());
     let commit_store =
@@ -74,16 +94,29 @@ async fn main() {
         }
     }
 
-    // Create MQTT config
-    let mqtt_config = MqttConfig {
-        broker_url: args.mqtt_broker.clone(),
-        client_id: args.mqtt_client_id.clone(),
-        ..Default::default()
+    // Start the embedded broker if requested; it lives until the process exits
+    let embedded_broker = match args.embedded_broker {
+        Some(addr) => match EmbeddedBroker::start(addr, Arc::new(AllowAll)).await {
+            Ok(broker) => Some(broker),
+            Err(e) => {
+                tracing::error!("Failed to start embedded MQTT broker: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let broker_url = match (&args.mqtt_broker, &embedded_broker) {
+        (Some(url), _) => url.clone(),
+        (None, Some(broker)) => broker.url(),
+        (None, None) => unreachable!("clap requires --mqtt-broker or --embedded-broker"),
     };
 
+    // Create MQTT config
+    let mqtt_config = args.mqtt_connect.config(&broker_url, &args.mqtt_client_id);
+
     tracing::info!(
         "Connecting to MQTT broker: {} (client: {})",
-        args.mqtt_broker,
+        broker_url,
         args.mqtt_client_id
     );
 
@@ -120,89 +153,108 @@ async fn main() {
         .set_fs_root_path(args.fs_root.clone())
         .await;
 
-    // Subscribe to store-level commands (e.g., create-document)
+    // Service for store API requests and event logs; its commits are
+    // published to $store/commits and announced on {path}/head
+    let commit_broadcaster = CommitBroadcaster::new(1024);
+    let service = DocumentService::with_reconciler(
+        doc_store.clone(),
+        Some(commit_store.clone()),
+        Some(commit_broadcaster.clone()),
+        reconciler.clone(),
+        args.fs_root.clone(),
+    );
+
+    // Check edits against `owns` declarations in processes.json files
+    let service = if args.owns_policy != OwnershipPolicy::Off {
+        let guard = Arc::new(OwnershipGuard::new(
+            doc_store.clone(),
+            args.fs_root.clone(),
+            args.owns_policy,
+        ));
+        mqtt_service
+            .edits_handler()
+            .set_ownership(guard.clone())
+            .await;
+        tracing::info!("Ownership policy: {}", args.owns_policy);
+        Arc::new(service.with_ownership(guard))
+    } else {
+        Arc::new(service)
+    };
+
+    // Subscribe to store-level commands (create-document and the store API)
     if let Err(e) = mqtt_service.subscribe_store_commands().await {
         tracing::warn!("Failed to subscribe to store commands: {}", e);
     } else {
         tracing::info!("Subscribed to store commands");
     }
+    mqtt_service
+        .serve_store_api(service.clone(), Some(args.fs_root.clone()), auth)
+        .await;
 
-    // Subscribe to fs-root document itself (so we receive updates to filesystem structure)
-    if let Err(e) = mqtt_service.subscribe_path(&args.fs_root).await {
-        tracing::warn!("Failed to subscribe to fs-root {}: {}", args.fs_root, e);
-    } else {
-        tracing::info!("Subscribed to fs-root path: {}", args.fs_root);
-    }
+    let feed = CommitFeed::new(mqtt_service.client().clone(), commit_store.clone());
+    let commits = commit_broadcaster.subscribe();
+    tokio::spawn(async move { feed.run(commits).await });
+    let announcer = HeadAnnouncer::new(
+        mqtt_service.client().clone(),
+        doc_store.clone(),
+        commit_store.clone(),
+        args.fs_root.clone(),
+    );
+    let commits = commit_broadcaster.subscribe();
+    tokio::spawn(async move { announcer.run(commits).await });
 
-    // Subscribe to paths based on fs-root content
-    // Parse the versioned fs-root schema to get document paths
-    if let Ok(json) = serde_json::from_str::<serde_json::Value>(&content) {
-        if let Some(root) = json.get("root") {
-            subscribe_to_entry(&mqtt_service, root, "").await;
+    // Record events for --record-events paths into {path}.events.jsonl
+    if !args.record_events.is_empty() {
+        let event_log = EventLog::new(
+            service.clone(),
+            doc_store.clone(),
+            args.fs_root.clone(),
+            event_log_config(
+                &args.record_events,
+                args.events_max_entries,
+                args.events_max_age,
+            ),
+        );
+        if let Err(e) = mqtt_service.set_event_log(Arc::new(event_log)).await {
+            tracing::warn!("Failed to subscribe to recorded events: {}", e);
+        } else {
+            tracing::info!("Recording events for {:?}", args.record_events);
         }
     }
 
-    // Run the MQTT event loop (blocks forever)
-    tracing::info!("Starting MQTT event loop");
-    if let Err(e) = mqtt_service.run().await {
-        tracing::error!("MQTT event loop error: {}", e);
-        std::process::exit(1);
+    // Enforce bridges declared in bridges.json files
+    if let Err(e) = mqtt_service
+        .clone()
+        .enable_bridges(
+            service.clone(),
+            args.fs_root.clone(),
+            commit_broadcaster.subscribe(),
+        )
+        .await
+    {
+        tracing::warn!("Failed to enable bridges: {}", e);
     }
-}
 
-/// Recursively subscribe to document paths found in the versioned fs-root schema.
-///
-/// The schema format is:
-/// ```json
-/// {
-///   "version": 1,
-///   "root": {
-///     "type": "dir",
-///     "entries": {
-///       "notes": { "type": "dir", "entries": { "todo.txt": { "type": "doc" } } },
-///       "readme.txt": { "type": "doc" }
-///     }
-///   }
-/// }
-/// ```
-fn subscribe_to_entry<'a>(
-    mqtt: &'a MqttService,
-    entry: &'a serde_json::Value,
-    prefix: &'a str,
-) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send + 'a>> {
-    Box::pin(async move {
-        let entry_type = match entry.get("type").and_then(|t| t.as_str()) {
-            Some(t) => t,
-            None => return,
-        };
+    // Run the MQTT event loop
+    tracing::info!("Starting MQTT event loop");
+    let service_for_loop = mqtt_service.clone();
+    let event_loop = tokio::spawn(async move { service_for_loop.run().await });
 
-        match entry_type {
-            "doc" => {
-                // This is a document - subscribe to its path
-                if !prefix.is_empty() {
-                    if let Err(e) = mqtt.subscribe_path(prefix).await {
-                        tracing::warn!("Failed to subscribe to {}: {}", prefix, e);
-                    } else {
-                        tracing::info!("Subscribed to path: {}", prefix);
-                    }
-                }
-            }
-            "dir" => {
-                // This is a directory - recurse into entries
-                if let Some(serde_json::Value::Object(map)) = entry.get("entries") {
-                    for (name, child) in map {
-                        let path = if prefix.is_empty() {
-                            name.clone()
-                        } else {
-                            format!("{}/{}", prefix, name)
-                        };
-                        subscribe_to_entry(mqtt, child, &path).await;
-                    }
-                }
-            }
-            _ => {
-                tracing::debug!("Unknown entry type: {}", entry_type);
-            }
+    // Subscribe to every document in the fs-root tree, following changes
+    mqtt_service
+        .clone()
+        .follow_reconciler(reconciler.clone())
+        .await;
+
+    match event_loop.await {
+        Ok(Err(e)) => {
+            tracing::error!("MQTT event loop error: {}", e);
+            std::process::exit(1);
         }
-    })
+        Err(e) => {
+            tracing::error!("MQTT event loop panicked: {}", e);
+            std::process::exit(1);
+        }
+        Ok(Ok(())) => {}
+    }
 }
@@ -3,21 +3,24 @@
 //! This binary syncs a local file or directory with a server-side document node.
 //! It watches both directions: local changes push to server,
 //! server changes update local files.
+//!
+//! With --mqtt-broker, a single text file syncs through the broker instead,
+//! against a commonplace-store that needs no HTTP server.
 
 use clap::Parser;
+use commonplace_doc::cli::MqttConnectArgs;
+use commonplace_doc::mqtt::{MqttClient, MqttConfig};
 use commonplace_doc::sync::state_file::{compute_content_hash, SyncStateFile};
 use commonplace_doc::sync::{
-    acquire_sync_lock, build_replace_url, build_uuid_map_recursive, check_server_has_content,
-    detect_from_path, directory_sse_task, directory_watcher_task, encode_node_id,
-    ensure_fs_root_exists, file_watcher_task, fork_node, get_all_node_backed_dir_ids,
-    handle_file_created, handle_file_deleted, handle_file_modified, handle_schema_change,
-    initial_sync, is_binary_content, push_schema_to_server, scan_directory_with_contents,
-    spawn_file_sync_tasks, sse_task, subdir_sse_task, sync_schema, sync_single_file, upload_task,
-    DirEvent, FileEvent, FileSyncState, ReplaceResponse, ScanOptions, SyncState, SCHEMA_FILENAME,
+    acquire_sync_lock, collect_status, detect_from_path, directory_scan_options, encode_node_id,
+    file_watcher_task, fork_node, is_text_syncable, local_author, mqtt_sync_task,
+    plan_directory_sync, plan_file_sync, print_plan, probe_ws, push_schema_to_server,
+    run_directory_sync, run_file_sync, start_control_socket, stop_control_socket,
+    write_sparse_file, ControlSource, ScanOptions, StatusOptions, SyncContext, SyncState,
+    WsTransport,
 };
 use reqwest::Client;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Duration;
@@ -69,10 +72,18 @@ struct Args {
     #[arg(long, default_value = "false")]
     include_hidden: bool,
 
-    /// Glob patterns to ignore (can be specified multiple times)
+    /// Gitignore-style patterns to ignore, on top of any .commonplaceignore
+    /// files (can be specified multiple times)
     #[arg(long)]
     ignore: Vec<String>,
 
+    /// Only check out the subtrees matching this pattern when syncing a
+    /// directory (can be specified multiple times). Saved to the directory's
+    /// .commonplace-sparse file, which later runs keep using; delete it to go
+    /// back to a full checkout
+    #[arg(long = "include", value_name = "PATTERN", conflicts_with = "file")]
+    include: Vec<String>,
+
     /// Initial sync strategy when both sides have content
     #[arg(long, default_value = "skip", value_parser = ["local", "server", "skip"], env = "COMMONPLACE_INITIAL_SYNC")]
     initial_sync: String,
@@ -117,6 +128,47 @@ struct Args {
     /// Use case: source-of-truth files, recovery scenarios
     #[arg(long)]
     force_push: bool,
+
+    /// Name for this client's versions in conflict copies
+    /// (`name.conflict-<author>-<timestamp>.ext`); defaults to $USER
+    #[arg(long, env = "COMMONPLACE_AUTHOR")]
+    author: Option<String>,
+
+    /// Print the status of --file or --directory, as commonplace-status
+    /// does, and exit
+    #[arg(long, conflicts_with_all = ["exec", "sandbox"])]
+    status: bool,
+
+    /// Print what the initial sync would push, pull, create, delete or
+    /// overwrite with the given --initial-sync strategy, and exit without
+    /// changing anything locally or on the server
+    #[arg(long, conflicts_with_all = ["exec", "sandbox", "status", "fork_from"])]
+    dry_run: bool,
+
+    /// Bearer token for an authenticated server (also reads from COMMONPLACE_TOKEN,
+    /// which the orchestrator sets for the processes it manages)
+    #[arg(long, env = "COMMONPLACE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// How file content syncs: "sse" (SSE subscriptions and HTTP edits) or
+    /// "ws" (a WebSocket per text document, speaking the commonplace
+    /// subprotocol). Falls back to SSE if the server doesn't support it.
+    #[arg(long, default_value = "sse", value_parser = ["sse", "ws"], env = "COMMONPLACE_TRANSPORT")]
+    transport: String,
+
+    /// Sync --file through this MQTT broker (e.g. mqtt://localhost:1883)
+    /// instead of an HTTP server. --path is the file's path in the store's
+    /// fs-root; only text files can sync this way
+    #[arg(
+        long,
+        value_name = "URL",
+        requires_all = ["path", "file"],
+        conflicts_with_all = ["fork_from", "use_paths", "force_push", "dry_run"]
+    )]
+    mqtt_broker: Option<String>,
+
+    #[command(flatten)]
+    mqtt_connect: MqttConnectArgs,
 }
 
 /// Discover the fs-root document ID from the server.
@@ -430,6 +482,20 @@ async fn main() -> ExitCode {
         return ExitCode::from(1);
     }
 
+    if args.status {
+        let target = args.file.as_ref().or(args.directory.as_ref());
+        return match target {
+            Some(target) => {
+                let client = commonplace_doc::auth::bearer_client(args.token.as_deref());
+                print_status(&client, target, args.ignore.clone()).await
+            }
+            None => {
+                error!("--status requires --file or --directory");
+                ExitCode::from(1)
+            }
+        };
+    }
+
     // Exec mode requires --directory or --sandbox (doesn't make sense with single file)
     if args.exec.is_some() && args.directory.is_none() && !args.sandbox {
         error!(
@@ -438,8 +504,34 @@ async fn main() -> ExitCode {
         return ExitCode::from(1);
     }
 
-    // Create HTTP client
-    let client = Client::new();
+    if let Some(ref broker) = args.mqtt_broker {
+        let config = args.mqtt_connect.config(
+            broker,
+            &format!("commonplace-sync-{}", uuid::Uuid::new_v4()),
+        );
+        let (Some(path), Some(file)) = (args.path.clone(), args.file.clone()) else {
+            unreachable!("clap requires --path and --file with --mqtt-broker");
+        };
+        let result = run_mqtt_file_mode(
+            config,
+            path,
+            file,
+            args.push_only,
+            args.pull_only,
+            local_author(args.author.as_deref()),
+        )
+        .await;
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Error: {}", e);
+                ExitCode::from(1)
+            }
+        };
+    }
+
+    // Create HTTP client (sending the bearer token, if any, on every request)
+    let client = commonplace_doc::auth::bearer_client(args.token.as_deref());
 
     // Determine the node ID to sync with
     // Priority: --node > --path > --fork-from > --use-paths discovery
@@ -449,7 +541,7 @@ async fn main() -> ExitCode {
     } else if let Some(ref path) = args.path {
         // Path provided - resolve to UUID (or create if using single-file mode)
         info!("Resolving path '{}' to UUID...", path);
-        if let Some(ref file) = args.file {
+        if let Some(file) = args.file.as_ref().filter(|_| !args.dry_run) {
             // Single-file mode with --path: create document if it doesn't exist
             match resolve_or_create_path(&client, &args.server, path, file).await {
                 Ok(id) => {
@@ -505,6 +597,30 @@ async fn main() -> ExitCode {
         return ExitCode::from(1);
     };
 
+    if args.dry_run {
+        return run_dry_run(&client, &args, &node_id).await;
+    }
+
+    let mut context = SyncContext::default();
+    if args.transport == "ws" {
+        if args.use_paths {
+            warn!("--transport ws addresses documents by ID; using SSE with --use-paths");
+        } else {
+            match probe_ws(&args.server, &node_id, args.token.as_deref()).await {
+                Ok(()) => {
+                    info!("Syncing text files over WebSocket");
+                    context.ws = Some(WsTransport {
+                        token: args.token.clone(),
+                    });
+                }
+                Err(e) => warn!(
+                    "WebSocket transport unavailable ({}), falling back to SSE",
+                    e
+                ),
+            }
+        }
+    }
+
     // Route to appropriate mode
     let result = if args.sandbox {
         // Sandbox mode: create temp directory, sync there, run command, clean up
@@ -527,16 +643,15 @@ async fn main() -> ExitCode {
             warn!("Failed to write PID file: {}", e);
         }
 
-        // Always ignore the schema file and PID file when scanning
+        if let Err(e) = save_include_patterns(&sandbox_dir, &args.include).await {
+            error!("Failed to write sparse-checkout file: {}", e);
+            return ExitCode::from(1);
+        }
+
+        // Ignore sync's own files as in a directory sync, and the PID file
         let mut ignore_patterns = args.ignore;
-        ignore_patterns.push(SCHEMA_FILENAME.to_string());
         ignore_patterns.push(".pid".to_string());
-        ignore_patterns.push(".commonplace-synced-dirs.json".to_string());
-
-        let scan_options = ScanOptions {
-            include_hidden: args.include_hidden,
-            ignore_patterns,
-        };
+        let scan_options = directory_scan_options(args.include_hidden, ignore_patterns);
 
         // exec is required by clap when sandbox is set
         let exec_cmd = args.exec.expect("--sandbox requires --exec");
@@ -562,6 +677,8 @@ async fn main() -> ExitCode {
             true, // sandbox mode
             args.push_only,
             args.pull_only,
+            local_author(args.author.as_deref()),
+            context,
         )
         .await;
 
@@ -582,16 +699,14 @@ async fn main() -> ExitCode {
             }
         };
 
-        // Always ignore the schema file (.commonplace.json) when scanning
-        let mut ignore_patterns = args.ignore;
-        ignore_patterns.push(SCHEMA_FILENAME.to_string());
-        ignore_patterns.push(".commonplace-sync.lock".to_string()); // Ignore lock file
-        ignore_patterns.push(".commonplace-synced-dirs.json".to_string()); // Ignore synced dirs state
+        if let Err(e) = save_include_patterns(&directory, &args.include).await {
+            error!("Failed to write sparse-checkout file: {}", e);
+            return ExitCode::from(1);
+        }
 
-        let scan_options = ScanOptions {
-            include_hidden: args.include_hidden,
-            ignore_patterns,
-        };
+        // Always ignore the schema file (.commonplace.json) and sync's own
+        // files when scanning
+        let scan_options = directory_scan_options(args.include_hidden, args.ignore);
 
         if let Some(exec_cmd) = args.exec {
             // Exec mode: sync directory, run command, exit when command exits
@@ -608,11 +723,13 @@ async fn main() -> ExitCode {
                 false, // not sandbox mode
                 args.push_only,
                 args.pull_only,
+                local_author(args.author.as_deref()),
+                context,
             )
             .await
         } else {
             // Normal directory sync mode
-            run_directory_mode(
+            run_directory_sync(
                 client,
                 args.server,
                 node_id,
@@ -622,12 +739,15 @@ async fn main() -> ExitCode {
                 args.use_paths,
                 args.push_only,
                 args.pull_only,
+                local_author(args.author.as_deref()),
+                context,
+                ctrl_c(),
             )
             .await
             .map(|_| 0u8)
         }
     } else if let Some(file) = args.file {
-        run_file_mode(
+        run_file_sync(
             client,
             args.server,
             node_id,
@@ -635,6 +755,9 @@ async fn main() -> ExitCode {
             args.push_only,
             args.pull_only,
             args.force_push,
+            local_author(args.author.as_deref()),
+            context,
+            ctrl_c(),
         )
         .await
         .map(|_| 0u8)
@@ -643,7 +766,10 @@ async fn main() -> ExitCode {
     };
 
     match result {
-        Ok(code) => ExitCode::from(code),
+        Ok(code) => {
+            info!("Goodbye!");
+            ExitCode::from(code)
+        }
         Err(e) => {
             error!("Error: {}", e);
             ExitCode::from(1)
@@ -651,195 +777,97 @@ async fn main() -> ExitCode {
     }
 }
 
-/// Run single-file sync mode (original behavior)
-async fn run_file_mode(
-    client: Client,
-    server: String,
-    node_id: String,
-    file: PathBuf,
-    push_only: bool,
-    pull_only: bool,
-    force_push: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mode = if force_push {
-        "force-push"
-    } else if push_only {
-        "push-only"
-    } else if pull_only {
-        "pull-only"
-    } else {
-        "bidirectional"
-    };
-    info!(
-        "Starting commonplace-sync (file mode, {}): server={}, node={}, file={}",
-        mode,
-        server,
-        node_id,
-        file.display()
-    );
+/// Wait for Ctrl+C.
+async fn ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+    info!("Shutting down...");
+}
 
-    // Verify document exists
-    let doc_url = format!("{}/docs/{}/info", server, encode_node_id(&node_id));
-    let resp = client.get(&doc_url).send().await?;
-    if !resp.status().is_success() {
-        error!("Document {} not found on server", node_id);
-        return Err(format!("Document {} not found", node_id).into());
+/// Make `directory` a sparse checkout of the --include patterns, if any were
+/// given. Without them an existing sparse-checkout file is left as it is.
+async fn save_include_patterns(directory: &Path, include: &[String]) -> std::io::Result<()> {
+    if include.is_empty() {
+        return Ok(());
     }
-    info!("Connected to document {}", node_id);
+    tokio::fs::create_dir_all(directory).await?;
+    write_sparse_file(directory, include).await?;
+    info!(
+        "Checking out only {} in {}",
+        include.join(", "),
+        directory.display()
+    );
+    Ok(())
+}
 
-    // Load or create state file for offline change detection
-    let state_file_path = SyncStateFile::state_file_path(&file);
-    let state_file = SyncStateFile::load_or_create(&file, &server, &node_id)
+/// Print the plan for the initial sync of --file or --directory without
+/// carrying it out.
+async fn run_dry_run(client: &Client, args: &Args, node_id: &str) -> ExitCode {
+    let plan = if let Some(ref directory) = args.directory {
+        // Scan as a real run would, previewing --include without saving it
+        let scan_options = ScanOptions {
+            include_patterns: (!args.include.is_empty()).then(|| args.include.clone()),
+            ..directory_scan_options(args.include_hidden, args.ignore.clone())
+        };
+        plan_directory_sync(
+            client,
+            &args.server,
+            node_id,
+            directory,
+            &scan_options,
+            &args.initial_sync,
+        )
         .await
-        .map_err(|e| format!("Failed to load state file: {}", e))?;
-
-    // Check for offline local changes and merge them before initial_sync
-    if let Some(last_cid) = &state_file.last_synced_cid {
-        if file.exists() {
-            let current_content = tokio::fs::read(&file).await?;
-            let current_hash = compute_content_hash(&current_content);
-            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
-
-            if state_file.has_file_changed(&file_name, &current_hash) {
-                info!(
-                    "Detected offline local changes (last synced at {})",
-                    last_cid
-                );
+    } else if let Some(ref file) = args.file {
+        plan_file_sync(client, &args.server, node_id, file, args.force_push).await
+    } else {
+        Err("--dry-run requires --file or --directory".into())
+    };
 
-                // Push offline changes using replace endpoint with last_synced_cid as parent.
-                // The server computes a diff from historical state and creates a CRDT update,
-                // which automatically merges with any concurrent server changes.
-                let content_info = detect_from_path(&file);
-                let is_binary = content_info.is_binary || is_binary_content(&current_content);
-                let content_str = if is_binary {
-                    use base64::{engine::general_purpose::STANDARD, Engine};
-                    STANDARD.encode(&current_content)
-                } else {
-                    String::from_utf8_lossy(&current_content).to_string()
-                };
-
-                let replace_url = build_replace_url(&server, &node_id, last_cid, false);
-                info!("Pushing offline changes via CRDT merge...");
-
-                match client
-                    .post(&replace_url)
-                    .header("content-type", "text/plain")
-                    .body(content_str)
-                    .send()
-                    .await
-                {
-                    Ok(resp) => {
-                        if resp.status().is_success() {
-                            match resp.json::<ReplaceResponse>().await {
-                                Ok(result) => {
-                                    info!(
-                                        "Merged offline changes: {} chars inserted, {} deleted (new cid: {})",
-                                        result.summary.chars_inserted,
-                                        result.summary.chars_deleted,
-                                        &result.cid[..8.min(result.cid.len())]
-                                    );
-                                }
-                                Err(e) => {
-                                    warn!("Failed to parse replace response: {}", e);
-                                }
-                            }
-                        } else {
-                            let status = resp.status();
-                            let body = resp.text().await.unwrap_or_default();
-                            warn!("Failed to push offline changes: {} - {}", status, body);
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to push offline changes: {}", e);
-                    }
-                }
-            }
+    match plan {
+        Ok(plan) => {
+            print_plan(&plan);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Dry run failed: {}", e);
+            ExitCode::from(1)
         }
     }
+}
 
-    // Initialize shared state with loaded state file
-    let state = Arc::new(RwLock::new(SyncState::with_state_file(
-        state_file,
-        state_file_path,
-    )));
-
-    // Perform initial sync
-    initial_sync(&client, &server, &node_id, &file, &state).await?;
-
-    // Create channel for file events
-    let (file_tx, file_rx) = mpsc::channel::<FileEvent>(100);
-
-    // Start file watcher task (skip if pull-only)
-    let watcher_handle = if !pull_only {
-        Some(tokio::spawn(file_watcher_task(file.clone(), file_tx)))
-    } else {
-        info!("Pull-only mode: skipping file watcher");
-        None
+/// Print the status of `target` (a synced file or directory) as
+/// `commonplace-status` would, with the same exit codes.
+async fn print_status(client: &Client, target: &Path, ignore: Vec<String>) -> ExitCode {
+    let options = StatusOptions {
+        ignore_patterns: ignore,
+        ..Default::default()
     };
-
-    // Start file change handler task (skip if pull-only)
-    // File mode always uses ID-based API (use_paths=false)
-    let upload_handle = if !pull_only {
-        Some(tokio::spawn(upload_task(
-            client.clone(),
-            server.clone(),
-            node_id.clone(),
-            file.clone(),
-            state.clone(),
-            file_rx,
-            false, // use_paths: file mode uses ID-based API
-            force_push,
-        )))
-    } else {
-        None
+    let report = match collect_status(client, target, &options).await {
+        Ok(report) => report,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::from(2);
+        }
     };
-
-    // Start SSE subscription task (skip if push-only)
-    let sse_handle = if !push_only {
-        Some(tokio::spawn(sse_task(
-            client.clone(),
-            server.clone(),
-            node_id.clone(),
-            file.clone(),
-            state.clone(),
-            false, // use_paths: file mode uses ID-based API
-        )))
+    print!("{}", report);
+    if report.is_clean() {
+        ExitCode::SUCCESS
     } else {
-        info!("Push-only mode: skipping SSE subscription");
-        None
-    };
-
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c().await?;
-    info!("Shutting down...");
-
-    // Cancel tasks
-    if let Some(handle) = watcher_handle {
-        handle.abort();
-    }
-    if let Some(handle) = upload_handle {
-        handle.abort();
-    }
-    if let Some(handle) = sse_handle {
-        handle.abort();
+        ExitCode::from(1)
     }
-
-    info!("Goodbye!");
-    Ok(())
 }
 
-/// Run directory sync mode
-#[allow(clippy::too_many_arguments)]
-async fn run_directory_mode(
-    client: Client,
-    server: String,
-    fs_root_id: String,
-    directory: PathBuf,
-    options: ScanOptions,
-    initial_sync_strategy: String,
-    use_paths: bool,
+/// Run single-file sync mode over MQTT
+async fn run_mqtt_file_mode(
+    config: MqttConfig,
+    path: String,
+    file: PathBuf,
     push_only: bool,
     pull_only: bool,
+    author: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mode = if push_only {
         "push-only"
@@ -849,273 +877,116 @@ async fn run_directory_mode(
         "bidirectional"
     };
     info!(
-        "Starting commonplace-sync (directory mode, {}): server={}, fs-root={}, directory={}, use_paths={}",
+        "Starting commonplace-sync (file mode over MQTT, {}): broker={}, path={}, file={}",
         mode,
-        server,
-        fs_root_id,
-        directory.display(),
-        use_paths
+        config.broker_url,
+        path,
+        file.display()
     );
 
-    // Verify directory exists
-    if !directory.is_dir() {
-        error!("Not a directory: {}", directory.display());
-        return Err(format!("Not a directory: {}", directory.display()).into());
-    }
-
-    // Verify fs-root document exists (or create it)
-    ensure_fs_root_exists(&client, &server, &fs_root_id).await?;
-
-    // Check if server has existing schema
-    let server_has_content = check_server_has_content(&client, &server, &fs_root_id).await;
-
-    // If strategy is "server" and server has content, pull server files first
-    // This creates the temporary file_states that handle_schema_change needs
-    let file_states: Arc<RwLock<HashMap<String, FileSyncState>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-
-    if initial_sync_strategy == "server" && server_has_content {
-        info!("Pulling server schema first (initial-sync=server)...");
-        // Don't spawn tasks here - the main loop will spawn them for all files
-        handle_schema_change(
-            &client,
-            &server,
-            &fs_root_id,
-            &directory,
-            &file_states,
-            false,
-            use_paths,
-            push_only,
-            pull_only,
+    if !is_text_syncable(&file) {
+        return Err(format!(
+            "{} is not a text file; only text files sync over MQTT",
+            file.display()
         )
-        .await?;
-        info!("Server files pulled to local directory");
+        .into());
     }
 
-    // Synchronize schema between local and server
-    sync_schema(
-        &client,
-        &server,
-        &fs_root_id,
-        &directory,
-        &options,
-        &initial_sync_strategy,
-        server_has_content,
-    )
-    .await?;
+    // The state file records the broker and path in place of server and node
+    let state_file_path = SyncStateFile::state_file_path(&file);
+    let state_file = SyncStateFile::load_or_create(&file, &config.broker_url, &path)
+        .await
+        .map_err(|e| format!("Failed to load state file: {}", e))?;
 
-    // Scan files with contents and push each one
-    info!("Syncing file contents...");
-    let files = scan_directory_with_contents(&directory, &options)
-        .map_err(|e| format!("Scan error: {}", e))?;
-
-    // Wait for reconciler to process the schema and create documents
-    sleep(Duration::from_millis(100)).await;
-
-    // When not using paths, fetch the updated schema to get UUIDs assigned by reconciler
-    // Build a map of relative_path -> node_id for UUID resolution
-    // This recursively follows node-backed directories to get all UUIDs
-    let uuid_map = if !use_paths {
-        let map = build_uuid_map_recursive(&client, &server, &fs_root_id).await;
-        info!(
-            "Resolved {} UUIDs from server schema for initial sync",
-            map.len()
-        );
-        for (path, uuid) in &map {
-            debug!("  UUID map: {} -> {}", path, uuid);
+    // A file unchanged since the last sync takes the store's edits; one
+    // edited offline is merged over them once the history is in
+    let unchanged = match (&state_file.last_synced_cid, tokio::fs::read(&file).await) {
+        (Some(_), Ok(content)) => {
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            (!state_file.has_file_changed(&file_name, &compute_content_hash(&content)))
+                .then(|| String::from_utf8_lossy(&content).to_string())
         }
-        map
-    } else {
-        std::collections::HashMap::new()
+        _ => None,
     };
-
-    // Sync each file
-    for file in &files {
-        let file_path = directory.join(&file.relative_path);
-        if let Err(e) = sync_single_file(
-            &client,
-            &server,
-            &fs_root_id,
-            &directory,
-            file,
-            &file_path,
-            &uuid_map,
-            &initial_sync_strategy,
-            &file_states,
-            use_paths,
-        )
-        .await
-        {
-            warn!("Failed to sync file {}: {}", file.relative_path, e);
-        }
+    let mut sync_state = SyncState::with_state_file(state_file, state_file_path);
+    if let Some(content) = unchanged {
+        sync_state.last_written_content = content;
     }
+    let state = Arc::new(RwLock::new(sync_state));
 
-    info!("Initial sync complete: {} files synced", files.len());
-
-    // Start directory watcher (skip if pull-only)
-    let (dir_tx, mut dir_rx) = mpsc::channel::<DirEvent>(100);
-    let watcher_handle = if !pull_only {
-        Some(tokio::spawn(directory_watcher_task(
-            directory.clone(),
-            dir_tx,
-            options.clone(),
-        )))
-    } else {
-        info!("Pull-only mode: skipping directory watcher");
-        None
-    };
+    let client = Arc::new(MqttClient::connect(config).await?);
+    let client_for_loop = client.clone();
+    let loop_handle = tokio::spawn(async move {
+        if let Err(e) = client_for_loop.run_event_loop().await {
+            error!("MQTT client event loop error: {}", e);
+        }
+    });
 
-    // Start SSE task for fs-root (skip if push-only)
-    let sse_handle = if !push_only {
-        Some(tokio::spawn(directory_sse_task(
-            client.clone(),
-            server.clone(),
-            fs_root_id.clone(),
-            directory.clone(),
-            file_states.clone(),
-            use_paths,
-            push_only,
-            pull_only,
-        )))
+    let (rx, watcher_handle) = if pull_only {
+        info!("Pull-only mode: skipping file watcher");
+        (None, None)
     } else {
-        info!("Push-only mode: skipping SSE subscription");
-        None
+        let (tx, rx) = mpsc::channel(100);
+        (
+            Some(rx),
+            Some(tokio::spawn(file_watcher_task(file.clone(), tx, None))),
+        )
     };
-
-    // Start SSE tasks for all node-backed subdirectories (skip if push-only)
-    // This allows files created in subdirectories to propagate to other sync clients
-    if !push_only {
-        let node_backed_subdirs = get_all_node_backed_dir_ids(&client, &server, &fs_root_id).await;
-        info!(
-            "Found {} node-backed subdirectories to watch",
-            node_backed_subdirs.len()
-        );
-        for (subdir_path, subdir_node_id) in node_backed_subdirs {
-            info!(
-                "Spawning SSE task for node-backed subdir: {} ({})",
-                subdir_path, subdir_node_id
-            );
-            tokio::spawn(subdir_sse_task(
-                client.clone(),
-                server.clone(),
-                fs_root_id.clone(),
-                subdir_path,
-                subdir_node_id,
-                directory.clone(),
-                file_states.clone(),
-                use_paths,
-                push_only,
-                pull_only,
-            ));
-        }
-    }
-
-    // Start file sync tasks for each file and store handles in FileSyncState
-    {
-        let mut states = file_states.write().await;
-        for (relative_path, file_state) in states.iter_mut() {
-            let file_path = directory.join(relative_path);
-
-            // Spawn sync tasks and store handles in FileSyncState for cleanup on deletion
-            file_state.task_handles = spawn_file_sync_tasks(
-                client.clone(),
-                server.clone(),
-                file_state.identifier.clone(),
-                file_path,
-                file_state.state.clone(),
-                file_state.use_paths,
-                push_only,
-                pull_only,
-                false, // force_push: directory mode doesn't support force-push
-            );
-        }
+    if push_only {
+        info!("Push-only mode: not writing the store's edits");
     }
+    let mut sync_handle = tokio::spawn(mqtt_sync_task(
+        client.clone(),
+        path,
+        file.clone(),
+        state.clone(),
+        rx,
+        push_only,
+        author,
+    ));
+
+    let control_handle = start_control_socket(
+        &file,
+        ControlSource::File {
+            path: file.clone(),
+            state: state.clone(),
+        },
+    )
+    .await;
 
-    // Handle directory-level events (file creation/deletion)
-    let dir_event_handle = tokio::spawn({
-        let client = client.clone();
-        let server = server.clone();
-        let fs_root_id = fs_root_id.clone();
-        let directory = directory.clone();
-        let options = options.clone();
-        let file_states = file_states.clone();
-        async move {
-            while let Some(event) = dir_rx.recv().await {
-                match event {
-                    DirEvent::Created(path) => {
-                        handle_file_created(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                            &file_states,
-                            use_paths,
-                            push_only,
-                            pull_only,
-                        )
-                        .await;
-                    }
-                    DirEvent::Modified(path) => {
-                        handle_file_modified(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                        )
-                        .await;
-                    }
-                    DirEvent::Deleted(path) => {
-                        handle_file_deleted(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                            &file_states,
-                        )
-                        .await;
-                    }
-                }
-            }
+    let result = tokio::select! {
+        signal = tokio::signal::ctrl_c() => {
+            info!("Shutting down...");
+            sync_handle.abort();
+            signal.map_err(Into::into)
         }
-    });
-
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c().await?;
-    info!("Shutting down...");
+        finished = &mut sync_handle => match finished {
+            Ok(Ok(())) => Err("MQTT client stopped".into()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(e) => Err(e.into()),
+        },
+    };
 
-    // Cancel all tasks
     if let Some(handle) = watcher_handle {
         handle.abort();
     }
-    if let Some(handle) = sse_handle {
-        handle.abort();
-    }
-    dir_event_handle.abort();
-
-    // Abort all per-file sync tasks
-    {
-        let states = file_states.read().await;
-        for file_state in states.values() {
-            for handle in &file_state.task_handles {
-                handle.abort();
-            }
-        }
-    }
+    state.write().await.checkpoint(&file).await;
+    stop_control_socket(&file, control_handle);
+    let _ = client.disconnect().await;
+    loop_handle.abort();
 
-    info!("Goodbye!");
-    Ok(())
+    if result.is_ok() {
+        info!("Goodbye!");
+    }
+    result
 }
 
 /// Run exec mode: sync directory, run command, exit when command exits
 ///
 /// This mode is designed for workflows where a user wants to work on synced files
 /// with their preferred editor/tool, and have everything tear down cleanly when done.
+/// The sync is [`run_directory_sync`]'s; this only launches the command once the
+/// initial sync is done and supervises it.
 #[allow(clippy::too_many_arguments)]
 async fn run_exec_mode(
     client: Client,
@@ -1130,19 +1001,11 @@ async fn run_exec_mode(
     sandbox: bool,
     push_only: bool,
     pull_only: bool,
+    author: String,
+    mut context: SyncContext,
 ) -> Result<u8, Box<dyn std::error::Error>> {
-    let mode = if push_only {
-        "push-only"
-    } else if pull_only {
-        "pull-only"
-    } else {
-        "bidirectional"
-    };
     info!(
-        "Starting commonplace-sync (exec mode, {}): server={}, fs-root={}, directory={}, exec={}",
-        mode,
-        server,
-        fs_root_id,
+        "Starting commonplace-sync (exec mode): directory={}, exec={}",
         directory.display(),
         exec_cmd
     );
@@ -1152,227 +1015,9 @@ async fn run_exec_mode(
         info!("Creating directory: {}", directory.display());
         tokio::fs::create_dir_all(&directory).await?;
     }
-    if !directory.is_dir() {
-        error!("Not a directory: {}", directory.display());
-        return Err(format!("Not a directory: {}", directory.display()).into());
-    }
 
-    // Verify fs-root document exists (or create it)
-    ensure_fs_root_exists(&client, &server, &fs_root_id).await?;
-
-    // Check if server has existing schema
-    let server_has_content = check_server_has_content(&client, &server, &fs_root_id).await;
-
-    // If strategy is "server" and server has content, pull server files first
-    let file_states: Arc<RwLock<HashMap<String, FileSyncState>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-
-    if initial_sync_strategy == "server" && server_has_content {
-        info!("Pulling server schema first (initial-sync=server)...");
-        handle_schema_change(
-            &client,
-            &server,
-            &fs_root_id,
-            &directory,
-            &file_states,
-            false,
-            use_paths,
-            push_only,
-            pull_only,
-        )
-        .await?;
-        info!("Server files pulled to local directory");
-    }
-
-    // Synchronize schema between local and server
-    sync_schema(
-        &client,
-        &server,
-        &fs_root_id,
-        &directory,
-        &options,
-        &initial_sync_strategy,
-        server_has_content,
-    )
-    .await?;
-
-    // Sync file contents
-    info!("Syncing file contents...");
-    let files = scan_directory_with_contents(&directory, &options)
-        .map_err(|e| format!("Scan error: {}", e))?;
-
-    // Wait for reconciler to process the schema and create documents
-    sleep(Duration::from_millis(100)).await;
-
-    // When not using paths, fetch the updated schema to get UUIDs assigned by reconciler
-    // Build a map of relative_path -> node_id for UUID resolution
-    // This recursively follows node-backed directories to get all UUIDs
-    let uuid_map = if !use_paths {
-        let map = build_uuid_map_recursive(&client, &server, &fs_root_id).await;
-        info!(
-            "Resolved {} UUIDs from server schema for initial sync",
-            map.len()
-        );
-        for (path, uuid) in &map {
-            debug!("  UUID map: {} -> {}", path, uuid);
-        }
-        map
-    } else {
-        std::collections::HashMap::new()
-    };
-
-    // Sync each file
-    for file in &files {
-        let file_path = directory.join(&file.relative_path);
-        if let Err(e) = sync_single_file(
-            &client,
-            &server,
-            &fs_root_id,
-            &directory,
-            file,
-            &file_path,
-            &uuid_map,
-            &initial_sync_strategy,
-            &file_states,
-            use_paths,
-        )
-        .await
-        {
-            warn!("Failed to sync file {}: {}", file.relative_path, e);
-        }
-    }
-
-    info!("Initial sync complete: {} files synced", files.len());
-
-    // Start directory watcher (skip if pull-only)
-    let (dir_tx, mut dir_rx) = mpsc::channel::<DirEvent>(100);
-    let watcher_handle = if !pull_only {
-        Some(tokio::spawn(directory_watcher_task(
-            directory.clone(),
-            dir_tx,
-            options.clone(),
-        )))
-    } else {
-        info!("Pull-only mode: skipping directory watcher");
-        None
-    };
-
-    // Start SSE task for fs-root (skip if push-only)
-    let sse_handle = if !push_only {
-        Some(tokio::spawn(directory_sse_task(
-            client.clone(),
-            server.clone(),
-            fs_root_id.clone(),
-            directory.clone(),
-            file_states.clone(),
-            use_paths,
-            push_only,
-            pull_only,
-        )))
-    } else {
-        info!("Push-only mode: skipping SSE subscription");
-        None
-    };
-
-    // Start SSE tasks for all node-backed subdirectories (skip if push-only)
-    if !push_only {
-        let node_backed_subdirs = get_all_node_backed_dir_ids(&client, &server, &fs_root_id).await;
-        info!(
-            "Found {} node-backed subdirectories to watch",
-            node_backed_subdirs.len()
-        );
-        for (subdir_path, subdir_node_id) in node_backed_subdirs {
-            info!(
-                "Spawning SSE task for node-backed subdir: {} ({})",
-                subdir_path, subdir_node_id
-            );
-            tokio::spawn(subdir_sse_task(
-                client.clone(),
-                server.clone(),
-                fs_root_id.clone(),
-                subdir_path,
-                subdir_node_id,
-                directory.clone(),
-                file_states.clone(),
-                use_paths,
-                push_only,
-                pull_only,
-            ));
-        }
-    }
-
-    // Start file sync tasks for each file
-    {
-        let mut states = file_states.write().await;
-        for (relative_path, file_state) in states.iter_mut() {
-            let file_path = directory.join(relative_path);
-            file_state.task_handles = spawn_file_sync_tasks(
-                client.clone(),
-                server.clone(),
-                file_state.identifier.clone(),
-                file_path,
-                file_state.state.clone(),
-                file_state.use_paths,
-                push_only,
-                pull_only,
-                false, // force_push: sandbox mode doesn't support force-push
-            );
-        }
-    }
-
-    // Start directory event handler (same logic as run_directory_mode)
-    let dir_event_handle = tokio::spawn({
-        let client = client.clone();
-        let server = server.clone();
-        let fs_root_id = fs_root_id.clone();
-        let directory = directory.clone();
-        let options = options.clone();
-        let file_states = file_states.clone();
-        async move {
-            while let Some(event) = dir_rx.recv().await {
-                match event {
-                    DirEvent::Created(path) => {
-                        handle_file_created(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                            &file_states,
-                            use_paths,
-                            push_only,
-                            pull_only,
-                        )
-                        .await;
-                    }
-                    DirEvent::Modified(path) => {
-                        handle_file_modified(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                        )
-                        .await;
-                    }
-                    DirEvent::Deleted(path) => {
-                        handle_file_deleted(
-                            &client,
-                            &server,
-                            &fs_root_id,
-                            &directory,
-                            &path,
-                            &options,
-                            &file_states,
-                        )
-                        .await;
-                    }
-                }
-            }
-        }
-    });
+    // A sandbox is deleted afterwards, so it has nothing to journal or report
+    context.ephemeral = sandbox;
 
     // Build the command to execute
     // Parse exec_cmd - if it contains spaces and no exec_args, treat as shell command
@@ -1431,13 +1076,44 @@ async fn run_exec_mode(
         }
     }
 
+    // The sync only starts waiting on this once the initial sync is done, so
+    // the command starts in a synced directory
+    let mut exit_code = 1;
+    run_directory_sync(
+        client,
+        server,
+        fs_root_id,
+        directory,
+        options,
+        initial_sync_strategy,
+        use_paths,
+        push_only,
+        pull_only,
+        author,
+        context,
+        async {
+            exit_code = supervise_command(cmd, &program).await;
+        },
+    )
+    .await?;
+
+    Ok(exit_code)
+}
+
+/// Spawn `cmd` and wait for it to exit, or for SIGINT/SIGTERM, which it
+/// passes on to the command's process group. Returns the exit code.
+async fn supervise_command(mut cmd: tokio::process::Command, program: &str) -> u8 {
     // Spawn the child process in the synced directory
-    let mut child = cmd
-        .spawn()
-        .map_err(|e| format!("Failed to spawn command '{}': {}", program, e))?;
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to spawn command '{}': {}", program, e);
+            return 1;
+        }
+    };
 
     // Wait for child to exit OR signal
-    let exit_code = tokio::select! {
+    tokio::select! {
         status = child.wait() => {
             match status {
                 Ok(s) => {
@@ -1503,31 +1179,7 @@ async fn run_exec_mode(
             }
             130 // Standard exit code for Ctrl+C
         }
-    };
-
-    info!("Shutting down sync...");
-
-    // Cancel all tasks
-    if let Some(handle) = watcher_handle {
-        handle.abort();
-    }
-    if let Some(handle) = sse_handle {
-        handle.abort();
-    }
-    dir_event_handle.abort();
-
-    // Abort all per-file sync tasks
-    {
-        let states = file_states.read().await;
-        for file_state in states.values() {
-            for handle in &file_state.task_handles {
-                handle.abort();
-            }
-        }
     }
-
-    info!("Goodbye!");
-    Ok(exit_code)
 }
 
 /// Clean up stale sandbox directories from previous runs.
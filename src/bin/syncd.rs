@@ -0,0 +1,230 @@
+//! commonplace-syncd: Sync many files and directories in one process
+//!
+//! Usage:
+//!   commonplace-syncd --config roots.json                     # Run the daemon
+//!   commonplace-syncd add notes --node <id> --directory notes # Add a root
+//!   commonplace-syncd pause notes                             # Stop it for now
+//!   commonplace-syncd resume notes                            # Start it again
+//!   commonplace-syncd remove notes                            # Stop it for good
+//!   commonplace-syncd list [--json]                           # What each root is doing
+//!
+//! The daemon syncs each root as `commonplace-sync` would, sharing one HTTP
+//! client and one file watcher between them, and takes commands on the Unix
+//! socket given by --socket. Each root journals its offline edits beside
+//! itself, as `commonplace-sync` does.
+
+use clap::Parser;
+use commonplace_doc::cli::{SyncdArgs, SyncdCommand, SyncdRootArgs};
+use commonplace_doc::sync::{
+    local_author, query_daemon, serve_daemon_socket, DaemonConfig, DaemonRequest, RootInfo,
+    RootSpec, RootState, SyncDaemon,
+};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+#[cfg(not(unix))]
+use tokio::signal;
+#[cfg(unix)]
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tracing::{error, info};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = SyncdArgs::parse();
+
+    match args.command {
+        Some(command) => send_command(&args.socket, command, args.json).await,
+        None => {
+            tracing_subscriber::fmt()
+                .with_env_filter(
+                    tracing_subscriber::EnvFilter::from_default_env()
+                        .add_directive(tracing::Level::INFO.into()),
+                )
+                .init();
+            run_daemon(args).await
+        }
+    }
+}
+
+/// Run the daemon until SIGINT or SIGTERM, then stop every root cleanly.
+async fn run_daemon(args: SyncdArgs) -> ExitCode {
+    let config = match &args.config {
+        Some(path) => match load_config(path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to load {}: {}", path.display(), e);
+                return ExitCode::from(1);
+            }
+        },
+        None => DaemonConfig::default(),
+    };
+
+    let client = commonplace_doc::auth::bearer_client(args.token.as_deref());
+    let daemon = SyncDaemon::new(
+        client,
+        args.server.clone(),
+        local_author(args.author.as_deref()),
+    );
+
+    let socket_handle = match serve_daemon_socket(&args.socket, daemon.clone()).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", args.socket.display(), e);
+            return ExitCode::from(1);
+        }
+    };
+    info!(
+        "commonplace-syncd listening on {} (server={})",
+        args.socket.display(),
+        args.server
+    );
+
+    for root in config.roots {
+        let name = root.name.clone();
+        if let Err(e) = daemon.add(root) {
+            error!("Failed to add root {}: {}", name, e);
+        }
+    }
+
+    wait_for_shutdown_signal().await;
+    info!("Shutting down...");
+
+    socket_handle.abort();
+    daemon.shutdown().await;
+    let _ = std::fs::remove_file(&args.socket);
+
+    info!("Goodbye!");
+    ExitCode::SUCCESS
+}
+
+/// Wait for either SIGINT (Ctrl+C) or SIGTERM.
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    let mut sigint =
+        unix_signal(SignalKind::interrupt()).expect("Failed to register SIGINT handler");
+    let mut sigterm =
+        unix_signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+    tokio::select! {
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    signal::ctrl_c()
+        .await
+        .expect("Failed to register Ctrl+C handler");
+}
+
+fn load_config(path: &Path) -> Result<DaemonConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config: DaemonConfig = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    for root in &config.roots {
+        root.validate()?;
+    }
+    Ok(config)
+}
+
+/// Send one command to the daemon and print its answer.
+async fn send_command(socket: &Path, command: SyncdCommand, json: bool) -> ExitCode {
+    let request = match command {
+        SyncdCommand::List => DaemonRequest::List,
+        SyncdCommand::Add(root) => DaemonRequest::Add {
+            root: root_spec(root),
+        },
+        SyncdCommand::Remove { name } => DaemonRequest::Remove { name },
+        SyncdCommand::Pause { name } => DaemonRequest::Pause { name },
+        SyncdCommand::Resume { name } => DaemonRequest::Resume { name },
+    };
+
+    let response = match query_daemon(socket, &request).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!(
+                "Failed to reach commonplace-syncd at {}: {}",
+                socket.display(),
+                e
+            );
+            return ExitCode::from(2);
+        }
+    };
+    if let Some(error) = response.error {
+        eprintln!("{}", error);
+        return ExitCode::from(1);
+    }
+
+    if json {
+        match serde_json::to_string_pretty(&response.roots) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Failed to serialize roots: {}", e);
+                return ExitCode::from(2);
+            }
+        }
+    } else {
+        print_roots(&response.roots);
+    }
+    ExitCode::SUCCESS
+}
+
+/// The root to add; paths are made absolute, as the daemon's working
+/// directory needn't be ours.
+fn root_spec(args: SyncdRootArgs) -> RootSpec {
+    let absolute = |path: PathBuf| match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => cwd.join(path),
+        _ => path,
+    };
+    RootSpec {
+        name: args.name,
+        node: args.node,
+        directory: args.directory.map(absolute),
+        file: args.file.map(absolute),
+        push_only: args.push_only,
+        pull_only: args.pull_only,
+        force_push: args.force_push,
+        initial_sync: args.initial_sync,
+        include_hidden: args.include_hidden,
+        ignore: args.ignore,
+    }
+}
+
+fn print_roots(roots: &[RootInfo]) {
+    if roots.is_empty() {
+        println!("No roots");
+        return;
+    }
+    for root in roots {
+        let mode = if root.spec.force_push {
+            "force-push"
+        } else if root.spec.push_only {
+            "push-only"
+        } else if root.spec.pull_only {
+            "pull-only"
+        } else {
+            "bidirectional"
+        };
+        let state = match root.state {
+            RootState::Running => "running",
+            RootState::Paused => "paused",
+            RootState::Failed => "failed",
+        };
+        println!(
+            "{}: {} <-> {} ({}, {})",
+            root.spec.name,
+            root.spec.target().display(),
+            root.spec.node,
+            mode,
+            state
+        );
+        if let Some(error) = &root.error {
+            println!("    error: {}", error);
+        }
+        if let Some(sync) = &root.sync {
+            println!(
+                "    {} file(s), {} in flight",
+                sync.files,
+                sync.in_flight.len()
+            );
+        }
+    }
+}
@@ -1,5 +1,14 @@
+use crate::auth::{AuthChain, AuthError, Authenticator, SigningKey, StaticTokens};
+use crate::fs::OwnershipPolicy;
+use crate::mqtt::broker::DEFAULT_BROKER_ADDR;
+use crate::mqtt::event_log::{EventLogConfig, EventRetention, DEFAULT_MAX_EVENTS};
+use crate::mqtt::{MqttConfig, MqttProtocol, MqttTls, ReconnectPolicy};
+use crate::orchestrator::DEFAULT_TOKEN_TTL;
 use clap::Parser;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// CLI arguments for the combined server (legacy, for backwards compatibility)
 #[derive(Parser, Debug)]
@@ -30,10 +39,43 @@ pub struct Args {
     #[clap(long, value_name = "ID")]
     pub mqtt_client_id: Option<String>,
 
-    /// Document paths to subscribe via MQTT (repeatable, requires --mqtt-broker)
+    #[clap(flatten)]
+    pub mqtt_connect: MqttConnectArgs,
+
+    /// Run an in-process MQTT broker (default address 127.0.0.1:1883).
+    /// The server connects to it unless --mqtt-broker points elsewhere.
+    /// It keeps no persistent sessions (clients must connect clean), never
+    /// retransmits QoS 1 deliveries and does not support topic aliases.
+    #[clap(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = DEFAULT_BROKER_ADDR)]
+    pub embedded_broker: Option<SocketAddr>,
+
+    /// Extra document paths to subscribe via MQTT (repeatable, requires --mqtt-broker).
+    /// Documents in the --fs-root tree are subscribed automatically.
     /// Paths must include file extensions (e.g., notes/todo.txt, config.json)
     #[clap(long = "mqtt-subscribe", value_name = "PATH")]
     pub mqtt_subscribe: Vec<String>,
+
+    #[clap(flatten)]
+    pub auth: AuthArgs,
+
+    /// How to treat writes to files owned (via `owns`) by another process:
+    /// off, warn, or enforce (requires --auth-tokens or --auth-key, and no
+    /// MQTT). Admin tokens may pass `override_owner=true`.
+    #[clap(long, value_name = "POLICY", default_value = "warn")]
+    pub owns_policy: OwnershipPolicy,
+
+    /// Record MQTT events for a document path into `{PATH}.events.jsonl`
+    /// (repeatable, requires --fs-root and a database)
+    #[clap(long = "record-events", value_name = "PATH")]
+    pub record_events: Vec<String>,
+
+    /// Maximum events kept per event log (0 = unlimited)
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_MAX_EVENTS)]
+    pub events_max_entries: usize,
+
+    /// Drop recorded events older than this many seconds
+    #[clap(long, value_name = "SECS")]
+    pub events_max_age: Option<u64>,
 }
 
 /// CLI arguments for commonplace-store (document storage, no HTTP)
@@ -45,17 +87,49 @@ pub struct StoreArgs {
     #[clap(short, long, value_name = "FILE")]
     pub database: PathBuf,
 
-    /// MQTT broker URL (e.g., mqtt://localhost:1883) (required)
-    #[clap(long, value_name = "URL")]
-    pub mqtt_broker: String,
+    /// MQTT broker URL (e.g., mqtt://localhost:1883) (required unless --embedded-broker)
+    #[clap(long, value_name = "URL", required_unless_present = "embedded_broker")]
+    pub mqtt_broker: Option<String>,
+
+    /// Run an in-process MQTT broker (default address 127.0.0.1:1883) and
+    /// connect to it, so no external broker is needed. It keeps no
+    /// persistent sessions (clients must connect clean), never retransmits
+    /// QoS 1 deliveries and does not support topic aliases
+    #[clap(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = DEFAULT_BROKER_ADDR)]
+    pub embedded_broker: Option<SocketAddr>,
 
     /// MQTT client ID (defaults to "commonplace-store")
     #[clap(long, value_name = "ID", default_value = "commonplace-store")]
     pub mqtt_client_id: String,
 
+    #[clap(flatten)]
+    pub mqtt_connect: MqttConnectArgs,
+
     /// Node ID for filesystem root document (required - determines MQTT subscriptions)
     #[clap(long, value_name = "NODE_ID")]
     pub fs_root: String,
+
+    #[clap(flatten)]
+    pub auth: AuthArgs,
+
+    /// How to treat edits to files owned (via `owns`) by another process:
+    /// off or warn. MQTT edits carry no authenticated author, so the store
+    /// refuses to start with enforce
+    #[clap(long, value_name = "POLICY", default_value = "warn")]
+    pub owns_policy: OwnershipPolicy,
+
+    /// Record MQTT events for a document path into `{PATH}.events.jsonl`
+    /// (repeatable, requires --fs-root and a database)
+    #[clap(long = "record-events", value_name = "PATH")]
+    pub record_events: Vec<String>,
+
+    /// Maximum events kept per event log (0 = unlimited)
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_MAX_EVENTS)]
+    pub events_max_entries: usize,
+
+    /// Drop recorded events older than this many seconds
+    #[clap(long, value_name = "SECS")]
+    pub events_max_age: Option<u64>,
 }
 
 /// CLI arguments for commonplace-http (HTTP gateway via MQTT)
@@ -78,6 +152,16 @@ pub struct HttpArgs {
     /// MQTT client ID (defaults to "commonplace-http")
     #[clap(long, value_name = "ID", default_value = "commonplace-http")]
     pub mqtt_client_id: String,
+
+    /// Seconds to wait for the store to answer a request
+    #[clap(long, value_name = "SECS", default_value = "10")]
+    pub store_timeout: u64,
+
+    #[clap(flatten)]
+    pub auth: AuthArgs,
+
+    #[clap(flatten)]
+    pub mqtt_connect: MqttConnectArgs,
 }
 
 /// CLI arguments for the orchestrator binary
@@ -104,6 +188,19 @@ pub struct OrchestratorArgs {
     /// HTTP server URL for recursive process discovery
     #[clap(long, value_name = "URL", default_value = "http://localhost:3000")]
     pub server: String,
+
+    /// Secret key file for minting per-process credentials.
+    /// Each managed process receives a token scoped to its path in
+    /// COMMONPLACE_TOKEN; servers, stores and gateways it launches get the
+    /// file as --auth-key.
+    #[clap(long, value_name = "FILE")]
+    pub auth_key: Option<PathBuf>,
+
+    /// Lifetime of minted tokens in seconds. A token is minted each time a
+    /// process starts and isn't refreshed while it runs, so processes that
+    /// run longer than this must be restarted to keep access
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_TOKEN_TTL.as_secs())]
+    pub token_ttl: u64,
 }
 
 /// CLI arguments for commonplace-cmd (send commands to paths)
@@ -115,7 +212,12 @@ pub struct CmdArgs {
     pub path: String,
 
     /// Command verb (e.g., "increment", "reset")
-    pub verb: String,
+    #[clap(required_unless_present = "list")]
+    pub verb: Option<String>,
+
+    /// List the commands the path accepts (from its retained manifest)
+    #[clap(long, conflicts_with_all = ["verb", "wait"])]
+    pub list: bool,
 
     /// JSON payload (optional, defaults to {})
     #[clap(short, long, default_value = "{}")]
@@ -125,9 +227,33 @@ pub struct CmdArgs {
     #[clap(long, default_value = "mqtt://localhost:1883")]
     pub mqtt_broker: String,
 
+    #[clap(flatten)]
+    pub mqtt_connect: MqttConnectArgs,
+
     /// Source identifier for the command
     #[clap(long, default_value = "commonplace-cmd")]
     pub source: String,
+
+    /// Wait for the handler's reply and print its result
+    #[clap(long)]
+    pub wait: bool,
+
+    /// Seconds to wait for a reply (with --wait)
+    #[clap(long, value_name = "SECS", default_value = "10")]
+    pub timeout: u64,
+}
+
+/// CLI arguments for commonplace-mcp (MCP server for sending commands)
+#[derive(Parser, Debug)]
+#[clap(name = "commonplace-mcp")]
+#[clap(about = "MCP server exposing commonplace commands over stdio", long_about = None)]
+pub struct McpArgs {
+    /// MQTT broker URL
+    #[clap(long, env = "MQTT_BROKER", default_value = "mqtt://localhost:1883")]
+    pub mqtt_broker: String,
+
+    #[clap(flatten)]
+    pub mqtt_connect: MqttConnectArgs,
 }
 
 /// CLI arguments for commonplace-link (create document aliases)
@@ -291,6 +417,133 @@ pub struct ShowArgs {
     pub json: bool,
 }
 
+/// CLI arguments for commonplace-status (git-status style view of a synced checkout)
+#[derive(Parser, Debug)]
+#[clap(name = "commonplace-status")]
+#[clap(about = "Show unpushed, unpulled, conflicted and untracked files in a synced checkout (like git status)", long_about = None)]
+pub struct StatusArgs {
+    /// Synced file or directory, or any path inside a synced directory
+    #[clap(default_value = ".")]
+    pub path: PathBuf,
+
+    /// Server URL (default: the server the checkout was synced with)
+    #[clap(long, env = "COMMONPLACE_SERVER")]
+    pub server: Option<String>,
+
+    /// Don't contact the server; only report local changes
+    #[clap(long)]
+    pub offline: bool,
+
+    /// Glob patterns the sync was started with via --ignore
+    #[clap(long)]
+    pub ignore: Vec<String>,
+
+    /// Output in JSON format
+    #[clap(long)]
+    pub json: bool,
+}
+
+/// CLI arguments for commonplace-syncd (many sync roots in one process)
+#[derive(Parser, Debug)]
+#[clap(name = "commonplace-syncd")]
+#[clap(about = "Sync many files and directories in one process, managed over a control socket", long_about = None)]
+pub struct SyncdArgs {
+    /// Control socket the daemon listens on, and the other commands talk to
+    #[clap(
+        long,
+        default_value = ".commonplace-syncd.sock",
+        env = "COMMONPLACE_SYNCD_SOCKET"
+    )]
+    pub socket: PathBuf,
+
+    /// Server URL
+    #[clap(
+        long,
+        default_value = "http://localhost:3000",
+        env = "COMMONPLACE_SERVER"
+    )]
+    pub server: String,
+
+    /// Bearer token for an authenticated server
+    #[clap(long, env = "COMMONPLACE_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// JSON file of roots to start with: {"roots": [{"name": ..., "node": ..., "directory": ...}]}
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Name for this client's versions in conflict copies; defaults to $USER
+    #[clap(long, env = "COMMONPLACE_AUTHOR")]
+    pub author: Option<String>,
+
+    /// Output in JSON format
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Talk to a running daemon instead of starting one
+    #[clap(subcommand)]
+    pub command: Option<SyncdCommand>,
+}
+
+/// Commands sent to a running commonplace-syncd
+#[derive(clap::Subcommand, Debug)]
+pub enum SyncdCommand {
+    /// List the daemon's roots and what each is doing
+    List,
+    /// Start syncing a file or directory
+    Add(SyncdRootArgs),
+    /// Stop syncing a root and forget it
+    Remove { name: String },
+    /// Stop syncing a root until it is resumed
+    Pause { name: String },
+    /// Start a paused root again, or retry a failed one now
+    Resume { name: String },
+}
+
+/// A root for `commonplace-syncd add`, with the options commonplace-sync takes
+#[derive(clap::Args, Debug)]
+pub struct SyncdRootArgs {
+    /// Name to remove, pause and resume the root by
+    pub name: String,
+
+    /// Node ID to sync with
+    #[clap(long)]
+    pub node: String,
+
+    /// Local directory to sync
+    #[clap(long, conflicts_with = "file", required_unless_present = "file")]
+    pub directory: Option<PathBuf>,
+
+    /// Local file to sync
+    #[clap(long)]
+    pub file: Option<PathBuf>,
+
+    /// Only push local changes
+    #[clap(long, conflicts_with = "pull_only")]
+    pub push_only: bool,
+
+    /// Only pull server changes
+    #[clap(long)]
+    pub pull_only: bool,
+
+    /// Local content replaces the server's on every change (files only)
+    #[clap(long, conflicts_with = "directory")]
+    pub force_push: bool,
+
+    /// Initial sync strategy when both sides have content (directories only)
+    #[clap(long, default_value = "skip", value_parser = ["local", "server", "skip"])]
+    pub initial_sync: String,
+
+    /// Include hidden files (directories only)
+    #[clap(long)]
+    pub include_hidden: bool,
+
+    /// Gitignore-style patterns to ignore (directories only; can be
+    /// specified multiple times)
+    #[clap(long)]
+    pub ignore: Vec<String>,
+}
+
 /// CLI arguments for commonplace-signal (signal orchestrator process)
 #[derive(Parser, Debug)]
 #[clap(name = "commonplace-signal")]
@@ -312,3 +565,133 @@ pub struct SignalArgs {
     #[clap(long)]
     pub json: bool,
 }
+
+/// Build the event log configuration from `--record-events` and its limits.
+pub fn event_log_config(
+    paths: &[String],
+    max_entries: usize,
+    max_age_secs: Option<u64>,
+) -> EventLogConfig {
+    EventLogConfig {
+        paths: paths.to_vec(),
+        retention: EventRetention {
+            max_entries: (max_entries > 0).then_some(max_entries),
+            max_age: max_age_secs.map(Duration::from_secs),
+        },
+    }
+}
+
+/// Bearer-token credentials, shared by the binaries that check client tokens.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct AuthArgs {
+    /// JSON file of bearer tokens and their read/write scopes.
+    /// When set, every request except /health requires a valid token.
+    #[clap(long, value_name = "FILE")]
+    pub auth_tokens: Option<PathBuf>,
+
+    /// Secret key file for verifying signed tokens (e.g. minted by the orchestrator).
+    /// When set, every request except /health requires a valid token.
+    #[clap(long, value_name = "FILE")]
+    pub auth_key: Option<PathBuf>,
+}
+
+impl AuthArgs {
+    /// Load the configured credentials, or `None` when neither option is set.
+    pub fn authenticator(&self) -> Result<Option<Arc<dyn Authenticator>>, AuthError> {
+        let mut chain = AuthChain::new();
+        if let Some(ref path) = self.auth_tokens {
+            tracing::info!("Loading auth tokens from: {}", path.display());
+            chain.push(Arc::new(StaticTokens::load(path)?));
+        }
+        if let Some(ref path) = self.auth_key {
+            tracing::info!("Verifying signed tokens with key from: {}", path.display());
+            chain.push(Arc::new(SigningKey::load(path)?));
+        }
+        Ok((!chain.is_empty()).then(|| Arc::new(chain) as Arc<dyn Authenticator>))
+    }
+}
+
+/// MQTT connection security and reconnection options, shared by every
+/// binary that connects to a broker.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct MqttConnectArgs {
+    /// MQTT username (defaults to the client ID when a password is given)
+    #[clap(long, value_name = "USER", env = "MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+
+    /// MQTT password, e.g. a macaroon (see docs/MACAROONS.md)
+    #[clap(
+        long,
+        value_name = "PASSWORD",
+        env = "MQTT_PASSWORD",
+        hide_env_values = true
+    )]
+    pub mqtt_password: Option<String>,
+
+    /// PEM file of CA certificates for TLS (enables TLS; mqtts:// URLs
+    /// use TLS with the platform roots)
+    #[clap(long, value_name = "FILE", env = "MQTT_CA_FILE")]
+    pub mqtt_ca: Option<PathBuf>,
+
+    /// PEM client certificate for TLS client authentication (requires --mqtt-ca)
+    #[clap(long, value_name = "FILE", requires_all = ["mqtt_client_key", "mqtt_ca"])]
+    pub mqtt_client_cert: Option<PathBuf>,
+
+    /// PEM private key for --mqtt-client-cert
+    #[clap(long, value_name = "FILE", requires = "mqtt_client_cert")]
+    pub mqtt_client_key: Option<PathBuf>,
+
+    /// Topic for a retained {"online": ...} status, published on connect and
+    /// set as the last will so the broker announces disconnects
+    #[clap(long, value_name = "TOPIC")]
+    pub mqtt_status_topic: Option<String>,
+
+    /// Initial delay before reconnecting, in milliseconds (doubles on each failure)
+    #[clap(long, value_name = "MS", default_value = "500")]
+    pub mqtt_reconnect_min: u64,
+
+    /// Maximum delay between reconnection attempts, in milliseconds
+    #[clap(long, value_name = "MS", default_value = "30000")]
+    pub mqtt_reconnect_max: u64,
+
+    /// Give up after this many consecutive connection failures (default: never)
+    #[clap(long, value_name = "N")]
+    pub mqtt_reconnect_attempts: Option<u32>,
+
+    /// redb file that keeps QoS 1 publishes made while the broker is
+    /// unreachable, sent in order on reconnect
+    #[clap(long, value_name = "FILE")]
+    pub mqtt_outbox: Option<PathBuf>,
+
+    /// MQTT protocol version: auto (5, falling back to 3.1.1 if the broker
+    /// rejects it), 5 or 3.1.1
+    #[clap(long, value_name = "VERSION", default_value = "auto")]
+    pub mqtt_protocol: MqttProtocol,
+}
+
+impl MqttConnectArgs {
+    /// Build a config for `broker_url` and `client_id` with these options.
+    pub fn config(&self, broker_url: &str, client_id: &str) -> MqttConfig {
+        let tls = (self.mqtt_ca.is_some() || self.mqtt_client_cert.is_some()).then(|| MqttTls {
+            ca_file: self.mqtt_ca.clone(),
+            client_cert: self.mqtt_client_cert.clone(),
+            client_key: self.mqtt_client_key.clone(),
+        });
+        MqttConfig {
+            broker_url: broker_url.to_string(),
+            client_id: client_id.to_string(),
+            username: self.mqtt_username.clone(),
+            password: self.mqtt_password.clone(),
+            tls,
+            status_topic: self.mqtt_status_topic.clone(),
+            reconnect: ReconnectPolicy {
+                initial_delay: Duration::from_millis(self.mqtt_reconnect_min),
+                max_delay: Duration::from_millis(self.mqtt_reconnect_max),
+                max_attempts: self.mqtt_reconnect_attempts,
+            },
+            outbox: self.mqtt_outbox.clone(),
+            protocol: self.mqtt_protocol,
+            ..Default::default()
+        }
+    }
+}
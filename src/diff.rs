@@ -121,6 +121,15 @@ pub fn compute_diff_update(old_content: &str, new_content: &str) -> Result<DiffR
     })
 }
 
+/// Summarize the character-level diff from `old_content` to `new_content`
+/// without building a Yjs update.
+pub fn summarize_diff(old_content: &str, new_content: &str) -> DiffSummary {
+    let diff = TextDiff::from_chars(old_content, new_content);
+    let mut summary = DiffSummary::default();
+    collect_diff_operations(&diff, &mut summary);
+    summary
+}
+
 /// Compute character-level diff using actual Yjs state as base.
 ///
 /// Unlike `compute_diff_update`, this function uses the actual Yjs state
@@ -138,9 +147,24 @@ pub fn compute_diff_update_with_base(
     base_state_bytes: &[u8],
     old_content: &str,
     new_content: &str,
+) -> Result<DiffResult, DiffError> {
+    compute_diff_update_with_base_as(2, base_state_bytes, old_content, new_content)
+}
+
+/// Like [`compute_diff_update_with_base`], but making the edit as Yjs client
+/// `client_id`.
+///
+/// Edits diffed from the same base state as the same client would reuse item
+/// IDs and clobber each other when merged; a caller making several concurrent
+/// chains of edits gives each its own client.
+pub fn compute_diff_update_with_base_as(
+    client_id: u64,
+    base_state_bytes: &[u8],
+    old_content: &str,
+    new_content: &str,
 ) -> Result<DiffResult, DiffError> {
     // Create target doc and sync to actual base state
-    let target_doc = Doc::with_client_id(2);
+    let target_doc = Doc::with_client_id(client_id);
     let target_text = target_doc.get_or_insert_text(TEXT_ROOT_NAME);
 
     // Apply the actual base state from parent commits
@@ -309,6 +333,15 @@ mod tests {
         assert_eq!(result.summary.chars_deleted, 0);
     }
 
+    #[test]
+    fn test_summarize_diff_matches_update_summary() {
+        let summary = summarize_diff("hello world", "hello rust");
+        let result = compute_diff_update("hello world", "hello rust").unwrap();
+        assert_eq!(summary.chars_inserted, result.summary.chars_inserted);
+        assert_eq!(summary.chars_deleted, result.summary.chars_deleted);
+        assert_eq!(summary.unchanged_chars, result.summary.unchanged_chars);
+    }
+
     #[test]
     fn test_simple_delete() {
         let result = compute_diff_update("hello world", "hello").unwrap();
@@ -9,11 +9,12 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Extension, Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+use crate::auth::{effective_author, may_override_owner, Principal};
 use crate::document::DocumentStore;
 use crate::events::CommitBroadcaster;
 use crate::services::{DocumentService, ServiceError};
@@ -228,6 +229,9 @@ struct ReplaceParams {
     parent_cid: Option<String>,
     #[serde(default)]
     author: Option<String>,
+    /// Admin override of single-writer file ownership
+    #[serde(default)]
+    override_owner: bool,
 }
 
 #[derive(Serialize)]
@@ -288,13 +292,28 @@ async fn handle_file_request(
 async fn handle_file_delete(
     State(state): State<FileApiState>,
     Path(path): Path<String>,
-) -> Result<StatusCode, PathResolveError> {
-    let doc_id = resolve_path(&state, &path).await?;
+    Query(params): Query<ReplaceParams>,
+    principal: Option<Extension<Principal>>,
+) -> Result<StatusCode, Response> {
+    let principal = principal.as_deref();
+    let doc_id = resolve_path(&state, &path)
+        .await
+        .map_err(|e| e.into_response())?;
+
+    state
+        .service
+        .check_owner(
+            &doc_id,
+            effective_author(principal, params.author).as_deref(),
+            params.override_owner && may_override_owner(principal),
+        )
+        .await
+        .map_err(|e| e.into_response())?;
 
-    if state.doc_store.delete_document(&doc_id).await {
+    if state.service.delete_document(&doc_id).await {
         Ok(StatusCode::NO_CONTENT)
     } else {
-        Err(PathResolveError::PathNotFound)
+        Err(PathResolveError::PathNotFound.into_response())
     }
 }
 
@@ -303,8 +322,12 @@ async fn handle_file_post(
     State(state): State<FileApiState>,
     Path(path): Path<String>,
     Query(params): Query<ReplaceParams>,
+    principal: Option<Extension<Principal>>,
     body: String,
 ) -> Result<Response, Response> {
+    let principal = principal.as_deref();
+    let override_owner = params.override_owner && may_override_owner(principal);
+
     // Check if path ends with /edit
     if let Some(clean_path) = path.strip_suffix("/edit") {
         // Parse body as JSON edit request
@@ -315,10 +338,17 @@ async fn handle_file_post(
             .await
             .map_err(|e| e.into_response())?;
 
+        let author = effective_author(principal, req.author);
+        state
+            .service
+            .check_owner(&doc_id, author.as_deref(), override_owner)
+            .await
+            .map_err(|e| e.into_response())?;
+
         // Use service for edit
         let result = state
             .service
-            .edit_document(&doc_id, &req.update, req.author, req.message)
+            .edit_document(&doc_id, &req.update, author, req.message)
             .await
             .map_err(|e| e.into_response())?;
 
@@ -329,10 +359,17 @@ async fn handle_file_post(
             .await
             .map_err(|e| e.into_response())?;
 
+        let author = effective_author(principal, params.author);
+        state
+            .service
+            .check_owner(&doc_id, author.as_deref(), override_owner)
+            .await
+            .map_err(|e| e.into_response())?;
+
         // Use service for replace
         let result = state
             .service
-            .replace_content(&doc_id, &body, params.parent_cid, params.author)
+            .replace_content(&doc_id, &body, params.parent_cid, author)
             .await
             .map_err(|e| e.into_response())?;
 
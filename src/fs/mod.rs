@@ -5,9 +5,13 @@
 //! JSON document and creates document nodes for entries declared in it.
 
 mod error;
+mod ownership;
+mod paths;
 mod reconciler;
 mod schema;
 
 pub use error::FsError;
+pub use ownership::{OwnershipGuard, OwnershipPolicy, OwnershipViolation};
+pub use paths::{find_doc_paths, join_path, mount_doc, walk_tree, DocMount, TreeEntry};
 pub use reconciler::{FilesystemReconciler, MigrationResult};
 pub use schema::{DirEntry, DocEntry, Entry, FsSchema};
@@ -0,0 +1,471 @@
+//! Single-writer ownership of files.
+//!
+//! A process declared in a `processes.json` with `owns: "<file>"` is the only
+//! writer allowed for that file. The owner's identity is the principal name
+//! the orchestrator mints for it (`process:<dir>/<name>`), so with signed
+//! tokens the commit author is trustworthy.
+//!
+//! The store applies an [`OwnershipPolicy`] to writes from anyone else:
+//! ignore them, log a warning, or reject them. Admins can override.
+//!
+//! Edits arriving over MQTT carry no authenticated identity, so the store
+//! checks them as anonymous. [`OwnershipPolicy::Enforce`] would then refuse
+//! the owner's own MQTT edits, so the binaries refuse to start with it when
+//! MQTT is enabled (see [`OwnershipPolicy::check_enforceable`]).
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::paths::{join_path, walk_tree};
+use crate::auth::scope_matches;
+use crate::document::DocumentStore;
+use crate::orchestrator::{process_principal_name, ProcessesConfig};
+
+/// Name of the process config files that declare ownership.
+const PROCESSES_FILE: &str = "processes.json";
+
+/// How the store treats writes to an owned file from a non-owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OwnershipPolicy {
+    /// Don't check ownership
+    Off,
+    /// Accept the write but log a warning
+    #[default]
+    Warn,
+    /// Reject the write
+    Enforce,
+}
+
+impl FromStr for OwnershipPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(OwnershipPolicy::Off),
+            "warn" => Ok(OwnershipPolicy::Warn),
+            "enforce" => Ok(OwnershipPolicy::Enforce),
+            other => Err(format!(
+                "Unknown ownership policy '{}' (expected off, warn or enforce)",
+                other
+            )),
+        }
+    }
+}
+
+impl OwnershipPolicy {
+    /// Check that this policy can be applied by a store with (or without)
+    /// an authenticator, and with (or without) MQTT edits enabled.
+    ///
+    /// Enforcing judges anonymous writes as coming from a non-owner, so the
+    /// owner's own writes would be refused along with everyone else's. That
+    /// is every write without an authenticator, and every MQTT edit.
+    pub fn check_enforceable(self, authenticated: bool, mqtt_writes: bool) -> Result<(), String> {
+        if self != OwnershipPolicy::Enforce {
+            return Ok(());
+        }
+        if !authenticated {
+            return Err(
+                "--owns-policy enforce needs --auth-tokens or --auth-key to identify writers"
+                    .to_string(),
+            );
+        }
+        if mqtt_writes {
+            return Err(
+                "--owns-policy enforce can't be used with MQTT, whose edits carry no \
+                 authenticated writer"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OwnershipPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OwnershipPolicy::Off => write!(f, "off"),
+            OwnershipPolicy::Warn => write!(f, "warn"),
+            OwnershipPolicy::Enforce => write!(f, "enforce"),
+        }
+    }
+}
+
+/// A rejected write to an owned file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnershipViolation {
+    /// Path of the owned file
+    pub path: String,
+    /// Principal name of the owning process
+    pub owner: String,
+    /// Who attempted the write
+    pub writer: Option<String>,
+}
+
+impl fmt::Display for OwnershipViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is owned by {}; write from {} rejected",
+            self.path,
+            self.owner,
+            self.writer.as_deref().unwrap_or("anonymous")
+        )
+    }
+}
+
+impl std::error::Error for OwnershipViolation {}
+
+/// Owners read from the tree, and the documents they were read from.
+#[derive(Debug, Default)]
+struct OwnerMap {
+    /// Owned path (a file, or a directory owned as a whole) → owner
+    owners: HashMap<String, String>,
+    /// Document ID → paths it is mounted at
+    mounts: HashMap<String, Vec<String>>,
+    /// Schema and `processes.json` documents the map depends on
+    sources: HashSet<String>,
+}
+
+/// Checks writes against the `owns` declarations in the filesystem tree.
+pub struct OwnershipGuard {
+    doc_store: Arc<DocumentStore>,
+    fs_root: String,
+    policy: OwnershipPolicy,
+    /// Owner map, built on first use and dropped by [`Self::invalidate`]
+    cache: RwLock<Option<Arc<OwnerMap>>>,
+    /// Bumped on invalidation, so a map built from stale documents isn't cached
+    generation: AtomicU64,
+}
+
+impl OwnershipGuard {
+    pub fn new(doc_store: Arc<DocumentStore>, fs_root: String, policy: OwnershipPolicy) -> Self {
+        Self {
+            doc_store,
+            fs_root,
+            policy,
+            cache: RwLock::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> OwnershipPolicy {
+        self.policy
+    }
+
+    /// Note that document `doc_id` changed.
+    ///
+    /// Drops the cached owner map if it was read from that document: the
+    /// fs-root, a node-backed directory, or a `processes.json`.
+    pub fn invalidate(&self, doc_id: &str) {
+        let mut cache = self.cache.write().unwrap_or_else(|e| e.into_inner());
+        if cache
+            .as_ref()
+            .is_none_or(|map| map.sources.contains(doc_id))
+        {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            *cache = None;
+        }
+    }
+
+    /// The owner map, from the cache or read fresh from the tree.
+    async fn owner_map(&self) -> Arc<OwnerMap> {
+        if let Some(map) = self
+            .cache
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            return map.clone();
+        }
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        let map = Arc::new(self.read_owner_map().await);
+        let mut cache = self.cache.write().unwrap_or_else(|e| e.into_inner());
+        if self.generation.load(Ordering::SeqCst) == generation {
+            *cache = Some(map.clone());
+        }
+        map
+    }
+
+    async fn read_owner_map(&self) -> OwnerMap {
+        let mut map = OwnerMap::default();
+        map.sources.insert(self.fs_root.clone());
+        for entry in walk_tree(&self.doc_store, &self.fs_root).await {
+            let Some(node_id) = entry.node_id else {
+                continue;
+            };
+            if entry.is_dir {
+                map.sources.insert(node_id);
+                continue;
+            }
+            map.mounts
+                .entry(node_id.clone())
+                .or_default()
+                .push(entry.path.clone());
+
+            let (dir, file_name) = match entry.path.rsplit_once('/') {
+                Some((dir, name)) => (dir, name),
+                None => ("", entry.path.as_str()),
+            };
+            if file_name != PROCESSES_FILE {
+                continue;
+            }
+            map.sources.insert(node_id.clone());
+            let Some(doc) = self.doc_store.get_document(&node_id).await else {
+                continue;
+            };
+            let Ok(config) = ProcessesConfig::parse(&doc.content) else {
+                tracing::debug!("Skipping unparseable {}", entry.path);
+                continue;
+            };
+
+            for (name, process) in &config.processes {
+                let Some(ref owns) = process.owns else {
+                    continue;
+                };
+                let owned = join_path(dir, owns);
+                map.owners.insert(owned, process_principal_name(dir, name));
+            }
+        }
+        map
+    }
+
+    /// Map each owned path (a file, or a directory owned as a whole) to its
+    /// owner's principal name.
+    ///
+    /// Cached until a schema or `processes.json` document it was read from
+    /// changes, so edits to `processes.json` take effect on the next write.
+    pub async fn owners(&self) -> HashMap<String, String> {
+        self.owner_map().await.owners.clone()
+    }
+
+    /// The principal name owning `path`, if any.
+    pub async fn owner_of_path(&self, path: &str) -> Option<String> {
+        owner_in(&self.owner_map().await.owners, path.trim_matches('/'))
+    }
+
+    /// The path and owner of a document, if it is mounted at an owned path.
+    pub async fn owner_of_doc(&self, doc_id: &str) -> Option<(String, String)> {
+        let map = self.owner_map().await;
+        map.mounts
+            .get(doc_id)?
+            .iter()
+            .find_map(|path| owner_in(&map.owners, path).map(|owner| (path.clone(), owner)))
+    }
+
+    /// Check a write to the file at `path`.
+    pub async fn check_path(
+        &self,
+        path: &str,
+        writer: Option<&str>,
+        override_owner: bool,
+    ) -> Result<(), OwnershipViolation> {
+        if self.policy == OwnershipPolicy::Off {
+            return Ok(());
+        }
+        let path = path.trim_matches('/');
+        match self.owner_of_path(path).await {
+            Some(owner) => self.decide(path.to_string(), owner, writer, override_owner),
+            None => Ok(()),
+        }
+    }
+
+    /// Check a write to the document `doc_id`.
+    pub async fn check_doc(
+        &self,
+        doc_id: &str,
+        writer: Option<&str>,
+        override_owner: bool,
+    ) -> Result<(), OwnershipViolation> {
+        if self.policy == OwnershipPolicy::Off {
+            return Ok(());
+        }
+        match self.owner_of_doc(doc_id).await {
+            Some((path, owner)) => self.decide(path, owner, writer, override_owner),
+            None => Ok(()),
+        }
+    }
+
+    fn decide(
+        &self,
+        path: String,
+        owner: String,
+        writer: Option<&str>,
+        override_owner: bool,
+    ) -> Result<(), OwnershipViolation> {
+        if writer == Some(owner.as_str()) {
+            return Ok(());
+        }
+        let violation = OwnershipViolation {
+            path,
+            owner,
+            writer: writer.map(|w| w.to_string()),
+        };
+        if override_owner {
+            tracing::info!("Admin override: {}", violation);
+            return Ok(());
+        }
+        match self.policy {
+            OwnershipPolicy::Off => Ok(()),
+            OwnershipPolicy::Warn => {
+                tracing::warn!("Ownership violation: {}", violation);
+                Ok(())
+            }
+            OwnershipPolicy::Enforce => Err(violation),
+        }
+    }
+}
+
+/// Find the owner of `path`, preferring the most specific owned path.
+fn owner_in(owners: &HashMap<String, String>, path: &str) -> Option<String> {
+    owners
+        .iter()
+        .filter(|(owned, _)| scope_matches(owned, path))
+        .max_by_key(|(owned, _)| owned.len())
+        .map(|(_, owner)| owner.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::ContentType;
+
+    #[test]
+    fn test_enforce_needs_authentication() {
+        assert!(OwnershipPolicy::Enforce
+            .check_enforceable(false, false)
+            .is_err());
+        assert!(OwnershipPolicy::Enforce
+            .check_enforceable(true, false)
+            .is_ok());
+        assert!(OwnershipPolicy::Enforce
+            .check_enforceable(true, true)
+            .is_err());
+        assert!(OwnershipPolicy::Warn.check_enforceable(false, true).is_ok());
+    }
+
+    async fn guard(policy: OwnershipPolicy) -> OwnershipGuard {
+        let store = Arc::new(DocumentStore::new());
+        store.get_or_create_with_id("root", ContentType::Json).await;
+        store
+            .set_content(
+                "root",
+                r#"{"version":1,"root":{"type":"dir","entries":{
+                    "examples":{"type":"dir","entries":{
+                        "processes.json":{"type":"doc","node_id":"procs-id"},
+                        "counter.json":{"type":"doc","node_id":"counter-id"},
+                        "notes.txt":{"type":"doc","node_id":"notes-id"}}}}}}"#,
+            )
+            .await
+            .unwrap();
+        store
+            .get_or_create_with_id("procs-id", ContentType::Json)
+            .await;
+        store
+            .set_content(
+                "procs-id",
+                r#"{"processes":{"counter":{"command":"python counter.py","owns":"counter.json"}}}"#,
+            )
+            .await
+            .unwrap();
+        OwnershipGuard::new(store, "root".to_string(), policy)
+    }
+
+    #[tokio::test]
+    async fn test_owners_from_processes_json() {
+        let guard = guard(OwnershipPolicy::Enforce).await;
+        assert_eq!(
+            guard.owner_of_path("examples/counter.json").await,
+            Some("process:examples/counter".to_string())
+        );
+        assert_eq!(guard.owner_of_path("examples/notes.txt").await, None);
+        assert_eq!(guard.owner_of_path("examples/counter.json.bak").await, None);
+        assert_eq!(
+            guard.owner_of_doc("counter-id").await,
+            Some((
+                "examples/counter.json".to_string(),
+                "process:examples/counter".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enforce_rejects_non_owner() {
+        let guard = guard(OwnershipPolicy::Enforce).await;
+        assert!(guard
+            .check_doc("counter-id", Some("process:examples/counter"), false)
+            .await
+            .is_ok());
+        let err = guard
+            .check_doc("counter-id", Some("alice"), false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.owner, "process:examples/counter");
+        assert!(guard
+            .check_doc("notes-id", Some("alice"), false)
+            .await
+            .is_ok());
+        assert!(guard
+            .check_path("/examples/counter.json", None, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_override_and_warn_allow_write() {
+        let guard = guard(OwnershipPolicy::Enforce).await;
+        assert!(guard
+            .check_doc("counter-id", Some("admin"), true)
+            .await
+            .is_ok());
+
+        let guard = OwnershipGuard::new(
+            guard.doc_store.clone(),
+            guard.fs_root.clone(),
+            OwnershipPolicy::Warn,
+        );
+        assert!(guard
+            .check_doc("counter-id", Some("alice"), false)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_owner_map_cached_until_source_changes() {
+        let guard = guard(OwnershipPolicy::Enforce).await;
+        assert!(guard.owner_of_path("examples/counter.json").await.is_some());
+
+        guard
+            .doc_store
+            .set_content(
+                "procs-id",
+                r#"{"processes":{"notes":{"command":"python notes.py","owns":"notes.txt"}}}"#,
+            )
+            .await
+            .unwrap();
+        // Changes to other documents keep the cached map...
+        guard.invalidate("notes-id");
+        assert!(guard.owner_of_path("examples/counter.json").await.is_some());
+
+        // ...while changes to processes.json drop it
+        guard.invalidate("procs-id");
+        assert_eq!(guard.owner_of_path("examples/counter.json").await, None);
+        assert_eq!(
+            guard.owner_of_doc("notes-id").await,
+            Some((
+                "examples/notes.txt".to_string(),
+                "process:examples/notes".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_policy_parse() {
+        assert_eq!("enforce".parse(), Ok(OwnershipPolicy::Enforce));
+        assert_eq!("off".parse(), Ok(OwnershipPolicy::Off));
+        assert!("strict".parse::<OwnershipPolicy>().is_err());
+    }
+}
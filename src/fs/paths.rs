@@ -0,0 +1,330 @@
+//! Path lookups over the mounted filesystem tree.
+//!
+//! The fs-root schema maps paths to document IDs, with node-backed
+//! subdirectories stored in their own documents. These helpers walk that
+//! tree in the document store to answer path questions for a document ID.
+
+use super::{Entry, FsError};
+use crate::document::{ContentType, DocumentStore};
+use std::collections::HashSet;
+
+/// An entry mounted somewhere in the filesystem tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    /// Path relative to fs-root (no leading slash)
+    pub path: String,
+    /// Node ID of the entry, if it has one
+    pub node_id: Option<String>,
+    /// Whether the entry is a directory
+    pub is_dir: bool,
+}
+
+/// Walk every entry reachable from the fs-root schema.
+///
+/// Follows node-backed subdirectories (guarding against cycles) and inline
+/// directories. Documents that are missing or fail to parse are skipped.
+pub async fn walk_tree(doc_store: &DocumentStore, fs_root: &str) -> Vec<TreeEntry> {
+    let mut found = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(fs_root.to_string(), String::new())];
+
+    while let Some((dir_id, dir_path)) = pending.pop() {
+        if !visited.insert(dir_id.clone()) {
+            continue;
+        }
+        let Some(doc) = doc_store.get_document(&dir_id).await else {
+            continue;
+        };
+        let Ok(schema) = serde_json::from_str::<serde_json::Value>(&doc.content) else {
+            continue;
+        };
+        let Some(root) = schema.get("root") else {
+            continue;
+        };
+
+        // Inline directories are walked in place; node-backed ones are queued
+        let mut inline = vec![(root.clone(), dir_path)];
+        while let Some((dir, path)) = inline.pop() {
+            let Some(entries) = dir.get("entries").and_then(|e| e.as_object()) else {
+                continue;
+            };
+            for (name, entry) in entries {
+                let entry_path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                let node_id = entry
+                    .get("node_id")
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string());
+                let is_dir = entry.get("type").and_then(|t| t.as_str()) == Some("dir");
+
+                if is_dir {
+                    if let Some(ref node_id) = node_id {
+                        pending.push((node_id.clone(), entry_path.clone()));
+                    }
+                    inline.push((entry.clone(), entry_path.clone()));
+                }
+
+                found.push(TreeEntry {
+                    path: entry_path,
+                    node_id,
+                    is_dir,
+                });
+            }
+        }
+    }
+
+    found
+}
+
+/// Join a path relative to a directory in the tree (`""` is the root).
+pub fn join_path(dir: &str, name: &str) -> String {
+    let name = name.trim_matches('/');
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Find the filesystem paths at which a document is mounted.
+///
+/// The fs-root itself is mounted at the empty path.
+pub async fn find_doc_paths(doc_store: &DocumentStore, fs_root: &str, doc_id: &str) -> Vec<String> {
+    if doc_id == fs_root {
+        return vec![String::new()];
+    }
+
+    walk_tree(doc_store, fs_root)
+        .await
+        .into_iter()
+        .filter(|entry| entry.node_id.as_deref() == Some(doc_id))
+        .map(|entry| entry.path)
+        .collect()
+}
+
+/// Where a document is mounted, or how to mount it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocMount {
+    /// Already in the tree with this document ID
+    Existing(String),
+    /// Not in the tree yet; write `schema` to `schema_doc` to add it
+    New {
+        /// Document holding the parent directory's entries (fs-root or a
+        /// node-backed directory)
+        schema_doc: String,
+        /// Updated schema content including the new entry
+        schema: String,
+        /// Node ID assigned to the new document
+        node_id: String,
+    },
+}
+
+/// Look up the document at `path`, or plan a schema edit that mounts a new
+/// one there. The parent directory must already exist.
+pub async fn mount_doc(
+    doc_store: &DocumentStore,
+    fs_root: &str,
+    path: &str,
+    content_type: ContentType,
+) -> Result<DocMount, FsError> {
+    let path = path.trim_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    Entry::validate_name(name)?;
+
+    let tree = walk_tree(doc_store, fs_root).await;
+    if let Some(entry) = tree.iter().find(|e| e.path == path) {
+        if entry.is_dir {
+            return Err(FsError::SchemaError(format!("{} is a directory", path)));
+        }
+        // Same fallback ID as resolve_path_to_uuid for entries without one
+        let node_id = entry
+            .node_id
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", fs_root, path));
+        return Ok(DocMount::Existing(node_id));
+    }
+
+    // The parent's entries live in the nearest node-backed ancestor, nested
+    // under any inline directories below it
+    let mut schema_doc = fs_root.to_string();
+    let mut inline: Vec<&str> = Vec::new();
+    if !parent.is_empty() {
+        let mut dir_path = String::new();
+        for segment in parent.split('/') {
+            if !dir_path.is_empty() {
+                dir_path.push('/');
+            }
+            dir_path.push_str(segment);
+            match tree.iter().find(|e| e.path == dir_path) {
+                Some(TreeEntry {
+                    is_dir: true,
+                    node_id: Some(node_id),
+                    ..
+                }) => {
+                    schema_doc = node_id.clone();
+                    inline.clear();
+                }
+                Some(TreeEntry { is_dir: true, .. }) => inline.push(segment),
+                _ => {
+                    return Err(FsError::SchemaError(format!(
+                        "Parent directory not mounted: {}",
+                        parent
+                    )))
+                }
+            }
+        }
+    }
+
+    let content = doc_store
+        .get_document(&schema_doc)
+        .await
+        .map(|doc| doc.content)
+        .unwrap_or_default();
+    let mut schema: serde_json::Value = if content.trim().is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_str(&content).map_err(|e| FsError::ParseError(e.to_string()))?
+    };
+    let object = schema
+        .as_object_mut()
+        .ok_or_else(|| FsError::SchemaError("schema is not an object".to_string()))?;
+    object.entry("version").or_insert(serde_json::json!(1));
+
+    let mut dir = object
+        .entry("root")
+        .or_insert_with(|| serde_json::json!({"type": "dir"}));
+    for segment in inline {
+        dir = dir
+            .get_mut("entries")
+            .and_then(|entries| entries.get_mut(segment))
+            .ok_or_else(|| FsError::SchemaError(format!("Missing directory: {}", segment)))?;
+    }
+    let entries = dir
+        .as_object_mut()
+        .ok_or_else(|| FsError::SchemaError("directory is not an object".to_string()))?
+        .entry("entries")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+        .ok_or_else(|| FsError::SchemaError("entries is not an object".to_string()))?;
+
+    let node_id = uuid::Uuid::new_v4().to_string();
+    entries.insert(
+        name.to_string(),
+        serde_json::json!({
+            "type": "doc",
+            "node_id": node_id,
+            "content_type": content_type.to_mime(),
+        }),
+    );
+
+    Ok(DocMount::New {
+        schema_doc,
+        schema: schema.to_string(),
+        node_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::ContentType;
+
+    async fn store_with_tree() -> DocumentStore {
+        let store = DocumentStore::new();
+        store.get_or_create_with_id("root", ContentType::Json).await;
+        store
+            .set_content(
+                "root",
+                r#"{"version":1,"root":{"type":"dir","entries":{
+                    "top.txt":{"type":"doc","node_id":"top-id"},
+                    "examples":{"type":"dir","node_id":"examples-id"}}}}"#,
+            )
+            .await
+            .unwrap();
+        store
+            .get_or_create_with_id("examples-id", ContentType::Json)
+            .await;
+        store
+            .set_content(
+                "examples-id",
+                r#"{"version":1,"root":{"type":"dir","entries":{
+                    "counter.json":{"type":"doc","node_id":"counter-id"}}}}"#,
+            )
+            .await
+            .unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn test_walk_tree_follows_subdirectories() {
+        let store = store_with_tree().await;
+        let mut paths: Vec<String> = walk_tree(&store, "root")
+            .await
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["examples", "examples/counter.json", "top.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_find_doc_paths() {
+        let store = store_with_tree().await;
+        assert_eq!(find_doc_paths(&store, "root", "root").await, vec![""]);
+        assert_eq!(
+            find_doc_paths(&store, "root", "top-id").await,
+            vec!["top.txt"]
+        );
+        assert_eq!(
+            find_doc_paths(&store, "root", "examples-id").await,
+            vec!["examples"]
+        );
+        assert_eq!(
+            find_doc_paths(&store, "root", "counter-id").await,
+            vec!["examples/counter.json"]
+        );
+        assert!(find_doc_paths(&store, "root", "missing").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mount_doc() {
+        let store = store_with_tree().await;
+        assert_eq!(
+            mount_doc(&store, "root", "examples/counter.json", ContentType::Json)
+                .await
+                .unwrap(),
+            DocMount::Existing("counter-id".to_string())
+        );
+
+        let DocMount::New {
+            schema_doc,
+            schema,
+            node_id,
+        } = mount_doc(
+            &store,
+            "root",
+            "examples/counter.json.events.jsonl",
+            ContentType::Jsonl,
+        )
+        .await
+        .unwrap()
+        else {
+            panic!("expected a new mount");
+        };
+        assert_eq!(schema_doc, "examples-id");
+        let schema: serde_json::Value = serde_json::from_str(&schema).unwrap();
+        let entry = &schema["root"]["entries"]["counter.json.events.jsonl"];
+        assert_eq!(entry["node_id"], node_id.as_str());
+        assert_eq!(entry["content_type"], "application/x-ndjson");
+        assert!(schema["root"]["entries"]["counter.json"].is_object());
+
+        assert!(
+            mount_doc(&store, "root", "missing/log.jsonl", ContentType::Jsonl)
+                .await
+                .is_err()
+        );
+    }
+}
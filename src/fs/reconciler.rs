@@ -5,7 +5,7 @@ use super::schema::{DirEntry, Entry, FsSchema};
 use crate::document::{ContentType, DocumentStore};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 
 /// Result of a schema migration operation.
 pub struct MigrationResult {
@@ -38,6 +38,8 @@ pub struct FilesystemReconciler {
     /// (separate from last_valid_node_schemas since those are only populated after
     /// the directory content is parsed, but we need to track dirs immediately)
     node_backed_dir_ids: RwLock<HashSet<String>>,
+    /// Bumped after every successful reconciliation
+    generation: watch::Sender<u64>,
 }
 
 impl FilesystemReconciler {
@@ -50,6 +52,7 @@ impl FilesystemReconciler {
             known_documents: RwLock::new(HashSet::new()),
             last_valid_node_schemas: RwLock::new(std::collections::HashMap::new()),
             node_backed_dir_ids: RwLock::new(HashSet::new()),
+            generation: watch::channel(0).0,
         }
     }
 
@@ -150,6 +153,9 @@ impl FilesystemReconciler {
         // 7. Update last valid schema
         *self.last_valid_schema.write().await = Some(schema);
 
+        // 8. Tell watchers the tree may have changed
+        self.generation.send_modify(|n| *n += 1);
+
         Ok(())
     }
 
@@ -401,6 +407,11 @@ impl FilesystemReconciler {
         &self.fs_root_id
     }
 
+    /// Watch for reconciliations; the value changes after each one.
+    pub fn watch(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
     /// Check if a document ID is a known node-backed directory.
     ///
     /// Returns true if this document was identified as a node-backed directory
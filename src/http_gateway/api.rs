@@ -1,5 +1,9 @@
 //! HTTP Gateway API routes - translates HTTP to MQTT
 //!
+//! Mirrors the `/docs` routes of `commonplace-server`. Each request is
+//! forwarded to the store as a store API command and answered with the
+//! store's result. `/ws/docs/:id` answers `501 Not Implemented`.
+//!
 //! ## Document ID Encoding
 //!
 //! Document IDs may contain path separators (e.g., `foo/bar/file.txt`). When making
@@ -12,43 +16,66 @@
 //! the original document ID.
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 use rumqttc::QoS;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
 use std::sync::Arc;
 
-use super::HttpGateway;
-use crate::mqtt::{messages::EditMessage, topics::Topic};
+use super::{GatewayError, HttpGateway};
+use crate::auth::{require_auth, Authenticator, BearerToken};
+use crate::document::ContentType;
+use crate::mqtt::topics::Topic;
 
 /// Create the HTTP gateway router
-pub fn router(gateway: Arc<HttpGateway>) -> Router {
-    Router::new()
-        .route("/docs/:id", get(get_doc_content))
-        .route("/docs/:id/edit", post(send_edit))
+///
+/// With an authenticator, every route requires a bearer token, checked the
+/// same way as by `commonplace-server`. The token is also forwarded to the
+/// store, which checks it again and records the principal as the writer.
+pub fn router(gateway: Arc<HttpGateway>, auth: Option<Arc<dyn Authenticator>>) -> Router {
+    let routes = Router::new()
+        .route("/docs", post(create_doc))
+        .route("/docs/:id", get(get_doc_content).delete(delete_doc))
+        .route("/docs/:id/commit", post(create_commit))
+        .route("/docs/:id/info", get(get_doc_info))
+        .route("/docs/:id/head", get(get_doc_head))
+        .route("/docs/:id/edit", post(edit_doc))
+        .route("/docs/:id/replace", post(replace_doc))
+        .route("/docs/:id/push", post(push_doc))
+        .route("/docs/:id/fork", post(fork_doc))
         .route("/docs/:id/event", post(send_event))
-        .nest("/sse", super::sse::router(gateway.clone()))
-        .with_state(gateway)
+        .route("/fs-root", get(get_fs_root))
+        .route("/ws/docs/:id", get(ws_unsupported))
+        .merge(super::files::router())
+        .merge(super::sse::router())
+        .with_state(gateway.clone());
+
+    match auth {
+        Some(authenticator) => routes.layer(middleware::from_fn_with_state(
+            super::auth_state(gateway, authenticator),
+            require_auth,
+        )),
+        None => routes,
+    }
 }
 
 // ============================================================================
-// Request/Response types
+// Request types
 // ============================================================================
 
 #[derive(Deserialize)]
-struct SendEditRequest {
-    /// Yjs update as base64
-    update: String,
+struct CreateDocRequest {
     #[serde(default)]
-    author: Option<String>,
-}
-
-#[derive(Serialize)]
-struct SendEditResponse {
-    status: String,
+    content_type: Option<String>,
+    /// Optional initial content for the document
+    #[serde(default)]
+    content: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -57,55 +84,264 @@ struct SendEventRequest {
     payload: serde_json::Value,
 }
 
-#[derive(Serialize)]
-struct DocContentResponse {
-    /// Current HEAD commit ID (None if no commits yet)
-    cid: Option<String>,
-    /// Document content at HEAD
-    content: String,
+#[derive(Deserialize)]
+struct AtCommitParams {
+    at_commit: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ReplaceParams {
+    pub(super) parent_cid: Option<String>,
+    #[serde(default)]
+    pub(super) author: Option<String>,
+    #[serde(default)]
+    pub(super) override_owner: bool,
+}
+
+/// Query parameters for write endpoints that take a JSON body.
+#[derive(Deserialize)]
+pub(super) struct OwnerParams {
+    /// Admin override of single-writer file ownership
+    #[serde(default)]
+    pub(super) override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct PushParams {
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+/// A JSON request body with the target document's ID and the ownership
+/// override added.
+fn with_id(id: String, owner: OwnerParams, mut body: Map<String, Value>) -> Value {
+    body.insert("id".to_string(), Value::String(id));
+    body.insert(
+        "override_owner".to_string(),
+        Value::Bool(owner.override_owner),
+    );
+    Value::Object(body)
+}
+
+/// Respond with document content and its content type.
+pub(super) fn content_response(result: Value) -> Response {
+    let content_type = result["content_type"]
+        .as_str()
+        .unwrap_or("text/plain")
+        .to_string();
+    let content = result["content"].as_str().unwrap_or_default().to_string();
+    ([(axum::http::header::CONTENT_TYPE, content_type)], content).into_response()
 }
 
 // ============================================================================
 // Handlers
 // ============================================================================
 
-/// POST /docs/{id}/edit - Send a Yjs edit to a document via MQTT
-async fn send_edit(
+/// POST /docs - Create a document
+async fn create_doc(
     State(gateway): State<Arc<HttpGateway>>,
-    Path(id): Path<String>,
-    Json(req): Json<SendEditRequest>,
-) -> Result<Json<SendEditResponse>, (StatusCode, String)> {
-    // Build the edit message
-    let author = req.author.unwrap_or_else(|| "http-gateway".to_string());
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
-    let edit_msg = EditMessage {
-        update: req.update,
-        author,
-        parents: vec![], // Store will determine parents
-        message: None,
-        timestamp,
+    token: Option<Extension<BearerToken>>,
+    headers: HeaderMap,
+    body: Option<Json<CreateDocRequest>>,
+) -> Result<Json<Value>, Response> {
+    // Same precedence as the server: JSON body content_type > Content-Type header
+    let header_mime = |default: &str| {
+        headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(default)
+            .to_string()
+    };
+    let content_type = match &body {
+        Some(Json(req)) => req
+            .content_type
+            .clone()
+            .filter(|ct| ContentType::from_mime(ct).is_some())
+            .unwrap_or_else(|| header_mime("text/plain")),
+        None => header_mime("application/json"),
     };
+    if ContentType::from_mime(&content_type).is_none() {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+    }
 
-    // Build the topic
-    let topic = Topic::edits(&id);
+    let content = body.and_then(|Json(req)| req.content);
+    gateway
+        .request(
+            token.as_deref(),
+            "create",
+            json!({ "content_type": content_type, "content": content }),
+        )
+        .await
+        .map(Json)
+        .map_err(IntoResponse::into_response)
+}
 
-    // Serialize and publish
-    let payload =
-        serde_json::to_vec(&edit_msg).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+/// GET /fs-root - Discover the fs-root document ID
+async fn get_fs_root(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(token.as_deref(), "fs-root", json!({}))
+        .await
+        .map(Json)
+}
+
+/// GET /docs/{id} - Document content
+async fn get_doc_content(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+) -> Result<Response, GatewayError> {
+    let result = gateway
+        .request(token.as_deref(), "get", json!({ "id": id }))
+        .await?;
+    Ok(content_response(result))
+}
 
+/// DELETE /docs/{id}
+async fn delete_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(owner): Query<OwnerParams>,
+) -> Result<StatusCode, GatewayError> {
     gateway
-        .client
-        .publish(&topic.to_topic_string(), &payload, QoS::AtLeastOnce)
+        .request(
+            token.as_deref(),
+            "delete",
+            json!({ "id": id, "override_owner": owner.override_owner }),
+        )
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /docs/{id}/commit - Commit a Yjs update, merging if `parent_cid` isn't HEAD
+async fn create_commit(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(owner): Query<OwnerParams>,
+    Json(body): Json<Map<String, Value>>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(token.as_deref(), "commit", with_id(id, owner, body))
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .map(Json)
+}
+
+/// GET /docs/{id}/info
+async fn get_doc_info(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(token.as_deref(), "info", json!({ "id": id }))
+        .await
+        .map(Json)
+}
+
+/// GET /docs/{id}/head[?at_commit=CID]
+async fn get_doc_head(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(params): Query<AtCommitParams>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(
+            token.as_deref(),
+            "head",
+            json!({ "id": id, "at_commit": params.at_commit }),
+        )
+        .await
+        .map(Json)
+}
 
-    Ok(Json(SendEditResponse {
-        status: "published".to_string(),
-    }))
+/// POST /docs/{id}/edit - Apply a Yjs update
+async fn edit_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(owner): Query<OwnerParams>,
+    Json(body): Json<Map<String, Value>>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(token.as_deref(), "edit", with_id(id, owner, body))
+        .await
+        .map(Json)
+}
+
+/// POST /docs/{id}/replace - Replace content, computing the diff in the store
+async fn replace_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(params): Query<ReplaceParams>,
+    body: String,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(
+            token.as_deref(),
+            "replace",
+            json!({
+                "id": id,
+                "content": body,
+                "parent_cid": params.parent_cid,
+                "author": params.author,
+                "override_owner": params.override_owner,
+            }),
+        )
+        .await
+        .map(Json)
+}
+
+/// POST /docs/{id}/push - Replay commits made offline onto the document
+async fn push_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(params): Query<PushParams>,
+    Json(mut body): Json<Map<String, Value>>,
+) -> Result<Json<Value>, GatewayError> {
+    body.insert("author".to_string(), json!(params.author));
+    let owner = OwnerParams {
+        override_owner: params.override_owner,
+    };
+    gateway
+        .request(token.as_deref(), "push", with_id(id, owner, body))
+        .await
+        .map(Json)
+}
+
+/// GET /ws/docs/{id} - Not served: a WebSocket session keeps per-connection
+/// document state, which the stateless gateway doesn't have
+async fn ws_unsupported() -> (StatusCode, &'static str) {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        "WebSocket sync is only served by commonplace-server; \
+         use /sse/docs/:id with /docs/:id/commit instead",
+    )
+}
+
+/// POST /docs/{id}/fork[?at_commit=CID]
+async fn fork_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+    Query(params): Query<AtCommitParams>,
+) -> Result<Json<Value>, GatewayError> {
+    gateway
+        .request(
+            token.as_deref(),
+            "fork",
+            json!({ "id": id, "at_commit": params.at_commit }),
+        )
+        .await
+        .map(Json)
 }
 
 /// POST /docs/{id}/event - Send an event to a document via MQTT
@@ -129,39 +365,3 @@ async fn send_event(
 
     Ok(StatusCode::NO_CONTENT)
 }
-
-/// GET /docs/{id} - Get current document content via MQTT sync protocol
-///
-/// Note: This is a simplified implementation. Full content retrieval would
-/// require running the MQTT event loop to receive responses. For now, this
-/// returns a placeholder indicating the gateway doesn't maintain document state.
-async fn get_doc_content(
-    State(_gateway): State<Arc<HttpGateway>>,
-    Path(id): Path<String>,
-) -> Result<Json<DocContentResponse>, (StatusCode, String)> {
-    // The HTTP gateway is stateless - it doesn't maintain document state.
-    // To get document content, clients should:
-    // 1. Subscribe via SSE to receive updates
-    // 2. Use the sync protocol directly via MQTT
-    //
-    // For now, return a response indicating the document ID was received
-    // but content is not available through the stateless gateway.
-    //
-    // TODO: Implement proper sync protocol request/response via MQTT
-    // This would require changes to MqttClient to support per-request
-    // response correlation.
-
-    tracing::warn!(
-        "GET /docs/{} - content retrieval not implemented in stateless gateway",
-        id
-    );
-
-    Err((
-        StatusCode::NOT_IMPLEMENTED,
-        format!(
-            "Content retrieval not available via stateless HTTP gateway. \
-             Subscribe via SSE at /sse/docs/{} to receive updates.",
-            id
-        ),
-    ))
-}
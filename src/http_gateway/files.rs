@@ -0,0 +1,106 @@
+//! HTTP Gateway path-based file routes
+//!
+//! Mirrors `/files/*path` of `commonplace-server`. The store resolves the
+//! path against its fs-root, so each request is a single store command.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+use super::api::{content_response, OwnerParams, ReplaceParams};
+use super::{GatewayError, HttpGateway};
+use crate::auth::BearerToken;
+
+/// Create the file router for the HTTP gateway
+pub fn router() -> Router<Arc<HttpGateway>> {
+    Router::new().route(
+        "/files/*path",
+        get(handle_file_request)
+            .delete(handle_file_delete)
+            .post(handle_file_post),
+    )
+}
+
+/// GET /files/*path - Content, or HEAD for `.../head`
+async fn handle_file_request(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(path): Path<String>,
+) -> Result<Response, GatewayError> {
+    if let Some(clean_path) = path.strip_suffix("/head") {
+        let head = gateway
+            .request(token.as_deref(), "head", json!({ "path": clean_path }))
+            .await?;
+        Ok(Json(head).into_response())
+    } else {
+        let result = gateway
+            .request(token.as_deref(), "get", json!({ "path": path }))
+            .await?;
+        Ok(content_response(result))
+    }
+}
+
+/// DELETE /files/*path
+async fn handle_file_delete(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(path): Path<String>,
+    Query(owner): Query<OwnerParams>,
+) -> Result<StatusCode, GatewayError> {
+    gateway
+        .request(
+            token.as_deref(),
+            "delete",
+            json!({ "path": path, "override_owner": owner.override_owner }),
+        )
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /files/*path - `.../edit` or `.../replace`
+async fn handle_file_post(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(path): Path<String>,
+    Query(params): Query<ReplaceParams>,
+    body: String,
+) -> Result<Response, Response> {
+    let result = if let Some(clean_path) = path.strip_suffix("/edit") {
+        let mut req: Map<String, Value> =
+            serde_json::from_str(&body).map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+        req.insert("path".to_string(), Value::String(clean_path.to_string()));
+        req.insert(
+            "override_owner".to_string(),
+            Value::Bool(params.override_owner),
+        );
+        gateway
+            .request(token.as_deref(), "edit", Value::Object(req))
+            .await
+    } else if let Some(clean_path) = path.strip_suffix("/replace") {
+        gateway
+            .request(
+                token.as_deref(),
+                "replace",
+                json!({
+                    "path": clean_path,
+                    "content": body,
+                    "parent_cid": params.parent_cid,
+                    "author": params.author,
+                    "override_owner": params.override_owner,
+                }),
+            )
+            .await
+    } else {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    };
+
+    result
+        .map(|result| Json(result).into_response())
+        .map_err(IntoResponse::into_response)
+}
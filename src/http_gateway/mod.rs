@@ -1,18 +1,102 @@
 //! HTTP Gateway - translates HTTP requests to MQTT messages
 //!
 //! This module provides a stateless HTTP interface that communicates
-//! with the document store via MQTT.
+//! with the document store via MQTT. It serves the HTTP and SSE routes of
+//! `commonplace-server`: requests become store API commands on
+//! `$store/commands/{verb}` (see [`crate::mqtt::store_api`]) and SSE
+//! streams follow the store's `$store/commits` feed. WebSocket sessions
+//! (`/ws/docs/:id`) aren't served, since they hold per-connection document
+//! state; the gateway answers them with `501 Not Implemented`.
 
 mod api;
+mod files;
 mod sse;
 
 pub use api::router;
 
-use crate::mqtt::{client::MqttClient, MqttConfig, MqttError};
+use crate::auth::{AuthState, Authenticator, BearerToken};
+use crate::mqtt::store_api::{codes, store_command};
+use crate::mqtt::{
+    client::MqttClient, CommandMessage, CommandReply, MessageProperties, MqttConfig, MqttError,
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use rumqttc::QoS;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{oneshot, Mutex, RwLock};
+
+/// Default time to wait for the store to answer a request.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Error from a request forwarded to the store.
+#[derive(Debug)]
+pub enum GatewayError {
+    /// The store answered with an error
+    Store {
+        code: Option<String>,
+        message: String,
+    },
+    /// The request didn't reach the store or wasn't answered
+    Mqtt(MqttError),
+}
+
+impl From<MqttError> for GatewayError {
+    fn from(e: MqttError) -> Self {
+        GatewayError::Mqtt(e)
+    }
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        match self {
+            GatewayError::Store { code, message } => {
+                let status = match code.as_deref() {
+                    Some(codes::NOT_FOUND) => StatusCode::NOT_FOUND,
+                    Some(codes::NO_PERSISTENCE) | Some(codes::UNKNOWN_COMMAND) => {
+                        StatusCode::NOT_IMPLEMENTED
+                    }
+                    Some(codes::INVALID_INPUT) => StatusCode::BAD_REQUEST,
+                    Some(codes::CONFLICT) => StatusCode::CONFLICT,
+                    Some(codes::UNAUTHORIZED) => StatusCode::UNAUTHORIZED,
+                    Some(codes::FORBIDDEN) => StatusCode::FORBIDDEN,
+                    Some(codes::UNSUPPORTED_MEDIA_TYPE) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    Some(codes::UNAVAILABLE) => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, message).into_response()
+            }
+            GatewayError::Mqtt(MqttError::Timeout(message)) => {
+                (StatusCode::GATEWAY_TIMEOUT, message).into_response()
+            }
+            GatewayError::Mqtt(e) => (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+        }
+    }
+}
+
+/// Auth state for the gateway's routes. Path-scoped principals are checked
+/// against the paths the store reports for a document, asked for with the
+/// caller's own token.
+fn auth_state(gateway: Arc<HttpGateway>, authenticator: Arc<dyn Authenticator>) -> AuthState {
+    AuthState::new(authenticator).with_doc_paths(Arc::new(move |token, doc_id| {
+        let gateway = gateway.clone();
+        Box::pin(async move {
+            let token = BearerToken(token);
+            match gateway
+                .request(Some(&token), "paths", serde_json::json!({ "id": doc_id }))
+                .await
+            {
+                Ok(result) => serde_json::from_value(result["paths"].clone()).unwrap_or_default(),
+                Err(e) => {
+                    tracing::debug!("Couldn't look up paths of {}: {:?}", doc_id, e);
+                    Vec::new()
+                }
+            }
+        })
+    }))
+}
 
 /// HTTP Gateway that translates HTTP to MQTT
 pub struct HttpGateway {
@@ -20,6 +104,11 @@ pub struct HttpGateway {
     pub(crate) client: Arc<MqttClient>,
     /// Reference counts for MQTT topic subscriptions (for SSE)
     pub(crate) subscription_counts: Arc<RwLock<HashMap<String, usize>>>,
+    /// Store requests awaiting a reply, by request ID
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<CommandReply>>>>,
+    /// Prefix of this gateway's reply topics
+    reply_prefix: String,
+    request_timeout: Duration,
 }
 
 impl HttpGateway {
@@ -28,8 +117,42 @@ impl HttpGateway {
     /// This spawns the MQTT event loop in the background so that
     /// publish/subscribe operations can actually execute.
     pub async fn new(config: MqttConfig) -> Result<Self, MqttError> {
+        let reply_prefix = format!("$store/replies/{}/", config.client_id);
         let client = Arc::new(MqttClient::connect(config).await?);
 
+        // Route store replies to the requests waiting for them
+        let pending: Arc<Mutex<HashMap<String, oneshot::Sender<CommandReply>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        client
+            .subscribe(&format!("{}+", reply_prefix), QoS::AtLeastOnce)
+            .await?;
+        let mut messages = client.subscribe_messages();
+        let pending_for_replies = pending.clone();
+        let prefix = reply_prefix.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = match messages.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("HTTP gateway lagged by {} MQTT messages", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !msg.topic.starts_with(&prefix) {
+                    continue;
+                }
+                match serde_json::from_slice::<CommandReply>(&msg.payload) {
+                    Ok(reply) => {
+                        if let Some(waiter) = pending_for_replies.lock().await.remove(&reply.req) {
+                            let _ = waiter.send(reply);
+                        }
+                    }
+                    Err(e) => tracing::warn!("Ignoring malformed reply on {}: {}", msg.topic, e),
+                }
+            }
+        });
+
         // Spawn the MQTT event loop so publishes/subscribes actually work
         let client_for_loop = client.clone();
         tokio::spawn(async move {
@@ -41,14 +164,82 @@ impl HttpGateway {
         Ok(Self {
             client,
             subscription_counts: Arc::new(RwLock::new(HashMap::new())),
+            pending,
+            reply_prefix,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
         })
     }
 
+    /// Set how long to wait for the store to answer a request.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
     /// Get a reference to the MQTT client
     pub fn client(&self) -> &Arc<MqttClient> {
         &self.client
     }
 
+    /// Send a store API command on behalf of the caller holding `token`
+    /// and wait for its result.
+    pub(crate) async fn request(
+        &self,
+        token: Option<&BearerToken>,
+        verb: &str,
+        payload: serde_json::Value,
+    ) -> Result<serde_json::Value, GatewayError> {
+        let req = uuid::Uuid::new_v4().to_string();
+        let reply_to = format!("{}{}", self.reply_prefix, req);
+        let properties = MessageProperties::request(&reply_to, &req);
+        let message = CommandMessage {
+            payload,
+            source: Some(self.client.client_id().to_string()),
+            reply_to: Some(reply_to),
+            req: Some(req.clone()),
+            token: token.map(|t| t.0.clone()),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(req.clone(), tx);
+        let published = self
+            .client
+            .publish_with_properties(
+                &store_command(verb),
+                &serde_json::to_vec(&message).map_err(MqttError::from)?,
+                QoS::AtLeastOnce,
+                &properties,
+            )
+            .await;
+        if let Err(e) = published {
+            self.pending.lock().await.remove(&req);
+            return Err(e.into());
+        }
+
+        let reply = match tokio::time::timeout(self.request_timeout, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => {
+                return Err(MqttError::Connection("MQTT client closed".to_string()).into())
+            }
+            Err(_) => {
+                self.pending.lock().await.remove(&req);
+                return Err(MqttError::Timeout(format!(
+                    "store did not answer '{}' within {:?}",
+                    verb, self.request_timeout
+                ))
+                .into());
+            }
+        };
+
+        match reply.error {
+            Some(message) => Err(GatewayError::Store {
+                code: reply.code,
+                message,
+            }),
+            None => Ok(reply.result.unwrap_or(serde_json::Value::Null)),
+        }
+    }
+
     /// Increment the subscription count for a topic.
     /// If this is the first subscriber, actually subscribe to MQTT.
     pub(crate) async fn add_subscriber(&self, topic: &str) -> Result<(), MqttError> {
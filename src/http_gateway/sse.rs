@@ -1,5 +1,9 @@
 //! HTTP Gateway SSE routes - bridges MQTT to Server-Sent Events
 //!
+//! Mirrors the change-history and SSE routes of `commonplace-server`.
+//! Streams follow the store's `$store/commits` feed; change lists come from
+//! the store's `changes` command.
+//!
 //! ## Document ID Encoding
 //!
 //! Document IDs may contain path separators (e.g., `foo/bar/file.txt`). When making
@@ -11,103 +15,317 @@
 //! Axum automatically URL-decodes the path parameter.
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::StatusCode,
     response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Extension, Json, Router,
 };
-use futures::stream::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
 use std::convert::Infallible;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
 
-use super::HttpGateway;
+use super::{GatewayError, HttpGateway};
+use crate::auth::BearerToken;
+use crate::mqtt::client::IncomingMessage;
+use crate::mqtt::{StoreCommit, STORE_COMMITS};
+use crate::sse::{commit_url, parse_doc_ids, CommitChange};
+
+type EventStream = Sse<ReceiverStream<Result<Event, Infallible>>>;
 
 /// Create the SSE router for the HTTP gateway
-pub fn router(_gateway: Arc<HttpGateway>) -> Router<Arc<HttpGateway>> {
-    Router::new().route("/docs/:id", get(subscribe_to_doc))
+pub fn router() -> Router<Arc<HttpGateway>> {
+    Router::new()
+        .route("/documents/:id/changes", get(get_document_changes))
+        .route("/documents/changes", get(get_documents_changes))
+        .route("/documents/:id/stream", get(stream_document_changes))
+        .route("/documents/stream", get(stream_documents_changes))
+        .route("/sse/docs/:id", get(stream_doc))
+        .route("/sse/files/*path", get(stream_file))
+}
+
+#[derive(Deserialize)]
+struct SinceQuery {
+    #[serde(default)]
+    since: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct MultiDocQuery {
+    doc_ids: String,
+    #[serde(default)]
+    since: Option<u64>,
+}
+
+/// Edit event data, as sent by the server's SSE endpoints
+#[derive(Serialize)]
+struct EditEventData {
+    source: String,
+    commit: CommitEventData,
+}
+
+#[derive(Serialize)]
+struct CommitEventData {
+    update: String,
+    parents: Vec<String>,
+    timestamp: u64,
+    author: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+// ============================================================================
+// Change history
+// ============================================================================
+
+async fn request_changes(
+    gateway: &HttpGateway,
+    token: Option<&BearerToken>,
+    doc_ids: &[String],
+    since: Option<u64>,
+) -> Result<Vec<CommitChange>, GatewayError> {
+    let result = gateway
+        .request(
+            token,
+            "changes",
+            json!({ "doc_ids": doc_ids, "since": since }),
+        )
+        .await?;
+    serde_json::from_value(result["changes"].clone()).map_err(|e| GatewayError::Store {
+        code: None,
+        message: format!("Malformed changes from store: {}", e),
+    })
 }
 
-/// GET /sse/docs/{id} - Subscribe to document updates via SSE
-///
-/// This subscribes to the MQTT edits topic for the document and streams
-/// updates to the client as SSE events.
-async fn subscribe_to_doc(
+/// GET /documents/{id}/changes[?since=MS]
+async fn get_document_changes(
     State(gateway): State<Arc<HttpGateway>>,
-    Path(id): Path<String>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    // Create a channel for SSE events
-    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(100);
+    token: Option<Extension<BearerToken>>,
+    Path(doc_id): Path<String>,
+    Query(query): Query<SinceQuery>,
+) -> Result<Json<Value>, GatewayError> {
+    let changes = request_changes(&gateway, token.as_deref(), &[doc_id], query.since).await?;
+    Ok(Json(json!({ "changes": changes })))
+}
+
+/// GET /documents/changes?doc_ids=a,b[&since=MS]
+async fn get_documents_changes(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Query(query): Query<MultiDocQuery>,
+) -> Result<Json<Value>, Response> {
+    let doc_ids = parse_doc_ids(&query.doc_ids);
+    if doc_ids.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    let changes = request_changes(&gateway, token.as_deref(), &doc_ids, query.since)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    Ok(Json(json!({ "changes": changes })))
+}
 
-    // Subscribe to the MQTT edits topic
-    let edits_topic = format!("{}/edits", id);
+/// GET /documents/{id}/stream[?since=MS] - Commit changes as SSE
+async fn stream_document_changes(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(doc_id): Path<String>,
+    Query(query): Query<SinceQuery>,
+) -> Result<EventStream, GatewayError> {
+    stream_changes(
+        gateway,
+        token.as_deref(),
+        vec![doc_id],
+        query.since.unwrap_or(0),
+    )
+    .await
+}
 
-    let gateway_clone = gateway.clone();
-    let edits_topic_clone = edits_topic.clone();
-    let tx_clone = tx.clone();
-    let id_clone = id.clone();
+/// GET /documents/stream?doc_ids=a,b[&since=MS]
+async fn stream_documents_changes(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Query(query): Query<MultiDocQuery>,
+) -> Result<EventStream, Response> {
+    let doc_ids = parse_doc_ids(&query.doc_ids);
+    if doc_ids.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+    stream_changes(gateway, token.as_deref(), doc_ids, query.since.unwrap_or(0))
+        .await
+        .map_err(IntoResponse::into_response)
+}
 
-    // Spawn a task to handle the MQTT subscription and forward messages
-    tokio::spawn(async move {
-        // Subscribe to edits using reference-counted subscription
-        if let Err(e) = gateway_clone.add_subscriber(&edits_topic_clone).await {
-            tracing::error!("Failed to subscribe to {}: {}", edits_topic_clone, e);
-            let _ = tx_clone
-                .send(Ok(Event::default()
-                    .event("error")
-                    .data(format!("Failed to subscribe: {}", e))))
-                .await;
-            return;
+async fn stream_changes(
+    gateway: Arc<HttpGateway>,
+    token: Option<&BearerToken>,
+    doc_ids: Vec<String>,
+    since: u64,
+) -> Result<EventStream, GatewayError> {
+    // Follow the feed before listing changes so none fall in between
+    let feed = follow_commits(&gateway).await?;
+    let initial = match request_changes(&gateway, token, &doc_ids, Some(since)).await {
+        Ok(initial) => initial,
+        Err(e) => {
+            gateway.remove_subscriber(STORE_COMMITS).await;
+            return Err(e);
+        }
+    };
+
+    let mut seen: HashSet<String> = initial
+        .iter()
+        .map(|change| format!("{}:{}", change.doc_id, change.commit_id))
+        .collect();
+    let initial = initial
+        .iter()
+        .filter_map(|change| serde_json::to_string(change).ok())
+        .map(|data| Event::default().event("commit").data(data))
+        .collect();
+    let doc_filter: HashSet<String> = doc_ids.into_iter().collect();
+
+    Ok(stream_commits(gateway, feed, initial, move |commit| {
+        if !doc_filter.contains(&commit.doc_id) || commit.timestamp < since {
+            return None;
         }
+        if !seen.insert(format!("{}:{}", commit.doc_id, commit.cid)) {
+            return None;
+        }
+        let change = CommitChange {
+            url: commit_url(&commit.doc_id, &commit.cid),
+            doc_id: commit.doc_id,
+            commit_id: commit.cid,
+            timestamp: commit.timestamp,
+        };
+        let data = serde_json::to_string(&change).ok()?;
+        Some(Event::default().event("commit").data(data))
+    }))
+}
 
-        // Send initial connected event
-        let _ = tx_clone
-            .send(Ok(Event::default()
-                .event("connected")
-                .data(format!("{{\"node_id\": \"{}\"}}", id_clone))))
-            .await;
+// ============================================================================
+// Edit streams (for sync client)
+// ============================================================================
 
-        // Subscribe to the MQTT message broadcast and forward matching messages
-        let mut mqtt_rx = gateway_clone.client.subscribe_messages();
+/// GET /sse/docs/{id} - Stream edits to a document
+async fn stream_doc(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(id): Path<String>,
+) -> Result<EventStream, GatewayError> {
+    gateway
+        .request(token.as_deref(), "info", json!({ "id": id }))
+        .await?;
+    stream_edits(gateway, id).await
+}
+
+/// GET /sse/files/*path - Stream edits to the document at a path
+async fn stream_file(
+    State(gateway): State<Arc<HttpGateway>>,
+    token: Option<Extension<BearerToken>>,
+    Path(path): Path<String>,
+) -> Result<EventStream, GatewayError> {
+    let resolved = gateway
+        .request(token.as_deref(), "resolve", json!({ "path": path }))
+        .await?;
+    let id = resolved["id"].as_str().unwrap_or_default().to_string();
+    stream_edits(gateway, id).await
+}
+
+async fn stream_edits(
+    gateway: Arc<HttpGateway>,
+    doc_id: String,
+) -> Result<EventStream, GatewayError> {
+    let feed = follow_commits(&gateway).await?;
+    Ok(stream_commits(gateway, feed, Vec::new(), move |commit| {
+        if commit.doc_id != doc_id {
+            return None;
+        }
+        let event = EditEventData {
+            source: "server".to_string(),
+            commit: CommitEventData {
+                update: commit.update,
+                parents: commit.parents,
+                timestamp: commit.timestamp,
+                author: commit.author,
+                message: commit.message,
+            },
+        };
+        let data = serde_json::to_string(&event).ok()?;
+        Some(Event::default().event("edit").data(data))
+    }))
+}
+
+// ============================================================================
+// Commit feed
+// ============================================================================
+
+/// Start receiving `$store/commits`.
+async fn follow_commits(
+    gateway: &HttpGateway,
+) -> Result<broadcast::Receiver<IncomingMessage>, GatewayError> {
+    // Take the receiver first so nothing published after subscribing is missed
+    let messages = gateway.client.subscribe_messages();
+    gateway.add_subscriber(STORE_COMMITS).await?;
+    Ok(messages)
+}
+
+/// Stream `initial`, then an event for each feed commit `to_event` accepts,
+/// until the client disconnects.
+fn stream_commits<F>(
+    gateway: Arc<HttpGateway>,
+    mut feed: broadcast::Receiver<IncomingMessage>,
+    initial: Vec<Event>,
+    mut to_event: F,
+) -> EventStream
+where
+    F: FnMut(StoreCommit) -> Option<Event> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(100);
+
+    tokio::spawn(async move {
+        for event in initial {
+            if tx.send(Ok(event)).await.is_err() {
+                break;
+            }
+        }
 
         loop {
             tokio::select! {
-                // Wait for MQTT messages
-                result = mqtt_rx.recv() => {
-                    match result {
-                        Ok(msg) => {
-                            // Check if this message is for our topic
-                            if msg.topic == edits_topic_clone {
-                                // Convert payload to string (it's JSON)
-                                let payload = String::from_utf8_lossy(&msg.payload);
-                                if tx_clone.send(Ok(Event::default()
-                                    .event("edit")
-                                    .data(&*payload))).await.is_err() {
-                                    // Client disconnected
-                                    break;
-                                }
-                            }
-                        }
-                        Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                result = feed.recv() => {
+                    let msg = match result {
+                        Ok(msg) => msg,
+                        Err(RecvError::Lagged(n)) => {
                             tracing::warn!("SSE subscriber lagged {} messages", n);
+                            continue;
                         }
-                        Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                            // MQTT client shut down
+                        // MQTT client shut down
+                        Err(RecvError::Closed) => break,
+                    };
+                    if msg.topic != STORE_COMMITS {
+                        continue;
+                    }
+                    let Ok(commit) = serde_json::from_slice::<StoreCommit>(&msg.payload) else {
+                        continue;
+                    };
+                    if let Some(event) = to_event(commit) {
+                        if tx.send(Ok(event)).await.is_err() {
+                            // Client disconnected
                             break;
                         }
                     }
                 }
                 // Check if SSE client disconnected
-                _ = tx_clone.closed() => {
-                    break;
-                }
+                _ = tx.closed() => break,
             }
         }
 
         // Cleanup: decrement reference count (only unsubscribes if last subscriber)
-        gateway_clone.remove_subscriber(&edits_topic_clone).await;
-        tracing::debug!("SSE client disconnected from {}", edits_topic_clone);
+        gateway.remove_subscriber(STORE_COMMITS).await;
+        tracing::debug!("SSE client disconnected from {}", STORE_COMMITS);
     });
 
     Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default())
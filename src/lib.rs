@@ -1,4 +1,5 @@
 pub mod api;
+pub mod auth;
 pub mod b64;
 pub mod cli;
 pub mod commit;
@@ -19,11 +20,12 @@ pub mod sync;
 pub mod workspace;
 pub mod ws;
 
-use axum::{routing::get, Router};
+use auth::{AuthState, Authenticator};
+use axum::{middleware, routing::get, Router};
 use content_type::ContentType;
 use document::DocumentStore;
 use events::CommitBroadcaster;
-use fs::FilesystemReconciler;
+use fs::{FilesystemReconciler, OwnershipGuard, OwnershipPolicy};
 use services::DocumentService;
 use std::sync::Arc;
 use store::CommitStore;
@@ -44,6 +46,13 @@ pub struct RouterConfig {
     pub mqtt: Option<mqtt::MqttConfig>,
     /// Document paths to subscribe via MQTT (requires mqtt to be set)
     pub mqtt_subscribe: Vec<String>,
+    /// Authenticator for bearer tokens (if unset, all routes are open)
+    pub auth: Option<Arc<dyn Authenticator>>,
+    /// How to treat writes to files owned by another process (requires fs_root)
+    pub ownership: OwnershipPolicy,
+    /// Paths whose MQTT events are recorded to `{path}.events.jsonl`
+    /// (requires mqtt, fs_root and commit_store)
+    pub event_log: mqtt::EventLogConfig,
 }
 
 /// Create a router with the given configuration.
@@ -85,6 +94,38 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
         (None, None)
     };
 
+    // Ownership is declared in processes.json files inside the fs-root tree
+    let ownership = match (&config.fs_root, config.ownership) {
+        (Some(_), OwnershipPolicy::Off) | (None, _) => None,
+        (Some(fs_root_id), policy) => Some(Arc::new(OwnershipGuard::new(
+            doc_store.clone(),
+            fs_root_id.clone(),
+            policy,
+        ))),
+    };
+
+    // Create shared service for handlers
+    let service =
+        if let (Some(reconciler), Some(ref fs_root_id)) = (reconciler.clone(), &config.fs_root) {
+            DocumentService::with_reconciler(
+                doc_store.clone(),
+                commit_store.clone(),
+                commit_broadcaster.clone(),
+                reconciler,
+                fs_root_id.clone(),
+            )
+        } else {
+            DocumentService::new(
+                doc_store.clone(),
+                commit_store.clone(),
+                commit_broadcaster.clone(),
+            )
+        };
+    let service = Arc::new(match ownership {
+        Some(ref guard) => service.with_ownership(guard.clone()),
+        None => service,
+    });
+
     // Initialize MQTT service if configured
     if let Some(mqtt_config) = config.mqtt {
         match mqtt::MqttService::new(mqtt_config, doc_store.clone(), commit_store.clone()).await {
@@ -110,6 +151,12 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
                         .sync_handler()
                         .set_fs_root_path(fs_root_id.clone())
                         .await;
+                    if let Some(ref guard) = ownership {
+                        mqtt_service
+                            .edits_handler()
+                            .set_ownership(guard.clone())
+                            .await;
+                    }
                     tracing::info!("MQTT handlers initialized with fs-root context");
                 }
 
@@ -120,6 +167,19 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
                     tracing::info!("MQTT subscribed to store commands");
                 }
 
+                // Answer store API requests from HTTP gateways
+                mqtt_service
+                    .serve_store_api(service.clone(), config.fs_root.clone(), config.auth.clone())
+                    .await;
+                if let (Some(ref commit_store), Some(ref broadcaster)) =
+                    (&commit_store, &commit_broadcaster)
+                {
+                    let feed =
+                        mqtt::CommitFeed::new(mqtt_service.client().clone(), commit_store.clone());
+                    let commits = broadcaster.subscribe();
+                    tokio::spawn(async move { feed.run(commits).await });
+                }
+
                 // Subscribe to configured document paths
                 for path in &config.mqtt_subscribe {
                     if let Err(e) = mqtt_service.subscribe_path(path).await {
@@ -129,6 +189,57 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
                     }
                 }
 
+                // Announce heads of commits made over HTTP and WebSocket
+                if let (Some(ref fs_root_id), Some(ref commit_store), Some(ref broadcaster)) =
+                    (&config.fs_root, &commit_store, &commit_broadcaster)
+                {
+                    let announcer = mqtt::HeadAnnouncer::new(
+                        mqtt_service.client().clone(),
+                        doc_store.clone(),
+                        commit_store.clone(),
+                        fs_root_id.clone(),
+                    );
+                    let commits = broadcaster.subscribe();
+                    tokio::spawn(async move { announcer.run(commits).await });
+                }
+
+                // Record events for configured paths
+                if let (Some(ref fs_root_id), Some(_)) = (&config.fs_root, &commit_store) {
+                    if !config.event_log.paths.is_empty() {
+                        let event_log = mqtt::EventLog::new(
+                            service.clone(),
+                            doc_store.clone(),
+                            fs_root_id.clone(),
+                            config.event_log.clone(),
+                        );
+                        if let Err(e) = mqtt_service.set_event_log(Arc::new(event_log)).await {
+                            tracing::warn!("Failed to subscribe to recorded events: {}", e);
+                        } else {
+                            tracing::info!(
+                                "Recording MQTT events for {:?}",
+                                config.event_log.paths
+                            );
+                        }
+                    }
+                }
+
+                // Enforce bridges declared in bridges.json files
+                if let (Some(ref fs_root_id), Some(ref broadcaster)) =
+                    (&config.fs_root, &commit_broadcaster)
+                {
+                    if let Err(e) = mqtt_service
+                        .clone()
+                        .enable_bridges(
+                            service.clone(),
+                            fs_root_id.clone(),
+                            broadcaster.subscribe(),
+                        )
+                        .await
+                    {
+                        tracing::warn!("Failed to enable bridges: {}", e);
+                    }
+                }
+
                 // Start the event loop
                 let service_for_loop = mqtt_service.clone();
                 tokio::spawn(async move {
@@ -136,6 +247,15 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
                         tracing::error!("MQTT event loop error: {}", e);
                     }
                 });
+
+                // Subscribe to documents as they're added to (and removed
+                // from) the fs-root tree
+                if let Some(ref reconciler) = reconciler {
+                    mqtt_service
+                        .clone()
+                        .follow_reconciler(reconciler.clone())
+                        .await;
+                }
             }
             Err(e) => {
                 tracing::error!("Failed to connect MQTT service: {}", e);
@@ -143,27 +263,7 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
         }
     }
 
-    // Create shared service for handlers
-    let service = Arc::new(
-        if let (Some(reconciler), Some(ref fs_root_id)) = (reconciler, &config.fs_root) {
-            DocumentService::with_reconciler(
-                doc_store.clone(),
-                commit_store.clone(),
-                commit_broadcaster.clone(),
-                reconciler,
-                fs_root_id.clone(),
-            )
-        } else {
-            DocumentService::new(
-                doc_store.clone(),
-                commit_store.clone(),
-                commit_broadcaster.clone(),
-            )
-        },
-    );
-
-    Router::new()
-        .route("/health", get(health_check))
+    let routes = Router::new()
         .merge(api::router(
             doc_store.clone(),
             commit_store.clone(),
@@ -176,7 +276,7 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
             commit_store.clone(),
             commit_broadcaster.clone(),
             config.fs_root.clone(),
-            service,
+            service.clone(),
         ))
         .merge(sse::router(
             doc_store.clone(),
@@ -185,11 +285,32 @@ pub async fn create_router_with_config(config: RouterConfig) -> Router {
             config.fs_root.clone(),
         ))
         .merge(ws::router(
-            doc_store,
+            doc_store.clone(),
             commit_store,
             commit_broadcaster,
-            config.fs_root,
-        ))
+            config.fs_root.clone(),
+            ownership,
+            service,
+        ));
+
+    // Health stays open so load balancers can probe without credentials
+    let routes = match config.auth {
+        Some(authenticator) => {
+            let auth_state = match config.fs_root {
+                Some(fs_root) => AuthState::new(authenticator).with_fs_root(doc_store, fs_root),
+                None => AuthState::new(authenticator),
+            };
+            routes.layer(middleware::from_fn_with_state(
+                auth_state,
+                auth::require_auth,
+            ))
+        }
+        None => routes,
+    };
+
+    Router::new()
+        .route("/health", get(health_check))
+        .merge(routes)
         .layer(CorsLayer::permissive())
 }
 
@@ -220,7 +341,7 @@ pub fn create_router_with_store(store: Option<CommitStore>) -> Router {
             commit_store.clone(),
             commit_broadcaster.clone(),
             None, // No fs-root in this variant
-            service,
+            service.clone(),
         ))
         .merge(sse::router(
             doc_store.clone(),
@@ -233,6 +354,8 @@ pub fn create_router_with_store(store: Option<CommitStore>) -> Router {
             commit_store,
             commit_broadcaster,
             None,
+            None,
+            service,
         ))
         .layer(CorsLayer::permissive())
 }
@@ -0,0 +1,100 @@
+//! Bloom filter of commit IDs for sync negotiation.
+//!
+//! A client with a long history can describe the commits it has in a few
+//! hundred bytes instead of listing every CID. Bit positions use double
+//! hashing over SHA-256 of the CID, so other implementations can build
+//! compatible filters: with `h1`/`h2` the first two little-endian `u64`s of
+//! the digest, hash `i` sets bit `(h1 + i * (h2 | 1)) mod m`, where bit `n`
+//! is `bits[n / 8] & (1 << (n % 8))`.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A Bloom filter over commit IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HaveFilter {
+    /// Base64-encoded bit array
+    pub bits: String,
+    /// Number of hash functions
+    pub hashes: u32,
+}
+
+impl HaveFilter {
+    /// Build a filter sized for `cids` at the given false-positive rate.
+    pub fn from_cids<'a>(cids: impl ExactSizeIterator<Item = &'a str>, fp_rate: f64) -> Self {
+        let n = cids.len().max(1) as f64;
+        let fp_rate = fp_rate.clamp(1e-9, 0.5);
+        let m_bits = (-n * fp_rate.ln() / std::f64::consts::LN_2.powi(2)).ceil() as usize;
+        let bytes = m_bits.div_ceil(8).max(1);
+        let hashes = ((bytes * 8) as f64 / n * std::f64::consts::LN_2)
+            .round()
+            .clamp(1.0, 32.0) as u32;
+
+        let mut bits = vec![0u8; bytes];
+        for cid in cids {
+            for bit in positions(cid, hashes, bytes * 8) {
+                bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self {
+            bits: crate::b64::encode(&bits),
+            hashes,
+        }
+    }
+
+    /// Decode into a filter that can be queried.
+    pub fn decode(&self) -> Result<DecodedFilter, String> {
+        let bits = crate::b64::decode(&self.bits).map_err(|e| format!("Invalid bloom: {}", e))?;
+        if bits.is_empty() || self.hashes == 0 {
+            return Err("Empty bloom filter".to_string());
+        }
+        Ok(DecodedFilter {
+            bits,
+            hashes: self.hashes,
+        })
+    }
+}
+
+/// A [`HaveFilter`] with its bits decoded.
+pub struct DecodedFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl DecodedFilter {
+    /// Whether `cid` may be in the set (false positives are possible).
+    pub fn contains(&self, cid: &str) -> bool {
+        positions(cid, self.hashes, self.bits.len() * 8)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+fn positions(cid: &str, hashes: u32, m: usize) -> impl Iterator<Item = usize> {
+    let digest = Sha256::digest(cid.as_bytes());
+    let h1 = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+    let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_contains_members() {
+        let cids: Vec<String> = (0..500).map(|i| format!("cid-{}", i)).collect();
+        let filter = HaveFilter::from_cids(cids.iter().map(|s| s.as_str()), 0.01);
+        let decoded = filter.decode().unwrap();
+
+        assert!(cids.iter().all(|cid| decoded.contains(cid)));
+        let false_positives = (0..1000)
+            .filter(|i| decoded.contains(&format!("other-{}", i)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+
+        // Survives the wire
+        let json = serde_json::to_string(&filter).unwrap();
+        let parsed: HaveFilter = serde_json::from_str(&json).unwrap();
+        assert!(parsed.decode().unwrap().contains("cid-42"));
+    }
+}
@@ -0,0 +1,426 @@
+//! Declarative topic bridges between paths.
+//!
+//! A `bridges.json` anywhere in the fs-root tree wires paths together, so
+//! simple glue doesn't need a process of its own:
+//!
+//! ```json
+//! { "bridges": [
+//!     { "from": "sensors/temp.json", "port": "events", "name": "reading",
+//!       "to": "alerts/monitor.json", "as": "commands", "rename": "check" },
+//!     { "from": "a.txt", "port": "edits", "to": "b.txt" } ] }
+//! ```
+//!
+//! The store enforces bridges over MQTT. Events and commands are republished
+//! on the target path; messages a bridge produced are not bridged again, so
+//! bridges can't loop. An edits bridge mirrors content: after each commit to
+//! the source document (as notified by the store's commit broadcaster), the
+//! target is replaced with the source's content.
+
+use crate::document::DocumentStore;
+use crate::fs::{join_path, walk_tree};
+use crate::mqtt::client::MqttClient;
+use crate::mqtt::messages::{CommandMessage, EventMessage};
+use crate::mqtt::topics::{validate_extension, Port, Topic};
+use crate::mqtt::MqttError;
+use crate::services::DocumentService;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+/// Name of the files that declare bridges.
+pub const BRIDGES_FILE: &str = "bridges.json";
+
+/// Author recorded on commits made by edits bridges.
+const BRIDGE_AUTHOR: &str = "commonplace-bridge";
+
+/// Prefix of the `source` on bridged events and commands.
+pub const BRIDGE_SOURCE_PREFIX: &str = "bridge:";
+
+/// Event published on a bridges file's path when a bridge is invalid.
+pub const BRIDGE_ERROR_EVENT: &str = "bridge-error";
+
+/// Contents of a `bridges.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BridgesConfig {
+    #[serde(default)]
+    pub bridges: Vec<BridgeSpec>,
+}
+
+impl BridgesConfig {
+    pub fn parse(content: &str) -> Result<Self, serde_json::Error> {
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(content)
+    }
+}
+
+/// Port a bridge reads from or writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgePort {
+    Edits,
+    Events,
+    Commands,
+}
+
+impl From<BridgePort> for Port {
+    fn from(port: BridgePort) -> Self {
+        match port {
+            BridgePort::Edits => Port::Edits,
+            BridgePort::Events => Port::Events,
+            BridgePort::Commands => Port::Commands,
+        }
+    }
+}
+
+/// One bridge as written in `bridges.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeSpec {
+    /// Source path, relative to the bridges file's directory
+    pub from: String,
+    /// Port to read on the source
+    pub port: BridgePort,
+    /// Target path, relative to the bridges file's directory
+    pub to: String,
+    /// Port to write on the target (defaults to `port`)
+    #[serde(default, rename = "as", skip_serializing_if = "Option::is_none")]
+    pub as_port: Option<BridgePort>,
+    /// Only bridge this event name or command verb (all if unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Event name or verb on the target (the source's if unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename: Option<String>,
+}
+
+impl BridgeSpec {
+    /// Validate and resolve paths against the directory `dir`.
+    pub fn resolve(&self, dir: &str) -> Result<Bridge, String> {
+        let from = join_path(dir, &self.from);
+        let to = join_path(dir, &self.to);
+        for path in [&from, &to] {
+            validate_extension(path).map_err(|e| e.to_string())?;
+        }
+        let to_port = self.as_port.unwrap_or(self.port);
+        if (self.port == BridgePort::Edits) != (to_port == BridgePort::Edits) {
+            return Err("edits can only be bridged to edits".to_string());
+        }
+        if self.port == BridgePort::Edits && (self.name.is_some() || self.rename.is_some()) {
+            return Err("edits bridges take no name or rename".to_string());
+        }
+        if from == to && self.port == to_port && self.rename.is_none() {
+            return Err(format!("{} is bridged to itself", from));
+        }
+        Ok(Bridge {
+            from,
+            port: self.port,
+            to,
+            to_port,
+            name: self.name.clone(),
+            rename: self.rename.clone(),
+        })
+    }
+}
+
+/// A validated bridge with paths relative to fs-root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bridge {
+    pub from: String,
+    pub port: BridgePort,
+    pub to: String,
+    pub to_port: BridgePort,
+    pub name: Option<String>,
+    pub rename: Option<String>,
+}
+
+impl Bridge {
+    /// Whether a message on `topic` should cross this bridge.
+    fn matches(&self, topic: &Topic) -> bool {
+        topic.path == self.from
+            && topic.port == Port::from(self.port)
+            && match &self.name {
+                Some(name) => topic.qualifier.as_deref() == Some(name.as_str()),
+                None => topic.qualifier.is_some(),
+            }
+    }
+}
+
+#[derive(Default)]
+struct BridgeState {
+    bridges: Vec<Bridge>,
+    /// Document IDs of bridges files, to reload when they change
+    bridge_docs: HashSet<String>,
+    /// Document IDs by path, for edits bridges
+    doc_ids: HashMap<String, String>,
+}
+
+/// Enforces the bridges declared in the fs-root tree.
+pub struct Bridges {
+    client: Arc<MqttClient>,
+    service: Arc<DocumentService>,
+    document_store: Arc<DocumentStore>,
+    fs_root: String,
+    state: RwLock<BridgeState>,
+}
+
+impl Bridges {
+    pub fn new(
+        client: Arc<MqttClient>,
+        service: Arc<DocumentService>,
+        document_store: Arc<DocumentStore>,
+        fs_root: String,
+    ) -> Self {
+        Self {
+            client,
+            service,
+            document_store,
+            fs_root,
+            state: RwLock::new(BridgeState::default()),
+        }
+    }
+
+    /// Re-read every `bridges.json` in the tree.
+    ///
+    /// Invalid files and bridges are skipped, logged and reported as a
+    /// `bridge-error` event on the bridges file's path. Returns the paths
+    /// whose events are bridged, which need an events subscription.
+    pub async fn reload(&self) -> HashSet<String> {
+        let mut state = BridgeState::default();
+        for entry in walk_tree(&self.document_store, &self.fs_root).await {
+            if entry.is_dir {
+                continue;
+            }
+            let Some(node_id) = entry.node_id else {
+                continue;
+            };
+            state.doc_ids.insert(entry.path.clone(), node_id.clone());
+            let (dir, file_name) = match entry.path.rsplit_once('/') {
+                Some((dir, name)) => (dir, name),
+                None => ("", entry.path.as_str()),
+            };
+            if file_name != BRIDGES_FILE {
+                continue;
+            }
+            state.bridge_docs.insert(node_id.clone());
+            let Some(doc) = self.document_store.get_document(&node_id).await else {
+                continue;
+            };
+            let config = match BridgesConfig::parse(&doc.content) {
+                Ok(config) => config,
+                Err(e) => {
+                    self.report(&entry.path, format!("Invalid {}: {}", BRIDGES_FILE, e))
+                        .await;
+                    continue;
+                }
+            };
+            for spec in &config.bridges {
+                match spec.resolve(dir) {
+                    Ok(bridge) => state.bridges.push(bridge),
+                    Err(e) => self.report(&entry.path, e).await,
+                }
+            }
+        }
+
+        let sources = state
+            .bridges
+            .iter()
+            .filter(|bridge| bridge.port == BridgePort::Events)
+            .map(|bridge| bridge.from.clone())
+            .collect();
+        info!("Loaded {} bridges", state.bridges.len());
+        *self.state.write().await = state;
+        sources
+    }
+
+    /// Republish an event or command received on `topic` across matching
+    /// bridges.
+    pub async fn forward(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+        if topic.is_manifest() || topic.is_reply() {
+            return Ok(());
+        }
+        let bridges: Vec<Bridge> = self
+            .state
+            .read()
+            .await
+            .bridges
+            .iter()
+            .filter(|bridge| bridge.matches(topic))
+            .cloned()
+            .collect();
+        if bridges.is_empty() {
+            return Ok(());
+        }
+
+        let (payload, source) = match topic.port {
+            Port::Commands => serde_json::from_slice::<CommandMessage>(payload)
+                .map(|command| (command.payload, command.source))?,
+            _ => serde_json::from_slice::<EventMessage>(payload)
+                .map(|event| (event.payload, Some(event.source)))?,
+        };
+        if source
+            .as_deref()
+            .is_some_and(|source| source.starts_with(BRIDGE_SOURCE_PREFIX))
+        {
+            return Ok(());
+        }
+
+        let name = topic.qualifier.as_deref().unwrap_or_default();
+        let source = format!("{}{}", BRIDGE_SOURCE_PREFIX, topic.path);
+        for bridge in bridges {
+            let name = bridge.rename.as_deref().unwrap_or(name);
+            let (target, message, qos) = match bridge.to_port {
+                BridgePort::Commands => (
+                    Topic::commands(&bridge.to, name),
+                    serde_json::to_vec(&CommandMessage {
+                        payload: payload.clone(),
+                        source: Some(source.clone()),
+                        reply_to: None,
+                        req: None,
+                        token: None,
+                    })?,
+                    QoS::AtLeastOnce,
+                ),
+                _ => (
+                    Topic::events(&bridge.to, name),
+                    serde_json::to_vec(&EventMessage {
+                        payload: payload.clone(),
+                        source: source.clone(),
+                    })?,
+                    QoS::AtMostOnce,
+                ),
+            };
+            let target = target.to_topic_string();
+            self.client.publish(&target, &message, qos).await?;
+            debug!("Bridged {} to {}", topic.to_topic_string(), target);
+        }
+        Ok(())
+    }
+
+    /// React to a commit to `doc_id`: mirror it across edits bridges.
+    /// Returns whether the commit changed a bridges file, so bridges need
+    /// reloading.
+    pub async fn handle_commit(&self, doc_id: &str) -> bool {
+        let (targets, reload) = {
+            let state = self.state.read().await;
+            let targets: Vec<(String, Option<String>)> = state
+                .bridges
+                .iter()
+                .filter(|bridge| {
+                    bridge.port == BridgePort::Edits
+                        && state.doc_ids.get(&bridge.from).map(String::as_str) == Some(doc_id)
+                })
+                .map(|bridge| (bridge.to.clone(), state.doc_ids.get(&bridge.to).cloned()))
+                .collect();
+            (targets, state.bridge_docs.contains(doc_id))
+        };
+
+        if !targets.is_empty() {
+            let content = self
+                .document_store
+                .get_document(doc_id)
+                .await
+                .map(|doc| doc.content)
+                .unwrap_or_default();
+            for (path, target) in targets {
+                let Some(target) = target else {
+                    warn!("Edits bridge target {} is not in the tree", path);
+                    continue;
+                };
+                if let Err(e) = self.mirror(&target, &content).await {
+                    warn!("Failed to mirror {} into {}: {}", doc_id, path, e);
+                }
+            }
+        }
+        reload
+    }
+
+    /// Mirror every edits bridge from its source's current content, for when
+    /// commit notifications were missed.
+    pub async fn resync(&self) {
+        let sources: HashSet<String> = {
+            let state = self.state.read().await;
+            state
+                .bridges
+                .iter()
+                .filter(|bridge| bridge.port == BridgePort::Edits)
+                .filter_map(|bridge| state.doc_ids.get(&bridge.from).cloned())
+                .collect()
+        };
+        for doc_id in sources {
+            self.handle_commit(&doc_id).await;
+        }
+    }
+
+    async fn mirror(&self, target: &str, content: &str) -> Result<(), MqttError> {
+        let current = self
+            .document_store
+            .get_document(target)
+            .await
+            .map(|doc| doc.content);
+        // Unchanged targets end mirror cycles (a.txt <-> b.txt)
+        if current.as_deref() == Some(content) {
+            return Ok(());
+        }
+        self.service
+            .replace_content(target, content, None, Some(BRIDGE_AUTHOR.to_string()))
+            .await
+            .map_err(|e| MqttError::Node(format!("{:?}", e)))?;
+        debug!("Mirrored content into {}", target);
+        Ok(())
+    }
+
+    async fn report(&self, bridges_path: &str, error: String) {
+        warn!("Skipping bridge in {}: {}", bridges_path, error);
+        let message = EventMessage {
+            payload: serde_json::json!({ "error": error }),
+            source: format!("{}{}", BRIDGE_SOURCE_PREFIX, bridges_path),
+        };
+        let topic = Topic::events(bridges_path, BRIDGE_ERROR_EVENT).to_topic_string();
+        if let Ok(payload) = serde_json::to_vec(&message) {
+            let _ = self.client.publish(&topic, &payload, QoS::AtMostOnce).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_bridges() {
+        let config = BridgesConfig::parse(
+            r#"{"bridges": [
+                {"from": "sensors/temp.json", "port": "events", "name": "reading",
+                 "to": "alerts/monitor.json", "as": "commands", "rename": "check"},
+                {"from": "a.txt", "port": "edits", "to": "b.txt"},
+                {"from": "a.txt", "port": "edits", "to": "b.txt", "as": "events"},
+                {"from": "a.txt", "port": "events", "to": "a.txt"},
+                {"from": "a", "port": "events", "to": "b.txt"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let bridge = config.bridges[0].resolve("home").unwrap();
+        assert_eq!(bridge.from, "home/sensors/temp.json");
+        assert_eq!(bridge.to, "home/alerts/monitor.json");
+        assert_eq!(bridge.to_port, BridgePort::Commands);
+        assert!(bridge.matches(&Topic::events("home/sensors/temp.json", "reading")));
+        assert!(!bridge.matches(&Topic::events("home/sensors/temp.json", "other")));
+
+        assert_eq!(
+            config.bridges[1].resolve("").unwrap().to_port,
+            BridgePort::Edits
+        );
+        assert!(config.bridges[2].resolve("").is_err());
+        assert!(config.bridges[3].resolve("").is_err());
+        assert!(config.bridges[4].resolve("").is_err());
+
+        assert!(BridgesConfig::parse(r#"{"bridges": [{"from": "a.txt"}]}"#).is_err());
+        assert_eq!(BridgesConfig::parse("").unwrap(), BridgesConfig::default());
+    }
+}
@@ -0,0 +1,1042 @@
+//! Embedded MQTT broker.
+//!
+//! A small in-process MQTT 3.1.1 and 5 broker for single-binary deployments
+//! and integration tests, so `commonplace-server`/`commonplace-store` can run
+//! without an external Mosquitto. It supports what the commonplace topic
+//! layout needs: `+`/`#` wildcard subscriptions, QoS 0 and 1 delivery
+//! (QoS 2 publishes are accepted and delivered at QoS 1), retained messages,
+//! and last-will messages. Publish properties from MQTT 5 clients (response
+//! topic, correlation data, user properties, message expiry) are forwarded
+//! to MQTT 5 subscribers; expired messages are not delivered.
+//!
+//! It is not a general-purpose broker. Sessions last exactly as long as
+//! their connection: a CONNECT asking for a persistent session (clean
+//! session/clean start unset) is refused, so nothing is queued for offline
+//! clients. QoS 1 deliveries are sent once and never retransmitted, since
+//! there is no in-flight tracking; a client that needs redelivery must
+//! resync (commonplace clients do, from the store). MQTT 5 topic aliases are
+//! not supported, and publishes that rely on one are dropped. Each client
+//! has a bounded outgoing queue; a subscriber too slow to drain it is
+//! disconnected rather than letting the broker's memory grow. Point
+//! deployments that need more at an external broker.
+//!
+//! Access control goes through [`BrokerAcl`], the hook for the macaroon
+//! verifier described in `docs/MACAROONS.md`. The default allows everything.
+//!
+//! Why not rumqttd: its authentication only sees the CONNECT credentials,
+//! while [`BrokerAcl`] has to authorize every publish and subscription by
+//! topic. It also runs its router on threads of its own with no way to stop
+//! it, where this broker lives on the caller's runtime and shuts down when
+//! [`EmbeddedBroker`] is dropped (tests start one per case on port 0). The
+//! packet codec is rumqttc's, already a dependency. The tests in
+//! `tests/mqtt_conformance_tests.rs` check the broker against the MQTT 3.1.1
+//! normative statements it relies on.
+
+use crate::mqtt::MqttError;
+use bytes::{Bytes, BytesMut};
+use rumqttc::mqttbytes::v4::{
+    self, ConnAck, ConnectReturnCode, PingResp, PubAck, PubComp, PubRec, Publish, SubAck,
+    SubscribeReasonCode, UnsubAck,
+};
+use rumqttc::mqttbytes::{valid_filter, valid_topic, Error as PacketError, QoS};
+use rumqttc::v5::mqttbytes::v5 as mqtt5;
+use rumqttc::v5::mqttbytes::{Error as Packet5Error, QoS as QoS5};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Default address for `--embedded-broker` without an explicit address.
+pub const DEFAULT_BROKER_ADDR: &str = "127.0.0.1:1883";
+
+/// Largest packet the broker accepts.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a new connection has to send CONNECT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames queued per client before it is disconnected as too slow.
+const CLIENT_QUEUE_CAPACITY: usize = 1024;
+
+/// Operations checked by a [`BrokerAcl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerAction {
+    Publish,
+    Subscribe,
+}
+
+/// Authorization hook for the embedded broker.
+///
+/// Credentials arrive in the CONNECT username/password (a macaroon travels
+/// in the password). Publishes are checked per topic and subscriptions per
+/// filter; denied publishes are dropped and denied filters get a SUBACK
+/// failure code.
+pub trait BrokerAcl: Send + Sync {
+    /// Accept or refuse a connecting client.
+    fn connect(&self, client_id: &str, username: Option<&str>, password: Option<&str>) -> bool;
+
+    /// Check a publish to `topic` or a subscription to filter `topic`.
+    fn allow(&self, client_id: &str, action: BrokerAction, topic: &str) -> bool;
+}
+
+/// ACL that allows every client and topic.
+pub struct AllowAll;
+
+impl BrokerAcl for AllowAll {
+    fn connect(&self, _client_id: &str, _username: Option<&str>, _password: Option<&str>) -> bool {
+        true
+    }
+
+    fn allow(&self, _client_id: &str, _action: BrokerAction, _topic: &str) -> bool {
+        true
+    }
+}
+
+/// A running embedded broker.
+///
+/// Stops accepting connections when dropped.
+pub struct EmbeddedBroker {
+    local_addr: SocketAddr,
+    accept_task: JoinHandle<()>,
+}
+
+impl EmbeddedBroker {
+    /// Bind to `addr` and start accepting clients.
+    ///
+    /// Use port 0 to pick a free port (see [`EmbeddedBroker::url`]).
+    pub async fn start(addr: SocketAddr, acl: Arc<dyn BrokerAcl>) -> Result<Self, MqttError> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| MqttError::Connection(format!("Failed to bind broker {}: {}", addr, e)))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| MqttError::Connection(e.to_string()))?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(BrokerState::default()),
+            acl,
+        });
+        let accept_task = tokio::spawn(accept_loop(listener, shared));
+
+        info!("Embedded MQTT broker listening on {}", local_addr);
+        Ok(Self {
+            local_addr,
+            accept_task,
+        })
+    }
+
+    /// Address the broker is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Broker URL for clients in this process (`mqtt://host:port`).
+    pub fn url(&self) -> String {
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        format!("mqtt://{}", addr)
+    }
+}
+
+impl Drop for EmbeddedBroker {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Sending half of a connection's bounded outgoing queue.
+#[derive(Clone)]
+struct ClientTx {
+    frames: mpsc::Sender<BytesMut>,
+    /// Tells the writer task to close the connection
+    close: Arc<Notify>,
+}
+
+impl ClientTx {
+    /// Queue a frame, closing the connection if its queue is full.
+    fn send(&self, frame: BytesMut) {
+        match self.frames.try_send(frame) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("Broker client queue full; disconnecting slow client");
+                self.close();
+            }
+        }
+    }
+
+    /// Close the connection (e.g. a newer connection took over its client ID).
+    fn close(&self) {
+        self.close.notify_one();
+    }
+
+    /// Wait until the writer task has stopped.
+    async fn closed(&self) {
+        self.frames.closed().await
+    }
+}
+
+/// MQTT version a client connected with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    V4,
+    V5,
+}
+
+/// A published message, independent of the publisher's protocol version.
+#[derive(Debug, Clone)]
+struct Message {
+    topic: String,
+    payload: Bytes,
+    qos: QoS,
+    retain: bool,
+    /// MQTT 5 properties, without the expiry interval
+    properties: Option<mqtt5::PublishProperties>,
+    expires_at: Option<Instant>,
+}
+
+impl Message {
+    fn new(topic: String, payload: Bytes, qos: QoS, retain: bool) -> Self {
+        Self {
+            topic,
+            payload,
+            qos,
+            retain,
+            properties: None,
+            expires_at: None,
+        }
+    }
+
+    fn from_v5(publish: mqtt5::Publish) -> Self {
+        let mut message = Self::new(
+            String::from_utf8_lossy(&publish.topic).into_owned(),
+            publish.payload,
+            qos_from_v5(publish.qos),
+            publish.retain,
+        );
+        if let Some(mut properties) = publish.properties {
+            message.expires_at = properties
+                .message_expiry_interval
+                .take()
+                .map(|secs| Instant::now() + Duration::from_secs(secs.into()));
+            // Aliases and subscription IDs are per connection
+            properties.topic_alias = None;
+            properties.subscription_identifiers.clear();
+            message.properties = Some(properties);
+        }
+        message
+    }
+
+    fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Instant::now())
+    }
+
+    /// Encode as a PUBLISH for a client speaking `protocol`.
+    fn encode(
+        &self,
+        protocol: Protocol,
+        qos: QoS,
+        retain: bool,
+        pkid: u16,
+    ) -> Result<BytesMut, String> {
+        let mut frame = BytesMut::new();
+        match protocol {
+            Protocol::V4 => {
+                let mut out = Publish::from_bytes(self.topic.clone(), qos, self.payload.clone());
+                out.retain = retain;
+                out.pkid = pkid;
+                out.write(&mut frame).map_err(|e| e.to_string())?;
+            }
+            Protocol::V5 => {
+                let mut properties = self.properties.clone();
+                if let Some(at) = self.expires_at {
+                    // Forward the remaining lifetime, rounded up
+                    let remaining = at.saturating_duration_since(Instant::now());
+                    let secs = remaining.as_millis().div_ceil(1000).max(1) as u32;
+                    properties
+                        .get_or_insert_with(Default::default)
+                        .message_expiry_interval = Some(secs);
+                }
+                let mut out = mqtt5::Publish::new(
+                    self.topic.clone(),
+                    qos_to_v5(qos),
+                    self.payload.clone(),
+                    properties,
+                );
+                out.retain = retain;
+                out.pkid = pkid;
+                out.write(&mut frame).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(frame)
+    }
+}
+
+/// A client's CONNECT, independent of protocol version.
+struct ClientConnect {
+    protocol: Protocol,
+    client_id: String,
+    keep_alive: u16,
+    username: Option<String>,
+    password: Option<String>,
+    last_will: Option<Message>,
+    /// Whether the client asked for a fresh session (clean session/clean start)
+    clean: bool,
+}
+
+/// A packet from a connected client, independent of protocol version.
+enum Inbound {
+    Connect(ClientConnect),
+    Publish {
+        message: Message,
+        pkid: u16,
+    },
+    PubRel(u16),
+    Subscribe {
+        pkid: u16,
+        filters: Vec<(String, QoS)>,
+    },
+    Unsubscribe {
+        pkid: u16,
+        filters: Vec<String>,
+    },
+    PingReq,
+    Disconnect,
+    /// Acks for our QoS 1 deliveries; nothing is retransmitted
+    Ignored,
+}
+
+impl Inbound {
+    fn from_v4(packet: v4::Packet) -> Self {
+        match packet {
+            v4::Packet::Connect(connect) => Inbound::Connect(ClientConnect {
+                protocol: Protocol::V4,
+                client_id: connect.client_id,
+                keep_alive: connect.keep_alive,
+                username: connect.login.as_ref().map(|l| l.username.clone()),
+                password: connect.login.map(|l| l.password),
+                clean: connect.clean_session,
+                last_will: connect
+                    .last_will
+                    .map(|will| Message::new(will.topic, will.message, will.qos, will.retain)),
+            }),
+            v4::Packet::Publish(publish) => Inbound::Publish {
+                pkid: publish.pkid,
+                message: Message::new(publish.topic, publish.payload, publish.qos, publish.retain),
+            },
+            v4::Packet::PubRel(pubrel) => Inbound::PubRel(pubrel.pkid),
+            v4::Packet::Subscribe(subscribe) => Inbound::Subscribe {
+                pkid: subscribe.pkid,
+                filters: subscribe
+                    .filters
+                    .into_iter()
+                    .map(|f| (f.path, f.qos))
+                    .collect(),
+            },
+            v4::Packet::Unsubscribe(unsubscribe) => Inbound::Unsubscribe {
+                pkid: unsubscribe.pkid,
+                filters: unsubscribe.topics,
+            },
+            v4::Packet::PingReq => Inbound::PingReq,
+            v4::Packet::Disconnect => Inbound::Disconnect,
+            _ => Inbound::Ignored,
+        }
+    }
+
+    fn from_v5(packet: mqtt5::Packet) -> Self {
+        match packet {
+            mqtt5::Packet::Connect(connect, will, login) => Inbound::Connect(ClientConnect {
+                protocol: Protocol::V5,
+                client_id: connect.client_id,
+                keep_alive: connect.keep_alive,
+                username: login.as_ref().map(|l| l.username.clone()),
+                password: login.map(|l| l.password),
+                clean: connect.clean_start,
+                last_will: will.map(|will| {
+                    Message::new(
+                        String::from_utf8_lossy(&will.topic).into_owned(),
+                        will.message,
+                        qos_from_v5(will.qos),
+                        will.retain,
+                    )
+                }),
+            }),
+            mqtt5::Packet::Publish(publish) => Inbound::Publish {
+                pkid: publish.pkid,
+                message: Message::from_v5(publish),
+            },
+            mqtt5::Packet::PubRel(pubrel) => Inbound::PubRel(pubrel.pkid),
+            mqtt5::Packet::Subscribe(subscribe) => Inbound::Subscribe {
+                pkid: subscribe.pkid,
+                filters: subscribe
+                    .filters
+                    .into_iter()
+                    .map(|f| (f.path, qos_from_v5(f.qos)))
+                    .collect(),
+            },
+            mqtt5::Packet::Unsubscribe(unsubscribe) => Inbound::Unsubscribe {
+                pkid: unsubscribe.pkid,
+                filters: unsubscribe.filters,
+            },
+            mqtt5::Packet::PingReq(_) => Inbound::PingReq,
+            mqtt5::Packet::Disconnect(_) => Inbound::Disconnect,
+            _ => Inbound::Ignored,
+        }
+    }
+}
+
+/// Encode broker-to-client control packets for a protocol version.
+fn encode_control(protocol: Protocol, packet: Control) -> Result<BytesMut, String> {
+    let mut frame = BytesMut::new();
+    let written = match protocol {
+        Protocol::V4 => match packet {
+            Control::ConnAck(result) => {
+                let code = match result {
+                    Ok(()) => ConnectReturnCode::Success,
+                    Err(Refusal::NotAuthorized) => ConnectReturnCode::NotAuthorized,
+                    Err(Refusal::PersistentSession) => ConnectReturnCode::ServiceUnavailable,
+                };
+                ConnAck::new(code, false).write(&mut frame)
+            }
+            Control::PubAck(pkid) => PubAck::new(pkid).write(&mut frame),
+            Control::PubRec(pkid) => PubRec::new(pkid).write(&mut frame),
+            Control::PubComp(pkid) => PubComp::new(pkid).write(&mut frame),
+            Control::SubAck(pkid, granted) => SubAck::new(
+                pkid,
+                granted
+                    .into_iter()
+                    .map(|qos| {
+                        qos.map_or(SubscribeReasonCode::Failure, SubscribeReasonCode::Success)
+                    })
+                    .collect(),
+            )
+            .write(&mut frame),
+            Control::UnsubAck(pkid, _) => UnsubAck::new(pkid).write(&mut frame),
+            Control::PingResp => PingResp.write(&mut frame),
+        }
+        .map_err(|e: PacketError| e.to_string()),
+        Protocol::V5 => match packet {
+            Control::ConnAck(result) => mqtt5::ConnAck {
+                session_present: false,
+                code: match result {
+                    Ok(()) => mqtt5::ConnectReturnCode::Success,
+                    Err(Refusal::NotAuthorized) => mqtt5::ConnectReturnCode::NotAuthorized,
+                    Err(Refusal::PersistentSession) => {
+                        mqtt5::ConnectReturnCode::ImplementationSpecificError
+                    }
+                },
+                properties: None,
+            }
+            .write(&mut frame),
+            Control::PubAck(pkid) => mqtt5::PubAck::new(pkid, None).write(&mut frame),
+            Control::PubRec(pkid) => mqtt5::PubRec::new(pkid, None).write(&mut frame),
+            Control::PubComp(pkid) => mqtt5::PubComp::new(pkid, None).write(&mut frame),
+            Control::SubAck(pkid, granted) => mqtt5::SubAck {
+                pkid,
+                return_codes: granted
+                    .into_iter()
+                    .map(|qos| match qos {
+                        Some(qos) => mqtt5::SubscribeReasonCode::Success(qos_to_v5(qos)),
+                        None => mqtt5::SubscribeReasonCode::NotAuthorized,
+                    })
+                    .collect(),
+                properties: None,
+            }
+            .write(&mut frame),
+            Control::UnsubAck(pkid, count) => mqtt5::UnsubAck {
+                pkid,
+                reasons: vec![mqtt5::UnsubAckReason::Success; count],
+                properties: None,
+            }
+            .write(&mut frame),
+            Control::PingResp => mqtt5::PingResp::write(&mut frame),
+        }
+        .map_err(|e: Packet5Error| e.to_string()),
+    };
+    written.map(|_| frame)
+}
+
+/// Why a CONNECT was refused.
+#[derive(Debug, Clone, Copy)]
+enum Refusal {
+    /// The ACL turned the client away
+    NotAuthorized,
+    /// The client asked for a session that outlives its connection
+    PersistentSession,
+}
+
+/// Control packets the broker sends.
+enum Control {
+    /// Whether the connection was accepted
+    ConnAck(Result<(), Refusal>),
+    PubAck(u16),
+    PubRec(u16),
+    PubComp(u16),
+    /// Granted QoS per filter, `None` for refused filters
+    SubAck(u16, Vec<Option<QoS>>),
+    /// Number of filters unsubscribed
+    UnsubAck(u16, usize),
+    PingResp,
+}
+
+/// A connected client and its subscriptions.
+struct Session {
+    client_id: String,
+    protocol: Protocol,
+    tx: ClientTx,
+    filters: Vec<(String, QoS)>,
+    next_pkid: u16,
+}
+
+impl Session {
+    fn deliver(&mut self, message: &Message, qos: QoS, retain: bool) {
+        let mut pkid = 0;
+        if qos != QoS::AtMostOnce {
+            self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+            pkid = self.next_pkid;
+        }
+        match message.encode(self.protocol, qos, retain, pkid) {
+            Ok(frame) => self.tx.send(frame),
+            Err(e) => warn!("Failed to encode publish for {}: {}", self.client_id, e),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    sessions: HashMap<u64, Session>,
+    retained: HashMap<String, Message>,
+    next_conn_id: u64,
+}
+
+struct Shared {
+    /// Recovered if poisoned: the tables stay usable after a panicking
+    /// connection task, and one client shouldn't take down the broker
+    state: Mutex<BrokerState>,
+    acl: Arc<dyn BrokerAcl>,
+}
+
+impl Shared {
+    /// Register a session, closing any existing one with the same client ID.
+    fn register(&self, client_id: &str, protocol: Protocol, tx: ClientTx) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.sessions.retain(|_, session| {
+            if session.client_id == client_id {
+                debug!("Client {} reconnected; closing old session", client_id);
+                session.tx.close();
+                false
+            } else {
+                true
+            }
+        });
+        state.next_conn_id += 1;
+        let conn_id = state.next_conn_id;
+        state.sessions.insert(
+            conn_id,
+            Session {
+                client_id: client_id.to_string(),
+                protocol,
+                tx,
+                filters: Vec::new(),
+                next_pkid: 0,
+            },
+        );
+        conn_id
+    }
+
+    fn unregister(&self, conn_id: u64) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sessions
+            .remove(&conn_id);
+    }
+
+    /// Route a message to every matching subscriber and update retained state.
+    fn route(&self, message: &Message) {
+        if message.expired() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if message.retain {
+            if message.payload.is_empty() {
+                state.retained.remove(&message.topic);
+            } else {
+                state
+                    .retained
+                    .insert(message.topic.clone(), message.clone());
+            }
+        }
+        for session in state.sessions.values_mut() {
+            let granted = session
+                .filters
+                .iter()
+                .filter(|(filter, _)| topic_matches(&message.topic, filter))
+                .map(|(_, qos)| *qos)
+                .max_by_key(|qos| *qos as u8);
+            if let Some(granted) = granted {
+                session.deliver(message, min_qos(message.qos, granted), false);
+            }
+        }
+    }
+
+    fn publish_from(&self, client_id: &str, message: &Message) {
+        if message.topic.is_empty() {
+            // Only a topic alias would leave the topic empty
+            warn!(
+                "Dropping publish from {} with no topic (topic aliases are not supported)",
+                client_id
+            );
+        } else if !valid_topic(&message.topic) {
+            warn!("Dropping publish to invalid topic {:?}", message.topic);
+        } else if !self
+            .acl
+            .allow(client_id, BrokerAction::Publish, &message.topic)
+        {
+            warn!(
+                "Client {} not authorized to publish to {}",
+                client_id, message.topic
+            );
+        } else {
+            self.route(message);
+        }
+    }
+
+    /// Add subscriptions, acknowledge them, then send matching retained messages.
+    fn subscribe(&self, conn_id: u64, client_id: &str, pkid: u16, filters: Vec<(String, QoS)>) {
+        let mut return_codes = Vec::with_capacity(filters.len());
+        let mut granted = Vec::new();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let BrokerState {
+            sessions, retained, ..
+        } = &mut *state;
+        let Some(session) = sessions.get_mut(&conn_id) else {
+            return;
+        };
+
+        for (filter, requested) in filters {
+            if !valid_filter(&filter)
+                || !self.acl.allow(client_id, BrokerAction::Subscribe, &filter)
+            {
+                warn!(
+                    "Client {} not authorized to subscribe to {}",
+                    client_id, filter
+                );
+                return_codes.push(None);
+                continue;
+            }
+
+            // QoS 2 is not supported for delivery; grant at most QoS 1
+            let qos = min_qos(requested, QoS::AtLeastOnce);
+            session.filters.retain(|(existing, _)| *existing != filter);
+            session.filters.push((filter.clone(), qos));
+            return_codes.push(Some(qos));
+            granted.push((filter, qos));
+        }
+
+        Shared::send(
+            &session.tx,
+            encode_control(session.protocol, Control::SubAck(pkid, return_codes)),
+        );
+
+        retained.retain(|_, message| !message.expired());
+        for (filter, qos) in granted {
+            for message in retained.values() {
+                if topic_matches(&message.topic, &filter) {
+                    session.deliver(message, min_qos(message.qos, qos), true);
+                }
+            }
+        }
+    }
+
+    fn unsubscribe(&self, conn_id: u64, filters: &[String]) {
+        if let Some(session) = self
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sessions
+            .get_mut(&conn_id)
+        {
+            session
+                .filters
+                .retain(|(existing, _)| !filters.contains(existing));
+        }
+    }
+
+    /// Queue a packet for a connection, bypassing the session table.
+    fn send(tx: &ClientTx, frame: Result<BytesMut, String>) {
+        match frame {
+            Ok(frame) => tx.send(frame),
+            Err(e) => warn!("Failed to encode packet: {}", e),
+        }
+    }
+}
+
+async fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, shared).await {
+                        debug!("Broker connection from {} ended: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Broker accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, shared: Arc<Shared>) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = BytesMut::with_capacity(4096);
+
+    let connect =
+        match tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buf, None)).await
+        {
+            Ok(Ok(Inbound::Connect(connect))) => connect,
+            Ok(Ok(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Expected CONNECT",
+                ))
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "No CONNECT")),
+        };
+    let protocol = connect.protocol;
+
+    let refusal = if !connect.clean {
+        warn!(
+            "Refusing client {}: persistent sessions are not supported",
+            connect.client_id
+        );
+        Some(Refusal::PersistentSession)
+    } else if !shared.acl.connect(
+        &connect.client_id,
+        connect.username.as_deref(),
+        connect.password.as_deref(),
+    ) {
+        warn!("Refusing connection from client {}", connect.client_id);
+        Some(Refusal::NotAuthorized)
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        if let Ok(frame) = encode_control(protocol, Control::ConnAck(Err(refusal))) {
+            writer.write_all(&frame).await?;
+        }
+        return Ok(());
+    }
+
+    let client_id = if connect.client_id.is_empty() {
+        format!("anonymous-{}", uuid::Uuid::new_v4())
+    } else {
+        connect.client_id.clone()
+    };
+
+    let (frames, mut rx) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+    let close = Arc::new(Notify::new());
+    let tx = ClientTx {
+        frames,
+        close: close.clone(),
+    };
+    Shared::send(&tx, encode_control(protocol, Control::ConnAck(Ok(()))));
+    let conn_id = shared.register(&client_id, protocol, tx.clone());
+    debug!("Broker client {} connected ({:?})", client_id, protocol);
+
+    let writer_task = tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                frame = rx.recv() => frame,
+                _ = close.notified() => None,
+            };
+            let Some(frame) = frame else { break };
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let graceful = serve_client(
+        &shared,
+        conn_id,
+        &client_id,
+        &connect,
+        &mut reader,
+        &mut buf,
+        &tx,
+    )
+    .await
+    .unwrap_or(false);
+
+    shared.unregister(conn_id);
+    debug!("Broker client {} disconnected", client_id);
+
+    if !graceful {
+        if let Some(ref will) = connect.last_will {
+            shared.publish_from(&client_id, will);
+        }
+    }
+
+    drop(tx);
+    let _ = writer_task.await;
+    Ok(())
+}
+
+/// Process packets from a connected client.
+///
+/// Returns `true` if the client disconnected cleanly with DISCONNECT.
+async fn serve_client(
+    shared: &Shared,
+    conn_id: u64,
+    client_id: &str,
+    connect: &ClientConnect,
+    reader: &mut OwnedReadHalf,
+    buf: &mut BytesMut,
+    tx: &ClientTx,
+) -> io::Result<bool> {
+    let protocol = connect.protocol;
+    // Clients must send something within 1.5x their keep-alive interval
+    let idle_timeout = (connect.keep_alive > 0)
+        .then(|| Duration::from_millis(u64::from(connect.keep_alive) * 1500));
+
+    loop {
+        let packet = tokio::select! {
+            packet = read_with_timeout(reader, buf, protocol, idle_timeout) => packet?,
+            _ = tx.closed() => return Ok(false),
+        };
+
+        match packet {
+            Inbound::Publish { message, pkid } => {
+                match message.qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => {
+                        Shared::send(tx, encode_control(protocol, Control::PubAck(pkid)))
+                    }
+                    QoS::ExactlyOnce => {
+                        Shared::send(tx, encode_control(protocol, Control::PubRec(pkid)))
+                    }
+                }
+                shared.publish_from(client_id, &message);
+            }
+            Inbound::PubRel(pkid) => {
+                Shared::send(tx, encode_control(protocol, Control::PubComp(pkid)));
+            }
+            Inbound::Subscribe { pkid, filters } => {
+                shared.subscribe(conn_id, client_id, pkid, filters)
+            }
+            Inbound::Unsubscribe { pkid, filters } => {
+                shared.unsubscribe(conn_id, &filters);
+                Shared::send(
+                    tx,
+                    encode_control(protocol, Control::UnsubAck(pkid, filters.len())),
+                );
+            }
+            Inbound::PingReq => Shared::send(tx, encode_control(protocol, Control::PingResp)),
+            Inbound::Disconnect => return Ok(true),
+            Inbound::Connect(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unexpected second CONNECT",
+                ))
+            }
+            Inbound::Ignored => {}
+        }
+    }
+}
+
+async fn read_with_timeout(
+    reader: &mut OwnedReadHalf,
+    buf: &mut BytesMut,
+    protocol: Protocol,
+    timeout: Option<Duration>,
+) -> io::Result<Inbound> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read_packet(reader, buf, Some(protocol)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Keep-alive expired"))?,
+        None => read_packet(reader, buf, Some(protocol)).await,
+    }
+}
+
+/// Read the next complete packet from the stream.
+///
+/// Before CONNECT (`protocol` is `None`) the version is taken from the
+/// CONNECT's protocol level.
+async fn read_packet(
+    reader: &mut OwnedReadHalf,
+    buf: &mut BytesMut,
+    mut protocol: Option<Protocol>,
+) -> io::Result<Inbound> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    loop {
+        if protocol.is_none() {
+            protocol = match connect_protocol_level(buf) {
+                Some(5) => Some(Protocol::V5),
+                Some(_) => Some(Protocol::V4),
+                None => None,
+            };
+        }
+        match protocol {
+            Some(Protocol::V4) => match v4::read(buf, MAX_PACKET_SIZE) {
+                Ok(packet) => return Ok(Inbound::from_v4(packet)),
+                Err(PacketError::InsufficientBytes(_)) => {}
+                Err(e) => return Err(invalid(e.to_string())),
+            },
+            Some(Protocol::V5) => match mqtt5::Packet::read(buf, Some(MAX_PACKET_SIZE)) {
+                Ok(packet) => return Ok(Inbound::from_v5(packet)),
+                Err(Packet5Error::InsufficientBytes(_)) => {}
+                // A DISCONNECT with no reason code
+                Err(Packet5Error::PayloadRequired) => return Ok(Inbound::Disconnect),
+                Err(e) => return Err(invalid(e.to_string())),
+            },
+            // Not enough of the CONNECT yet to tell
+            None => {}
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed",
+            ));
+        }
+    }
+}
+
+/// The protocol level of a buffered CONNECT, once enough of it has arrived.
+///
+/// Anything that isn't a CONNECT is reported as level 4 so the 3.1.1
+/// parser rejects it.
+fn connect_protocol_level(buf: &[u8]) -> Option<u8> {
+    let first = *buf.first()?;
+    if first >> 4 != 1 {
+        return Some(4);
+    }
+    // Skip the remaining-length varint
+    let mut index = 1;
+    loop {
+        let byte = *buf.get(index)?;
+        index += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if index > 4 {
+            return Some(4);
+        }
+    }
+    let name_len = u16::from_be_bytes([*buf.get(index)?, *buf.get(index + 1)?]) as usize;
+    buf.get(index + 2 + name_len).copied()
+}
+
+fn qos_from_v5(qos: QoS5) -> QoS {
+    match qos {
+        QoS5::AtMostOnce => QoS::AtMostOnce,
+        QoS5::AtLeastOnce => QoS::AtLeastOnce,
+        QoS5::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+fn qos_to_v5(qos: QoS) -> QoS5 {
+    match qos {
+        QoS::AtMostOnce => QoS5::AtMostOnce,
+        QoS::AtLeastOnce => QoS5::AtLeastOnce,
+        QoS::ExactlyOnce => QoS5::ExactlyOnce,
+    }
+}
+
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if (a as u8) <= (b as u8) {
+        a
+    } else {
+        b
+    }
+}
+
+/// Match a topic against a subscription filter.
+///
+/// Topics starting with `$` (such as `$store/commands/...`) only match
+/// filters that name their first level explicitly.
+pub fn topic_matches(topic: &str, filter: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        if filter_level == "#" {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(level) if filter_level == "+" || filter_level == level => continue,
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches_wildcards() {
+        assert!(topic_matches(
+            "notes/todo.txt/edits",
+            "notes/todo.txt/edits"
+        ));
+        assert!(topic_matches("notes/todo.txt/edits", "notes/todo.txt/#"));
+        assert!(topic_matches("notes/todo.txt/edits", "#"));
+        assert!(topic_matches("a.json/events/tick", "+/events/+"));
+        assert!(topic_matches("a.json", "a.json/#"));
+        assert!(!topic_matches("a.json/events/tick", "+/events"));
+        assert!(!topic_matches("a/b.json/edits", "+/edits"));
+    }
+
+    #[test]
+    fn test_connect_protocol_level() {
+        let mut v4 = BytesMut::new();
+        v4::Connect::new("a").write(&mut v4).unwrap();
+        let mut v5 = BytesMut::new();
+        mqtt5::Connect {
+            keep_alive: 10,
+            client_id: "a".to_string(),
+            clean_start: true,
+            properties: None,
+        }
+        .write(&None, &None, &mut v5)
+        .unwrap();
+
+        assert_eq!(connect_protocol_level(&v4), Some(4));
+        assert_eq!(connect_protocol_level(&v5), Some(5));
+        assert_eq!(connect_protocol_level(&v5[..3]), None);
+    }
+
+    #[test]
+    fn test_dollar_topics_need_explicit_prefix() {
+        assert!(topic_matches(
+            "$store/commands/create-document",
+            "$store/commands/create-document"
+        ));
+        assert!(topic_matches("$store/commands/create-document", "$store/#"));
+        assert!(!topic_matches("$store/commands/create-document", "#"));
+        assert!(!topic_matches(
+            "$store/commands/create-document",
+            "+/commands/+"
+        ));
+    }
+}
@@ -1,13 +1,25 @@
 //! MQTT client wrapper using rumqttc.
 //!
-//! Provides a high-level async interface for MQTT operations.
+//! Provides a high-level async interface for MQTT operations. Speaks MQTT 5
+//! or 3.1.1 (see [`MqttProtocol`]); in `Auto` mode it tries MQTT 5 and
+//! switches to 3.1.1 for good if the broker rejects the first handshake.
 
-use crate::mqtt::{MqttConfig, MqttError};
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
-use std::sync::Arc;
+use crate::mqtt::messages::StatusMessage;
+use crate::mqtt::outbox::{Outbox, QueuedPublish};
+use crate::mqtt::properties::MessageProperties;
+use crate::mqtt::{MqttConfig, MqttError, MqttProtocol, ReconnectPolicy};
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::Packet as V5Packet;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::sync::{broadcast, Mutex};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Incoming MQTT message.
 #[derive(Debug, Clone)]
@@ -16,42 +28,107 @@ pub struct IncomingMessage {
     pub topic: String,
     /// Message payload
     pub payload: Vec<u8>,
+    /// MQTT 5 properties (empty on an MQTT 3.1.1 connection)
+    pub properties: MessageProperties,
 }
 
 /// MQTT client wrapper.
 pub struct MqttClient {
-    client: AsyncClient,
+    client: RwLock<Requests>,
     client_id: String,
-    event_loop: Arc<Mutex<EventLoop>>,
+    event_loop: Arc<Mutex<Connection>>,
     message_tx: broadcast::Sender<IncomingMessage>,
+    /// Options to fall back to if the broker rejects MQTT 5
+    fallback: std::sync::Mutex<Option<MqttOptions>>,
+    /// Active subscriptions, restored after reconnecting without a session
+    subscriptions: std::sync::Mutex<HashMap<String, QoS>>,
+    status_topic: Option<String>,
+    reconnect: ReconnectPolicy,
+    /// Durable queue for QoS 1 publishes made while disconnected
+    outbox: Option<Outbox>,
+    /// Whether the outbox may hold publishes still to be drained
+    outbox_pending: AtomicBool,
+    /// Queued publishes handed to the connection, until the broker acks them
+    outbox_flight: std::sync::Mutex<OutboxFlight>,
+    /// Whether the broker has accepted our current connection
+    connected: AtomicBool,
 }
 
+/// Most queued publishes handed to the connection per event loop turn.
+const OUTBOX_BATCH: usize = 64;
+
+/// Outbox publishes handed to the connection but not yet acknowledged.
+///
+/// They go on the event loop's pending queue, which it sends from before
+/// anything else, so the next outgoing packet IDs are theirs in order.
+#[derive(Debug, Default)]
+struct OutboxFlight {
+    /// Sequence numbers on the pending queue, still without a packet ID
+    unsent: VecDeque<u64>,
+    /// Packet ID → sequence number of publishes awaiting a PUBACK
+    unacked: HashMap<u16, u64>,
+    /// Last sequence number handed over
+    last: Option<u64>,
+}
+
+/// Capacity of the client's request queue.
+const REQUEST_CAPACITY: usize = 256;
+
 impl MqttClient {
     /// Connect to an MQTT broker.
     pub async fn connect(config: MqttConfig) -> Result<Self, MqttError> {
-        // Parse the broker URL
-        let (host, port) = parse_broker_url(&config.broker_url)?;
-
-        let mut options = MqttOptions::new(&config.client_id, host, port);
-        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
-        options.set_clean_session(config.clean_session);
+        let outbox = config.outbox.as_ref().map(Outbox::open).transpose()?;
+        let outbox_pending = match outbox {
+            Some(ref outbox) => !outbox.is_empty()?,
+            None => false,
+        };
 
         // Create client and event loop
-        let (client, event_loop) = AsyncClient::new(options, 256);
+        let (client, event_loop, fallback) = match config.protocol {
+            MqttProtocol::V311 => {
+                let (client, event_loop) =
+                    AsyncClient::new(mqtt_options(&config)?, REQUEST_CAPACITY);
+                (
+                    Requests::V4(client),
+                    Connection::V4(Box::new(event_loop)),
+                    None,
+                )
+            }
+            MqttProtocol::V5 | MqttProtocol::Auto => {
+                let (client, event_loop) =
+                    v5::AsyncClient::new(mqtt5_options(&config)?, REQUEST_CAPACITY);
+                let fallback = (config.protocol == MqttProtocol::Auto)
+                    .then(|| mqtt_options(&config))
+                    .transpose()?;
+                (
+                    Requests::V5(client),
+                    Connection::V5(Box::new(event_loop)),
+                    fallback,
+                )
+            }
+        };
 
         // Create broadcast channel for incoming messages
         let (message_tx, _) = broadcast::channel(1024);
 
         info!(
-            "MQTT client created for {}:{} as {}",
-            config.broker_url, port, config.client_id
+            "MQTT client created for {} as {} ({:?})",
+            config.broker_url, config.client_id, config.protocol
         );
 
         Ok(Self {
-            client,
+            client: RwLock::new(client),
             client_id: config.client_id,
             event_loop: Arc::new(Mutex::new(event_loop)),
             message_tx,
+            fallback: std::sync::Mutex::new(fallback),
+            subscriptions: std::sync::Mutex::new(HashMap::new()),
+            status_topic: config.status_topic,
+            reconnect: config.reconnect,
+            outbox,
+            outbox_pending: AtomicBool::new(outbox_pending),
+            outbox_flight: std::sync::Mutex::new(OutboxFlight::default()),
+            connected: AtomicBool::new(false),
         })
     }
 
@@ -60,36 +137,230 @@ impl MqttClient {
         &self.client_id
     }
 
+    /// Number of publishes in the outbox the broker hasn't acked yet.
+    pub fn outbox_len(&self) -> Result<u64, MqttError> {
+        match self.outbox {
+            Some(ref outbox) => outbox.len(),
+            None => Ok(0),
+        }
+    }
+
+    /// The protocol version of the connection: `V5` or `V311`.
+    ///
+    /// In `Auto` mode this is `V5` until the broker rejects it.
+    pub fn protocol(&self) -> MqttProtocol {
+        match *self.client.read().unwrap() {
+            Requests::V4(_) => MqttProtocol::V311,
+            Requests::V5(_) => MqttProtocol::V5,
+        }
+    }
+
+    fn requests(&self) -> Requests {
+        self.client.read().unwrap().clone()
+    }
+
     /// Subscribe to a topic.
     pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), MqttError> {
-        self.client
+        self.requests()
             .subscribe(topic, qos)
             .await
-            .map_err(|e| MqttError::Subscribe(e.to_string()))?;
+            .map_err(MqttError::Subscribe)?;
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), qos);
         debug!("Subscribed to topic: {}", topic);
         Ok(())
     }
 
     /// Unsubscribe from a topic.
     pub async fn unsubscribe(&self, topic: &str) -> Result<(), MqttError> {
-        self.client
+        self.requests()
             .unsubscribe(topic)
             .await
-            .map_err(|e| MqttError::Subscribe(e.to_string()))?;
+            .map_err(MqttError::Subscribe)?;
+        self.subscriptions.lock().unwrap().remove(topic);
         debug!("Unsubscribed from topic: {}", topic);
         Ok(())
     }
 
     /// Publish a message to a topic.
     pub async fn publish(&self, topic: &str, payload: &[u8], qos: QoS) -> Result<(), MqttError> {
-        self.client
-            .publish(topic, qos, false, payload)
+        self.publish_with_properties(topic, payload, qos, &MessageProperties::default())
             .await
-            .map_err(|e| MqttError::Publish(e.to_string()))?;
+    }
+
+    /// Publish a message with MQTT 5 properties.
+    ///
+    /// The properties are dropped on an MQTT 3.1.1 connection and for
+    /// publishes queued in the outbox.
+    pub async fn publish_with_properties(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        properties: &MessageProperties,
+    ) -> Result<(), MqttError> {
+        if self.enqueue(topic, payload, qos, false)? {
+            return Ok(());
+        }
+        self.requests()
+            .publish(topic, qos, false, payload, properties)
+            .await
+            .map_err(MqttError::Publish)?;
         debug!("Published {} bytes to topic: {}", payload.len(), topic);
         Ok(())
     }
 
+    /// Queue a publish in the outbox instead of sending it, if it should be.
+    ///
+    /// QoS 1+ publishes are queued while disconnected, and also while older
+    /// ones are still queued so order is kept. Returns whether it was queued.
+    fn enqueue(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<bool, MqttError> {
+        let Some(ref outbox) = self.outbox else {
+            return Ok(false);
+        };
+        if qos == QoS::AtMostOnce
+            || (self.connected.load(Ordering::SeqCst)
+                && !self.outbox_pending.load(Ordering::SeqCst))
+        {
+            return Ok(false);
+        }
+        outbox.push(&QueuedPublish::new(topic, payload, retain))?;
+        self.outbox_pending.store(true, Ordering::SeqCst);
+        debug!(
+            "Queued {} bytes for topic: {} (offline)",
+            payload.len(),
+            topic
+        );
+        Ok(true)
+    }
+
+    /// Hand queued publishes to the connection, oldest first.
+    ///
+    /// Runs inside the event loop, between polls. Only as many go as fit in
+    /// the connection's inflight window, and only once the previous batch
+    /// has packet IDs; rows stay in the outbox until the broker acks them.
+    async fn drain_outbox(&self) {
+        let Some(ref outbox) = self.outbox else {
+            return;
+        };
+        let mut event_loop = self.event_loop.lock().await;
+        let room = event_loop.publish_room().min(OUTBOX_BATCH);
+        let mut flight = self.outbox_flight.lock().unwrap();
+        if room == 0 || !flight.unsent.is_empty() {
+            return;
+        }
+        let from = flight.last.map_or(0, |last| last + 1);
+        let queued = match outbox.peek_from(from, room) {
+            Ok(queued) => queued,
+            Err(e) => {
+                warn!("Failed to read outbox: {}", e);
+                return;
+            }
+        };
+        if queued.is_empty() {
+            if flight.unacked.is_empty() {
+                drop(flight);
+                self.settle_outbox(outbox);
+            }
+            return;
+        }
+        for (seq, publish) in queued {
+            flight.last = Some(seq);
+            match publish.payload_bytes() {
+                Ok(payload) => {
+                    event_loop.push_publish(&publish.topic, publish.retain, payload);
+                    flight.unsent.push_back(seq);
+                    debug!("Sent queued publish to topic: {}", publish.topic);
+                }
+                Err(e) => {
+                    warn!("Dropping queued publish to {}: {}", publish.topic, e);
+                    let _ = outbox.remove(seq);
+                }
+            }
+        }
+    }
+
+    /// Note the packet ID of an outgoing publish.
+    fn outbox_sent(&self, pkid: u16) {
+        // QoS 0 publishes have no packet ID, and aren't queued
+        if pkid == 0 {
+            return;
+        }
+        let mut flight = self.outbox_flight.lock().unwrap();
+        if let Some(seq) = flight.unsent.pop_front() {
+            flight.unacked.insert(pkid, seq);
+        }
+    }
+
+    /// Remove a queued publish from the outbox once the broker acks it.
+    fn outbox_acked(&self, pkid: u16) {
+        let Some(ref outbox) = self.outbox else {
+            return;
+        };
+        let mut flight = self.outbox_flight.lock().unwrap();
+        let Some(seq) = flight.unacked.remove(&pkid) else {
+            return;
+        };
+        if let Err(e) = outbox.remove(seq) {
+            warn!("Failed to remove acked publish from outbox: {}", e);
+        }
+        let idle = flight.unacked.is_empty() && flight.unsent.is_empty();
+        drop(flight);
+        if idle {
+            self.settle_outbox(outbox);
+        }
+    }
+
+    /// Forget which publishes are in flight; whatever wasn't acked is sent
+    /// again. Used when a packet ID collision makes acks ambiguous.
+    fn reset_outbox_flight(&self) {
+        *self.outbox_flight.lock().unwrap() = OutboxFlight::default();
+    }
+
+    /// Stop queueing new publishes if the outbox is empty.
+    ///
+    /// `enqueue` pushes before raising the flag, so checking again after
+    /// clearing it catches a publish queued in between.
+    fn settle_outbox(&self, outbox: &Outbox) {
+        if !outbox.is_empty().unwrap_or(false) {
+            return;
+        }
+        self.outbox_pending.store(false, Ordering::SeqCst);
+        if !outbox.is_empty().unwrap_or(true) {
+            self.outbox_pending.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Publish a retained message, replacing the topic's previous one.
+    pub async fn publish_retained(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), MqttError> {
+        if self.enqueue(topic, payload, qos, true)? {
+            return Ok(());
+        }
+        self.requests()
+            .publish(topic, qos, true, payload, &MessageProperties::default())
+            .await
+            .map_err(MqttError::Publish)?;
+        debug!(
+            "Published {} retained bytes to topic: {}",
+            payload.len(),
+            topic
+        );
+        Ok(())
+    }
+
     /// Get a receiver for incoming messages.
     pub fn subscribe_messages(&self) -> broadcast::Receiver<IncomingMessage> {
         self.message_tx.subscribe()
@@ -98,8 +369,12 @@ impl MqttClient {
     /// Run the MQTT event loop.
     ///
     /// This should be spawned as a background task. It processes incoming
-    /// messages and broadcasts them to subscribers.
+    /// messages and broadcasts them to subscribers. Connection failures are
+    /// retried with the configured [`ReconnectPolicy`]; the loop returns an
+    /// error only once its attempts are exhausted.
     pub async fn run_event_loop(&self) -> Result<(), MqttError> {
+        let mut failures = 0u32;
+        let mut connected_before = false;
         loop {
             let notification = {
                 let mut event_loop = self.event_loop.lock().await;
@@ -107,109 +382,655 @@ impl MqttClient {
             };
 
             match notification {
-                Ok(Event::Incoming(Packet::Publish(publish))) => {
-                    let msg = IncomingMessage {
-                        topic: publish.topic.clone(),
-                        payload: publish.payload.to_vec(),
-                    };
-
+                Ok(Notification::Publish(msg)) => {
                     // Broadcast to all receivers (ignore if no receivers)
                     let _ = self.message_tx.send(msg);
                 }
-                Ok(Event::Incoming(Packet::SubAck(_))) => {
-                    debug!("Subscription acknowledged");
-                }
-                Ok(Event::Incoming(Packet::UnsubAck(_))) => {
-                    debug!("Unsubscription acknowledged");
-                }
-                Ok(Event::Incoming(Packet::ConnAck(ack))) => {
-                    info!("Connected to MQTT broker: {:?}", ack.code);
-                }
-                Ok(Event::Incoming(Packet::PingResp)) => {
-                    // Ping response, ignore
-                }
-                Ok(Event::Outgoing(_)) => {
-                    // Outgoing packet, ignore
-                }
-                Ok(event) => {
-                    debug!("MQTT event: {:?}", event);
+                Ok(Notification::ConnAck {
+                    code,
+                    session_present,
+                }) => {
+                    info!("Connected to MQTT broker: {}", code);
+                    failures = 0;
+                    self.connected.store(true, Ordering::SeqCst);
+                    // The protocol is settled once a connection succeeds
+                    self.fallback.lock().unwrap().take();
+                    // Subscriptions made before the first connect are still
+                    // queued; after a reconnect they're gone unless the
+                    // broker kept our session
+                    if connected_before && !session_present {
+                        self.resubscribe();
+                    }
+                    connected_before = true;
+                    self.announce_status(true);
                 }
+                Ok(Notification::PublishSent(pkid)) => self.outbox_sent(pkid),
+                Ok(Notification::PublishAcked(pkid)) => self.outbox_acked(pkid),
+                Ok(Notification::Collision) => self.reset_outbox_flight(),
+                Ok(Notification::Other) => {}
                 Err(e) => {
-                    error!("MQTT error: {:?}", e);
-                    // Connection errors will be retried by rumqttc
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    self.connected.store(false, Ordering::SeqCst);
+                    if e.protocol_rejected && self.fall_back().await {
+                        warn!(
+                            "Broker rejected MQTT 5 ({}); falling back to MQTT 3.1.1",
+                            e.message
+                        );
+                        continue;
+                    }
+                    failures += 1;
+                    if self
+                        .reconnect
+                        .max_attempts
+                        .is_some_and(|max| failures >= max)
+                    {
+                        return Err(MqttError::Connection(format!(
+                            "giving up after {} attempts: {}",
+                            failures, e.message
+                        )));
+                    }
+                    let delay = self.reconnect.delay(failures);
+                    error!("MQTT error: {} (retrying in {:?})", e.message, delay);
+                    // The next poll reconnects
+                    tokio::time::sleep(delay).await;
                 }
             }
+
+            if self.connected.load(Ordering::SeqCst) && self.outbox_pending.load(Ordering::SeqCst) {
+                self.drain_outbox().await;
+            }
+        }
+    }
+
+    /// Switch to MQTT 3.1.1, if in `Auto` mode and not yet connected.
+    ///
+    /// Requests still queued for the MQTT 5 connection move to the new one.
+    async fn fall_back(&self) -> bool {
+        let Some(options) = self.fallback.lock().unwrap().take() else {
+            return false;
+        };
+        let (client, mut event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let mut connection = self.event_loop.lock().await;
+        if let Connection::V5(ref mut old) = *connection {
+            old.clean();
+            event_loop
+                .pending
+                .extend(old.pending.drain(..).filter_map(v311_request));
+        }
+        *connection = Connection::V4(Box::new(event_loop));
+        *self.client.write().unwrap() = Requests::V4(client);
+        true
+    }
+
+    /// Restore subscriptions after reconnecting to a fresh session.
+    fn resubscribe(&self) {
+        let subscriptions = self.subscriptions.lock().unwrap().clone();
+        let client = self.requests();
+        for (topic, qos) in subscriptions {
+            if let Err(e) = client.try_subscribe(&topic, qos) {
+                warn!("Failed to resubscribe to {}: {}", topic, e);
+            }
+        }
+        debug!("Restored subscriptions after reconnect");
+    }
+
+    /// Publish our retained status, if a status topic is configured.
+    ///
+    /// Uses `try_publish` since this runs inside the event loop, which is
+    /// what drains the request queue.
+    fn announce_status(&self, online: bool) {
+        let Some(ref topic) = self.status_topic else {
+            return;
+        };
+        let payload = status_payload(&self.client_id, online);
+        if let Err(e) = self
+            .requests()
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+        {
+            warn!("Failed to publish status to {}: {}", topic, e);
         }
     }
 
     /// Disconnect from the broker.
     pub async fn disconnect(&self) -> Result<(), MqttError> {
-        self.client
+        self.requests()
             .disconnect()
             .await
-            .map_err(|e| MqttError::Connection(e.to_string()))?;
+            .map_err(MqttError::Connection)?;
         info!("Disconnected from MQTT broker");
         Ok(())
     }
 }
 
-/// Parse a broker URL into host and port.
-fn parse_broker_url(url: &str) -> Result<(String, u16), MqttError> {
-    // Handle mqtt:// or tcp:// prefix
-    let stripped = url
-        .strip_prefix("mqtt://")
-        .or_else(|| url.strip_prefix("tcp://"))
-        .unwrap_or(url);
+/// Request handle for the connection's protocol version.
+#[derive(Clone)]
+enum Requests {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Requests {
+    async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client
+                .subscribe(topic, qos)
+                .await
+                .map_err(|e| e.to_string()),
+            Requests::V5(client) => client
+                .subscribe(topic, v5_qos(qos))
+                .await
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn try_subscribe(&self, topic: &str, qos: QoS) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client.try_subscribe(topic, qos).map_err(|e| e.to_string()),
+            Requests::V5(client) => client
+                .try_subscribe(topic, v5_qos(qos))
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    async fn unsubscribe(&self, topic: &str) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client.unsubscribe(topic).await.map_err(|e| e.to_string()),
+            Requests::V5(client) => client.unsubscribe(topic).await.map_err(|e| e.to_string()),
+        }
+    }
+
+    async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+        properties: &MessageProperties,
+    ) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client
+                .publish(topic, qos, retain, payload)
+                .await
+                .map_err(|e| e.to_string()),
+            Requests::V5(client) => match properties.to_v5() {
+                Some(properties) => client
+                    .publish_with_properties(
+                        topic,
+                        v5_qos(qos),
+                        retain,
+                        payload.to_vec(),
+                        properties,
+                    )
+                    .await
+                    .map_err(|e| e.to_string()),
+                None => client
+                    .publish(topic, v5_qos(qos), retain, payload.to_vec())
+                    .await
+                    .map_err(|e| e.to_string()),
+            },
+        }
+    }
+
+    fn try_publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client
+                .try_publish(topic, qos, retain, payload)
+                .map_err(|e| e.to_string()),
+            Requests::V5(client) => client
+                .try_publish(topic, v5_qos(qos), retain, payload)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        match self {
+            Requests::V4(client) => client.disconnect().await.map_err(|e| e.to_string()),
+            Requests::V5(client) => client.disconnect().await.map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Event loop for the connection's protocol version.
+enum Connection {
+    V4(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+/// What the event loop reported, whatever the protocol version.
+enum Notification {
+    Publish(IncomingMessage),
+    ConnAck {
+        code: String,
+        session_present: bool,
+    },
+    /// A QoS 1+ publish went out with this packet ID
+    PublishSent(u16),
+    /// The broker acknowledged the publish with this packet ID
+    PublishAcked(u16),
+    /// A publish is waiting for a packet ID still in use
+    Collision,
+    Other,
+}
+
+/// A connection failure.
+struct ConnectionFailure {
+    message: String,
+    /// Whether the broker looked to refuse MQTT 5 itself, rather than being
+    /// unreachable
+    protocol_rejected: bool,
+}
+
+impl Connection {
+    /// How many more QoS 1 publishes the connection can take before its
+    /// packet IDs wrap around; none while it has requests pending.
+    fn publish_room(&self) -> usize {
+        let (idle, collision, inflight, limit) = match self {
+            Connection::V4(event_loop) => (
+                event_loop.pending.is_empty(),
+                event_loop.state.collision.is_some(),
+                event_loop.state.inflight(),
+                event_loop.mqtt_options.inflight(),
+            ),
+            Connection::V5(event_loop) => (
+                event_loop.pending.is_empty(),
+                event_loop.state.collision.is_some(),
+                event_loop.state.inflight(),
+                event_loop
+                    .options
+                    .get_outgoing_inflight_upper_limit()
+                    .unwrap_or(OUTBOX_BATCH as u16),
+            ),
+        };
+        if !idle || collision {
+            return 0;
+        }
+        limit.saturating_sub(inflight) as usize
+    }
+
+    /// Queue a QoS 1 publish ahead of the request channel.
+    fn push_publish(&mut self, topic: &str, retain: bool, payload: Vec<u8>) {
+        match self {
+            Connection::V4(event_loop) => {
+                let mut publish = rumqttc::Publish::new(topic, QoS::AtLeastOnce, payload);
+                publish.retain = retain;
+                event_loop
+                    .pending
+                    .push_back(rumqttc::Request::Publish(publish));
+            }
+            Connection::V5(event_loop) => {
+                let mut publish =
+                    v5::mqttbytes::v5::Publish::new(topic, v5_qos(QoS::AtLeastOnce), payload, None);
+                publish.retain = retain;
+                event_loop.pending.push_back(v5::Request::Publish(publish));
+            }
+        }
+    }
+
+    async fn poll(&mut self) -> Result<Notification, ConnectionFailure> {
+        match self {
+            Connection::V4(event_loop) => match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    Ok(Notification::Publish(IncomingMessage {
+                        topic: publish.topic.clone(),
+                        payload: publish.payload.to_vec(),
+                        properties: MessageProperties::default(),
+                    }))
+                }
+                Ok(Event::Incoming(Packet::ConnAck(ack))) => Ok(Notification::ConnAck {
+                    code: format!("{:?}", ack.code),
+                    session_present: ack.session_present,
+                }),
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Ok(Notification::PublishSent(pkid)),
+                Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                    Ok(Notification::PublishAcked(ack.pkid))
+                }
+                Ok(Event::Outgoing(Outgoing::AwaitAck(_))) => Ok(Notification::Collision),
+                Ok(event) => {
+                    log_event(&event);
+                    Ok(Notification::Other)
+                }
+                Err(e) => Err(ConnectionFailure {
+                    message: format!("{:?}", e),
+                    protocol_rejected: false,
+                }),
+            },
+            Connection::V5(event_loop) => match event_loop.poll().await {
+                Ok(v5::Event::Incoming(V5Packet::Publish(publish))) => {
+                    Ok(Notification::Publish(IncomingMessage {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        payload: publish.payload.to_vec(),
+                        properties: MessageProperties::from_v5(publish.properties.as_ref()),
+                    }))
+                }
+                Ok(v5::Event::Incoming(V5Packet::ConnAck(ack))) => Ok(Notification::ConnAck {
+                    code: format!("{:?} (MQTT 5)", ack.code),
+                    session_present: ack.session_present,
+                }),
+                Ok(v5::Event::Outgoing(Outgoing::Publish(pkid))) => {
+                    Ok(Notification::PublishSent(pkid))
+                }
+                Ok(v5::Event::Incoming(V5Packet::PubAck(ack))) => {
+                    Ok(Notification::PublishAcked(ack.pkid))
+                }
+                Ok(v5::Event::Outgoing(Outgoing::AwaitAck(_))) => Ok(Notification::Collision),
+                Ok(v5::Event::Incoming(V5Packet::SubAck(_))) => {
+                    debug!("Subscription acknowledged");
+                    Ok(Notification::Other)
+                }
+                Ok(v5::Event::Incoming(V5Packet::UnsubAck(_))) => {
+                    debug!("Unsubscription acknowledged");
+                    Ok(Notification::Other)
+                }
+                Ok(v5::Event::Incoming(V5Packet::PingResp(_))) | Ok(v5::Event::Outgoing(_)) => {
+                    Ok(Notification::Other)
+                }
+                Ok(event) => {
+                    debug!("MQTT event: {:?}", event);
+                    Ok(Notification::Other)
+                }
+                Err(e) => Err(ConnectionFailure {
+                    message: format!("{:?}", e),
+                    protocol_rejected: rejects_mqtt5(&e),
+                }),
+            },
+        }
+    }
+}
+
+fn log_event(event: &Event) {
+    match event {
+        Event::Incoming(Packet::SubAck(_)) => debug!("Subscription acknowledged"),
+        Event::Incoming(Packet::UnsubAck(_)) => debug!("Unsubscription acknowledged"),
+        // Ping responses and outgoing packets
+        Event::Incoming(Packet::PingResp) | Event::Outgoing(_) => {}
+        event => debug!("MQTT event: {:?}", event),
+    }
+}
+
+/// Whether an MQTT 5 connection error means the broker doesn't speak it.
+///
+/// A 3.1.1 broker answers an MQTT 5 CONNECT with return code 1, which an
+/// MQTT 5 client can't parse, or just closes the connection; either way the
+/// TCP connection was made. Unreachable brokers and timeouts don't count.
+fn rejects_mqtt5(error: &v5::ConnectionError) -> bool {
+    use rumqttc::v5::mqttbytes::v5::ConnectReturnCode;
+    use std::io::ErrorKind;
+    match error {
+        v5::ConnectionError::ConnectionRefused(code) => matches!(
+            code,
+            ConnectReturnCode::UnsupportedProtocolVersion
+                | ConnectReturnCode::RefusedProtocolVersion
+        ),
+        v5::ConnectionError::MqttState(v5::StateError::Deserialization(_))
+        | v5::ConnectionError::NotConnAck(_) => true,
+        v5::ConnectionError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::InvalidData
+                | ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Translate a queued MQTT 5 request for a 3.1.1 connection.
+fn v311_request(request: v5::Request) -> Option<rumqttc::Request> {
+    use rumqttc::{Publish, Subscribe, SubscribeFilter, Unsubscribe};
+    match request {
+        v5::Request::Publish(publish) => {
+            let mut out = Publish::from_bytes(
+                String::from_utf8_lossy(&publish.topic),
+                v311_qos(publish.qos),
+                publish.payload,
+            );
+            out.retain = publish.retain;
+            Some(rumqttc::Request::Publish(out))
+        }
+        v5::Request::Subscribe(subscribe) => {
+            Some(rumqttc::Request::Subscribe(Subscribe::new_many(
+                subscribe
+                    .filters
+                    .into_iter()
+                    .map(|f| SubscribeFilter::new(f.path, v311_qos(f.qos))),
+            )))
+        }
+        v5::Request::Unsubscribe(unsubscribe) => unsubscribe
+            .filters
+            .into_iter()
+            .next()
+            .map(|topic| rumqttc::Request::Unsubscribe(Unsubscribe::new(topic))),
+        v5::Request::Disconnect => Some(rumqttc::Request::Disconnect(rumqttc::Disconnect)),
+        // Acks and pings belong to the old connection
+        _ => None,
+    }
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn v311_qos(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+/// Build MQTT 5 options from a config; see [`mqtt_options`].
+fn mqtt5_options(config: &MqttConfig) -> Result<v5::MqttOptions, MqttError> {
+    let broker = parse_broker_url(&config.broker_url)?;
+
+    let mut options = v5::MqttOptions::new(&config.client_id, broker.host, broker.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    options.set_clean_start(config.clean_session);
+
+    if let Some(ref password) = config.password {
+        let username = config.username.as_ref().unwrap_or(&config.client_id);
+        options.set_credentials(username, password);
+    } else if let Some(ref username) = config.username {
+        options.set_credentials(username, "");
+    }
+
+    if broker.tls || config.tls.is_some() {
+        let tls = config.tls.clone().unwrap_or_default();
+        options.set_transport(Transport::tls_with_config(tls_configuration(&tls)?));
+    }
+
+    if let Some(ref topic) = config.status_topic {
+        options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+            topic,
+            status_payload(&config.client_id, false),
+            v5::mqttbytes::QoS::AtLeastOnce,
+            true,
+            None,
+        ));
+    }
+
+    Ok(options)
+}
+
+/// Build rumqttc options from a config: address, credentials, TLS and last will.
+pub fn mqtt_options(config: &MqttConfig) -> Result<MqttOptions, MqttError> {
+    let broker = parse_broker_url(&config.broker_url)?;
+
+    let mut options = MqttOptions::new(&config.client_id, broker.host, broker.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    options.set_clean_session(config.clean_session);
+
+    if let Some(ref password) = config.password {
+        let username = config.username.as_ref().unwrap_or(&config.client_id);
+        options.set_credentials(username, password);
+    } else if let Some(ref username) = config.username {
+        options.set_credentials(username, "");
+    }
+
+    if broker.tls || config.tls.is_some() {
+        let tls = config.tls.clone().unwrap_or_default();
+        options.set_transport(Transport::tls_with_config(tls_configuration(&tls)?));
+    }
+
+    if let Some(ref topic) = config.status_topic {
+        options.set_last_will(LastWill::new(
+            topic,
+            status_payload(&config.client_id, false),
+            QoS::AtLeastOnce,
+            true,
+        ));
+    }
+
+    Ok(options)
+}
+
+fn tls_configuration(tls: &super::MqttTls) -> Result<TlsConfiguration, MqttError> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|e| MqttError::Connection(format!("{}: {}", path.display(), e)))
+    };
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(MqttError::Connection(
+                "client certificate and key must be given together".to_string(),
+            ))
+        }
+    };
+    match (&tls.ca_file, client_auth) {
+        (Some(ca), client_auth) => Ok(TlsConfiguration::Simple {
+            ca: read(ca)?,
+            alpn: None,
+            client_auth,
+        }),
+        (None, None) => Ok(TlsConfiguration::default()),
+        (None, Some(_)) => Err(MqttError::Connection(
+            "a client certificate requires a CA file".to_string(),
+        )),
+    }
+}
+
+fn status_payload(client_id: &str, online: bool) -> Vec<u8> {
+    let status = StatusMessage {
+        online,
+        client_id: client_id.to_string(),
+    };
+    serde_json::to_vec(&status).unwrap_or_default()
+}
+
+/// Broker address parsed from a URL.
+#[derive(Debug, PartialEq, Eq)]
+struct BrokerAddress {
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+/// Parse a broker URL into host, port and whether it uses TLS.
+///
+/// `mqtt://` and `tcp://` (or no scheme) are plain, defaulting to port 1883;
+/// `mqtts://`, `ssl://` and `tls://` use TLS, defaulting to port 8883.
+fn parse_broker_url(url: &str) -> Result<BrokerAddress, MqttError> {
+    let (tls, stripped) = match url.split_once("://") {
+        Some(("mqtt" | "tcp", rest)) => (false, rest),
+        Some(("mqtts" | "ssl" | "tls", rest)) => (true, rest),
+        Some((scheme, _)) => {
+            return Err(MqttError::Connection(format!(
+                "Unsupported scheme '{}' in broker URL: {}",
+                scheme, url
+            )))
+        }
+        None => (false, url),
+    };
+    let stripped = stripped.trim_end_matches('/');
 
     // Split host and port
-    let parts: Vec<&str> = stripped.split(':').collect();
-
-    let host = parts
-        .first()
-        .ok_or_else(|| MqttError::Connection(format!("Invalid broker URL: {}", url)))?
-        .to_string();
-
-    let port = if parts.len() > 1 {
-        parts[1]
-            .parse()
-            .map_err(|_| MqttError::Connection(format!("Invalid port in URL: {}", url)))?
-    } else {
-        1883 // Default MQTT port
+    let (host, port) = match stripped.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| MqttError::Connection(format!("Invalid port in URL: {}", url)))?,
+        ),
+        None => (stripped, if tls { 8883 } else { 1883 }),
     };
+    if host.is_empty() {
+        return Err(MqttError::Connection(format!(
+            "Invalid broker URL: {}",
+            url
+        )));
+    }
 
-    Ok((host, port))
+    Ok(BrokerAddress {
+        host: host.to_string(),
+        port,
+        tls,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn host_port(url: &str) -> (String, u16) {
+        let broker = parse_broker_url(url).unwrap();
+        (broker.host, broker.port)
+    }
+
     #[test]
     fn test_parse_broker_url_with_port() {
-        let (host, port) = parse_broker_url("mqtt://localhost:1883").unwrap();
+        let (host, port) = host_port("mqtt://localhost:1883");
         assert_eq!(host, "localhost");
         assert_eq!(port, 1883);
     }
 
     #[test]
     fn test_parse_broker_url_without_scheme() {
-        let (host, port) = parse_broker_url("localhost:1883").unwrap();
+        let (host, port) = host_port("localhost:1883");
         assert_eq!(host, "localhost");
         assert_eq!(port, 1883);
     }
 
     #[test]
     fn test_parse_broker_url_default_port() {
-        let (host, port) = parse_broker_url("mqtt://broker.example.com").unwrap();
+        let (host, port) = host_port("mqtt://broker.example.com");
         assert_eq!(host, "broker.example.com");
         assert_eq!(port, 1883);
     }
 
     #[test]
     fn test_parse_broker_url_custom_port() {
-        let (host, port) = parse_broker_url("mqtt://broker.example.com:8883").unwrap();
+        let (host, port) = host_port("mqtt://broker.example.com:8883");
         assert_eq!(host, "broker.example.com");
         assert_eq!(port, 8883);
     }
+
+    #[test]
+    fn test_parse_broker_url_tls() {
+        let broker = parse_broker_url("mqtts://broker.example.com").unwrap();
+        assert!(broker.tls);
+        assert_eq!(broker.port, 8883);
+        assert!(!parse_broker_url("mqtt://broker.example.com").unwrap().tls);
+        assert!(parse_broker_url("ws://broker.example.com").is_err());
+    }
+
+    #[test]
+    fn test_reconnect_backoff_is_capped() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(10), Duration::from_secs(1));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+    }
 }
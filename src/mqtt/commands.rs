@@ -1,15 +1,32 @@
 //! Commands port handler.
 //!
 //! Handles store-level commands like create-document and path-specific commands.
+//!
+//! Path-specific commands are answered by the node that owns the path. The
+//! store tracks each path's retained command manifest and rejects payloads
+//! that don't match it, replying with the validation error when the sender
+//! asked for a reply.
+//!
+//! The broker delivers every command to the node whatever the store makes of
+//! it, so the store's rejection is advisory only: nodes must check commands
+//! against their manifest themselves. [`serve_commands`] does so, and never
+//! passes a rejected command to its handler.
 
 use crate::document::{ContentType, DocumentStore};
 use crate::mqtt::client::MqttClient;
-use crate::mqtt::messages::{CommandMessage, CreateDocumentRequest, CreateDocumentResponse};
-use crate::mqtt::topics::Topic;
+use crate::mqtt::messages::{
+    CommandManifest, CommandMessage, CommandReply, CreateDocumentRequest, CreateDocumentResponse,
+};
+use crate::mqtt::properties::MessageProperties;
+use crate::mqtt::store_api::codes;
+use crate::mqtt::topics::{Port, Topic};
 use crate::mqtt::MqttError;
 use rumqttc::QoS;
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::RwLock;
 use tracing::{debug, warn};
 
@@ -19,6 +36,8 @@ pub struct CommandsHandler {
     document_store: Arc<DocumentStore>,
     /// Map of path -> set of subscribed verbs
     subscribed_commands: RwLock<HashMap<String, HashSet<String>>>,
+    /// Latest command manifest published for each path
+    manifests: RwLock<HashMap<String, CommandManifest>>,
 }
 
 impl CommandsHandler {
@@ -28,11 +47,12 @@ impl CommandsHandler {
             client,
             document_store,
             subscribed_commands: RwLock::new(HashMap::new()),
+            manifests: RwLock::new(HashMap::new()),
         }
     }
 
     /// Subscribe to commands for a path.
-    /// Uses wildcard: `{path}/commands/#`
+    /// Uses wildcard: `{path}/commands/#`, plus the path's manifest topic.
     pub async fn subscribe_commands(&self, path: &str) -> Result<(), MqttError> {
         let topic_pattern = Topic::commands_wildcard(path);
 
@@ -40,6 +60,9 @@ impl CommandsHandler {
         self.client
             .subscribe(&topic_pattern, QoS::AtLeastOnce)
             .await?;
+        self.client
+            .subscribe(&Topic::manifest(path).to_topic_string(), QoS::AtLeastOnce)
+            .await?;
 
         let mut commands = self.subscribed_commands.write().await;
         commands.entry(path.to_string()).or_default();
@@ -71,9 +94,13 @@ impl CommandsHandler {
         let topic_pattern = Topic::commands_wildcard(path);
 
         self.client.unsubscribe(&topic_pattern).await?;
+        self.client
+            .unsubscribe(&Topic::manifest(path).to_topic_string())
+            .await?;
 
         let mut commands = self.subscribed_commands.write().await;
         commands.remove(path);
+        self.manifests.write().await.remove(path);
 
         debug!("Unsubscribed from commands for path: {}", path);
         Ok(())
@@ -81,9 +108,15 @@ impl CommandsHandler {
 
     /// Handle an incoming command on a path-specific topic.
     ///
-    /// Note: Path-specific commands are not currently implemented.
-    /// Use store-level commands like `$store/commands/create-document` instead.
-    pub async fn handle_command(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+    /// The owning node executes the command; the store only checks it
+    /// against the path's manifest, if one has been published. The node
+    /// receives the command either way, so the error reply is advisory.
+    pub async fn handle_command(
+        &self,
+        topic: &Topic,
+        payload: &[u8],
+        properties: &MessageProperties,
+    ) -> Result<(), MqttError> {
         let verb = topic.qualifier.as_deref().ok_or_else(|| {
             MqttError::InvalidTopic("Command topic missing verb qualifier".to_string())
         })?;
@@ -97,14 +130,63 @@ impl CommandsHandler {
             verb, topic.path, command.source
         );
 
-        // Path-specific commands are not currently implemented
-        warn!(
-            "Path-specific command '{}' at path '{}' not implemented - use store-level commands",
-            verb, topic.path
+        let result = match self.manifests.read().await.get(&topic.path) {
+            Some(manifest) => manifest.validate(verb, &command.payload),
+            None => return Ok(()),
+        };
+
+        if let Err(error) = result {
+            warn!(
+                "Rejected command '{}' at path '{}': {}",
+                verb, topic.path, error
+            );
+            if let Some((reply_to, req)) = reply_route(&command, properties) {
+                let reply = CommandReply {
+                    req,
+                    result: None,
+                    error: Some(error),
+                    code: Some(codes::INVALID_INPUT.to_string()),
+                    source: Some(self.client.client_id().to_string()),
+                };
+                self.client
+                    .publish_with_properties(
+                        &reply_to,
+                        &serde_json::to_vec(&reply)?,
+                        QoS::AtLeastOnce,
+                        &properties.reply(),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the command manifest retained on `{path}/events/manifest`.
+    /// An empty payload clears it.
+    pub async fn handle_manifest(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+        let mut manifests = self.manifests.write().await;
+        if payload.is_empty() {
+            manifests.remove(&topic.path);
+            debug!("Cleared command manifest for path: {}", topic.path);
+            return Ok(());
+        }
+
+        let manifest: CommandManifest = serde_json::from_slice(payload)
+            .map_err(|e| MqttError::InvalidMessage(e.to_string()))?;
+        debug!(
+            "Command manifest for path {}: {:?}",
+            topic.path,
+            manifest.commands.keys().collect::<Vec<_>>()
         );
+        manifests.insert(topic.path.clone(), manifest);
         Ok(())
     }
 
+    /// The latest command manifest seen for a path.
+    pub async fn manifest(&self, path: &str) -> Option<CommandManifest> {
+        self.manifests.read().await.get(path).cloned()
+    }
+
     /// Handle create-document command.
     /// Topic: $store/commands/create-document
     pub async fn handle_create_document(&self, payload: &[u8]) -> Result<(), MqttError> {
@@ -159,3 +241,212 @@ impl CommandsHandler {
         commands.contains_key(path)
     }
 }
+
+/// Where to reply to a command, and the request ID to reply with.
+///
+/// An MQTT 5 response topic wins over `reply_to`; correlation data stands in
+/// for a missing `req`, so MQTT 5 senders may leave both out of the JSON.
+pub(crate) fn reply_route(
+    command: &CommandMessage,
+    properties: &MessageProperties,
+) -> Option<(String, String)> {
+    let topic = properties
+        .response_topic
+        .clone()
+        .or_else(|| command.reply_to.clone())?;
+    let req = command
+        .req
+        .clone()
+        .or_else(|| properties.correlation_id().map(str::to_string))?;
+    Some((topic, req))
+}
+
+/// Read the retained command manifest for a path.
+///
+/// Returns `None` if no manifest arrives within `timeout`. The client's event
+/// loop must be running.
+pub async fn fetch_manifest(
+    client: &MqttClient,
+    path: &str,
+    timeout: Duration,
+) -> Result<Option<CommandManifest>, MqttError> {
+    let manifest_topic = Topic::manifest(path).to_topic_string();
+    let mut messages = client.subscribe_messages();
+    client.subscribe(&manifest_topic, QoS::AtLeastOnce).await?;
+
+    let wait = async {
+        loop {
+            let msg = match messages.recv().await {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => {
+                    return Err(MqttError::Connection("MQTT client closed".to_string()))
+                }
+            };
+            if msg.topic == manifest_topic && !msg.payload.is_empty() {
+                return Ok(serde_json::from_slice::<CommandManifest>(&msg.payload)?);
+            }
+        }
+    };
+
+    let result = match tokio::time::timeout(timeout, wait).await {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
+    };
+    let _ = client.unsubscribe(&manifest_topic).await;
+    result
+}
+
+/// Send a command and wait for the handler's reply.
+///
+/// Fills in `reply_to` and `req` on `message` (and the matching response
+/// topic and correlation data on MQTT 5), subscribes to the reply topic
+/// before publishing, and returns the first [`CommandReply`] with a matching
+/// request ID. The client's event loop must be running.
+pub async fn request_command(
+    client: &MqttClient,
+    path: &str,
+    verb: &str,
+    mut message: CommandMessage,
+    timeout: Duration,
+) -> Result<CommandReply, MqttError> {
+    let req = uuid::Uuid::new_v4().to_string();
+    let reply_topic = Topic::reply(path, &req).to_topic_string();
+    message.reply_to = Some(reply_topic.clone());
+    message.req = Some(req.clone());
+
+    // Subscribe before publishing so a fast handler's reply isn't missed
+    let mut messages = client.subscribe_messages();
+    client.subscribe(&reply_topic, QoS::AtLeastOnce).await?;
+
+    let payload = serde_json::to_vec(&message)?;
+    client
+        .publish_with_properties(
+            &Topic::commands(path, verb).to_topic_string(),
+            &payload,
+            QoS::AtLeastOnce,
+            &MessageProperties::request(&reply_topic, &req),
+        )
+        .await?;
+
+    let wait = async {
+        loop {
+            let msg = match messages.recv().await {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Skipped {} messages while waiting for reply", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    return Err(MqttError::Connection("MQTT client closed".to_string()))
+                }
+            };
+            if msg.topic != reply_topic {
+                continue;
+            }
+            match serde_json::from_slice::<CommandReply>(&msg.payload) {
+                Ok(reply) if reply.req == req => return Ok(reply),
+                Ok(_) => continue,
+                Err(e) => warn!("Ignoring malformed reply on {}: {}", reply_topic, e),
+            }
+        }
+    };
+
+    let result = tokio::time::timeout(timeout, wait)
+        .await
+        .unwrap_or_else(|_| {
+            Err(MqttError::Timeout(format!(
+                "no reply to '{}' on {} within {:?}",
+                verb, path, timeout
+            )))
+        });
+    let _ = client.unsubscribe(&reply_topic).await;
+    result
+}
+
+/// Answer the commands for `path` as the node that owns it.
+///
+/// Publishes `manifest` retained on `{path}/events/manifest`, then calls
+/// `handler` with the verb and payload of each command the manifest accepts,
+/// replying with its result when the sender asked for a reply. Commands the
+/// manifest rejects never reach `handler`; they get an `invalid_input` error
+/// reply instead. Runs until the client closes. The client's event loop must
+/// be running.
+pub async fn serve_commands<F, Fut>(
+    client: &MqttClient,
+    path: &str,
+    manifest: CommandManifest,
+    mut handler: F,
+) -> Result<(), MqttError>
+where
+    F: FnMut(String, serde_json::Value) -> Fut,
+    Fut: Future<Output = Result<serde_json::Value, String>>,
+{
+    let mut messages = client.subscribe_messages();
+    client
+        .subscribe(&Topic::commands_wildcard(path), QoS::AtLeastOnce)
+        .await?;
+    client
+        .publish_retained(
+            &Topic::manifest(path).to_topic_string(),
+            &serde_json::to_vec(&manifest)?,
+            QoS::AtLeastOnce,
+        )
+        .await?;
+
+    loop {
+        let msg = match messages.recv().await {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Skipped {} messages while serving commands", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let Ok(topic) = Topic::parse(&msg.topic) else {
+            continue;
+        };
+        if topic.path != path || topic.port != Port::Commands {
+            continue;
+        }
+        let Some(verb) = topic.qualifier.as_deref() else {
+            continue;
+        };
+        let command: CommandMessage = match serde_json::from_slice(&msg.payload) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("Ignoring malformed command on {}: {}", msg.topic, e);
+                continue;
+            }
+        };
+
+        let (result, error, code) = match manifest.validate(verb, &command.payload) {
+            Ok(()) => match handler(verb.to_string(), command.payload.clone()).await {
+                Ok(result) => (Some(result), None, None),
+                Err(error) => (None, Some(error), None),
+            },
+            Err(error) => {
+                warn!("Rejected command '{}' at path '{}': {}", verb, path, error);
+                (None, Some(error), Some(codes::INVALID_INPUT.to_string()))
+            }
+        };
+
+        if let Some((reply_to, req)) = reply_route(&command, &msg.properties) {
+            let reply = CommandReply {
+                req,
+                result,
+                error,
+                code,
+                source: Some(client.client_id().to_string()),
+            };
+            client
+                .publish_with_properties(
+                    &reply_to,
+                    &serde_json::to_vec(&reply)?,
+                    QoS::AtLeastOnce,
+                    &msg.properties.reply(),
+                )
+                .await?;
+        }
+    }
+}
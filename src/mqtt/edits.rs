@@ -4,19 +4,28 @@
 //! and applies them to documents.
 //!
 //! IMPORTANT: The doc store does NOT re-emit edits. MQTT broker handles fanout.
+//!
+//! An edit's `author` is whatever the publisher wrote in the message, and the
+//! broker passes on no authenticated identity, so ownership can't be proven
+//! over MQTT. Edits are checked as anonymous, which only matters under the
+//! `warn` policy: `enforce` can't be combined with MQTT.
 
 use crate::commit::Commit;
 use crate::document::{resolve_path_to_uuid, DocumentStore};
+use crate::fs::{FilesystemReconciler, OwnershipGuard};
 use crate::mqtt::client::MqttClient;
-use crate::mqtt::messages::EditMessage;
+use crate::mqtt::heads::publish_head;
+use crate::mqtt::messages::{EditMessage, HeadMessage};
+use crate::mqtt::properties::{MessageProperties, TRACE_ID_PROPERTY};
+use crate::mqtt::store_api::publish_commit;
 use crate::mqtt::topics::{content_type_for_path, Topic};
 use crate::mqtt::MqttError;
 use crate::store::CommitStore;
 use rumqttc::QoS;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// Handler for the edits port.
 pub struct EditsHandler {
@@ -27,7 +36,14 @@ pub struct EditsHandler {
     fs_root_content: RwLock<String>,
     /// The fs-root path, so we can refresh the cache when it's edited.
     fs_root_path: RwLock<Option<String>>,
+    /// Paths of documents anywhere in the tree (including node-backed
+    /// subdirectories), mapped to their document IDs.
+    doc_paths: RwLock<HashMap<String, String>>,
+    /// Reconciler to run when the fs-root is edited (if configured).
+    reconciler: RwLock<Option<Arc<FilesystemReconciler>>>,
     subscribed_paths: RwLock<HashSet<String>>,
+    /// Single-writer ownership checks (if configured).
+    ownership: RwLock<Option<Arc<OwnershipGuard>>>,
 }
 
 impl EditsHandler {
@@ -43,10 +59,19 @@ impl EditsHandler {
             commit_store,
             fs_root_content: RwLock::new(String::new()),
             fs_root_path: RwLock::new(None),
+            doc_paths: RwLock::new(HashMap::new()),
+            reconciler: RwLock::new(None),
             subscribed_paths: RwLock::new(HashSet::new()),
+            ownership: RwLock::new(None),
         }
     }
 
+    /// Check edits against file ownership declared in `processes.json`.
+    pub async fn set_ownership(&self, guard: Arc<OwnershipGuard>) {
+        let mut ownership = self.ownership.write().await;
+        *ownership = Some(guard);
+    }
+
     /// Update the cached fs-root content for path resolution.
     pub async fn set_fs_root_content(&self, content: String) {
         let mut fs_root = self.fs_root_content.write().await;
@@ -59,6 +84,18 @@ impl EditsHandler {
         *fs_root_path = Some(path);
     }
 
+    /// Update the cached path→document ID map for the whole tree.
+    pub async fn set_doc_paths(&self, paths: HashMap<String, String>) {
+        let mut doc_paths = self.doc_paths.write().await;
+        *doc_paths = paths;
+    }
+
+    /// Reconcile the filesystem after edits to the fs-root.
+    pub async fn set_reconciler(&self, reconciler: Arc<FilesystemReconciler>) {
+        let mut current = self.reconciler.write().await;
+        *current = Some(reconciler);
+    }
+
     /// Subscribe to edits for a path.
     pub async fn subscribe_path(&self, path: &str) -> Result<(), MqttError> {
         let topic = Topic::edits(path);
@@ -92,18 +129,30 @@ impl EditsHandler {
     ///
     /// This:
     /// 1. Parses the EditMessage
-    /// 2. Creates a Commit and stores it (if commit_store is available)
+    /// 2. Creates a Commit and stores it (if commit_store is available),
+    ///    announcing the new head on `{path}/head`
     /// 3. Applies the Yjs update to the Document via DocumentStore
     ///
     /// IMPORTANT: Does NOT re-emit. MQTT broker handles fanout.
-    pub async fn handle_edit(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+    ///
+    /// A `trace-id` user property (MQTT 5) is carried over to the commit's
+    /// `$store/commits` notice.
+    pub async fn handle_edit(
+        &self,
+        topic: &Topic,
+        payload: &[u8],
+        properties: &MessageProperties,
+    ) -> Result<(), MqttError> {
         // Parse the edit message
         let edit_msg: EditMessage = serde_json::from_slice(payload)
             .map_err(|e| MqttError::InvalidMessage(e.to_string()))?;
+        let trace_id = properties.user_property(TRACE_ID_PROPERTY);
 
         debug!(
-            "Received edit for path: {} from author: {}",
-            topic.path, edit_msg.author
+            "Received edit for path: {} from author: {} (trace: {})",
+            topic.path,
+            edit_msg.author,
+            trace_id.unwrap_or("none")
         );
 
         // Check if this is an fs-root edit (special case: path IS the document ID)
@@ -120,17 +169,25 @@ impl EditsHandler {
         } else {
             let fs_root = self.fs_root_content.read().await;
             let fs_root_id = fs_root_id.as_deref().unwrap_or("");
-            let uuid =
-                resolve_path_to_uuid(&fs_root, &topic.path, fs_root_id).ok_or_else(|| {
-                    MqttError::InvalidMessage(format!(
-                        "Path not mounted in fs-root: {}",
-                        topic.path
-                    ))
-                })?;
+            let uuid = match resolve_path_to_uuid(&fs_root, &topic.path, fs_root_id) {
+                Some(uuid) => Some(uuid),
+                None => self.doc_paths.read().await.get(&topic.path).cloned(),
+            };
             drop(fs_root);
-            uuid
+            uuid.ok_or_else(|| {
+                MqttError::InvalidMessage(format!("Path not mounted in fs-root: {}", topic.path))
+            })?
         };
 
+        // The claimed author is unauthenticated, so check as anonymous (and
+        // with no admin override)
+        if let Some(guard) = self.ownership.read().await.as_ref() {
+            guard
+                .check_path(&topic.path, None, false)
+                .await
+                .map_err(|v| MqttError::InvalidMessage(v.to_string()))?;
+        }
+
         // Determine parents: use provided parents, or infer from current HEAD if empty
         let parents = if edit_msg.parents.is_empty() {
             // No parents provided - infer from current document HEAD
@@ -165,6 +222,15 @@ impl EditsHandler {
             store.set_document_head(&document_id, &cid).await?;
 
             debug!("Stored commit {} for document {}", cid, document_id);
+
+            let head = HeadMessage {
+                cid: cid.clone(),
+                timestamp: commit.timestamp,
+                author: commit.author.clone(),
+            };
+            if let Err(e) = publish_head(&self.client, &topic.path, &head).await {
+                warn!("Failed to announce head for {}: {}", topic.path, e);
+            }
             Some(cid)
         } else {
             None
@@ -188,19 +254,39 @@ impl EditsHandler {
                 MqttError::InvalidMessage(format!("Failed to apply Yjs update: {:?}", e))
             })?;
 
+        if let Some(guard) = self.ownership.read().await.as_ref() {
+            guard.invalidate(&document_id);
+        }
+
         debug!(
             "Applied edit to document {} (path: {}, cid: {:?})",
             document_id,
             topic.path,
             cid.as_deref().unwrap_or("none")
         );
+        if let Some(cid) = &cid {
+            if let Err(e) = publish_commit(&self.client, &document_id, cid, &commit, trace_id).await
+            {
+                warn!("Failed to publish commit {}: {}", cid, e);
+            }
+        }
 
         // If this was an fs-root edit, refresh the fs-root content cache
         if is_fs_root_edit {
             if let Some(doc) = self.document_store.get_document(&document_id).await {
                 let mut fs_root = self.fs_root_content.write().await;
                 *fs_root = doc.content.clone();
+                drop(fs_root);
                 debug!("Refreshed fs-root content cache after edit");
+
+                // Create documents for new entries; watchers of the
+                // reconciler then pick up the new paths
+                let reconciler = self.reconciler.read().await.clone();
+                if let Some(reconciler) = reconciler {
+                    if let Err(e) = reconciler.reconcile(&doc.content).await {
+                        warn!("Filesystem reconciliation failed: {}", e);
+                    }
+                }
             }
         }
 
@@ -0,0 +1,354 @@
+//! Recording of events-port traffic.
+//!
+//! Events on `{path}/events/{name}` are fire-and-forget. For paths configured
+//! for recording, the store appends each event to a companion JSONL document
+//! at `{path}.events.jsonl`, mounted next to the file like any other, so the
+//! history can be queried and synced. Old entries are dropped according to
+//! an [`EventRetention`] policy.
+//!
+//! Events are queued and written by a separate task, each batch appended to
+//! its log as one incremental edit, so a busy events topic doesn't hold up
+//! the rest of MQTT handling.
+
+use crate::document::{ContentType, DocumentStore};
+use crate::fs::{mount_doc, DocMount};
+use crate::mqtt::messages::EventMessage;
+use crate::mqtt::topics::Topic;
+use crate::mqtt::MqttError;
+use crate::services::{DocumentService, ServiceError};
+use crate::sync::yjs::{base64_encode, create_yjs_jsonl_append_update};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, warn};
+
+/// Author recorded on event log commits.
+const EVENT_LOG_AUTHOR: &str = "commonplace-events";
+
+/// Default number of events kept per log.
+pub const DEFAULT_MAX_EVENTS: usize = 1000;
+
+/// How many recorded events to keep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRetention {
+    /// Keep at most this many events (oldest dropped first)
+    pub max_entries: Option<usize>,
+    /// Drop events older than this
+    pub max_age: Option<Duration>,
+}
+
+impl Default for EventRetention {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(DEFAULT_MAX_EVENTS),
+            max_age: None,
+        }
+    }
+}
+
+/// Which paths to record events for, and for how long.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventLogConfig {
+    /// Document paths whose events are recorded
+    pub paths: Vec<String>,
+    /// Retention applied to every log
+    pub retention: EventRetention,
+}
+
+/// One line of an event log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventRecord {
+    /// When the store received the event, in milliseconds since Unix epoch
+    pub timestamp: u64,
+    /// Event name (the topic qualifier)
+    pub name: String,
+    /// Source node, if the event carried one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Event payload
+    pub payload: serde_json::Value,
+}
+
+/// Path of the event log for a document path.
+pub fn event_log_path(path: &str) -> String {
+    format!("{}.events.jsonl", path)
+}
+
+/// Events queued for the writer before new ones are dropped.
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// Queues events for configured paths to be appended to their JSONL logs.
+///
+/// Appending happens on a separate task, so handling an event never waits
+/// for the log to be written.
+pub struct EventLog {
+    paths: Vec<String>,
+    queue: mpsc::Sender<(String, EventRecord)>,
+}
+
+impl EventLog {
+    /// Create an event log, spawning the task that writes it.
+    pub fn new(
+        service: Arc<DocumentService>,
+        document_store: Arc<DocumentStore>,
+        fs_root: String,
+        config: EventLogConfig,
+    ) -> Self {
+        let (queue, rx) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let writer = LogWriter {
+            service,
+            document_store,
+            fs_root,
+            retention: config.retention,
+        };
+        tokio::spawn(writer.run(rx));
+        Self {
+            paths: config.paths,
+            queue,
+        }
+    }
+
+    /// Paths whose events are recorded.
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Whether events for `path` are recorded.
+    pub fn records(&self, path: &str) -> bool {
+        self.paths.iter().any(|p| p == path)
+    }
+
+    /// Queue an event received on `topic` for its path's log.
+    ///
+    /// If the writer has fallen too far behind, the event is dropped.
+    pub fn record(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+        if !self.records(&topic.path) || topic.is_manifest() || topic.is_reply() {
+            return Ok(());
+        }
+        let name = topic.qualifier.clone().unwrap_or_default();
+
+        // Events normally carry {payload, source}; keep anything else verbatim
+        let (payload, source) = match serde_json::from_slice::<EventMessage>(payload) {
+            Ok(event) => (event.payload, Some(event.source)),
+            Err(_) => match serde_json::from_slice(payload) {
+                Ok(value) => (value, None),
+                Err(_) => (
+                    serde_json::Value::String(String::from_utf8_lossy(payload).into_owned()),
+                    None,
+                ),
+            },
+        };
+        let record = EventRecord {
+            timestamp: now_millis(),
+            name,
+            source,
+            payload,
+        };
+
+        match self.queue.try_send((topic.path.clone(), record)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                warn!("Event log queue full; dropping event for {}", topic.path);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => {
+                Err(MqttError::Node("Event log writer stopped".to_string()))
+            }
+        }
+    }
+}
+
+/// Appends queued events to the logs, one at a time.
+struct LogWriter {
+    service: Arc<DocumentService>,
+    document_store: Arc<DocumentStore>,
+    fs_root: String,
+    retention: EventRetention,
+}
+
+impl LogWriter {
+    async fn run(self, mut queue: mpsc::Receiver<(String, EventRecord)>) {
+        while let Some(first) = queue.recv().await {
+            // Anything queued meanwhile goes into the same edit of its log
+            let mut batches: Vec<(String, Vec<EventRecord>)> = Vec::new();
+            let mut next = Some(first);
+            while let Some((path, record)) = next {
+                match batches.iter_mut().find(|(p, _)| *p == path) {
+                    Some((_, records)) => records.push(record),
+                    None => batches.push((path, vec![record])),
+                }
+                next = queue.try_recv().ok();
+            }
+
+            for (path, records) in batches {
+                let count = records.len();
+                match self.append(&path, records).await {
+                    Ok(()) => debug!("Recorded {} event(s) for {}", count, path),
+                    Err(e) => warn!("Failed to record events for {}: {}", path, e),
+                }
+            }
+        }
+    }
+
+    /// Append `records` to the log for `path` as a single edit.
+    async fn append(&self, path: &str, mut records: Vec<EventRecord>) -> Result<(), MqttError> {
+        if let Some(max_entries) = self.retention.max_entries {
+            let excess = records.len().saturating_sub(max_entries);
+            records.drain(..excess);
+        }
+
+        let log_id = self.log_document(path).await?;
+        let content = self
+            .document_store
+            .get_document(&log_id)
+            .await
+            .map(|doc| doc.content)
+            .unwrap_or_default();
+        let expired = expired_entries(&content, records.len(), &self.retention, now_millis());
+        let state = self
+            .document_store
+            .get_yjs_state(&log_id)
+            .await
+            .map(|state| base64_encode(&state));
+        let lines = records
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let update = create_yjs_jsonl_append_update(state.as_deref(), &expired, lines)
+            .map_err(|e| MqttError::Node(e.to_string()))?;
+
+        self.service
+            .edit_document(&log_id, &update, Some(EVENT_LOG_AUTHOR.to_string()), None)
+            .await
+            .map_err(service_error)?;
+        Ok(())
+    }
+
+    /// Find the log document for `path`, mounting it if needed.
+    async fn log_document(&self, path: &str) -> Result<String, MqttError> {
+        let log_path = event_log_path(path);
+        let node_id = match mount_doc(
+            &self.document_store,
+            &self.fs_root,
+            &log_path,
+            ContentType::Jsonl,
+        )
+        .await
+        .map_err(|e| MqttError::Node(e.to_string()))?
+        {
+            DocMount::Existing(node_id) => node_id,
+            DocMount::New {
+                schema_doc,
+                schema,
+                node_id,
+            } => {
+                self.service
+                    .replace_content(
+                        &schema_doc,
+                        &schema,
+                        None,
+                        Some(EVENT_LOG_AUTHOR.to_string()),
+                    )
+                    .await
+                    .map_err(service_error)?;
+                tracing::info!("Created event log {} ({})", log_path, node_id);
+                node_id
+            }
+        };
+
+        self.document_store
+            .get_or_create_with_id(&node_id, ContentType::Jsonl)
+            .await;
+        Ok(node_id)
+    }
+}
+
+/// Indices of the lines of JSONL `content` to drop so that, with `adding`
+/// more records appended, the log satisfies the retention policy.
+fn expired_entries(content: &str, adding: usize, retention: &EventRetention, now: u64) -> Vec<u32> {
+    let lines: Vec<&str> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let mut expired = vec![false; lines.len()];
+
+    if let Some(max_age) = retention.max_age {
+        let cutoff = now.saturating_sub(max_age.as_millis() as u64);
+        // Lines that aren't records (hand-edited) are kept
+        for (line, expired) in lines.iter().zip(expired.iter_mut()) {
+            *expired = serde_json::from_str::<EventRecord>(line)
+                .map(|r| r.timestamp < cutoff)
+                .unwrap_or(false);
+        }
+    }
+    if let Some(max_entries) = retention.max_entries {
+        let kept = expired.iter().filter(|e| !**e).count();
+        let mut excess = (kept + adding).saturating_sub(max_entries);
+        for expired in expired.iter_mut().filter(|e| !**e) {
+            if excess == 0 {
+                break;
+            }
+            *expired = true;
+            excess -= 1;
+        }
+    }
+
+    (0..lines.len() as u32)
+        .filter(|&i| expired[i as usize])
+        .collect()
+}
+
+fn service_error(e: ServiceError) -> MqttError {
+    MqttError::Node(format!("{:?}", e))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64, name: &str) -> EventRecord {
+        EventRecord {
+            timestamp,
+            name: name.to_string(),
+            source: None,
+            payload: serde_json::json!({}),
+        }
+    }
+
+    fn log(records: &[EventRecord]) -> String {
+        records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn test_expired_keeps_max_entries() {
+        let retention = EventRetention {
+            max_entries: Some(2),
+            max_age: None,
+        };
+        let content = log(&[record(0, "a"), record(1, "b")]);
+        assert_eq!(expired_entries(&content, 1, &retention, 10), vec![0]);
+        assert_eq!(expired_entries(&content, 2, &retention, 10), vec![0, 1]);
+        assert!(expired_entries(&content, 0, &retention, 10).is_empty());
+    }
+
+    #[test]
+    fn test_expired_drops_old_entries() {
+        let retention = EventRetention {
+            max_entries: None,
+            max_age: Some(Duration::from_secs(1)),
+        };
+        let content = log(&[record(1_000, "old"), record(4_500, "new")]) + "\"hand-edited\"\n";
+        assert_eq!(expired_entries(&content, 1, &retention, 5_000), vec![0]);
+    }
+}
@@ -1,24 +1,78 @@
 //! Events port handler.
 //!
-//! Publishes events from nodes to `{path}/events/{event-name}` topics.
+//! Publishes events from nodes to `{path}/events/{event-name}` topics, and
+//! records incoming events for paths configured with an [`EventLog`].
 
 use crate::mqtt::client::MqttClient;
+use crate::mqtt::event_log::EventLog;
 use crate::mqtt::messages::EventMessage;
 use crate::mqtt::topics::Topic;
 use crate::mqtt::MqttError;
 use rumqttc::QoS;
 use std::sync::Arc;
+use tokio::sync::RwLock;
 use tracing::debug;
 
 /// Handler for the events port.
 pub struct EventsHandler {
     client: Arc<MqttClient>,
+    event_log: RwLock<Option<Arc<EventLog>>>,
 }
 
 impl EventsHandler {
     /// Create a new events handler.
     pub fn new(client: Arc<MqttClient>) -> Self {
-        Self { client }
+        Self {
+            client,
+            event_log: RwLock::new(None),
+        }
+    }
+
+    /// Record events for the log's paths, subscribing to their events topics.
+    pub async fn set_event_log(&self, event_log: Arc<EventLog>) -> Result<(), MqttError> {
+        for path in event_log.paths() {
+            self.subscribe_events(path).await?;
+        }
+        *self.event_log.write().await = Some(event_log);
+        Ok(())
+    }
+
+    /// Subscribe to all events for a path (`{path}/events/#`).
+    pub async fn subscribe_events(&self, path: &str) -> Result<(), MqttError> {
+        let topic = Topic::events_wildcard(path);
+        self.client.subscribe(&topic, QoS::AtMostOnce).await?;
+        debug!("Subscribed to events: {}", topic);
+        Ok(())
+    }
+
+    /// Unsubscribe from all events for a path.
+    pub async fn unsubscribe_events(&self, path: &str) -> Result<(), MqttError> {
+        let topic = Topic::events_wildcard(path);
+        self.client.unsubscribe(&topic).await?;
+        debug!("Unsubscribed from events: {}", topic);
+        Ok(())
+    }
+
+    /// Whether events for `path` are recorded to an event log.
+    pub async fn records(&self, path: &str) -> bool {
+        self.event_log
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|event_log| event_log.records(path))
+    }
+
+    /// Handle an incoming event, queuing it for the event log if its path is
+    /// logged.
+    pub async fn handle_event(&self, topic: &Topic, payload: &[u8]) -> Result<(), MqttError> {
+        let event_log = self.event_log.read().await.clone();
+        match event_log {
+            Some(event_log) => event_log.record(topic, payload),
+            None => {
+                debug!("Ignoring event on {}", topic.to_topic_string());
+                Ok(())
+            }
+        }
     }
 
     /// Publish an event from a node.
@@ -0,0 +1,116 @@
+//! Retained head announcements.
+//!
+//! After every commit the store publishes a retained [`HeadMessage`] to
+//! `{path}/head`, so late subscribers learn a document's head without a
+//! sync round trip.
+
+use crate::document::DocumentStore;
+use crate::events::CommitNotification;
+use crate::fs::find_doc_paths;
+use crate::mqtt::client::MqttClient;
+use crate::mqtt::messages::HeadMessage;
+use crate::mqtt::topics::Topic;
+use crate::mqtt::MqttError;
+use crate::store::CommitStore;
+use rumqttc::QoS;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+/// Publish a retained head announcement for a path.
+pub async fn publish_head(
+    client: &MqttClient,
+    path: &str,
+    head: &HeadMessage,
+) -> Result<(), MqttError> {
+    let payload = serde_json::to_vec(head)?;
+    client
+        .publish_retained(&Topic::head_topic_str(path), &payload, QoS::AtLeastOnce)
+        .await?;
+    debug!("Announced head {} for path: {}", head.cid, path);
+    Ok(())
+}
+
+/// Announces heads for commits made outside MQTT (HTTP, WebSocket).
+///
+/// Documents are announced at every path they're mounted at in the fs-root
+/// tree; the fs-root itself is announced under its ID, like its edits topic.
+pub struct HeadAnnouncer {
+    client: Arc<MqttClient>,
+    document_store: Arc<DocumentStore>,
+    commit_store: Arc<CommitStore>,
+    fs_root: String,
+}
+
+impl HeadAnnouncer {
+    pub fn new(
+        client: Arc<MqttClient>,
+        document_store: Arc<DocumentStore>,
+        commit_store: Arc<CommitStore>,
+        fs_root: String,
+    ) -> Self {
+        Self {
+            client,
+            document_store,
+            commit_store,
+            fs_root,
+        }
+    }
+
+    /// Announce heads for commit notifications until the channel closes.
+    pub async fn run(&self, mut commits: broadcast::Receiver<CommitNotification>) {
+        // Last head announced per document, to skip intermediate commits
+        // (an edit followed by its merge) and repeats
+        let mut announced: HashMap<String, String> = HashMap::new();
+        loop {
+            let notification = match commits.recv().await {
+                Ok(notification) => notification,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Head announcer lagged by {} commits", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let doc_id = notification.doc_id;
+            let head = match self.commit_store.get_document_head(&doc_id).await {
+                Ok(Some(head)) => head,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to read head of {}: {}", doc_id, e);
+                    continue;
+                }
+            };
+            if announced.get(&doc_id) == Some(&head) {
+                continue;
+            }
+            if let Err(e) = self.announce(&doc_id, &head).await {
+                warn!("Failed to announce head of {}: {}", doc_id, e);
+                continue;
+            }
+            announced.insert(doc_id, head);
+        }
+    }
+
+    /// Publish the head of `doc_id` at each of its paths.
+    async fn announce(&self, doc_id: &str, cid: &str) -> Result<(), MqttError> {
+        let commit = self.commit_store.get_commit(cid).await?;
+        let head = HeadMessage {
+            cid: cid.to_string(),
+            timestamp: commit.timestamp,
+            author: commit.author,
+        };
+
+        for path in find_doc_paths(&self.document_store, &self.fs_root, doc_id).await {
+            let path = if path.is_empty() {
+                self.fs_root.as_str()
+            } else {
+                path.as_str()
+            };
+            publish_head(&self.client, path, &head).await?;
+        }
+        Ok(())
+    }
+}
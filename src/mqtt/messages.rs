@@ -7,6 +7,9 @@
 //! - Commands: Commands to nodes
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::bloom::HaveFilter;
 
 /// Message published to the edits port.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +36,28 @@ pub struct EventMessage {
     pub source: String,
 }
 
+/// Retained online/offline status of a client, published to its status
+/// topic on connect and set as its last will.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusMessage {
+    /// Whether the client is connected
+    pub online: bool,
+    /// Client ID
+    pub client_id: String,
+}
+
+/// Retained announcement of a document's head, published to `{path}/head`
+/// after every commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeadMessage {
+    /// Head commit ID
+    pub cid: String,
+    /// Commit timestamp in milliseconds since Unix epoch
+    pub timestamp: u64,
+    /// Commit author
+    pub author: String,
+}
+
 /// Message received on the commands port.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandMessage {
@@ -41,6 +66,114 @@ pub struct CommandMessage {
     /// Optional source identifier
     #[serde(skip_serializing_if = "Option::is_none")]
     pub source: Option<String>,
+    /// Topic the handler should publish a [`CommandReply`] to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Request ID for correlating the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub req: Option<String>,
+    /// Bearer token of the caller, checked by the store API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Reply from a command handler, published to the command's `reply_to`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandReply {
+    /// Request ID of the command being answered
+    pub req: String,
+    /// Handler result (present on success)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Error message (present on failure)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Machine-readable error kind, e.g. `not_found` (see
+    /// [`crate::mqtt::store_api::codes`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Identifier of the replying node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// A commit made by the store, published to `$store/commits`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoreCommit {
+    /// Document the commit belongs to
+    pub doc_id: String,
+    /// Commit ID
+    pub cid: String,
+    /// Parent commit IDs
+    pub parents: Vec<String>,
+    /// Base64-encoded Yjs update
+    pub update: String,
+    /// Timestamp in milliseconds since Unix epoch
+    pub timestamp: u64,
+    /// Author
+    pub author: String,
+    /// Optional commit message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Retained manifest of the commands a node accepts.
+///
+/// Published by the node that owns a path to `{path}/events/manifest`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandManifest {
+    /// Accepted verbs
+    #[serde(default)]
+    pub commands: BTreeMap<String, CommandSpec>,
+    /// Identifier of the publishing node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Description of one command verb.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandSpec {
+    /// Human-readable description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema for the command payload (any payload if absent)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+}
+
+impl CommandManifest {
+    /// Check that `verb` is accepted and `payload` matches its schema.
+    pub fn validate(&self, verb: &str, payload: &serde_json::Value) -> Result<(), String> {
+        let spec = self
+            .commands
+            .get(verb)
+            .ok_or_else(|| format!("Unknown command: {}", verb))?;
+        let Some(schema) = &spec.schema else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| format!("Invalid schema for '{}': {}", verb, e))?;
+        let errors: Vec<String> = validator
+            .iter_errors(payload)
+            .map(|e| {
+                let path = e.instance_path().to_string();
+                if path.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{}: {}", path, e)
+                }
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid payload for '{}': {}",
+                verb,
+                errors.join("; ")
+            ))
+        }
+    }
 }
 
 /// Sync protocol messages.
@@ -98,6 +231,24 @@ pub enum SyncMessage {
         depth: Option<u32>,
     },
 
+    /// Have/want negotiation: stream exactly the commits the client lacks
+    Negotiate {
+        /// Request ID for correlation
+        req: String,
+        /// Target commit (or "HEAD")
+        #[serde(default = "default_want")]
+        want: String,
+        /// Commit IDs the client already has
+        #[serde(default)]
+        have: Vec<String>,
+        /// Bloom filter of further commit IDs the client has
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bloom: Option<HaveFilter>,
+        /// Send a snapshot instead when more than this many commits are missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snapshot_after: Option<usize>,
+    },
+
     // ========== Responses (from doc store) ==========
     /// Commit data
     Commit {
@@ -118,6 +269,18 @@ pub enum SyncMessage {
         message: Option<String>,
     },
 
+    /// Compacted state at a commit, sent instead of a long run of commits
+    Snapshot {
+        /// Request ID for correlation
+        req: String,
+        /// Commit the state corresponds to
+        commit: String,
+        /// Base64-encoded Yjs state
+        data: String,
+        /// Number of commits the snapshot stands in for
+        missing: usize,
+    },
+
     /// All requested commits have been sent
     Done {
         /// Request ID for correlation
@@ -135,6 +298,10 @@ pub enum SyncMessage {
     },
 }
 
+fn default_want() -> String {
+    "HEAD".to_string()
+}
+
 /// Request to create a new document.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateDocumentRequest {
@@ -166,7 +333,9 @@ impl SyncMessage {
             SyncMessage::Get { req, .. } => req,
             SyncMessage::Pull { req, .. } => req,
             SyncMessage::Ancestors { req, .. } => req,
+            SyncMessage::Negotiate { req, .. } => req,
             SyncMessage::Commit { req, .. } => req,
+            SyncMessage::Snapshot { req, .. } => req,
             SyncMessage::Done { req, .. } => req,
             SyncMessage::Error { req, .. } => req,
         }
@@ -180,6 +349,7 @@ impl SyncMessage {
                 | SyncMessage::Get { .. }
                 | SyncMessage::Pull { .. }
                 | SyncMessage::Ancestors { .. }
+                | SyncMessage::Negotiate { .. }
         )
     }
 
@@ -189,6 +359,7 @@ impl SyncMessage {
             self,
             SyncMessage::HeadResponse { .. }
                 | SyncMessage::Commit { .. }
+                | SyncMessage::Snapshot { .. }
                 | SyncMessage::Done { .. }
                 | SyncMessage::Error { .. }
         )
@@ -199,6 +370,62 @@ impl SyncMessage {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_command_message_reply_fields_optional() {
+        let msg: CommandMessage = serde_json::from_str(r#"{"payload":{}}"#).unwrap();
+        assert_eq!(msg.reply_to, None);
+        assert_eq!(msg.req, None);
+
+        let msg = CommandMessage {
+            payload: serde_json::json!({"amount": 5}),
+            source: None,
+            reply_to: Some("counter.json/events/reply/r-1".to_string()),
+            req: Some("r-1".to_string()),
+            token: None,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("\"reply_to\":\"counter.json/events/reply/r-1\""));
+        assert!(json.contains("\"req\":\"r-1\""));
+        assert!(!json.contains("\"source\""));
+    }
+
+    #[test]
+    fn test_command_manifest_validate() {
+        let manifest: CommandManifest = serde_json::from_str(
+            r#"{"commands":{
+                "increment":{"description":"Add to the counter","schema":{
+                    "type":"object",
+                    "properties":{"amount":{"type":"integer"}},
+                    "additionalProperties":false}},
+                "reset":{}}}"#,
+        )
+        .unwrap();
+
+        assert!(manifest
+            .validate("increment", &serde_json::json!({"amount": 5}))
+            .is_ok());
+        assert!(manifest
+            .validate("reset", &serde_json::json!("anything"))
+            .is_ok());
+
+        let err = manifest
+            .validate("increment", &serde_json::json!({"amount": "five"}))
+            .unwrap_err();
+        assert!(err.contains("/amount"), "{}", err);
+        assert!(manifest
+            .validate("decrement", &serde_json::json!({}))
+            .unwrap_err()
+            .contains("Unknown command"));
+    }
+
+    #[test]
+    fn test_command_reply_roundtrip() {
+        let reply: CommandReply =
+            serde_json::from_str(r#"{"req":"r-1","result":{"value":3}}"#).unwrap();
+        assert_eq!(reply.result, Some(serde_json::json!({"value": 3})));
+        assert_eq!(reply.error, None);
+    }
+
     #[test]
     fn test_edit_message_serialize() {
         let msg = EditMessage {
@@ -269,6 +496,37 @@ mod tests {
         assert!(json.contains("\"want\":\"HEAD\""));
     }
 
+    #[test]
+    fn test_sync_negotiate_defaults() {
+        let msg: SyncMessage =
+            serde_json::from_str(r#"{"type":"negotiate","req":"r-9","have":["abc"]}"#).unwrap();
+        match msg {
+            SyncMessage::Negotiate {
+                want,
+                have,
+                bloom,
+                snapshot_after,
+                ..
+            } => {
+                assert_eq!(want, "HEAD");
+                assert_eq!(have, vec!["abc".to_string()]);
+                assert!(bloom.is_none());
+                assert!(snapshot_after.is_none());
+            }
+            other => panic!("Expected Negotiate, got {:?}", other),
+        }
+
+        let snapshot = SyncMessage::Snapshot {
+            req: "r-9".to_string(),
+            commit: "abc".to_string(),
+            data: "AAA=".to_string(),
+            missing: 500,
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(json.contains("\"type\":\"snapshot\""));
+        assert!(snapshot.is_response());
+    }
+
     #[test]
     fn test_sync_commit_response() {
         let msg = SyncMessage::Commit {
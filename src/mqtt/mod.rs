@@ -7,25 +7,51 @@
 //! - `events`: Node broadcasts
 //! - `commands`: Commands to nodes
 
+pub mod bloom;
+pub mod bridges;
+pub mod broker;
 pub mod client;
 pub mod commands;
 pub mod edits;
+pub mod event_log;
 pub mod events;
+pub mod heads;
 pub mod messages;
+pub mod outbox;
+pub mod properties;
+pub mod store_api;
 pub mod sync;
 pub mod topics;
 
+use crate::auth::{AuthState, Authenticator};
 use crate::document::DocumentStore;
+use crate::events::CommitNotification;
+use crate::fs::{walk_tree, FilesystemReconciler};
+use crate::services::DocumentService;
 use crate::store::CommitStore;
 use rumqttc::QoS;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-
-pub use client::MqttClient;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::RwLock;
+
+pub use bloom::HaveFilter;
+pub use bridges::{Bridges, BridgesConfig, BRIDGES_FILE};
+pub use broker::{AllowAll, BrokerAcl, BrokerAction, EmbeddedBroker};
+pub use client::{mqtt_options, MqttClient};
+pub use commands::{fetch_manifest, request_command, serve_commands};
+pub use event_log::{event_log_path, EventLog, EventLogConfig, EventRetention};
+pub use heads::{publish_head, HeadAnnouncer};
 pub use messages::{
-    CommandMessage, CreateDocumentRequest, CreateDocumentResponse, EditMessage, EventMessage,
+    CommandManifest, CommandMessage, CommandReply, CommandSpec, CreateDocumentRequest,
+    CreateDocumentResponse, EditMessage, EventMessage, HeadMessage, StatusMessage, StoreCommit,
     SyncMessage,
 };
+pub use properties::{MessageProperties, AUTHOR_PROPERTY, TRACE_ID_PROPERTY};
+pub use store_api::{store_command, CommitFeed, StoreApi, STORE_COMMITS};
 pub use topics::{Port, Topic};
 
 /// Errors that can occur in MQTT operations.
@@ -55,6 +81,9 @@ pub enum MqttError {
     #[error("Node error: {0}")]
     Node(String),
 
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 }
@@ -62,7 +91,7 @@ pub enum MqttError {
 /// Configuration for MQTT connection.
 #[derive(Debug, Clone)]
 pub struct MqttConfig {
-    /// MQTT broker URL (e.g., "mqtt://localhost:1883")
+    /// MQTT broker URL (e.g., "mqtt://localhost:1883", or "mqtts://" for TLS)
     pub broker_url: String,
     /// Client ID for this doc store instance
     pub client_id: String,
@@ -70,6 +99,21 @@ pub struct MqttConfig {
     pub keep_alive_secs: u64,
     /// Whether to use clean session
     pub clean_session: bool,
+    /// Username (defaults to the client ID when only a password is set)
+    pub username: Option<String>,
+    /// Password, e.g. a macaroon (see docs/MACAROONS.md)
+    pub password: Option<String>,
+    /// TLS settings; TLS is also enabled by an `mqtts://` URL
+    pub tls: Option<MqttTls>,
+    /// Topic for retained online/offline status, set as the last will
+    pub status_topic: Option<String>,
+    /// How to back off between reconnection attempts
+    pub reconnect: ReconnectPolicy,
+    /// redb file for QoS 1 publishes made while disconnected (dropped or
+    /// held in memory if unset)
+    pub outbox: Option<PathBuf>,
+    /// MQTT protocol version to speak
+    pub protocol: MqttProtocol,
 }
 
 impl Default for MqttConfig {
@@ -79,10 +123,87 @@ impl Default for MqttConfig {
             client_id: uuid::Uuid::new_v4().to_string(),
             keep_alive_secs: 60,
             clean_session: true,
+            username: None,
+            password: None,
+            tls: None,
+            status_topic: None,
+            reconnect: ReconnectPolicy::default(),
+            outbox: None,
+            protocol: MqttProtocol::default(),
+        }
+    }
+}
+
+/// MQTT protocol version for the broker connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MqttProtocol {
+    /// MQTT 5, falling back to 3.1.1 if the broker rejects it
+    #[default]
+    Auto,
+    /// MQTT 5 only
+    V5,
+    /// MQTT 3.1.1 only
+    V311,
+}
+
+impl std::str::FromStr for MqttProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "5" | "5.0" => Ok(Self::V5),
+            "3" | "3.1.1" => Ok(Self::V311),
+            _ => Err(format!(
+                "unknown MQTT protocol '{}' (expected auto, 5 or 3.1.1)",
+                s
+            )),
+        }
+    }
+}
+
+/// TLS settings for the broker connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MqttTls {
+    /// PEM file of CA certificates to trust (platform roots if unset)
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate, for brokers that require client auth
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key for `client_cert`
+    pub client_key: Option<PathBuf>,
+}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Delay after the first failure
+    pub initial_delay: Duration,
+    /// Upper bound on the delay
+    pub max_delay: Duration,
+    /// Give up after this many consecutive failures (retry forever if unset)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
         }
     }
 }
 
+impl ReconnectPolicy {
+    /// Delay before retrying after `failures` consecutive failures (1-based).
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
 /// Main MQTT service that coordinates all port handlers.
 #[allow(dead_code)] // Fields used for future integration
 pub struct MqttService {
@@ -93,6 +214,15 @@ pub struct MqttService {
     sync_handler: sync::SyncHandler,
     events_handler: events::EventsHandler,
     commands_handler: commands::CommandsHandler,
+    /// Paths subscribed because they're in the fs-root tree (see
+    /// [`MqttService::follow_reconciler`]), as opposed to configured ones.
+    followed_paths: RwLock<HashSet<String>>,
+    /// Answers `$store/commands/{verb}` requests (see [`Self::serve_store_api`])
+    store_api: RwLock<Option<store_api::StoreApi>>,
+    /// Bridges declared in `bridges.json` files (see [`Self::enable_bridges`])
+    bridges: RwLock<Option<Arc<bridges::Bridges>>>,
+    /// Paths whose events are subscribed for bridges
+    bridged_events: RwLock<HashSet<String>>,
 }
 
 impl MqttService {
@@ -125,26 +255,132 @@ impl MqttService {
             sync_handler,
             events_handler,
             commands_handler,
+            followed_paths: RwLock::new(HashSet::new()),
+            store_api: RwLock::new(None),
+            bridges: RwLock::new(None),
+            bridged_events: RwLock::new(HashSet::new()),
         })
     }
 
     /// Subscribe to store-level commands.
-    /// This subscribes to the `$store/commands/create-document` topic.
+    /// This subscribes to `$store/commands/+`: create-document and the store
+    /// API verbs.
     pub async fn subscribe_store_commands(&self) -> Result<(), MqttError> {
         self.client
-            .subscribe(Self::STORE_COMMANDS_CREATE_DOCUMENT, QoS::AtLeastOnce)
+            .subscribe(store_api::STORE_COMMANDS_WILDCARD, QoS::AtLeastOnce)
             .await?;
         tracing::debug!(
             "Subscribed to store commands: {}",
-            Self::STORE_COMMANDS_CREATE_DOCUMENT
+            store_api::STORE_COMMANDS_WILDCARD
         );
         Ok(())
     }
 
-    /// Subscribe to edits for a path.
+    /// Answer store API commands (used by `commonplace-http`) with `service`.
+    ///
+    /// With an authenticator, each request must carry a token granting the
+    /// access its verb needs, and writes are made as the token's principal.
+    pub async fn serve_store_api(
+        &self,
+        service: Arc<DocumentService>,
+        fs_root: Option<String>,
+        auth: Option<Arc<dyn Authenticator>>,
+    ) {
+        let auth = auth.map(|authenticator| match fs_root {
+            Some(ref fs_root) => AuthState::new(authenticator)
+                .with_fs_root(self.document_store.clone(), fs_root.clone()),
+            None => AuthState::new(authenticator),
+        });
+        let api = store_api::StoreApi::new(
+            self.client.clone(),
+            service,
+            self.document_store.clone(),
+            self.commit_store.clone(),
+            fs_root,
+        );
+        *self.store_api.write().await = Some(match auth {
+            Some(auth) => api.with_auth(auth),
+            None => api,
+        });
+    }
+
+    /// Enforce the bridges declared in `bridges.json` files in the fs-root
+    /// tree, writing mirrored edits through `service`.
+    ///
+    /// `commits` (from the store's commit broadcaster) drives edits bridges,
+    /// in a background task. Bridges are reloaded when the tree or a bridges
+    /// file changes.
+    pub async fn enable_bridges(
+        self: Arc<Self>,
+        service: Arc<DocumentService>,
+        fs_root: String,
+        mut commits: broadcast::Receiver<CommitNotification>,
+    ) -> Result<(), MqttError> {
+        let bridges = Arc::new(bridges::Bridges::new(
+            self.client.clone(),
+            service,
+            self.document_store.clone(),
+            fs_root,
+        ));
+        *self.bridges.write().await = Some(bridges.clone());
+        self.refresh_bridges().await?;
+
+        tokio::spawn(async move {
+            loop {
+                let reload = match commits.recv().await {
+                    Ok(notification) => bridges.handle_commit(&notification.doc_id).await,
+                    Err(RecvError::Lagged(n)) => {
+                        // Catch up from current content rather than drop edits
+                        tracing::warn!("Bridges lagged by {} commits; resyncing", n);
+                        if let Err(e) = self.refresh_bridges().await {
+                            tracing::warn!("Failed to reload bridges: {}", e);
+                        }
+                        bridges.resync().await;
+                        false
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if reload {
+                    if let Err(e) = self.refresh_bridges().await {
+                        tracing::warn!("Failed to reload bridges: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Reload bridges and subscribe to the events they read.
+    async fn refresh_bridges(&self) -> Result<(), MqttError> {
+        let Some(bridges) = self.bridges.read().await.clone() else {
+            return Ok(());
+        };
+        let wanted = bridges.reload().await;
+
+        let mut subscribed = self.bridged_events.write().await;
+        let removed: Vec<String> = subscribed.difference(&wanted).cloned().collect();
+        for path in removed {
+            // The event log may still want them
+            if !self.events_handler.records(&path).await {
+                self.events_handler.unsubscribe_events(&path).await?;
+            }
+            subscribed.remove(&path);
+        }
+        for path in wanted {
+            if subscribed.contains(&path) || self.events_handler.records(&path).await {
+                continue;
+            }
+            self.events_handler.subscribe_events(&path).await?;
+            subscribed.insert(path);
+        }
+        Ok(())
+    }
+
+    /// Subscribe to edits, sync requests and commands for a path.
     pub async fn subscribe_path(&self, path: &str) -> Result<(), MqttError> {
         self.edits_handler.subscribe_path(path).await?;
         self.sync_handler.subscribe_path(path).await?;
+        self.commands_handler.subscribe_commands(path).await?;
         Ok(())
     }
 
@@ -152,9 +388,101 @@ impl MqttService {
     pub async fn unsubscribe_path(&self, path: &str) -> Result<(), MqttError> {
         self.edits_handler.unsubscribe_path(path).await?;
         self.sync_handler.unsubscribe_path(path).await?;
+        self.commands_handler.unsubscribe_commands(path).await?;
         Ok(())
     }
 
+    /// Bring path caches and subscriptions in line with the fs-root tree.
+    ///
+    /// Every document in the tree (and the fs-root itself) gets edits, sync
+    /// and commands subscriptions; documents no longer in the tree are
+    /// unsubscribed. Paths subscribed explicitly via [`Self::subscribe_path`]
+    /// are left alone.
+    pub async fn refresh_paths(&self, fs_root_id: &str) -> Result<(), MqttError> {
+        let content = self
+            .document_store
+            .get_document(fs_root_id)
+            .await
+            .map(|doc| doc.content)
+            .unwrap_or_default();
+        let doc_paths: HashMap<String, String> = walk_tree(&self.document_store, fs_root_id)
+            .await
+            .into_iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| {
+                let id = entry
+                    .node_id
+                    .unwrap_or_else(|| format!("{}:{}", fs_root_id, entry.path));
+                (entry.path, id)
+            })
+            .collect();
+
+        self.edits_handler
+            .set_fs_root_content(content.clone())
+            .await;
+        self.edits_handler.set_doc_paths(doc_paths.clone()).await;
+        self.sync_handler.set_fs_root_content(content).await;
+        self.sync_handler.set_doc_paths(doc_paths.clone()).await;
+
+        // Topics need an extension to parse, so other paths can't be served
+        let wanted: HashSet<String> = doc_paths
+            .into_keys()
+            .chain(std::iter::once(fs_root_id.to_string()))
+            .filter(|path| topics::validate_extension(path).is_ok())
+            .collect();
+
+        let mut followed = self.followed_paths.write().await;
+        let removed: Vec<String> = followed.difference(&wanted).cloned().collect();
+        for path in removed {
+            self.unsubscribe_path(&path).await?;
+            followed.remove(&path);
+            tracing::info!("MQTT unsubscribed from removed path: {}", path);
+        }
+        for path in wanted {
+            if followed.contains(&path) || self.edits_handler.is_subscribed(&path).await {
+                continue;
+            }
+            self.subscribe_path(&path).await?;
+            tracing::info!("MQTT subscribed to path: {}", path);
+            followed.insert(path);
+        }
+        drop(followed);
+
+        // Bridges files may have been added, moved or removed
+        self.refresh_bridges().await
+    }
+
+    /// Follow the fs-root tree: refresh paths now and again after every
+    /// reconciliation, in a background task.
+    pub async fn follow_reconciler(
+        self: Arc<Self>,
+        reconciler: Arc<FilesystemReconciler>,
+    ) -> tokio::task::JoinHandle<()> {
+        // Watch before the first refresh so no reconciliation is missed
+        let mut changes = reconciler.watch();
+        let fs_root_id = reconciler.fs_root_id().to_string();
+        self.edits_handler.set_reconciler(reconciler).await;
+        if let Err(e) = self.refresh_paths(&fs_root_id).await {
+            tracing::warn!("Failed to refresh MQTT paths: {}", e);
+        }
+
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                if let Err(e) = self.refresh_paths(&fs_root_id).await {
+                    tracing::warn!("Failed to refresh MQTT paths: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Record events for the paths configured in `event_log`.
+    pub async fn set_event_log(
+        &self,
+        event_log: Arc<event_log::EventLog>,
+    ) -> Result<(), MqttError> {
+        self.events_handler.set_event_log(event_log).await
+    }
+
     /// Run the MQTT service event loop.
     /// This processes incoming messages and dispatches them to handlers.
     pub async fn run(&self) -> Result<(), MqttError> {
@@ -173,7 +501,7 @@ impl MqttService {
         loop {
             match message_rx.recv().await {
                 Ok(msg) => {
-                    if let Err(e) = self.dispatch_message(&msg.topic, &msg.payload).await {
+                    if let Err(e) = self.dispatch_message(&msg).await {
                         tracing::warn!("Error dispatching MQTT message: {}", e);
                     }
                 }
@@ -191,11 +519,22 @@ impl MqttService {
     }
 
     /// Dispatch an incoming message to the appropriate handler.
-    async fn dispatch_message(&self, topic_str: &str, payload: &[u8]) -> Result<(), MqttError> {
+    async fn dispatch_message(&self, msg: &client::IncomingMessage) -> Result<(), MqttError> {
+        let (topic_str, payload, properties) =
+            (msg.topic.as_str(), &msg.payload[..], &msg.properties);
         // Check for store-level commands first (these don't follow the document path pattern)
         if topic_str == Self::STORE_COMMANDS_CREATE_DOCUMENT {
             return self.commands_handler.handle_create_document(payload).await;
         }
+        if let Some(verb) = topic_str.strip_prefix(store_api::STORE_COMMANDS_PREFIX) {
+            return match self.store_api.read().await.as_ref() {
+                Some(api) => api.handle(verb, payload, properties).await,
+                None => {
+                    tracing::debug!("Ignoring store command '{}': no store API", verb);
+                    Ok(())
+                }
+            };
+        }
 
         // Parse the topic
         let topic = match topics::Topic::parse(topic_str) {
@@ -208,9 +547,19 @@ impl MqttService {
 
         tracing::debug!("Dispatching message for topic: {:?}", topic);
 
+        if matches!(topic.port, topics::Port::Events | topics::Port::Commands) {
+            if let Some(bridges) = self.bridges.read().await.clone() {
+                if let Err(e) = bridges.forward(&topic, payload).await {
+                    tracing::warn!("Failed to bridge {}: {}", topic_str, e);
+                }
+            }
+        }
+
         match topic.port {
             topics::Port::Edits => {
-                self.edits_handler.handle_edit(&topic, payload).await?;
+                self.edits_handler
+                    .handle_edit(&topic, payload, properties)
+                    .await?;
             }
             topics::Port::Sync => {
                 // Parse sync message
@@ -218,18 +567,22 @@ impl MqttService {
                 // Only handle requests, not responses
                 if sync_msg.is_request() {
                     self.sync_handler
-                        .handle_sync_request(&topic, sync_msg)
+                        .handle_sync_request(&topic, sync_msg, properties)
                         .await?;
                 }
             }
             topics::Port::Commands => {
                 self.commands_handler
-                    .handle_command(&topic, payload)
+                    .handle_command(&topic, payload, properties)
+                    .await?;
+            }
+            topics::Port::Events if topic.is_manifest() => {
+                self.commands_handler
+                    .handle_manifest(&topic, payload)
                     .await?;
             }
             topics::Port::Events => {
-                // Events are outbound only from this doc store - we don't receive them
-                tracing::debug!("Ignoring incoming event message on {}", topic_str);
+                self.events_handler.handle_event(&topic, payload).await?;
             }
         }
 
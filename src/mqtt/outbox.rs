@@ -0,0 +1,164 @@
+//! Durable outbound queue for QoS 1 publishes.
+//!
+//! While the client is disconnected, QoS 1 publishes are appended to a small
+//! redb file instead of rumqttc's in-memory queue, so they survive broker
+//! outages and process restarts. The event loop drains the queue in order
+//! once the broker accepts the connection again.
+
+use crate::mqtt::MqttError;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+const OUTBOX_TABLE: TableDefinition<u64, &str> = TableDefinition::new("outbox");
+
+/// A publish waiting for the broker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedPublish {
+    pub topic: String,
+    /// Base64-encoded payload
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl QueuedPublish {
+    pub fn new(topic: &str, payload: &[u8], retain: bool) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload: crate::b64::encode(payload),
+            retain,
+        }
+    }
+
+    /// Decoded payload bytes.
+    pub fn payload_bytes(&self) -> Result<Vec<u8>, MqttError> {
+        crate::b64::decode(&self.payload)
+            .map_err(|e| MqttError::InvalidMessage(format!("Corrupt queued payload: {}", e)))
+    }
+}
+
+/// Publishes persisted in arrival order, keyed by sequence number.
+pub struct Outbox {
+    db: Database,
+}
+
+fn db_error(e: impl std::fmt::Display) -> MqttError {
+    MqttError::Publish(format!("Outbox error: {}", e))
+}
+
+impl Outbox {
+    /// Create or open an outbox at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, MqttError> {
+        let db = Database::create(path).map_err(db_error)?;
+        Ok(Self { db })
+    }
+
+    /// Append a publish to the end of the queue.
+    pub fn push(&self, publish: &QueuedPublish) -> Result<(), MqttError> {
+        let json = serde_json::to_string(publish)?;
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(OUTBOX_TABLE).map_err(db_error)?;
+            let next = match table.last().map_err(db_error)? {
+                Some((seq, _)) => seq.value() + 1,
+                None => 0,
+            };
+            table.insert(next, json.as_str()).map_err(db_error)?;
+        }
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    /// The oldest `limit` publishes, with their sequence numbers.
+    pub fn peek(&self, limit: usize) -> Result<Vec<(u64, QueuedPublish)>, MqttError> {
+        self.peek_from(0, limit)
+    }
+
+    /// The oldest `limit` publishes from sequence number `from` on.
+    pub fn peek_from(
+        &self,
+        from: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, QueuedPublish)>, MqttError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = match txn.open_table(OUTBOX_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(db_error(e)),
+        };
+        let mut found = Vec::new();
+        for entry in table.range(from..).map_err(db_error)?.take(limit) {
+            let (seq, json) = entry.map_err(db_error)?;
+            found.push((seq.value(), serde_json::from_str(json.value())?));
+        }
+        Ok(found)
+    }
+
+    /// Remove a publish once the broker has acknowledged it.
+    pub fn remove(&self, seq: u64) -> Result<(), MqttError> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(OUTBOX_TABLE).map_err(db_error)?;
+            table.remove(seq).map_err(db_error)?;
+        }
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Number of queued publishes.
+    pub fn len(&self) -> Result<u64, MqttError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        match txn.open_table(OUTBOX_TABLE) {
+            Ok(table) => table.len().map_err(db_error),
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(0),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    /// Whether the queue is empty.
+    pub fn is_empty(&self) -> Result<bool, MqttError> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_preserves_order_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.redb");
+        {
+            let outbox = Outbox::open(&path).unwrap();
+            outbox
+                .push(&QueuedPublish::new("a/edits", b"1", false))
+                .unwrap();
+            outbox
+                .push(&QueuedPublish::new("a/edits", b"2", false))
+                .unwrap();
+        }
+
+        let outbox = Outbox::open(&path).unwrap();
+        let queued = outbox.peek(10).unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].1.payload_bytes().unwrap(), b"1");
+
+        outbox.remove(queued[0].0).unwrap();
+        outbox
+            .push(&QueuedPublish::new("a/edits", b"3", true))
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = outbox
+            .peek(10)
+            .unwrap()
+            .iter()
+            .map(|(_, p)| p.payload_bytes().unwrap())
+            .collect();
+        assert_eq!(payloads, vec![b"2".to_vec(), b"3".to_vec()]);
+        assert_eq!(outbox.len().unwrap(), 2);
+
+        let rest = outbox.peek_from(queued[1].0 + 1, 10).unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].1.payload_bytes().unwrap(), b"3");
+    }
+}
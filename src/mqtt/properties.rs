@@ -0,0 +1,135 @@
+//! MQTT 5 publish properties.
+//!
+//! On an MQTT 5 connection requests carry a native response topic and
+//! correlation data, ephemeral traffic carries a message expiry, and user
+//! properties carry the author and trace ID. Every message still carries the
+//! same information in its JSON payload (`req`, `reply_to`, `author`), so
+//! peers on MQTT 3.1.1 interoperate; properties are dropped on a 3.1.1
+//! connection.
+
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use std::time::Duration;
+
+/// User property naming the author of an edit or commit.
+pub const AUTHOR_PROPERTY: &str = "author";
+
+/// User property carrying a trace ID across related messages.
+pub const TRACE_ID_PROPERTY: &str = "trace-id";
+
+/// Properties of a published or received message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageProperties {
+    /// Where the receiver should send its reply
+    pub response_topic: Option<String>,
+    /// Opaque request ID, echoed in the reply
+    pub correlation_data: Option<Vec<u8>>,
+    /// How long the broker may hold the message for subscribers
+    pub message_expiry: Option<Duration>,
+    /// Application-defined name/value pairs
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl MessageProperties {
+    /// Properties for a request expecting a reply on `response_topic`.
+    pub fn request(response_topic: &str, correlation: &str) -> Self {
+        Self {
+            response_topic: Some(response_topic.to_string()),
+            correlation_data: Some(correlation.as_bytes().to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Properties for a reply to a message with these properties.
+    pub fn reply(&self) -> Self {
+        Self {
+            correlation_data: self.correlation_data.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the message expiry.
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.message_expiry = Some(expiry);
+        self
+    }
+
+    /// Add a user property.
+    pub fn with_user_property(mut self, name: &str, value: &str) -> Self {
+        self.user_properties
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The first value of a user property.
+    pub fn user_property(&self, name: &str) -> Option<&str> {
+        self.user_properties
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Correlation data as a request ID, if it is UTF-8.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_data
+            .as_deref()
+            .and_then(|data| std::str::from_utf8(data).ok())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(crate) fn to_v5(&self) -> Option<PublishProperties> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(PublishProperties {
+            response_topic: self.response_topic.clone(),
+            correlation_data: self.correlation_data.clone().map(Bytes::from),
+            // Round up so a sub-second expiry doesn't mean "never expires"
+            message_expiry_interval: self
+                .message_expiry
+                .map(|d| d.as_millis().div_ceil(1000).min(u32::MAX as u128) as u32),
+            user_properties: self.user_properties.clone(),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn from_v5(properties: Option<&PublishProperties>) -> Self {
+        let Some(properties) = properties else {
+            return Self::default();
+        };
+        Self {
+            response_topic: properties.response_topic.clone(),
+            correlation_data: properties.correlation_data.as_ref().map(|d| d.to_vec()),
+            message_expiry: properties
+                .message_expiry_interval
+                .map(|secs| Duration::from_secs(secs.into())),
+            user_properties: properties.user_properties.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v5_roundtrip() {
+        let properties = MessageProperties::request("a/replies/1", "req-1")
+            .with_expiry(Duration::from_millis(1500))
+            .with_user_property(TRACE_ID_PROPERTY, "t-1");
+        let v5 = properties.to_v5().unwrap();
+        assert_eq!(v5.message_expiry_interval, Some(2));
+
+        let back = MessageProperties::from_v5(Some(&v5));
+        assert_eq!(back.response_topic.as_deref(), Some("a/replies/1"));
+        assert_eq!(back.correlation_id(), Some("req-1"));
+        assert_eq!(back.user_property(TRACE_ID_PROPERTY), Some("t-1"));
+        assert_eq!(back.reply().correlation_id(), Some("req-1"));
+        assert!(back.reply().response_topic.is_none());
+
+        assert!(MessageProperties::default().to_v5().is_none());
+    }
+}
@@ -0,0 +1,665 @@
+//! Store API over the commands port.
+//!
+//! `commonplace-http` keeps no document state: it forwards each HTTP request
+//! to the store as a [`CommandMessage`] on `$store/commands/{verb}` and waits
+//! for the [`CommandReply`]. Results have the same shape as the
+//! corresponding `commonplace-server` responses. Every commit the store makes
+//! is published to `$store/commits`, which the gateway turns into SSE
+//! streams.
+//!
+//! Verbs that address a document take either `id` or an fs-root `path`.
+//!
+//! When the store has an authenticator, each request must carry the caller's
+//! bearer token in [`CommandMessage::token`]; it is checked against the same
+//! scopes as the HTTP routes, and the principal's name is the writer for
+//! ownership and the commit author. Without one, the port is open and the
+//! `author` a request names isn't trusted for ownership: writes are checked
+//! as anonymous. Since requests carry tokens, the broker should let only the
+//! store subscribe to `$store/commands/#`.
+
+use crate::auth::{
+    effective_author, file_scope, may_override_owner, Access, AuthState, Denied, Principal,
+};
+use crate::commit::Commit;
+use crate::document::{ContentType, DocumentStore};
+use crate::events::CommitNotification;
+use crate::fs::{find_doc_paths, walk_tree};
+use crate::mqtt::client::MqttClient;
+use crate::mqtt::commands::reply_route;
+use crate::mqtt::messages::{CommandMessage, CommandReply, StoreCommit};
+use crate::mqtt::properties::{MessageProperties, AUTHOR_PROPERTY, TRACE_ID_PROPERTY};
+use crate::mqtt::MqttError;
+use crate::services::{DocumentService, PushedCommit, ServiceError};
+use crate::sse::collect_changes_for_docs;
+use crate::store::CommitStore;
+use rumqttc::QoS;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+/// Prefix of store API command topics.
+pub const STORE_COMMANDS_PREFIX: &str = "$store/commands/";
+
+/// Subscription pattern for all store API commands.
+pub const STORE_COMMANDS_WILDCARD: &str = "$store/commands/+";
+
+/// Topic every store commit is published to.
+pub const STORE_COMMITS: &str = "$store/commits";
+
+/// Topic for a store API verb.
+pub fn store_command(verb: &str) -> String {
+    format!("{}{}", STORE_COMMANDS_PREFIX, verb)
+}
+
+/// Error codes carried in [`CommandReply::code`].
+pub mod codes {
+    pub const NOT_FOUND: &str = "not_found";
+    pub const NO_PERSISTENCE: &str = "no_persistence";
+    pub const INVALID_INPUT: &str = "invalid_input";
+    pub const CONFLICT: &str = "conflict";
+    /// The request carried no valid token
+    pub const UNAUTHORIZED: &str = "unauthorized";
+    pub const FORBIDDEN: &str = "forbidden";
+    pub const UNSUPPORTED_MEDIA_TYPE: &str = "unsupported_media_type";
+    /// The store isn't configured for the request (e.g. no fs-root)
+    pub const UNAVAILABLE: &str = "unavailable";
+    pub const UNKNOWN_COMMAND: &str = "unknown_command";
+    pub const INTERNAL: &str = "internal";
+}
+
+/// A failed store API request.
+struct ApiError {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ServiceError> for ApiError {
+    fn from(e: ServiceError) -> Self {
+        let code = match e {
+            ServiceError::NotFound => codes::NOT_FOUND,
+            ServiceError::NoPersistence => codes::NO_PERSISTENCE,
+            ServiceError::InvalidInput(_) => codes::INVALID_INPUT,
+            ServiceError::Internal(_) => codes::INTERNAL,
+            ServiceError::Conflict => codes::CONFLICT,
+            ServiceError::Forbidden(_) => codes::FORBIDDEN,
+        };
+        Self::new(code, format!("{:?}", e))
+    }
+}
+
+impl From<Denied> for ApiError {
+    fn from(e: Denied) -> Self {
+        let code = match e {
+            Denied::Unauthenticated(_) => codes::UNAUTHORIZED,
+            Denied::Forbidden { .. } => codes::FORBIDDEN,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+fn parse<T: DeserializeOwned>(payload: Value) -> Result<T, ApiError> {
+    serde_json::from_value(payload).map_err(|e| ApiError::new(codes::INVALID_INPUT, e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    content_type: String,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdRequest {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct HeadRequest {
+    id: String,
+    #[serde(default)]
+    at_commit: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EditRequest {
+    id: String,
+    update: String,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct CommitRequest {
+    id: String,
+    verb: String,
+    value: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    parent_cid: Option<String>,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct ReplaceRequest {
+    id: String,
+    content: String,
+    #[serde(default)]
+    parent_cid: Option<String>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct PushRequest {
+    id: String,
+    base: String,
+    commits: Vec<PushCommit>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct PushCommit {
+    id: String,
+    parent: String,
+    content: String,
+    timestamp: u64,
+}
+
+#[derive(Deserialize)]
+struct DeleteRequest {
+    id: String,
+    #[serde(default)]
+    override_owner: bool,
+}
+
+#[derive(Deserialize)]
+struct ChangesRequest {
+    doc_ids: Vec<String>,
+    #[serde(default)]
+    since: Option<u64>,
+}
+
+/// Answers store API commands on `$store/commands/+`.
+pub struct StoreApi {
+    client: Arc<MqttClient>,
+    service: Arc<DocumentService>,
+    document_store: Arc<DocumentStore>,
+    commit_store: Option<Arc<CommitStore>>,
+    fs_root: Option<String>,
+    /// Checks request tokens (if unset, every request is allowed)
+    auth: Option<AuthState>,
+}
+
+impl StoreApi {
+    pub fn new(
+        client: Arc<MqttClient>,
+        service: Arc<DocumentService>,
+        document_store: Arc<DocumentStore>,
+        commit_store: Option<Arc<CommitStore>>,
+        fs_root: Option<String>,
+    ) -> Self {
+        Self {
+            client,
+            service,
+            document_store,
+            commit_store,
+            fs_root,
+            auth: None,
+        }
+    }
+
+    /// Require each request to carry a token `auth` accepts.
+    pub fn with_auth(mut self, auth: AuthState) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Handle a command on `$store/commands/{verb}`, replying if asked to.
+    pub async fn handle(
+        &self,
+        verb: &str,
+        payload: &[u8],
+        properties: &MessageProperties,
+    ) -> Result<(), MqttError> {
+        let command: CommandMessage = serde_json::from_slice(payload)
+            .map_err(|e| MqttError::InvalidMessage(e.to_string()))?;
+
+        debug!(
+            "Received store command '{}' from: {:?}",
+            verb, command.source
+        );
+
+        let route = reply_route(&command, properties);
+        let outcome = match self
+            .authorize(verb, &command.payload, command.token.as_deref())
+            .await
+        {
+            Ok(principal) => {
+                self.execute(verb, command.payload, principal.as_ref())
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let Some((reply_to, req)) = route else {
+            if let Err(e) = outcome {
+                warn!("Store command '{}' failed: {}", verb, e.message);
+            }
+            return Ok(());
+        };
+
+        let source = Some(self.client.client_id().to_string());
+        let reply = match outcome {
+            Ok(result) => CommandReply {
+                req,
+                result: Some(result),
+                error: None,
+                code: None,
+                source,
+            },
+            Err(e) => CommandReply {
+                req,
+                result: None,
+                error: Some(e.message),
+                code: Some(e.code.to_string()),
+                source,
+            },
+        };
+        self.client
+            .publish_with_properties(
+                &reply_to,
+                &serde_json::to_vec(&reply)?,
+                QoS::AtLeastOnce,
+                &properties.reply(),
+            )
+            .await
+    }
+
+    /// Check the request's token, returning its principal (or `None` when
+    /// the store doesn't authenticate requests).
+    async fn authorize(
+        &self,
+        verb: &str,
+        payload: &Value,
+        token: Option<&str>,
+    ) -> Result<Option<Principal>, ApiError> {
+        let Some(auth) = self.auth.as_ref() else {
+            return Ok(None);
+        };
+        let (access, resources) = required_access(verb, payload);
+        Ok(Some(auth.authorize(token, access, &resources).await?))
+    }
+
+    async fn execute(
+        &self,
+        verb: &str,
+        mut payload: Value,
+        principal: Option<&Principal>,
+    ) -> Result<Value, ApiError> {
+        // Authenticated writes are made as the principal; otherwise the
+        // writer is unknown and the claimed author is only recorded
+        let writer = principal.map(|p| p.name.as_str());
+        let may_override = may_override_owner(principal);
+
+        // Address documents by fs-root path as well as by ID
+        if let Some(path) = payload.get("path").and_then(Value::as_str) {
+            if payload.get("id").is_none() {
+                let id = self.resolve(path).await?;
+                payload["id"] = Value::String(id);
+            }
+        }
+
+        match verb {
+            "create" => self.create(parse(payload)?).await,
+            "get" => {
+                let req: IdRequest = parse(payload)?;
+                let doc = self.service.get_document(&req.id).await?;
+                Ok(json!({ "content": doc.content, "content_type": doc.content_type.to_mime() }))
+            }
+            "info" => {
+                let req: IdRequest = parse(payload)?;
+                self.service.get_document(&req.id).await?;
+                Ok(json!({ "id": req.id, "type": "document" }))
+            }
+            "delete" => {
+                let req: DeleteRequest = parse(payload)?;
+                self.service
+                    .check_owner(&req.id, writer, req.override_owner && may_override)
+                    .await?;
+                if self.service.delete_document(&req.id).await {
+                    Ok(Value::Null)
+                } else {
+                    Err(ServiceError::NotFound.into())
+                }
+            }
+            "head" => {
+                let req: HeadRequest = parse(payload)?;
+                let head = self
+                    .service
+                    .get_head(&req.id, req.at_commit.as_deref())
+                    .await?;
+                let mut result = json!({ "cid": head.cid, "content": head.content });
+                if let Some(state) = head.state {
+                    result["state"] = Value::String(state);
+                }
+                Ok(result)
+            }
+            "edit" => {
+                let req: EditRequest = parse(payload)?;
+                self.service
+                    .check_owner(&req.id, writer, req.override_owner && may_override)
+                    .await?;
+                let author = effective_author(principal, req.author);
+                let result = self
+                    .service
+                    .edit_document(&req.id, &req.update, author, req.message)
+                    .await?;
+                Ok(json!({ "cid": result.cid }))
+            }
+            "commit" => {
+                let req: CommitRequest = parse(payload)?;
+                if req.verb != "update" {
+                    return Err(ServiceError::InvalidInput(
+                        "Only 'update' verb is supported".to_string(),
+                    )
+                    .into());
+                }
+                self.service
+                    .check_owner(&req.id, writer, req.override_owner && may_override)
+                    .await?;
+                let author = effective_author(principal, Some(req.author)).unwrap_or_default();
+                let result = self
+                    .service
+                    .create_commit(&req.id, &req.value, author, req.message, req.parent_cid)
+                    .await?;
+                let mut reply = json!({ "cid": result.cid });
+                if let Some(merge_cid) = result.merge_cid {
+                    reply["merge_cid"] = Value::String(merge_cid);
+                }
+                Ok(reply)
+            }
+            "replace" => {
+                let req: ReplaceRequest = parse(payload)?;
+                self.service
+                    .check_owner(&req.id, writer, req.override_owner && may_override)
+                    .await?;
+                let author = effective_author(principal, req.author);
+                let result = self
+                    .service
+                    .replace_content(&req.id, &req.content, req.parent_cid, author)
+                    .await?;
+                Ok(json!({
+                    "cid": result.cid,
+                    "edit_cid": result.edit_cid,
+                    "summary": {
+                        "chars_inserted": result.chars_inserted,
+                        "chars_deleted": result.chars_deleted,
+                        "operations": result.operations,
+                    },
+                }))
+            }
+            "push" => {
+                let req: PushRequest = parse(payload)?;
+                self.service
+                    .check_owner(&req.id, writer, req.override_owner && may_override)
+                    .await?;
+                let commits = req
+                    .commits
+                    .into_iter()
+                    .map(|c| PushedCommit {
+                        id: c.id,
+                        parent: c.parent,
+                        content: c.content,
+                        timestamp: c.timestamp,
+                    })
+                    .collect();
+                let author = effective_author(principal, req.author);
+                let result = self
+                    .service
+                    .push_commits(&req.id, &req.base, commits, author)
+                    .await?;
+                let mut reply = json!({ "cid": result.cid, "commits": result.commits });
+                if let Some(merge_cid) = result.merge_cid {
+                    reply["merge_cid"] = Value::String(merge_cid);
+                }
+                Ok(reply)
+            }
+            "fork" => {
+                let req: HeadRequest = parse(payload)?;
+                let result = self.service.fork_document(&req.id, req.at_commit).await?;
+                Ok(json!({ "id": result.id, "head": result.head }))
+            }
+            "fs-root" => {
+                let id = self
+                    .fs_root
+                    .as_ref()
+                    .ok_or_else(|| ApiError::new(codes::UNAVAILABLE, "No fs-root configured"))?;
+                Ok(json!({ "id": id }))
+            }
+            "resolve" => {
+                let req: IdRequest = parse(payload)?;
+                Ok(json!({ "id": req.id }))
+            }
+            "paths" => {
+                let req: IdRequest = parse(payload)?;
+                let fs_root = self
+                    .fs_root
+                    .as_ref()
+                    .ok_or_else(|| ApiError::new(codes::UNAVAILABLE, "No fs-root configured"))?;
+                let paths = find_doc_paths(&self.document_store, fs_root, &req.id).await;
+                Ok(json!({ "paths": paths }))
+            }
+            "changes" => self.changes(parse(payload)?).await,
+            other => Err(ApiError::new(
+                codes::UNKNOWN_COMMAND,
+                format!("Unknown store command: {}", other),
+            )),
+        }
+    }
+
+    /// Resolve an fs-root path to a document ID. The empty path is the
+    /// fs-root itself.
+    async fn resolve(&self, path: &str) -> Result<String, ApiError> {
+        let fs_root = self
+            .fs_root
+            .as_ref()
+            .ok_or_else(|| ApiError::new(codes::UNAVAILABLE, "No fs-root configured"))?;
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            return Ok(fs_root.clone());
+        }
+
+        walk_tree(&self.document_store, fs_root)
+            .await
+            .into_iter()
+            .find(|entry| !entry.is_dir && entry.path == path)
+            .map(|entry| {
+                entry
+                    .node_id
+                    .unwrap_or_else(|| format!("{}:{}", fs_root, path))
+            })
+            .ok_or_else(|| ApiError::new(codes::NOT_FOUND, "Path not found in filesystem"))
+    }
+
+    async fn create(&self, req: CreateRequest) -> Result<Value, ApiError> {
+        let content_type = ContentType::from_mime(&req.content_type).ok_or_else(|| {
+            ApiError::new(
+                codes::UNSUPPORTED_MEDIA_TYPE,
+                format!("Unsupported content type: {}", req.content_type),
+            )
+        })?;
+        let id = self.service.create_document(content_type).await;
+
+        if let Some(content) = req.content {
+            if let Err(e) = self.document_store.set_content(&id, &content).await {
+                // Don't leave behind a document we couldn't initialise
+                self.document_store.delete_document(&id).await;
+                return Err(ApiError::new(codes::INVALID_INPUT, format!("{:?}", e)));
+            }
+        }
+        Ok(json!({ "id": id }))
+    }
+
+    async fn changes(&self, req: ChangesRequest) -> Result<Value, ApiError> {
+        let commit_store = self
+            .commit_store
+            .as_ref()
+            .ok_or_else(|| ApiError::from(ServiceError::NoPersistence))?;
+        if req.doc_ids.is_empty() {
+            return Err(ApiError::new(codes::INVALID_INPUT, "No document IDs given"));
+        }
+        for doc_id in &req.doc_ids {
+            self.service.get_document(doc_id).await?;
+        }
+
+        let changes = collect_changes_for_docs(commit_store, &req.doc_ids, req.since.unwrap_or(0))
+            .await
+            .map_err(|e| ApiError::new(codes::INTERNAL, e.to_string()))?;
+        Ok(json!({ "changes": changes }))
+    }
+}
+
+/// The access a store API request needs and the resources it touches, named
+/// as in the scopes of [`crate::auth`].
+fn required_access(verb: &str, payload: &Value) -> (Access, Vec<String>) {
+    let access = match verb {
+        "create" | "delete" | "edit" | "commit" | "replace" | "push" | "fork" => Access::Write,
+        _ => Access::Read,
+    };
+
+    let resources = match verb {
+        "create" => vec!["create".to_string()],
+        "fs-root" => vec!["fs-root".to_string()],
+        "changes" => payload
+            .get("doc_ids")
+            .and_then(Value::as_array)
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(Value::as_str)
+                    .map(|id| format!("docs/{}", id))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        // An ID wins over a path, as in execute()
+        _ => match (
+            payload.get("id").and_then(Value::as_str),
+            payload.get("path").and_then(Value::as_str),
+        ) {
+            (Some(id), _) => vec![format!("docs/{}", id)],
+            (None, Some(path)) => vec![file_scope(path)],
+            (None, None) => Vec::new(),
+        },
+    };
+
+    (access, resources)
+}
+
+/// Publish a commit to `$store/commits`.
+///
+/// On MQTT 5 the author and any trace ID of the edit that made the commit
+/// go along as user properties.
+pub async fn publish_commit(
+    client: &MqttClient,
+    doc_id: &str,
+    cid: &str,
+    commit: &Commit,
+    trace_id: Option<&str>,
+) -> Result<(), MqttError> {
+    let notice = StoreCommit {
+        doc_id: doc_id.to_string(),
+        cid: cid.to_string(),
+        parents: commit.parents.clone(),
+        update: commit.update.clone(),
+        timestamp: commit.timestamp,
+        author: commit.author.clone(),
+        message: commit.message.clone(),
+    };
+    let mut properties =
+        MessageProperties::default().with_user_property(AUTHOR_PROPERTY, &commit.author);
+    if let Some(trace_id) = trace_id {
+        properties = properties.with_user_property(TRACE_ID_PROPERTY, trace_id);
+    }
+    // QoS 0, like sync responses: streams resync from `changes` anyway
+    client
+        .publish_with_properties(
+            STORE_COMMITS,
+            &serde_json::to_vec(&notice)?,
+            QoS::AtMostOnce,
+            &properties,
+        )
+        .await
+}
+
+/// Publishes commits made outside MQTT (HTTP, WebSocket, store API) to
+/// `$store/commits`.
+pub struct CommitFeed {
+    client: Arc<MqttClient>,
+    commit_store: Arc<CommitStore>,
+}
+
+impl CommitFeed {
+    pub fn new(client: Arc<MqttClient>, commit_store: Arc<CommitStore>) -> Self {
+        Self {
+            client,
+            commit_store,
+        }
+    }
+
+    /// Publish commit notifications until the channel closes.
+    pub async fn run(&self, mut commits: broadcast::Receiver<CommitNotification>) {
+        loop {
+            let notification = match commits.recv().await {
+                Ok(notification) => notification,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Commit feed lagged by {} commits", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let commit = match self.commit_store.get_commit(&notification.commit_id).await {
+                Ok(commit) => commit,
+                Err(e) => {
+                    warn!("Failed to read commit {}: {}", notification.commit_id, e);
+                    continue;
+                }
+            };
+            if let Err(e) = publish_commit(
+                &self.client,
+                &notification.doc_id,
+                &notification.commit_id,
+                &commit,
+                None,
+            )
+            .await
+            {
+                warn!("Failed to publish commit {}: {}", notification.commit_id, e);
+            }
+        }
+    }
+}
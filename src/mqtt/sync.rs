@@ -3,18 +3,33 @@
 //! Implements the git-like merkle tree synchronization protocol.
 //! Subscribes to `{path}/sync/+` wildcard and responds to sync requests.
 
+use crate::commit::Commit;
 use crate::document::resolve_path_to_uuid;
+use crate::mqtt::bloom::{DecodedFilter, HaveFilter};
 use crate::mqtt::client::MqttClient;
 use crate::mqtt::messages::SyncMessage;
-use crate::mqtt::topics::Topic;
+use crate::mqtt::properties::MessageProperties;
+use crate::mqtt::topics::{content_type_for_path, Topic};
 use crate::mqtt::MqttError;
+use crate::replay::CommitReplayer;
 use crate::store::CommitStore;
 use rumqttc::QoS;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::{debug, warn};
 
+/// How long the broker may hold a sync response on MQTT 5. Sync traffic is
+/// ephemeral: a client that wasn't listening just asks again.
+pub const SYNC_MESSAGE_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Where the responses to a sync request go.
+struct ReplyTo {
+    topic: String,
+    properties: MessageProperties,
+}
+
 /// Handler for the sync port.
 pub struct SyncHandler {
     client: Arc<MqttClient>,
@@ -24,6 +39,8 @@ pub struct SyncHandler {
     fs_root_content: RwLock<String>,
     /// The fs-root path/ID.
     fs_root_path: RwLock<Option<String>>,
+    /// Paths of documents anywhere in the tree, mapped to their document IDs.
+    doc_paths: RwLock<HashMap<String, String>>,
 }
 
 impl SyncHandler {
@@ -35,6 +52,7 @@ impl SyncHandler {
             subscribed_paths: RwLock::new(HashSet::new()),
             fs_root_content: RwLock::new(String::new()),
             fs_root_path: RwLock::new(None),
+            doc_paths: RwLock::new(HashMap::new()),
         }
     }
 
@@ -50,6 +68,12 @@ impl SyncHandler {
         *fs_root_path = Some(path);
     }
 
+    /// Update the cached path→document ID map for the whole tree.
+    pub async fn set_doc_paths(&self, paths: HashMap<String, String>) {
+        let mut doc_paths = self.doc_paths.write().await;
+        *doc_paths = paths;
+    }
+
     /// Resolve a path to a document ID.
     async fn resolve_document_id(&self, path: &str) -> Option<String> {
         let fs_root_path = self.fs_root_path.read().await;
@@ -62,7 +86,10 @@ impl SyncHandler {
         } else {
             let fs_root = self.fs_root_content.read().await;
             let fs_root_id = fs_root_id.as_deref().unwrap_or("");
-            resolve_path_to_uuid(&fs_root, path, fs_root_id)
+            match resolve_path_to_uuid(&fs_root, path, fs_root_id) {
+                Some(uuid) => Some(uuid),
+                None => self.doc_paths.read().await.get(path).cloned(),
+            }
         }
     }
 
@@ -97,10 +124,14 @@ impl SyncHandler {
     }
 
     /// Handle an incoming sync request.
+    ///
+    /// Responses go to the request's MQTT 5 response topic, echoing its
+    /// correlation data, or else to `{path}/sync/{client-id}`.
     pub async fn handle_sync_request(
         &self,
         topic: &Topic,
         message: SyncMessage,
+        properties: &MessageProperties,
     ) -> Result<(), MqttError> {
         let client_id = topic.qualifier.as_deref().ok_or_else(|| {
             MqttError::InvalidTopic("Sync topic missing client ID qualifier".to_string())
@@ -111,21 +142,38 @@ impl SyncHandler {
             client_id, topic.path
         );
 
+        let reply = &ReplyTo {
+            topic: properties
+                .response_topic
+                .clone()
+                .unwrap_or_else(|| Topic::sync(&topic.path, client_id).to_topic_string()),
+            properties: properties.reply().with_expiry(SYNC_MESSAGE_EXPIRY),
+        };
+
         match message {
-            SyncMessage::Head { req } => self.handle_head(&topic.path, client_id, &req).await,
-            SyncMessage::Get { req, commits } => {
-                self.handle_get(&topic.path, client_id, &req, commits).await
-            }
+            SyncMessage::Head { req } => self.handle_head(&topic.path, reply, &req).await,
+            SyncMessage::Get { req, commits } => self.handle_get(reply, &req, commits).await,
             SyncMessage::Pull { req, have, want } => {
-                self.handle_pull(&topic.path, client_id, &req, have, &want)
+                self.handle_pull(&topic.path, reply, &req, have, &want)
                     .await
             }
             SyncMessage::Ancestors { req, commit, depth } => {
-                self.handle_ancestors(&topic.path, client_id, &req, &commit, depth)
+                self.handle_ancestors(&topic.path, reply, &req, &commit, depth)
+                    .await
+            }
+            SyncMessage::Negotiate {
+                req,
+                want,
+                have,
+                bloom,
+                snapshot_after,
+            } => {
+                self.handle_negotiate(&topic.path, reply, &req, &want, have, bloom, snapshot_after)
                     .await
             }
             SyncMessage::HeadResponse { .. }
             | SyncMessage::Commit { .. }
+            | SyncMessage::Snapshot { .. }
             | SyncMessage::Done { .. }
             | SyncMessage::Error { .. } => {
                 // Response messages shouldn't be received by the doc store
@@ -136,7 +184,7 @@ impl SyncHandler {
     }
 
     /// Handle a HEAD request.
-    async fn handle_head(&self, path: &str, client_id: &str, req: &str) -> Result<(), MqttError> {
+    async fn handle_head(&self, path: &str, reply: &ReplyTo, req: &str) -> Result<(), MqttError> {
         // Resolve path to document ID
         let doc_id = self.resolve_document_id(path).await;
 
@@ -151,14 +199,13 @@ impl SyncHandler {
             commit,
         };
 
-        self.send_response(path, client_id, &response).await
+        self.send_response(reply, &response).await
     }
 
     /// Handle a GET request - fetch specific commits by ID.
     async fn handle_get(
         &self,
-        path: &str,
-        client_id: &str,
+        reply: &ReplyTo,
         req: &str,
         commit_ids: Vec<String>,
     ) -> Result<(), MqttError> {
@@ -182,7 +229,7 @@ impl SyncHandler {
                         message: commit.message,
                     };
 
-                    self.send_response(path, client_id, &response).await?;
+                    self.send_response(reply, &response).await?;
                     sent_commits.push(cid);
                 }
                 Err(e) => {
@@ -198,14 +245,14 @@ impl SyncHandler {
             commits: sent_commits,
         };
 
-        self.send_response(path, client_id, &done).await
+        self.send_response(reply, &done).await
     }
 
     /// Handle a PULL request - incremental sync.
     async fn handle_pull(
         &self,
         path: &str,
-        client_id: &str,
+        reply: &ReplyTo,
         req: &str,
         have: Vec<String>,
         want: &str,
@@ -231,56 +278,129 @@ impl SyncHandler {
                         req: req.to_string(),
                         commits: vec![],
                     };
-                    return self.send_response(path, client_id, &done).await;
+                    return self.send_response(reply, &done).await;
                 }
             }
         } else {
             want.to_string()
         };
 
-        // Find commits between 'have' and 'want'
-        // This is a simplified implementation - for a proper git-like protocol,
-        // we'd do a proper DAG traversal
-        let commits_to_send = self.find_commits_between(store, &have, &target_cid).await?;
+        let have_set: HashSet<&str> = have.iter().map(|s| s.as_str()).collect();
+        let missing = missing_commits(store, &target_cid, |cid| have_set.contains(cid)).await;
+        self.send_commits(reply, req, missing).await
+    }
 
-        let mut sent_commits = Vec::new();
+    /// Handle a NEGOTIATE request - send exactly the commits the client lacks.
+    ///
+    /// A commit counts as held if it is listed in `have` or matches `bloom`;
+    /// traversal stops there. A Bloom false positive can leave a parent out,
+    /// which the client fetches with `get`.
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_negotiate(
+        &self,
+        path: &str,
+        reply: &ReplyTo,
+        req: &str,
+        want: &str,
+        have: Vec<String>,
+        bloom: Option<HaveFilter>,
+        snapshot_after: Option<usize>,
+    ) -> Result<(), MqttError> {
+        let store = self
+            .commit_store
+            .as_ref()
+            .ok_or_else(|| MqttError::Node("Commit store not initialized".to_string()))?;
 
-        for cid in commits_to_send {
-            match store.get_commit(&cid).await {
-                Ok(commit) => {
-                    let response = SyncMessage::Commit {
+        let bloom: Option<DecodedFilter> = match bloom.map(|b| b.decode()).transpose() {
+            Ok(bloom) => bloom,
+            Err(message) => {
+                let error = SyncMessage::Error {
+                    req: req.to_string(),
+                    message,
+                };
+                return self.send_response(reply, &error).await;
+            }
+        };
+
+        let doc_id = self
+            .resolve_document_id(path)
+            .await
+            .ok_or_else(|| MqttError::InvalidTopic(format!("Path not mounted: {}", path)))?;
+
+        let target_cid = if want == "HEAD" {
+            match store.get_document_head(&doc_id).await? {
+                Some(cid) => cid,
+                None => {
+                    let done = SyncMessage::Done {
                         req: req.to_string(),
-                        id: cid.clone(),
-                        parents: commit.parents,
-                        data: commit.update,
-                        timestamp: commit.timestamp,
-                        author: commit.author,
-                        message: commit.message,
+                        commits: vec![],
                     };
-
-                    self.send_response(path, client_id, &response).await?;
-                    sent_commits.push(cid);
+                    return self.send_response(reply, &done).await;
                 }
-                Err(e) => {
-                    warn!("Error fetching commit {}: {}", cid, e);
+            }
+        } else {
+            want.to_string()
+        };
+
+        let have_set: HashSet<&str> = have.iter().map(|s| s.as_str()).collect();
+        let missing = missing_commits(store, &target_cid, |cid| {
+            have_set.contains(cid) || bloom.as_ref().is_some_and(|b| b.contains(cid))
+        })
+        .await;
+
+        if let Some(limit) = snapshot_after {
+            if missing.len() > limit {
+                if let Some(snapshot) = self
+                    .snapshot(store, path, &doc_id, req, &target_cid, missing.len())
+                    .await
+                {
+                    self.send_response(reply, &snapshot).await?;
+                    let done = SyncMessage::Done {
+                        req: req.to_string(),
+                        commits: vec![],
+                    };
+                    return self.send_response(reply, &done).await;
                 }
             }
         }
 
-        // Send done message
-        let done = SyncMessage::Done {
-            req: req.to_string(),
-            commits: sent_commits,
-        };
+        self.send_commits(reply, req, missing).await
+    }
 
-        self.send_response(path, client_id, &done).await
+    /// Build a snapshot of the state at `commit`, if the content type is known.
+    async fn snapshot(
+        &self,
+        store: &CommitStore,
+        path: &str,
+        doc_id: &str,
+        req: &str,
+        commit: &str,
+        missing: usize,
+    ) -> Option<SyncMessage> {
+        let content_type = content_type_for_path(path).ok()?;
+        let replayer = CommitReplayer::new(store);
+        match replayer
+            .get_content_and_state_at_commit(doc_id, commit, &content_type)
+            .await
+        {
+            Ok((_, state)) => Some(SyncMessage::Snapshot {
+                req: req.to_string(),
+                commit: commit.to_string(),
+                data: crate::b64::encode(&state),
+                missing,
+            }),
+            Err(e) => {
+                warn!("Failed to build snapshot at {}: {}", commit, e);
+                None
+            }
+        }
     }
 
     /// Handle an ANCESTORS request - full history from a commit.
     async fn handle_ancestors(
         &self,
         path: &str,
-        client_id: &str,
+        reply: &ReplyTo,
         req: &str,
         commit: &str,
         depth: Option<u32>,
@@ -306,7 +426,7 @@ impl SyncHandler {
                         req: req.to_string(),
                         commits: vec![],
                     };
-                    return self.send_response(path, client_id, &done).await;
+                    return self.send_response(reply, &done).await;
                 }
             }
         } else {
@@ -332,7 +452,7 @@ impl SyncHandler {
                         message: commit.message,
                     };
 
-                    self.send_response(path, client_id, &response).await?;
+                    self.send_response(reply, &response).await?;
                     sent_commits.push(cid);
                 }
                 Err(e) => {
@@ -347,65 +467,48 @@ impl SyncHandler {
             commits: sent_commits,
         };
 
-        self.send_response(path, client_id, &done).await
+        self.send_response(reply, &done).await
     }
 
-    /// Send a response to a client.
-    async fn send_response(
+    /// Send commits followed by `Done`.
+    async fn send_commits(
         &self,
-        path: &str,
-        client_id: &str,
-        message: &SyncMessage,
+        reply: &ReplyTo,
+        req: &str,
+        commits: Vec<(String, Commit)>,
     ) -> Result<(), MqttError> {
-        let topic = Topic::sync(path, client_id);
-        let topic_str = topic.to_topic_string();
+        let mut sent_commits = Vec::with_capacity(commits.len());
+        for (cid, commit) in commits {
+            let response = SyncMessage::Commit {
+                req: req.to_string(),
+                id: cid.clone(),
+                parents: commit.parents,
+                data: commit.update,
+                timestamp: commit.timestamp,
+                author: commit.author,
+                message: commit.message,
+            };
+            self.send_response(reply, &response).await?;
+            sent_commits.push(cid);
+        }
+
+        let done = SyncMessage::Done {
+            req: req.to_string(),
+            commits: sent_commits,
+        };
+        self.send_response(reply, &done).await
+    }
 
+    /// Send a response to a client.
+    async fn send_response(&self, reply: &ReplyTo, message: &SyncMessage) -> Result<(), MqttError> {
         let payload = serde_json::to_vec(message)?;
 
         // Use QoS 0 for sync responses - ephemeral
         self.client
-            .publish(&topic_str, &payload, QoS::AtMostOnce)
+            .publish_with_properties(&reply.topic, &payload, QoS::AtMostOnce, &reply.properties)
             .await
     }
 
-    /// Find commits between 'have' set and 'want' commit.
-    /// Returns commits in topological order (dependencies first).
-    async fn find_commits_between(
-        &self,
-        store: &CommitStore,
-        have: &[String],
-        want: &str,
-    ) -> Result<Vec<String>, MqttError> {
-        let have_set: HashSet<&str> = have.iter().map(|s| s.as_str()).collect();
-        let mut result = Vec::new();
-        let mut to_visit = vec![want.to_string()];
-        let mut visited = HashSet::new();
-
-        while let Some(cid) = to_visit.pop() {
-            if visited.contains(&cid) || have_set.contains(cid.as_str()) {
-                continue;
-            }
-
-            visited.insert(cid.clone());
-
-            match store.get_commit(&cid).await {
-                Ok(commit) => {
-                    result.push(cid);
-                    for parent in commit.parents {
-                        to_visit.push(parent);
-                    }
-                }
-                Err(_) => {
-                    // Commit not found, skip
-                }
-            }
-        }
-
-        // Reverse to get oldest first
-        result.reverse();
-        Ok(result)
-    }
-
     /// Collect ancestors of a commit up to a depth limit.
     async fn collect_ancestors(
         &self,
@@ -446,3 +549,95 @@ impl SyncHandler {
         Ok(result)
     }
 }
+
+/// Commits reachable from `want` that the client lacks, parents first.
+///
+/// Traversal stops at commits for which `held` returns true. Commits missing
+/// from the store are skipped.
+async fn missing_commits(
+    store: &CommitStore,
+    want: &str,
+    held: impl Fn(&str) -> bool,
+) -> Vec<(String, Commit)> {
+    let mut commits: HashMap<String, Commit> = HashMap::new();
+    let mut to_visit = vec![want.to_string()];
+    while let Some(cid) = to_visit.pop() {
+        if commits.contains_key(&cid) || held(&cid) {
+            continue;
+        }
+        match store.get_commit(&cid).await {
+            Ok(commit) => {
+                to_visit.extend(commit.parents.iter().cloned());
+                commits.insert(cid, commit);
+            }
+            Err(e) => warn!("Error fetching commit {}: {}", cid, e),
+        }
+    }
+
+    topological_order(commits, want)
+}
+
+/// Order `commits` so every commit follows its parents (post-order DFS).
+fn topological_order(mut commits: HashMap<String, Commit>, tip: &str) -> Vec<(String, Commit)> {
+    let mut order = Vec::with_capacity(commits.len());
+    let mut emitted: HashSet<String> = HashSet::new();
+    // (commit, parents already pushed)
+    let mut stack = vec![(tip.to_string(), false)];
+    while let Some((cid, expanded)) = stack.pop() {
+        if emitted.contains(&cid) {
+            continue;
+        }
+        let Some(commit) = commits.get(&cid) else {
+            continue;
+        };
+        if expanded {
+            emitted.insert(cid.clone());
+            order.push(cid);
+            continue;
+        }
+        let parents: Vec<String> = commit
+            .parents
+            .iter()
+            .filter(|p| commits.contains_key(*p) && !emitted.contains(*p))
+            .cloned()
+            .collect();
+        stack.push((cid, true));
+        stack.extend(parents.into_iter().rev().map(|p| (p, false)));
+    }
+
+    order
+        .into_iter()
+        .filter_map(|cid| commits.remove(&cid).map(|commit| (cid, commit)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(parents: &[&str]) -> Commit {
+        Commit::new(
+            parents.iter().map(|p| p.to_string()).collect(),
+            String::new(),
+            "test".to_string(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_topological_order_puts_parents_first() {
+        // m merges a and b, and a also descends from b
+        let commits = HashMap::from([
+            ("root".to_string(), commit(&[])),
+            ("b".to_string(), commit(&["root"])),
+            ("a".to_string(), commit(&["b"])),
+            ("m".to_string(), commit(&["a", "b"])),
+        ]);
+
+        let order: Vec<String> = topological_order(commits, "m")
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect();
+        assert_eq!(order, vec!["root", "b", "a", "m"]);
+    }
+}
@@ -155,6 +155,32 @@ impl Topic {
         }
     }
 
+    /// Construct the reply topic for a command request.
+    /// Returns `{path}/events/reply/{req}`
+    pub fn reply(path: &str, req: &str) -> Self {
+        Self::events(path, &format!("reply/{}", req))
+    }
+
+    /// Construct the retained command manifest topic for a path.
+    /// Returns `{path}/events/manifest`
+    pub fn manifest(path: &str) -> Self {
+        Self::events(path, MANIFEST_EVENT)
+    }
+
+    /// Whether this is a path's command manifest topic.
+    pub fn is_manifest(&self) -> bool {
+        self.port == Port::Events && self.qualifier.as_deref() == Some(MANIFEST_EVENT)
+    }
+
+    /// Whether this is a command reply topic (`{path}/events/reply/{req}`).
+    pub fn is_reply(&self) -> bool {
+        self.port == Port::Events
+            && self
+                .qualifier
+                .as_deref()
+                .is_some_and(|q| q.starts_with("reply/"))
+    }
+
     /// Convert the topic to its string representation.
     pub fn to_topic_string(&self) -> String {
         match &self.qualifier {
@@ -163,6 +189,12 @@ impl Topic {
         }
     }
 
+    /// Get the retained head announcement topic for a path.
+    /// Returns `{path}/head`, which is not a port topic and so has no [`Topic`]
+    pub fn head_topic_str(path: &str) -> String {
+        format!("{}/head", path)
+    }
+
     /// Get the wildcard pattern for subscribing to sync requests.
     /// Returns `{path}/sync/+`
     pub fn sync_wildcard(path: &str) -> String {
@@ -182,6 +214,9 @@ impl Topic {
     }
 }
 
+/// Event name of the retained command manifest.
+const MANIFEST_EVENT: &str = "manifest";
+
 /// Allowed file extensions for sync operations.
 const ALLOWED_EXTENSIONS: &[&str] = &["txt", "json", "xml", "xhtml", "bin", "md"];
 
@@ -258,6 +293,25 @@ mod tests {
         assert_eq!(topic.qualifier, Some("clear".to_string()));
     }
 
+    #[test]
+    fn test_reply_topic_roundtrip() {
+        let topic_str = Topic::reply("examples/counter.json", "r-42").to_topic_string();
+        assert_eq!(topic_str, "examples/counter.json/events/reply/r-42");
+
+        let topic = Topic::parse(&topic_str).unwrap();
+        assert_eq!(topic.port, Port::Events);
+        assert_eq!(topic.qualifier, Some("reply/r-42".to_string()));
+        assert!(topic.is_reply());
+    }
+
+    #[test]
+    fn test_manifest_topic() {
+        let topic = Topic::parse("examples/counter.json/events/manifest").unwrap();
+        assert!(topic.is_manifest());
+        assert_eq!(topic, Topic::manifest("examples/counter.json"));
+        assert!(!Topic::events("examples/counter.json", "reset").is_manifest());
+    }
+
     #[test]
     fn test_parse_nested_path() {
         let topic = Topic::parse("deep/nested/path/doc.txt/edits").unwrap();
@@ -306,6 +360,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_head_topic_str() {
+        assert_eq!(
+            Topic::head_topic_str("terminal/screen.txt"),
+            "terminal/screen.txt/head"
+        );
+        // Not a port topic, so it doesn't parse as one
+        assert!(Topic::parse("terminal/screen.txt/head").is_err());
+    }
+
     #[test]
     fn test_content_type_for_path() {
         assert_eq!(
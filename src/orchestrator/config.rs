@@ -31,6 +31,11 @@ pub struct ProcessConfig {
     pub restart: RestartPolicy,
     #[serde(default)]
     pub depends_on: Vec<String>,
+    /// Document path this process works on (e.g. "/" or "workspace/notes").
+    /// When the orchestrator mints credentials, the process's token is scoped
+    /// to this path; processes without a path receive a read-only token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,198 @@
+//! Per-process credential minting.
+//!
+//! When the orchestrator is given a signing key, every process it spawns
+//! receives a signed token in `COMMONPLACE_TOKEN` scoped to that process's
+//! path (or its `owns` target), so a misbehaving process cannot write to
+//! documents it doesn't own. Servers it launches get the key file as
+//! `--auth-key`, so they accept those tokens.
+
+use super::discovery::DiscoveredProcess;
+use crate::auth::{file_scope, AuthError, Principal, SigningKey};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default lifetime of minted process tokens (`--token-ttl`).
+///
+/// Tokens aren't refreshed while a process runs: each (re)start mints a
+/// fresh one, so a process (or the orchestrator's own discovery token) that
+/// outlives the TTL gets `401 Token expired` until it is restarted.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Mints scoped tokens for managed processes.
+#[derive(Clone)]
+pub struct CredentialMinter {
+    key: SigningKey,
+    ttl: Duration,
+    /// File the key was loaded from, passed to the servers we launch
+    key_file: Option<PathBuf>,
+}
+
+impl CredentialMinter {
+    pub fn new(key: SigningKey) -> Self {
+        Self {
+            key,
+            ttl: DEFAULT_TOKEN_TTL,
+            key_file: None,
+        }
+    }
+
+    /// Load the signing key from `path`, remembering the file so servers
+    /// launched by the orchestrator can verify the minted tokens.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let mut minter = Self::new(SigningKey::load(path)?);
+        minter.key_file = Some(path.to_path_buf());
+        Ok(minter)
+    }
+
+    /// File the signing key was loaded from, if any.
+    pub fn key_file(&self) -> Option<&Path> {
+        self.key_file.as_deref()
+    }
+
+    /// Override the lifetime of minted tokens.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Mint a token for a principal.
+    pub fn mint(&self, principal: &Principal) -> String {
+        self.key.mint(principal, self.ttl)
+    }
+
+    /// Token for the orchestrator itself: read access everywhere, for discovery.
+    pub fn orchestrator_token(&self) -> String {
+        self.mint(&Principal {
+            name: "orchestrator".to_string(),
+            read: vec!["*".to_string()],
+            write: vec![],
+            admin: false,
+        })
+    }
+}
+
+/// Identity recorded as the author of a discovered process's commits.
+///
+/// Derived from the directory holding its `.processes.json` and its name, so
+/// it is stable across restarts and unique across the tree.
+pub fn process_principal_name(source_path: &str, name: &str) -> String {
+    let source_path = source_path.trim_matches('/');
+    if source_path.is_empty() {
+        format!("process:{}", name)
+    } else {
+        format!("process:{}/{}", source_path, name)
+    }
+}
+
+/// Build the principal for a process from `commonplace.json`.
+///
+/// A process with a `path` may read and write it and create new documents;
+/// one without a path may only read, so it still reaches the server but
+/// can't write anywhere it hasn't been given.
+pub fn base_principal(name: &str, path: Option<&str>) -> Principal {
+    let principal_name = process_principal_name("", name);
+    match path {
+        Some(path) => {
+            let mut principal = Principal::for_path(principal_name, path);
+            principal.write.push("create".to_string());
+            principal
+        }
+        None => Principal {
+            name: principal_name,
+            read: vec!["*".to_string()],
+            write: vec![],
+            admin: false,
+        },
+    }
+}
+
+/// Build the principal for a process discovered from `.processes.json`.
+///
+/// File-attached processes (`owns`) may write only the file they own, and
+/// read the directory it lives in. Directory-attached processes may read and
+/// write their whole directory and create new documents in it.
+pub fn discovered_principal(
+    name: &str,
+    document_path: &str,
+    source_path: &str,
+    config: &DiscoveredProcess,
+) -> Principal {
+    let principal_name = process_principal_name(source_path, name);
+    if config.owns.is_some() {
+        Principal {
+            name: principal_name,
+            read: vec![file_scope(source_path)],
+            write: vec![file_scope(document_path)],
+            admin: false,
+        }
+    } else {
+        let mut principal = Principal::for_path(principal_name, document_path);
+        principal.write.push("create".to_string());
+        principal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Access;
+
+    fn process(owns: Option<&str>) -> DiscoveredProcess {
+        DiscoveredProcess {
+            command: None,
+            sandbox_exec: Some("python main.py".to_string()),
+            path: None,
+            owns: owns.map(|s| s.to_string()),
+            cwd: None,
+        }
+    }
+
+    #[test]
+    fn test_owning_process_writes_only_its_file() {
+        let p = discovered_principal(
+            "counter",
+            "examples/counter.json",
+            "examples",
+            &process(Some("counter.json")),
+        );
+        assert_eq!(p.name, "process:examples/counter");
+        assert!(p.can_write("files/examples/counter.json"));
+        assert!(!p.can_write("files/examples/other.json"));
+        assert!(p.allows(Access::Read, "files/examples/other.json"));
+        assert!(!p.allows(Access::Read, "files/private/secret.txt"));
+    }
+
+    #[test]
+    fn test_directory_process_writes_its_directory() {
+        let p = discovered_principal("bartleby", "bartleby", "", &process(None));
+        assert_eq!(p.name, "process:bartleby");
+        assert!(p.can_write("files/bartleby/notes.txt"));
+        assert!(p.can_write("create"));
+        assert!(!p.can_write("files/other/notes.txt"));
+    }
+
+    #[test]
+    fn test_base_process_without_path_is_read_only() {
+        let p = base_principal("sync", Some("workspace"));
+        assert_eq!(p.name, "process:sync");
+        assert!(p.can_write("files/workspace/notes.txt"));
+        assert!(p.can_write("create"));
+        assert!(!p.can_write("files/other/notes.txt"));
+
+        let p = base_principal("beads-sync", None);
+        assert!(p.allows(Access::Read, "files/workspace/notes.txt"));
+        assert!(p.allows(Access::Read, "docs/anything"));
+        assert!(!p.can_write("files/workspace/notes.txt"));
+        assert!(!p.can_write("create"));
+    }
+
+    #[test]
+    fn test_minted_token_verifies() {
+        let key = SigningKey::new("secret");
+        let minter = CredentialMinter::new(key.clone());
+        let token = minter.orchestrator_token();
+        let principal = key.verify(&token).unwrap();
+        assert!(principal.allows(Access::Read, "docs/anything"));
+        assert!(!principal.can_write("docs/anything"));
+    }
+}
@@ -4,8 +4,10 @@
 //! discovered from `.processes.json` files. For config parsing and discovery logic,
 //! see the `discovery` module.
 
+use super::credentials::{discovered_principal, CredentialMinter};
 use super::discovery::{DiscoveredProcess, ProcessesConfig};
 use super::status::{OrchestratorStatus, ProcessStatus};
+use crate::auth::TOKEN_ENV;
 use futures::StreamExt;
 use reqwest::Client;
 use reqwest_eventsource::{Event as SseEvent, EventSource};
@@ -65,6 +67,8 @@ pub struct DiscoveredProcessManager {
     max_backoff_ms: u64,
     /// Time in seconds after which to reset failure count
     reset_after_secs: u64,
+    /// Mints per-process credentials (if auth is enabled)
+    credentials: Option<CredentialMinter>,
 }
 
 /// Result of recursive discovery - includes both processes.json files and all schema node_ids.
@@ -85,9 +89,15 @@ impl DiscoveredProcessManager {
             initial_backoff_ms: 500,
             max_backoff_ms: 10_000,
             reset_after_secs: 30,
+            credentials: None,
         }
     }
 
+    /// Mint a scoped token for every process spawned from now on.
+    pub fn set_credentials(&mut self, minter: CredentialMinter) {
+        self.credentials = Some(minter);
+    }
+
     /// Get the MQTT broker address.
     pub fn mqtt_broker(&self) -> &str {
         &self.mqtt_broker
@@ -302,6 +312,17 @@ impl DiscoveredProcessManager {
         cmd.env("COMMONPLACE_SERVER", &self.server_url);
         cmd.env("COMMONPLACE_INITIAL_SYNC", "server");
 
+        // Inject a credential scoped to this process's path or owns target
+        if let Some(ref minter) = self.credentials {
+            let principal = discovered_principal(name, document_path, &process.source_path, config);
+            tracing::debug!(
+                "[discovery] Minted credential for '{}' (write: {:?})",
+                name,
+                principal.write
+            );
+            cmd.env(TOKEN_ENV, minter.mint(&principal));
+        }
+
         // Capture stdout/stderr
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
@@ -1,14 +1,32 @@
+use super::credentials::{base_principal, CredentialMinter};
 use super::status::{OrchestratorStatus, ProcessStatus};
 use super::{OrchestratorConfig, ProcessConfig, RestartMode};
+use crate::auth::TOKEN_ENV;
 use std::collections::HashMap;
 #[cfg(unix)]
 #[allow(unused_imports)]
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Stdio;
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 
+/// Binaries that verify bearer tokens, and so take `--auth-key`.
+const TOKEN_VERIFIERS: &[&str] = &[
+    "commonplace-server",
+    "commonplace-store",
+    "commonplace-http",
+];
+
+/// Whether `command` runs one of the [`TOKEN_VERIFIERS`].
+fn verifies_tokens(command: &str) -> bool {
+    Path::new(command)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| TOKEN_VERIFIERS.contains(&name))
+}
+
 #[derive(Debug)]
 pub struct ManagedProcess {
     pub config: ProcessConfig,
@@ -31,6 +49,7 @@ pub struct ProcessManager {
     processes: HashMap<String, ManagedProcess>,
     mqtt_broker_override: Option<String>,
     disabled: Vec<String>,
+    credentials: Option<CredentialMinter>,
 }
 
 impl ProcessManager {
@@ -61,9 +80,15 @@ impl ProcessManager {
             processes,
             mqtt_broker_override,
             disabled,
+            credentials: None,
         }
     }
 
+    /// Mint a token for every process, scoped to its configured `path`.
+    pub fn set_credentials(&mut self, minter: CredentialMinter) {
+        self.credentials = Some(minter);
+    }
+
     pub fn mqtt_broker(&self) -> &str {
         self.mqtt_broker_override
             .as_deref()
@@ -148,6 +173,26 @@ impl ProcessManager {
             cmd.arg(&mqtt_broker);
         }
 
+        // Inject a credential scoped to the process's configured path, or a
+        // read-only one if it has none. Servers get the key to verify them.
+        if let Some(ref minter) = self.credentials {
+            let verifier = verifies_tokens(&config.command);
+            if let Some(key_file) = minter.key_file() {
+                if verifier && !config.args.iter().any(|arg| arg == "--auth-key") {
+                    cmd.arg("--auth-key");
+                    cmd.arg(key_file);
+                }
+            }
+            if config.path.is_none() && !verifier {
+                tracing::warn!(
+                    "[orchestrator] '{}' has no path, its token is read-only",
+                    name
+                );
+            }
+            let principal = base_principal(name, config.path.as_deref());
+            cmd.env(TOKEN_ENV, minter.mint(&principal));
+        }
+
         // Capture stdout/stderr
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
@@ -1,10 +1,14 @@
 mod config;
+mod credentials;
 mod discovered_manager;
 mod discovery;
 mod manager;
 mod status;
 
 pub use config::{OrchestratorConfig, ProcessConfig, RestartMode, RestartPolicy};
+pub use credentials::{
+    discovered_principal, process_principal_name, CredentialMinter, DEFAULT_TOKEN_TTL,
+};
 pub use discovered_manager::{
     DiscoveredProcessManager, DiscoveredProcessState, ManagedDiscoveredProcess,
 };
@@ -4,16 +4,21 @@
 //! separating it from HTTP handler concerns. The service orchestrates
 //! between DocumentStore, CommitStore, and CommitBroadcaster.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
 use tracing::debug;
+use yrs::updates::decoder::Decode;
+use yrs::{ReadTxn, Transact};
 
 use crate::commit::Commit;
 use crate::document::{ApplyError, ContentType, Document, DocumentStore};
 use crate::events::{CommitBroadcaster, CommitNotification};
-use crate::fs::FilesystemReconciler;
+use crate::fs::{FilesystemReconciler, OwnershipGuard};
 use crate::store::CommitStore;
-use crate::sync::{base64_decode, create_yjs_json_update};
+use crate::sync::{base64_decode, create_yjs_json_update, create_yjs_jsonl_update};
 use crate::{b64, diff, replay::CommitReplayer};
 
 fn preview_text(text: &str, max_chars: usize) -> String {
@@ -33,6 +38,8 @@ pub enum ServiceError {
     Internal(String),
     /// Conflict (e.g., concurrent modification)
     Conflict,
+    /// Write rejected (e.g., file owned by another process)
+    Forbidden(String),
 }
 
 impl From<ApplyError> for ServiceError {
@@ -74,6 +81,29 @@ pub struct ReplaceResult {
     pub operations: usize,
 }
 
+/// A commit made on a client while it couldn't reach the server.
+pub struct PushedCommit {
+    /// The client's id for the commit, named by the next commit's parent
+    pub id: String,
+    /// The client's id for the parent: the push's base or the previous commit
+    pub parent: String,
+    /// Full document content after the commit
+    pub content: String,
+    /// When the commit was made, in Unix milliseconds
+    pub timestamp: u64,
+}
+
+/// Result of pushing a chain of commits.
+pub struct PushResult {
+    /// The document's new HEAD
+    pub cid: String,
+    /// Server CIDs of the pushed commits, in order
+    pub commits: Vec<String>,
+    /// The merge commit joining them with the server's history, if it had
+    /// moved on since the base
+    pub merge_cid: Option<String>,
+}
+
 /// Result of a fork operation.
 pub struct ForkResult {
     /// ID of the new document
@@ -104,6 +134,10 @@ pub struct DocumentService {
     reconciler: Option<Arc<FilesystemReconciler>>,
     /// Filesystem root document ID (for triggering reconciliation)
     fs_root_id: Option<String>,
+    /// Single-writer ownership checks (if fs-root is configured)
+    ownership: Option<Arc<OwnershipGuard>>,
+    /// Per-document locks held from reading HEAD to setting it
+    write_locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
 }
 
 impl DocumentService {
@@ -119,6 +153,8 @@ impl DocumentService {
             commit_broadcaster,
             reconciler: None,
             fs_root_id: None,
+            ownership: None,
+            write_locks: Mutex::new(HashMap::new()),
         }
     }
 
@@ -136,11 +172,55 @@ impl DocumentService {
             commit_broadcaster,
             reconciler: Some(reconciler),
             fs_root_id: Some(fs_root_id),
+            ownership: None,
+            write_locks: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Check writes against file ownership declared in `processes.json`.
+    pub fn with_ownership(mut self, guard: Arc<OwnershipGuard>) -> Self {
+        self.ownership = Some(guard);
+        self
+    }
+
+    /// Check that `writer` may write document `id`.
+    ///
+    /// Returns `Forbidden` when the document is owned by another process and
+    /// the ownership policy is enforced, unless `override_owner` is set.
+    pub async fn check_owner(
+        &self,
+        id: &str,
+        writer: Option<&str>,
+        override_owner: bool,
+    ) -> Result<(), ServiceError> {
+        match self.ownership.as_ref() {
+            Some(guard) => guard
+                .check_doc(id, writer, override_owner)
+                .await
+                .map_err(|v| ServiceError::Forbidden(v.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Take document `id`'s write lock, so HEAD can't move under a write
+    /// between reading it and setting it.
+    async fn lock_document(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .write_locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(id.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
     /// Broadcast a commit notification.
     fn broadcast_commit(&self, doc_id: &str, commit_id: &str, timestamp: u64) {
+        // Owners are read from schema and processes.json documents
+        if let Some(guard) = self.ownership.as_ref() {
+            guard.invalidate(doc_id);
+        }
         if let Some(broadcaster) = self.commit_broadcaster.as_ref() {
             broadcaster.notify(CommitNotification {
                 doc_id: doc_id.to_string(),
@@ -176,7 +256,11 @@ impl DocumentService {
 
     /// Delete a document by ID.
     pub async fn delete_document(&self, id: &str) -> bool {
-        self.doc_store.delete_document(id).await
+        let deleted = self.doc_store.delete_document(id).await;
+        if let Some(guard) = self.ownership.as_ref() {
+            guard.invalidate(id);
+        }
+        deleted
     }
 
     // ========================================================================
@@ -264,6 +348,8 @@ impl DocumentService {
         let update_bytes = b64::decode(update_b64)
             .map_err(|_| ServiceError::InvalidInput("Invalid base64".to_string()))?;
 
+        let write_lock = self.lock_document(id).await;
+
         // Get current head
         let current_head = commit_store
             .get_document_head(id)
@@ -289,6 +375,7 @@ impl DocumentService {
             .set_document_head(id, &cid)
             .await
             .map_err(|e| ServiceError::Internal(e.to_string()))?;
+        drop(write_lock);
 
         self.broadcast_commit(id, &cid, timestamp);
 
@@ -380,6 +467,7 @@ impl DocumentService {
         let update_bytes = b64::decode(update_b64)
             .map_err(|_| ServiceError::InvalidInput("Invalid base64".to_string()))?;
 
+        let write_lock = self.lock_document(id).await;
         let current_head = commit_store
             .get_document_head(id)
             .await
@@ -414,6 +502,7 @@ impl DocumentService {
                     .set_document_head(id, &edit_cid)
                     .await
                     .map_err(|e| ServiceError::Internal(e.to_string()))?;
+                drop(write_lock);
 
                 self.broadcast_commits(id, &notifications);
                 return Ok(CommitResult {
@@ -477,6 +566,7 @@ impl DocumentService {
             notifications.push((cid.clone(), commit_timestamp));
             (cid, None)
         };
+        drop(write_lock);
 
         self.broadcast_commits(id, &notifications);
 
@@ -579,6 +669,8 @@ impl DocumentService {
             .as_ref()
             .ok_or(ServiceError::NoPersistence)?;
 
+        // The diff is computed against the content at HEAD
+        let write_lock = self.lock_document(id).await;
         let doc = self.get_document(id).await?;
 
         // Get current HEAD to check if parent_cid differs
@@ -634,10 +726,19 @@ impl DocumentService {
                 let diff = if is_json_type {
                     // JSON documents use Y.Map/Y.Array - use JSON update with base state
                     let base_state_b64 = b64::encode(&base_state_bytes);
-                    compute_json_diff(new_content, &old_content, Some(&base_state_b64))?
+                    compute_json_diff(
+                        &doc.content_type,
+                        new_content,
+                        &old_content,
+                        Some(&base_state_b64),
+                    )?
                 } else {
-                    // Text/XML documents use Y.Text - use character-level diff
-                    diff::compute_diff_update_with_base(
+                    // Text/XML documents use Y.Text - use character-level diff.
+                    // As the default client, the edit would reuse the item
+                    // IDs of edits made since parent, and be dropped as
+                    // already applied
+                    diff::compute_diff_update_with_base_as(
+                        push_client_id(parent, new_content),
                         &base_state_bytes,
                         &old_content,
                         new_content,
@@ -673,7 +774,12 @@ impl DocumentService {
                         .get_yjs_state(id)
                         .await
                         .map(|b| b64::encode(&b));
-                    compute_json_diff(new_content, &doc.content, base_state.as_deref())?
+                    compute_json_diff(
+                        &doc.content_type,
+                        new_content,
+                        &doc.content,
+                        base_state.as_deref(),
+                    )?
                 } else {
                     // Text/XML: use server's actual Yjs state for proper CRDT merge
                     let base_state = self.doc_store.get_yjs_state(id).await;
@@ -702,7 +808,12 @@ impl DocumentService {
                     .get_yjs_state(id)
                     .await
                     .map(|b| b64::encode(&b));
-                compute_json_diff(new_content, &doc.content, base_state.as_deref())?
+                compute_json_diff(
+                    &doc.content_type,
+                    new_content,
+                    &doc.content,
+                    base_state.as_deref(),
+                )?
             } else {
                 // Text/XML: use server's actual Yjs state for proper CRDT merge
                 let base_state = self.doc_store.get_yjs_state(id).await;
@@ -763,6 +874,7 @@ impl DocumentService {
             .set_document_head(id, &cid)
             .await
             .map_err(|e| ServiceError::Internal(e.to_string()))?;
+        drop(write_lock);
 
         self.broadcast_commit(id, &cid, timestamp);
 
@@ -777,18 +889,221 @@ impl DocumentService {
             operations: diff_result.operation_count,
         })
     }
+
+    /// Push a chain of commits a client made on top of `base` while offline.
+    ///
+    /// Each commit carries the full content after it. The server diffs it
+    /// against its parent's content, replayed from `base`, and stores it with
+    /// its original timestamp, so the history shows every intermediate state.
+    /// If HEAD moved on since `base`, a merge commit joins the chain with it.
+    pub async fn push_commits(
+        &self,
+        id: &str,
+        base: &str,
+        commits: Vec<PushedCommit>,
+        author: Option<String>,
+    ) -> Result<PushResult, ServiceError> {
+        let commit_store = self
+            .commit_store
+            .as_ref()
+            .ok_or(ServiceError::NoPersistence)?;
+
+        let doc = self.get_document(id).await?;
+
+        if commits.is_empty() {
+            return Err(ServiceError::InvalidInput("No commits to push".to_string()));
+        }
+        let mut expected_parent = base;
+        for commit in &commits {
+            if commit.parent != expected_parent {
+                return Err(ServiceError::InvalidInput(format!(
+                    "Commit {} does not follow {}",
+                    commit.id, expected_parent
+                )));
+            }
+            expected_parent = &commit.id;
+        }
+
+        let replayer = CommitReplayer::new(commit_store);
+        if !replayer
+            .verify_commit_in_history(id, base)
+            .await
+            .map_err(|_| ServiceError::NotFound)?
+        {
+            return Err(ServiceError::NotFound);
+        }
+        let (mut content, mut state) = replayer
+            .get_content_and_state_at_commit(id, base, &doc.content_type)
+            .await
+            .map_err(|_| ServiceError::NotFound)?;
+
+        let client_id = push_client_id(base, &commits[0].id);
+        let author = author.unwrap_or_else(|| "anonymous".to_string());
+        let mut tip = base.to_string();
+        let mut cids = Vec::with_capacity(commits.len());
+        let mut updates = Vec::with_capacity(commits.len());
+        let mut notifications: Vec<(String, u64)> = Vec::new();
+
+        for pushed in commits {
+            let diff = compute_content_diff(
+                &doc.content_type,
+                client_id,
+                &state,
+                &content,
+                &pushed.content,
+            )?;
+            state = apply_to_state(&state, &diff.update_bytes)?;
+
+            let mut commit = Commit::new(vec![tip], diff.update_b64, author.clone(), None);
+            commit.timestamp = pushed.timestamp;
+            let cid = commit_store
+                .store_commit(&commit)
+                .await
+                .map_err(|e| ServiceError::Internal(e.to_string()))?;
+            notifications.push((cid.clone(), commit.timestamp));
+
+            updates.push(diff.update_bytes);
+            cids.push(cid.clone());
+            tip = cid;
+            content = pushed.content;
+        }
+
+        // Settle the new HEAD before touching the live document, so a
+        // refused push leaves it as it was
+        let write_lock = self.lock_document(id).await;
+        let current_head = commit_store
+            .get_document_head(id)
+            .await
+            .map_err(|e| ServiceError::Internal(e.to_string()))?;
+        let (head, merge_cid) = match current_head {
+            Some(head) if head != base => {
+                let already_pushed = commit_store
+                    .is_ancestor(&tip, &head)
+                    .await
+                    .map_err(|e| ServiceError::Internal(e.to_string()))?;
+                if already_pushed {
+                    // A retry of a push whose response was lost
+                    (head, None)
+                } else {
+                    let merge_commit = Commit::new(
+                        vec![tip, head],
+                        String::new(), // Empty update for merge
+                        author,
+                        Some("Merge commit".to_string()),
+                    );
+                    let merge_cid = commit_store
+                        .store_commit(&merge_commit)
+                        .await
+                        .map_err(|e| ServiceError::Internal(e.to_string()))?;
+                    notifications.push((merge_cid.clone(), merge_commit.timestamp));
+                    (merge_cid.clone(), Some(merge_cid))
+                }
+            }
+            _ => (tip, None),
+        };
+
+        commit_store
+            .validate_monotonic_descent(id, &head)
+            .await
+            .map_err(|_| ServiceError::Conflict)?;
+        for update in &updates {
+            self.doc_store.apply_yjs_update(id, update).await?;
+        }
+        commit_store
+            .set_document_head(id, &head)
+            .await
+            .map_err(|e| ServiceError::Internal(e.to_string()))?;
+        drop(write_lock);
+
+        self.broadcast_commits(id, &notifications);
+
+        // Trigger filesystem reconciliation if this is the fs-root document
+        self.maybe_reconcile(id).await;
+
+        Ok(PushResult {
+            cid: head,
+            commits: cids,
+            merge_cid,
+        })
+    }
+}
+
+/// Compute the update turning `old_content`, whose Yjs state is
+/// `base_state`, into `new_content`.
+fn compute_content_diff(
+    content_type: &ContentType,
+    client_id: u64,
+    base_state: &[u8],
+    old_content: &str,
+    new_content: &str,
+) -> Result<diff::DiffResult, ServiceError> {
+    if matches!(
+        content_type,
+        ContentType::Json | ContentType::JsonArray | ContentType::Jsonl
+    ) {
+        let base_state_b64 = (!base_state.is_empty()).then(|| b64::encode(base_state));
+        compute_json_diff(
+            content_type,
+            new_content,
+            old_content,
+            base_state_b64.as_deref(),
+        )
+    } else if base_state.is_empty() {
+        diff::compute_diff_update(old_content, new_content)
+            .map_err(|e| ServiceError::Internal(e.to_string()))
+    } else {
+        diff::compute_diff_update_with_base_as(client_id, base_state, old_content, new_content)
+            .map_err(|e| ServiceError::Internal(e.to_string()))
+    }
+}
+
+/// The Yjs state after applying `update` to `state`.
+fn apply_to_state(state: &[u8], update: &[u8]) -> Result<Vec<u8>, ServiceError> {
+    let doc = yrs::Doc::new();
+    {
+        let mut txn = doc.transact_mut();
+        for bytes in [state, update] {
+            if bytes.is_empty() {
+                continue;
+            }
+            let update =
+                yrs::Update::decode_v1(bytes).map_err(|e| ServiceError::Internal(e.to_string()))?;
+            txn.apply_update(update);
+        }
+    }
+    let txn = doc.transact();
+    Ok(txn.encode_state_as_update_v1(&yrs::StateVector::default()))
+}
+
+/// The Yjs client a pushed chain's edits, or a replace from an older parent,
+/// are made as.
+///
+/// Derived from the chain rather than random, so pushing it again produces
+/// the same commits; 32 bits like the ids Yjs itself picks.
+fn push_client_id(base: &str, first_commit: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(base.as_bytes());
+    hasher.update(b":");
+    hasher.update(first_commit.as_bytes());
+    let digest = hasher.finalize();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) as u64
 }
 
 /// Compute a JSON diff using Y.Map/Y.Array updates instead of Y.Text.
 ///
-/// Returns a DiffResult compatible with the text diff output.
+/// JSONL content is parsed line by line into the Y.Array. Returns a
+/// DiffResult compatible with the text diff output.
 fn compute_json_diff(
+    content_type: &ContentType,
     new_content: &str,
     old_content: &str,
     base_state_b64: Option<&str>,
 ) -> Result<diff::DiffResult, ServiceError> {
-    let update_b64 = create_yjs_json_update(new_content, base_state_b64)
-        .map_err(|e| ServiceError::Internal(format!("JSON update failed: {}", e)))?;
+    let update_b64 = match content_type {
+        ContentType::Jsonl => create_yjs_jsonl_update(new_content, base_state_b64),
+        _ => create_yjs_json_update(new_content, base_state_b64),
+    }
+    .map_err(|e| ServiceError::Internal(format!("JSON update failed: {}", e)))?;
 
     let update_bytes = base64_decode(&update_b64)
         .map_err(|e| ServiceError::Internal(format!("Base64 decode failed: {}", e)))?;
@@ -5,4 +5,4 @@
 
 pub mod document;
 
-pub use document::{DocumentService, ReplaceResult, ServiceError};
+pub use document::{DocumentService, PushResult, PushedCommit, ReplaceResult, ServiceError};
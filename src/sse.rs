@@ -67,12 +67,12 @@ struct MultiDocQuery {
     since: Option<u64>,
 }
 
-#[derive(Serialize, Clone)]
-struct CommitChange {
-    doc_id: String,
-    commit_id: String,
-    timestamp: u64,
-    url: String,
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct CommitChange {
+    pub(crate) doc_id: String,
+    pub(crate) commit_id: String,
+    pub(crate) timestamp: u64,
+    pub(crate) url: String,
 }
 
 #[derive(Serialize)]
@@ -229,7 +229,7 @@ async fn stream_changes(
     Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(30))))
 }
 
-async fn collect_changes_for_docs(
+pub(crate) async fn collect_changes_for_docs(
     commit_store: &CommitStore,
     doc_ids: &[String],
     since: u64,
@@ -262,11 +262,11 @@ async fn collect_changes_for_docs(
     Ok(changes)
 }
 
-fn commit_url(doc_id: &str, commit_id: &str) -> String {
+pub(crate) fn commit_url(doc_id: &str, commit_id: &str) -> String {
     format!("commonplace://document/{}/commit/{}", doc_id, commit_id)
 }
 
-fn parse_doc_ids(ids: &str) -> Vec<String> {
+pub(crate) fn parse_doc_ids(ids: &str) -> Vec<String> {
     let mut unique = HashSet::new();
     let mut result = Vec::new();
 
@@ -0,0 +1,161 @@
+//! Conflict copies for divergent edits that can't be merged.
+//!
+//! Text edits made while offline merge through the CRDT, but a binary file
+//! (base64 in a Y.Text) or a `--force-push` target can't be merged: one side
+//! has to win. When both the local file and the server changed since the last
+//! sync, the losing version is kept beside the file as
+//! `name.conflict-<author>-<timestamp>.ext` and the conflict is recorded in
+//! the sync state file. Conflict copies are local only: directory sync
+//! neither scans nor watches them.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Which version of a conflicted file was kept in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictSide {
+    Local,
+    Server,
+}
+
+/// A divergent edit that was resolved by keeping one side.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    /// File the conflict happened on, relative to the state file's directory
+    pub path: String,
+    /// Conflict copy holding the version that lost, relative to the same
+    /// directory
+    pub copy: String,
+    /// Author of the version in the conflict copy
+    pub author: String,
+    /// Which version was kept in place
+    pub kept: ConflictSide,
+    /// Commit both sides diverged from
+    pub base_cid: Option<String>,
+    /// Server HEAD when the conflict was detected
+    pub server_cid: Option<String>,
+    /// When the conflict was detected (RFC 3339)
+    pub detected_at: String,
+}
+
+/// Ignore pattern matching conflict copies, as named by [`conflict_copy_path`].
+pub const CONFLICT_COPY_PATTERN: &str = "*.conflict-*";
+
+/// Whether the file name `name` is that of a conflict copy.
+pub fn is_conflict_copy(name: &str) -> bool {
+    name.contains(".conflict-")
+}
+
+/// The author name used for local versions in conflict copies.
+///
+/// Uses `explicit` if given, then `$USER`, then "local".
+pub fn local_author(explicit: Option<&str>) -> String {
+    explicit
+        .map(str::to_string)
+        .or_else(|| std::env::var("USER").ok())
+        .filter(|author| !author.is_empty())
+        .unwrap_or_else(|| "local".to_string())
+}
+
+/// Path of the conflict copy of `file` for a version by `author`.
+///
+/// `notes/photo.png` becomes `notes/photo.conflict-alice-20250102T030405Z.png`.
+/// Characters in `author` that are awkward in file names become `_`.
+pub fn conflict_copy_path(file: &Path, author: &str, at: DateTime<Utc>) -> PathBuf {
+    let author: String = author
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let timestamp = at.format("%Y%m%dT%H%M%SZ");
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = match file.extension() {
+        Some(ext) => format!(
+            "{}.conflict-{}-{}.{}",
+            stem,
+            author,
+            timestamp,
+            ext.to_string_lossy()
+        ),
+        None => format!("{}.conflict-{}-{}", stem, author, timestamp),
+    };
+    file.with_file_name(name)
+}
+
+/// Write `content` to a new conflict copy of `file`, returning its path.
+///
+/// Never overwrites an existing file: a numeric suffix is added if the name
+/// is taken.
+pub async fn write_conflict_copy(file: &Path, author: &str, content: &[u8]) -> io::Result<PathBuf> {
+    let base = conflict_copy_path(file, author, Utc::now());
+    let mut path = base.clone();
+    let mut n = 1;
+    while tokio::fs::try_exists(&path).await? {
+        n += 1;
+        let name = base.file_name().unwrap_or_default().to_string_lossy();
+        let (stem, ext) = match base.extension() {
+            Some(ext) => {
+                let ext = ext.to_string_lossy();
+                (
+                    name[..name.len() - ext.len() - 1].to_string(),
+                    format!(".{}", ext),
+                )
+            }
+            None => (name.to_string(), String::new()),
+        };
+        path = base.with_file_name(format!("{}-{}{}", stem, n, ext));
+    }
+    tokio::fs::write(&path, content).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_conflict_copy_path() {
+        let at = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            conflict_copy_path(Path::new("notes/photo.png"), "alice", at),
+            PathBuf::from("notes/photo.conflict-alice-20250102T030405Z.png")
+        );
+        assert_eq!(
+            conflict_copy_path(Path::new("Makefile"), "bob smith/x", at),
+            PathBuf::from("Makefile.conflict-bob_smith_x-20250102T030405Z")
+        );
+        assert!(is_conflict_copy(
+            "photo.conflict-alice-20250102T030405Z.png"
+        ));
+        assert!(!is_conflict_copy("photo.png"));
+    }
+
+    #[tokio::test]
+    async fn test_write_conflict_copy_never_overwrites() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("data.bin");
+
+        let first = write_conflict_copy(&file, "server", b"one").await.unwrap();
+        let second = write_conflict_copy(&file, "server", b"two").await.unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read(&first).unwrap(), b"one");
+        assert_eq!(std::fs::read(&second).unwrap(), b"two");
+        let name = second.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("data.conflict-server-"));
+        assert!(name.ends_with(".bin"));
+    }
+}
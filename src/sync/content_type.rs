@@ -1,5 +1,6 @@
 //! Content type detection for files.
 
+use crate::sync::ignore_file::is_ignore_file;
 use std::path::Path;
 
 /// Known text file extensions and their MIME types.
@@ -96,7 +97,11 @@ pub const ALLOWED_EXTENSIONS: &[&str] = &[
 
 /// Check if a file path has an allowed extension for syncing.
 /// Returns true if the file should be synced, false otherwise.
+/// `.commonplaceignore` files are always allowed.
 pub fn is_allowed_extension(path: &Path) -> bool {
+    if is_ignore_file(path) {
+        return true;
+    }
     path.extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| ALLOWED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
@@ -164,6 +169,7 @@ pub fn detect_from_path(path: &Path) -> ContentTypeInfo {
                 return ContentTypeInfo::text("text/plain")
             }
             ".env" | ".envrc" => return ContentTypeInfo::text("text/plain"),
+            ".commonplaceignore" => return ContentTypeInfo::text("text/plain"),
             _ => {}
         }
     }
@@ -251,10 +257,40 @@ pub fn is_binary_content(content: &[u8]) -> bool {
     non_text_count > sample_size / 3
 }
 
+/// The bytes a local file holds when it matches server `content`.
+///
+/// Binary content is stored on the server base64-encoded; this decodes it the
+/// same way the sync client does when writing server content to disk.
+pub fn content_to_bytes(path: &Path, content: &str) -> Vec<u8> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    if detect_from_path(path).is_binary {
+        return STANDARD
+            .decode(content)
+            .unwrap_or_else(|_| content.as_bytes().to_vec());
+    }
+    if looks_like_base64_binary(content) {
+        if let Ok(decoded) = STANDARD.decode(content) {
+            if is_binary_content(&decoded) {
+                return decoded;
+            }
+        }
+    }
+    content.as_bytes().to_vec()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_content_to_bytes() {
+        assert_eq!(content_to_bytes(Path::new("a.txt"), "hello"), b"hello");
+        assert_eq!(
+            content_to_bytes(Path::new("a.png"), "AAECAw=="),
+            b"\x00\x01\x02\x03"
+        );
+    }
+
     #[test]
     fn test_text_extensions() {
         assert!(!detect_from_path(Path::new("foo.txt")).is_binary);
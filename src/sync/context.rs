@@ -0,0 +1,24 @@
+//! Handles a sync run shares between the tasks it spawns for each file.
+
+use crate::sync::journal::CommitJournal;
+use crate::sync::watcher::SharedWatcher;
+use crate::sync::ws::WsTransport;
+use std::sync::Arc;
+
+/// What every file's tasks in one sync run share.
+///
+/// A `commonplace-sync` run builds one at startup and passes it down to each
+/// file's tasks; each `commonplace-syncd` root builds its own, so one root's
+/// transport or credentials never leak into another's.
+#[derive(Debug, Clone, Default)]
+pub struct SyncContext {
+    /// Sync eligible text files over WebSocket, if set
+    pub ws: Option<WsTransport>,
+    /// Journal edits that can't reach the server here, if set
+    pub journal: Option<Arc<CommitJournal>>,
+    /// Watch files through this watcher rather than one per task, if set
+    pub watcher: Option<Arc<SharedWatcher>>,
+    /// The directory is thrown away when the run ends (a sandbox): nothing
+    /// is journaled, checkpointed or reported to `commonplace-status`
+    pub ephemeral: bool,
+}
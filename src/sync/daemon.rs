@@ -0,0 +1,805 @@
+//! Many sync roots in one process, for `commonplace-syncd`.
+//!
+//! Each root is a directory or single file synced with a server node, run
+//! the same way `commonplace-sync` runs it, offline journal included. The
+//! roots share the process's HTTP client and file watcher, and each still
+//! answers `commonplace-status` on its own control socket.
+//!
+//! The daemon's control socket is a Unix socket, so the daemon only runs on
+//! Unix. It takes one JSON [`DaemonRequest`] per line and answers each with
+//! one line of JSON ([`DaemonResponse`]), e.g.
+//!
+//! ```text
+//! {"command":"add","root":{"name":"beads","node":"...","file":".beads/issues.jsonl","push_only":true}}
+//! {"command":"pause","name":"beads"}
+//! {"command":"list"}
+//! ```
+
+use crate::sync::status::{control_socket_path, query_control_socket, SyncProcessStatus};
+use crate::sync::{
+    acquire_sync_lock, directory_scan_options, run_directory_sync, run_file_sync, SharedWatcher,
+    SyncContext,
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+#[cfg(unix)]
+use tracing::debug;
+use tracing::{info, warn};
+
+/// How long a failed root waits before it is started again.
+pub const ROOT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How long to wait for the daemon to answer on its control socket.
+#[cfg(unix)]
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A file or directory for the daemon to sync.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootSpec {
+    /// Name the root is removed, paused and resumed by
+    pub name: String,
+    /// Node ID to sync with
+    pub node: String,
+    /// Local directory to sync (exclusive with `file`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+    /// Local file to sync (exclusive with `directory`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Only push local changes; don't subscribe to server edits
+    #[serde(default)]
+    pub push_only: bool,
+    /// Only pull server edits; don't watch local files
+    #[serde(default)]
+    pub pull_only: bool,
+    /// Local content replaces the server's on every change (files only)
+    #[serde(default)]
+    pub force_push: bool,
+    /// Initial sync strategy when both sides have content (directories only)
+    #[serde(default = "default_initial_sync")]
+    pub initial_sync: String,
+    /// Include hidden files (directories only)
+    #[serde(default)]
+    pub include_hidden: bool,
+    /// Gitignore-style patterns to ignore (directories only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+}
+
+fn default_initial_sync() -> String {
+    "skip".to_string()
+}
+
+impl RootSpec {
+    /// Check that the options make sense together.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("root name must not be empty".to_string());
+        }
+        if self.node.is_empty() {
+            return Err(format!("root {}: node must not be empty", self.name));
+        }
+        match (&self.directory, &self.file) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "root {}: directory and file are mutually exclusive",
+                    self.name
+                ))
+            }
+            (None, None) => {
+                return Err(format!(
+                    "root {}: either directory or file is required",
+                    self.name
+                ))
+            }
+            _ => {}
+        }
+        if self.push_only && self.pull_only {
+            return Err(format!(
+                "root {}: push_only and pull_only are mutually exclusive",
+                self.name
+            ));
+        }
+        if self.force_push && self.directory.is_some() {
+            return Err(format!(
+                "root {}: force_push is only supported for files",
+                self.name
+            ));
+        }
+        if !["local", "server", "skip"].contains(&self.initial_sync.as_str()) {
+            return Err(format!(
+                "root {}: initial_sync must be local, server or skip",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// The synced file or directory.
+    pub fn target(&self) -> &Path {
+        self.directory
+            .as_deref()
+            .or(self.file.as_deref())
+            .unwrap_or(Path::new(""))
+    }
+}
+
+/// Roots to start the daemon with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DaemonConfig {
+    #[serde(default)]
+    pub roots: Vec<RootSpec>,
+}
+
+/// Where a root is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RootState {
+    /// Syncing, or doing its initial sync
+    Running,
+    /// Stopped until resumed
+    Paused,
+    /// Stopped by an error; started again after [`ROOT_RETRY_DELAY`]
+    Failed,
+}
+
+/// A root as `list` reports it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootInfo {
+    #[serde(flatten)]
+    pub spec: RootSpec,
+    pub state: RootState,
+    /// Why the root last failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the running sync reports on its control socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncProcessStatus>,
+}
+
+/// A request on the daemon's control socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    /// Start syncing a new root
+    Add { root: RootSpec },
+    /// Stop syncing a root and forget it
+    Remove { name: String },
+    /// Stop syncing a root until it is resumed
+    Pause { name: String },
+    /// Start a paused root again, or retry a failed one now
+    Resume { name: String },
+    /// Report every root
+    List,
+}
+
+/// The daemon's answer: the roots after the request, or why it failed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DaemonResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub roots: Vec<RootInfo>,
+}
+
+/// Sent to a root's supervisor task.
+enum RootCommand {
+    /// Resume a paused root, or retry a failed one
+    Resume,
+    /// Stop the root, answering once it has stopped
+    Pause(oneshot::Sender<()>),
+    /// Stop the root for good, answering once it has stopped
+    Remove(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone)]
+struct RootStatus {
+    state: RootState,
+    error: Option<String>,
+}
+
+struct RootHandle {
+    spec: RootSpec,
+    status: Arc<Mutex<RootStatus>>,
+    commands: mpsc::UnboundedSender<RootCommand>,
+}
+
+/// The roots a `commonplace-syncd` process syncs.
+pub struct SyncDaemon {
+    client: Client,
+    server: String,
+    author: String,
+    /// Watches every root's files, if it could be created
+    watcher: Option<Arc<SharedWatcher>>,
+    roots: Mutex<BTreeMap<String, RootHandle>>,
+}
+
+impl SyncDaemon {
+    /// A daemon syncing with `server` through `client`, naming this client
+    /// `author` in conflict copies.
+    pub fn new(client: Client, server: String, author: String) -> Arc<Self> {
+        let watcher = match SharedWatcher::new() {
+            Ok(watcher) => Some(Arc::new(watcher)),
+            Err(e) => {
+                warn!(
+                    "Failed to create shared file watcher, each root will watch on its own: {}",
+                    e
+                );
+                None
+            }
+        };
+        Arc::new(Self {
+            client,
+            server,
+            author,
+            watcher,
+            roots: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Start syncing `spec`.
+    pub fn add(self: &Arc<Self>, spec: RootSpec) -> Result<(), String> {
+        spec.validate()?;
+        let mut roots = self.roots.lock().unwrap();
+        if roots.contains_key(&spec.name) {
+            return Err(format!("root {} already exists", spec.name));
+        }
+        let target = canonical_target(spec.target());
+        if let Some(other) = roots
+            .values()
+            .find(|root| canonical_target(root.spec.target()) == target)
+        {
+            return Err(format!(
+                "{} is already synced by root {}",
+                spec.target().display(),
+                other.spec.name
+            ));
+        }
+
+        info!("Adding root {} ({})", spec.name, spec.target().display());
+        let status = Arc::new(Mutex::new(RootStatus {
+            state: RootState::Running,
+            error: None,
+        }));
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        tokio::spawn(supervise_root(
+            self.clone(),
+            spec.clone(),
+            status.clone(),
+            commands_rx,
+        ));
+        roots.insert(
+            spec.name.clone(),
+            RootHandle {
+                spec,
+                status,
+                commands,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop syncing the root `name` and forget it.
+    pub async fn remove(&self, name: &str) -> Result<(), String> {
+        let root = self
+            .roots
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| unknown_root(name))?;
+        info!("Removing root {}", name);
+        let (ack, stopped) = oneshot::channel();
+        if root.commands.send(RootCommand::Remove(ack)).is_ok() {
+            let _ = stopped.await;
+        }
+        Ok(())
+    }
+
+    /// Stop syncing the root `name` until it is resumed.
+    pub async fn pause(&self, name: &str) -> Result<(), String> {
+        let (ack, stopped) = oneshot::channel();
+        self.send(name, RootCommand::Pause(ack))?;
+        info!("Pausing root {}", name);
+        let _ = stopped.await;
+        Ok(())
+    }
+
+    /// Start the paused or failed root `name` again.
+    pub fn resume(&self, name: &str) -> Result<(), String> {
+        self.send(name, RootCommand::Resume)?;
+        info!("Resuming root {}", name);
+        Ok(())
+    }
+
+    fn send(&self, name: &str, command: RootCommand) -> Result<(), String> {
+        let roots = self.roots.lock().unwrap();
+        let root = roots.get(name).ok_or_else(|| unknown_root(name))?;
+        root.commands
+            .send(command)
+            .map_err(|_| format!("root {} has stopped", name))
+    }
+
+    /// Every root, with what each running sync reports.
+    pub async fn list(&self) -> Vec<RootInfo> {
+        let roots: Vec<(RootSpec, RootStatus)> = self
+            .roots
+            .lock()
+            .unwrap()
+            .values()
+            .map(|root| (root.spec.clone(), root.status.lock().unwrap().clone()))
+            .collect();
+
+        let mut infos = Vec::with_capacity(roots.len());
+        for (spec, status) in roots {
+            let sync = if status.state == RootState::Running {
+                query_control_socket(&control_socket_path(spec.target()))
+                    .await
+                    .ok()
+                    .flatten()
+            } else {
+                None
+            };
+            infos.push(RootInfo {
+                spec,
+                state: status.state,
+                error: status.error,
+                sync,
+            });
+        }
+        infos
+    }
+
+    /// Carry out a control socket request.
+    pub async fn handle(self: &Arc<Self>, request: DaemonRequest) -> DaemonResponse {
+        let result = match request {
+            DaemonRequest::Add { root } => self.add(root),
+            DaemonRequest::Remove { name } => self.remove(&name).await,
+            DaemonRequest::Pause { name } => self.pause(&name).await,
+            DaemonRequest::Resume { name } => self.resume(&name),
+            DaemonRequest::List => Ok(()),
+        };
+        match result {
+            Ok(()) => DaemonResponse {
+                error: None,
+                roots: self.list().await,
+            },
+            Err(error) => DaemonResponse {
+                error: Some(error),
+                roots: Vec::new(),
+            },
+        }
+    }
+
+    /// Stop every root, leaving each checkpointed and its socket removed.
+    pub async fn shutdown(&self) {
+        let names: Vec<String> = self.roots.lock().unwrap().keys().cloned().collect();
+        futures::future::join_all(names.iter().map(|name| self.remove(name))).await;
+    }
+}
+
+fn unknown_root(name: &str) -> String {
+    format!("no root named {}", name)
+}
+
+/// `target` in canonical form if it exists, for spotting a target added twice.
+fn canonical_target(target: &Path) -> PathBuf {
+    target
+        .canonicalize()
+        .unwrap_or_else(|_| target.to_path_buf())
+}
+
+/// Run a root until it is removed: pausing and resuming it on command, and
+/// starting it again after [`ROOT_RETRY_DELAY`] if it fails.
+async fn supervise_root(
+    daemon: Arc<SyncDaemon>,
+    spec: RootSpec,
+    status: Arc<Mutex<RootStatus>>,
+    mut commands: mpsc::UnboundedReceiver<RootCommand>,
+) {
+    let set_status = |state, error| *status.lock().unwrap() = RootStatus { state, error };
+    let mut paused = false;
+
+    loop {
+        if paused {
+            set_status(RootState::Paused, None);
+            match commands.recv().await {
+                Some(RootCommand::Resume) => paused = false,
+                Some(RootCommand::Pause(ack)) => {
+                    let _ = ack.send(());
+                }
+                Some(RootCommand::Remove(ack)) => {
+                    let _ = ack.send(());
+                    return;
+                }
+                None => return,
+            }
+            continue;
+        }
+
+        set_status(RootState::Running, None);
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let run = run_root(&daemon, &spec, async {
+            let _ = stop_rx.await;
+        });
+        tokio::pin!(run);
+
+        let mut stop_tx = Some(stop_tx);
+        let mut ack = None;
+        let mut removed = false;
+        let result = loop {
+            tokio::select! {
+                result = &mut run => break result,
+                command = commands.recv(), if stop_tx.is_some() => {
+                    match command {
+                        Some(RootCommand::Resume) => continue,
+                        Some(RootCommand::Pause(tx)) => {
+                            paused = true;
+                            ack = Some(tx);
+                        }
+                        Some(RootCommand::Remove(tx)) => {
+                            removed = true;
+                            ack = Some(tx);
+                        }
+                        None => removed = true,
+                    }
+                    if let Some(stop) = stop_tx.take() {
+                        let _ = stop.send(());
+                    }
+                }
+            }
+        };
+        // Answer once the root's sockets and lock are gone
+        if let Some(ack) = ack {
+            let _ = ack.send(());
+        }
+        if removed {
+            if let Err(e) = result {
+                warn!("Root {} stopped with an error: {}", spec.name, e);
+            }
+            return;
+        }
+        if paused {
+            continue;
+        }
+
+        let error = result
+            .err()
+            .unwrap_or_else(|| "sync stopped unexpectedly".to_string());
+        warn!(
+            "Root {} failed: {}; retrying in {:?}",
+            spec.name, error, ROOT_RETRY_DELAY
+        );
+        set_status(RootState::Failed, Some(error));
+        tokio::select! {
+            _ = sleep(ROOT_RETRY_DELAY) => {}
+            command = commands.recv() => match command {
+                Some(RootCommand::Resume) => {}
+                Some(RootCommand::Pause(tx)) => {
+                    paused = true;
+                    let _ = tx.send(());
+                }
+                Some(RootCommand::Remove(tx)) => {
+                    let _ = tx.send(());
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+/// Sync one root until `shutdown` completes.
+async fn run_root(
+    daemon: &SyncDaemon,
+    spec: &RootSpec,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), String> {
+    // Each root opens its own journal, but they all share the one watcher
+    let context = SyncContext {
+        watcher: daemon.watcher.clone(),
+        ..Default::default()
+    };
+    if let Some(directory) = &spec.directory {
+        // Held until the sync has stopped, as commonplace-sync holds it
+        let _sync_lock = acquire_sync_lock(directory).map_err(|e| e.to_string())?;
+        run_directory_sync(
+            daemon.client.clone(),
+            daemon.server.clone(),
+            spec.node.clone(),
+            directory.clone(),
+            directory_scan_options(spec.include_hidden, spec.ignore.clone()),
+            spec.initial_sync.clone(),
+            false,
+            spec.push_only,
+            spec.pull_only,
+            daemon.author.clone(),
+            context,
+            shutdown,
+        )
+        .await
+        .map_err(|e| e.to_string())
+    } else if let Some(file) = &spec.file {
+        run_file_sync(
+            daemon.client.clone(),
+            daemon.server.clone(),
+            spec.node.clone(),
+            file.clone(),
+            spec.push_only,
+            spec.pull_only,
+            spec.force_push,
+            daemon.author.clone(),
+            context,
+            shutdown,
+        )
+        .await
+        .map_err(|e| e.to_string())
+    } else {
+        Err(format!("root {} has nothing to sync", spec.name))
+    }
+}
+
+/// Answer requests on a Unix socket at `socket_path`.
+///
+/// As with the control socket of a single sync, a socket left behind by a
+/// daemon that didn't shut down cleanly is replaced; one that still answers is
+/// an error.
+#[cfg(unix)]
+pub async fn serve_daemon_socket(
+    socket_path: &Path,
+    daemon: Arc<SyncDaemon>,
+) -> io::Result<JoinHandle<()>> {
+    if UnixStream::connect(socket_path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!(
+                "another commonplace-syncd is already running ({})",
+                socket_path.display()
+            ),
+        ));
+    }
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Daemon socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            let daemon = daemon.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_daemon_connection(stream, &daemon).await {
+                    debug!("Daemon connection closed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// The daemon is managed over a Unix socket, so can't be run elsewhere.
+#[cfg(not(unix))]
+pub async fn serve_daemon_socket(
+    _socket_path: &Path,
+    _daemon: Arc<SyncDaemon>,
+) -> io::Result<JoinHandle<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "commonplace-syncd needs Unix sockets",
+    ))
+}
+
+#[cfg(unix)]
+async fn handle_daemon_connection(stream: UnixStream, daemon: &Arc<SyncDaemon>) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<DaemonRequest>(line) {
+            Ok(request) => daemon.handle(request).await,
+            Err(e) => DaemonResponse {
+                error: Some(format!("invalid request: {}", e)),
+                roots: Vec::new(),
+            },
+        };
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        write.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Send `request` to the daemon listening at `socket_path`.
+#[cfg(unix)]
+pub async fn query_daemon(
+    socket_path: &Path,
+    request: &DaemonRequest,
+) -> io::Result<DaemonResponse> {
+    let query = async {
+        let stream = UnixStream::connect(socket_path).await?;
+        let (read, mut write) = stream.into_split();
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+        let reply = BufReader::new(read)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no reply"))?;
+        serde_json::from_str(&reply).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    tokio::time::timeout(DAEMON_TIMEOUT, query)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "commonplace-syncd did not answer"))?
+}
+
+/// Without Unix sockets there is no daemon to reach.
+#[cfg(not(unix))]
+pub async fn query_daemon(
+    _socket_path: &Path,
+    _request: &DaemonRequest,
+) -> io::Result<DaemonResponse> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "commonplace-syncd needs Unix sockets",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn file_root(name: &str, file: &Path) -> RootSpec {
+        RootSpec {
+            name: name.to_string(),
+            node: "doc".to_string(),
+            directory: None,
+            file: Some(file.to_path_buf()),
+            push_only: false,
+            pull_only: false,
+            force_push: false,
+            initial_sync: default_initial_sync(),
+            include_hidden: false,
+            ignore: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let request: DaemonRequest = serde_json::from_str(
+            r#"{"command":"add","root":{"name":"beads","node":"workspace/beads/issues.jsonl","file":".beads/issues.jsonl","push_only":true,"force_push":true}}"#,
+        )
+        .unwrap();
+        let DaemonRequest::Add { root } = request else {
+            panic!("expected add, got {:?}", request);
+        };
+        assert_eq!(root.file, Some(PathBuf::from(".beads/issues.jsonl")));
+        assert!(root.push_only && root.force_push && !root.pull_only);
+        assert_eq!(root.initial_sync, "skip");
+        assert!(root.validate().is_ok());
+
+        assert_eq!(
+            serde_json::from_str::<DaemonRequest>(r#"{"command":"pause","name":"beads"}"#).unwrap(),
+            DaemonRequest::Pause {
+                name: "beads".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<DaemonRequest>(r#"{"command":"list"}"#).unwrap(),
+            DaemonRequest::List
+        );
+        assert!(serde_json::from_str::<DaemonRequest>(r#"{"command":"restart"}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_root() {
+        let mut root = file_root("notes", Path::new("notes.txt"));
+        assert!(root.validate().is_ok());
+
+        root.directory = Some(PathBuf::from("notes"));
+        assert!(root.validate().unwrap_err().contains("mutually exclusive"));
+        root.file = None;
+        root.force_push = true;
+        assert!(root.validate().unwrap_err().contains("force_push"));
+        root.force_push = false;
+        root.push_only = true;
+        root.pull_only = true;
+        assert!(root.validate().unwrap_err().contains("push_only"));
+        root.pull_only = false;
+        root.initial_sync = "merge".to_string();
+        assert!(root.validate().unwrap_err().contains("initial_sync"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_root_can_be_paused_and_removed() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("notes.txt");
+        std::fs::write(&file, "hello").unwrap();
+        // Nothing listens on port 1, so the root fails
+        let daemon = SyncDaemon::new(
+            Client::new(),
+            "http://127.0.0.1:1".to_string(),
+            "test".to_string(),
+        );
+        daemon.add(file_root("notes", &file)).unwrap();
+        assert!(daemon
+            .add(file_root("notes", Path::new("other.txt")))
+            .unwrap_err()
+            .contains("already exists"));
+        assert!(daemon
+            .add(file_root("again", &file))
+            .unwrap_err()
+            .contains("already synced by root notes"));
+
+        let failed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let roots = daemon.list().await;
+                if roots[0].state == RootState::Failed {
+                    return roots[0].clone();
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("root never failed");
+        assert!(failed.error.is_some());
+
+        daemon.pause("notes").await.unwrap();
+        let roots = daemon.list().await;
+        assert_eq!(roots[0].state, RootState::Paused);
+        assert_eq!(roots[0].error, None);
+
+        daemon.remove("notes").await.unwrap();
+        assert!(daemon.list().await.is_empty());
+        assert_eq!(daemon.resume("notes").unwrap_err(), "no root named notes");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_daemon_socket_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let socket = temp.path().join("syncd.sock");
+        let daemon = SyncDaemon::new(
+            Client::new(),
+            "http://127.0.0.1:1".to_string(),
+            "test".to_string(),
+        );
+        let handle = serve_daemon_socket(&socket, daemon.clone()).await.unwrap();
+        // A second daemon on the same socket is refused
+        assert!(serve_daemon_socket(&socket, daemon.clone()).await.is_err());
+
+        let response = query_daemon(&socket, &DaemonRequest::List).await.unwrap();
+        assert_eq!(response.error, None);
+        assert!(response.roots.is_empty());
+
+        let response = query_daemon(
+            &socket,
+            &DaemonRequest::Remove {
+                name: "missing".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.error.as_deref(), Some("no root named missing"));
+        handle.abort();
+    }
+}
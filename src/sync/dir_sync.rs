@@ -4,7 +4,13 @@
 //! with a server document, including schema traversal and UUID mapping.
 
 use crate::fs::{Entry, FsSchema};
-use crate::sync::directory::{scan_directory, schema_to_json, ScanOptions};
+use crate::sync::context::SyncContext;
+use crate::sync::directory::{
+    load_existing_node_ids, move_node_ids, scan_directory, scan_directory_with_node_ids,
+    schema_to_json, ScanOptions,
+};
+use crate::sync::ignore_file::{is_hidden_name, IgnoreRules};
+use crate::sync::sparse::SparseFilter;
 use crate::sync::state_file::{
     compute_content_hash, load_synced_directories, mark_directory_synced, unmark_directory_synced,
 };
@@ -79,6 +85,8 @@ async fn cleanup_orphaned_directories(directory: &Path, schema: &FsSchema) {
     };
 
     let mut dirs_to_remove = Vec::new();
+    // Directories excluded by .commonplaceignore are never in the schema
+    let mut ignore = IgnoreRules::new(directory, &ScanOptions::default());
 
     while let Ok(Some(entry)) = entries.next_entry().await {
         let path = entry.path();
@@ -97,7 +105,7 @@ async fn cleanup_orphaned_directories(directory: &Path, schema: &FsSchema) {
         }
 
         // If this directory isn't in the schema, mark it for removal
-        if !schema_dirs.contains(&name) {
+        if !schema_dirs.contains(&name) && !ignore.is_ignored(&path, true) {
             dirs_to_remove.push((path, name));
         }
     }
@@ -126,14 +134,19 @@ async fn cleanup_orphaned_directories(directory: &Path, schema: &FsSchema) {
 /// 3. Were previously synced (tracked in state file)
 ///
 /// These directories should be removed from the schema because the user
-/// intentionally deleted them locally.
+/// intentionally deleted them locally. Directories a sparse checkout leaves
+/// out are missing on purpose and never count as deleted.
 pub async fn find_locally_deleted_directories(directory: &Path, schema: &FsSchema) -> Vec<String> {
     let schema_dirs = collect_schema_directories(schema);
     let synced_dirs = load_synced_directories(directory).await;
+    let sparse = SparseFilter::load(directory);
 
     let mut deleted = Vec::new();
 
     for dir_name in &schema_dirs {
+        if !sparse.may_contain(dir_name) {
+            continue;
+        }
         let dir_path = directory.join(dir_name);
         // Directory in schema but not on disk
         if !dir_path.exists() {
@@ -176,6 +189,7 @@ fn collect_schema_directories(schema: &FsSchema) -> std::collections::HashSet<St
 /// schemas locally so they can be restored if the server's database is cleared.
 ///
 /// Uses content-based deduplication to prevent redundant writes and feedback loops.
+/// Directories a sparse checkout leaves out aren't created.
 pub async fn write_nested_schemas(
     client: &Client,
     server: &str,
@@ -189,6 +203,7 @@ pub async fn write_nested_schemas(
             client,
             server,
             directory,
+            &SparseFilter::load(directory),
             root,
             directory,
             &mut processed_hashes,
@@ -206,11 +221,22 @@ pub async fn write_nested_schemas(
 async fn write_nested_schemas_recursive(
     client: &Client,
     server: &str,
-    _base_dir: &Path,
+    base_dir: &Path,
+    sparse: &SparseFilter,
     entry: &Entry,
     current_dir: &Path,
     processed_hashes: &mut HashMap<String, String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    if !current_dir.exists() {
+        let relative = current_dir
+            .strip_prefix(base_dir)
+            .map(|p| normalize_path(&p.to_string_lossy()))
+            .unwrap_or_default();
+        if !sparse.may_contain(&relative) {
+            debug!("Not checking out {} (sparse checkout)", relative);
+            return Ok(());
+        }
+    }
     if let Entry::Dir(dir) = entry {
         // Handle node-backed directory - fetch and write its schema
         if let Some(ref node_id) = dir.node_id {
@@ -263,7 +289,8 @@ async fn write_nested_schemas_recursive(
                                     write_nested_schemas_recursive(
                                         client,
                                         server,
-                                        _base_dir,
+                                        base_dir,
+                                        sparse,
                                         sub_root,
                                         current_dir,
                                         processed_hashes,
@@ -285,7 +312,8 @@ async fn write_nested_schemas_recursive(
                     write_nested_schemas_recursive(
                         client,
                         server,
-                        _base_dir,
+                        base_dir,
+                        sparse,
                         child,
                         &child_dir,
                         processed_hashes,
@@ -426,6 +454,7 @@ pub async fn handle_schema_change(
     use_paths: bool,
     push_only: bool,
     pull_only: bool,
+    context: &SyncContext,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Fetch current schema from server (fs-root schema always uses ID-based API)
     let head_url = format!("{}/docs/{}/head", server, encode_node_id(fs_root_id));
@@ -482,9 +511,16 @@ pub async fn handle_schema_change(
     // Find directories that were synced but are now deleted locally
     // These should not be recreated (user intentionally deleted them)
     let locally_deleted_dirs = find_locally_deleted_directories(directory, &schema).await;
+    let sparse = SparseFilter::load(directory);
 
     for (path, explicit_node_id) in &schema_paths {
         if !known_paths.contains(path) {
+            // Paths outside a sparse checkout stay on the server only
+            if !sparse.includes(path) {
+                debug!("Not checking out {} (sparse checkout)", path);
+                continue;
+            }
+
             // Check if the file's parent directory was deleted locally
             // If so, skip creating this file (it will be removed from schema)
             let path_parts: Vec<&str> = path.split('/').collect();
@@ -595,8 +631,11 @@ pub async fn handle_schema_change(
                             current_write_id: 0,
                             pending_write: None,
                             needs_head_refresh: false,
-                            state_file: None, // Directory mode doesn't use state files yet
+                            // Directory mode checkpoints every file into one
+                            // state file instead (see checkpoint_directory_state)
+                            state_file: None,
                             state_file_path: None,
+                            upload_in_flight: Default::default(),
                         }));
 
                         info!("Created local file: {}", file_path.display());
@@ -613,6 +652,7 @@ pub async fn handle_schema_change(
                                 push_only,
                                 pull_only,
                                 false, // force_push: directory mode doesn't support force-push
+                                context,
                             )
                         } else {
                             Vec::new()
@@ -703,6 +743,7 @@ pub async fn directory_sse_task(
     use_paths: bool,
     push_only: bool,
     pull_only: bool,
+    context: SyncContext,
 ) {
     // fs-root schema subscription always uses ID-based API
     let sse_url = format!("{}/sse/docs/{}", server, encode_node_id(&fs_root_id));
@@ -749,6 +790,7 @@ pub async fn directory_sse_task(
                                 &mut last_schema_hash,
                                 push_only,
                                 pull_only,
+                                &context,
                             )
                             .await
                             {
@@ -799,6 +841,7 @@ pub async fn subdir_sse_task(
     use_paths: bool,
     push_only: bool,
     pull_only: bool,
+    context: SyncContext,
 ) {
     let sse_url = format!("{}/sse/docs/{}", server, encode_node_id(&subdir_node_id));
 
@@ -849,6 +892,7 @@ pub async fn subdir_sse_task(
                                 use_paths,
                                 push_only,
                                 pull_only,
+                                &context,
                             )
                             .await
                             {
@@ -897,6 +941,7 @@ async fn handle_schema_change_with_dedup(
     last_schema_hash: &mut Option<String>,
     push_only: bool,
     pull_only: bool,
+    context: &SyncContext,
 ) -> Result<bool, Box<dyn std::error::Error>> {
     // Fetch current schema from server
     let head_url = format!("{}/docs/{}/head", server, encode_node_id(fs_root_id));
@@ -933,6 +978,7 @@ async fn handle_schema_change_with_dedup(
         use_paths,
         push_only,
         pull_only,
+        context,
     )
     .await?;
 
@@ -1081,6 +1127,7 @@ pub async fn handle_file_created(
     use_paths: bool,
     push_only: bool,
     pull_only: bool,
+    context: &SyncContext,
 ) {
     debug!("Directory event: file created: {}", path.display());
 
@@ -1124,42 +1171,8 @@ pub async fn handle_file_created(
         relative_path, owning_doc.document_id, owning_doc.relative_path
     );
 
-    // Check if file matches ignore patterns
-    let file_name = path
-        .file_name()
-        .map(|n| n.to_string_lossy().to_string())
-        .unwrap_or_default();
-    let should_ignore = options.ignore_patterns.iter().any(|pattern| {
-        if pattern == &file_name {
-            true
-        } else if pattern.contains('*') {
-            let parts: Vec<&str> = pattern.split('*').collect();
-            if parts.len() == 2 {
-                file_name.starts_with(parts[0]) && file_name.ends_with(parts[1])
-            } else {
-                false
-            }
-        } else {
-            false
-        }
-    });
-    if should_ignore {
-        debug!(
-            "Ignoring new file (matches ignore pattern): {}",
-            relative_path
-        );
-        return;
-    }
-
-    // Skip hidden files unless configured to include them
-    if !options.include_hidden && file_name.starts_with('.') {
-        debug!("Ignoring hidden file: {}", relative_path);
-        return;
-    }
-
-    // Skip files with disallowed extensions
-    if !is_allowed_extension(path) {
-        debug!("Ignoring file with disallowed extension: {}", relative_path);
+    if is_ignored(directory, path, options) {
+        debug!("Ignoring new file: {}", relative_path);
         return;
     }
 
@@ -1325,6 +1338,7 @@ pub async fn handle_file_created(
             push_only,
             pull_only,
             false, // force_push: directory mode doesn't support force-push
+            context,
         );
 
         // Add to file_states with task handles
@@ -1347,6 +1361,197 @@ pub async fn handle_file_created(
     }
 }
 
+/// Whether a new or renamed file at `path` is left out of the sync: it is
+/// excluded by ignore patterns or `.commonplaceignore`, is hidden, or has a
+/// disallowed extension.
+fn is_ignored(directory: &Path, path: &Path, options: &ScanOptions) -> bool {
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (!options.include_hidden && is_hidden_name(&file_name))
+        || (!path.is_dir() && !is_allowed_extension(path))
+        || IgnoreRules::new(directory, options).is_ignored(path, path.is_dir())
+}
+
+/// Path of `path` relative to `directory`, normalized to forward slashes.
+/// The path need not exist any more.
+fn relative_path_in(directory: &Path, path: &Path) -> Option<String> {
+    let canonical_dir = directory.canonicalize().ok()?;
+    let absolute = match path.canonicalize() {
+        Ok(p) => p,
+        // Gone: resolve the parent and re-attach the name
+        Err(_) => {
+            let parent = path.parent()?.canonicalize().ok()?;
+            parent.join(path.file_name()?)
+        }
+    };
+    let relative = absolute.strip_prefix(&canonical_dir).ok()?;
+    Some(normalize_path(&relative.to_string_lossy()))
+}
+
+/// Handle a rename in directory sync mode.
+///
+/// The schema entry moves with its node_id (for a directory, with every
+/// node_id below it), so the server keeps the same documents and their
+/// history. Sync tasks for moved files restart on their new paths. A rename
+/// of something never synced, or to an ignored name, is handled as a
+/// delete plus a create.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_file_renamed(
+    client: &Client,
+    server: &str,
+    fs_root_id: &str,
+    directory: &Path,
+    from: &Path,
+    to: &Path,
+    options: &ScanOptions,
+    file_states: &Arc<RwLock<HashMap<String, FileSyncState>>>,
+    use_paths: bool,
+    push_only: bool,
+    pull_only: bool,
+    context: &SyncContext,
+) {
+    debug!(
+        "Directory event: renamed: {} -> {}",
+        from.display(),
+        to.display()
+    );
+    let (Some(from_rel), Some(to_rel)) = (
+        relative_path_in(directory, from),
+        relative_path_in(directory, to),
+    ) else {
+        warn!(
+            "Could not resolve rename {} -> {} within {}",
+            from.display(),
+            to.display(),
+            directory.display()
+        );
+        return;
+    };
+
+    let from_owner = find_owning_document(directory, fs_root_id, &from_rel);
+    let to_owner = find_owning_document(directory, fs_root_id, &to_rel);
+    let mut from_ids = load_existing_node_ids(&from_owner.directory);
+    let mut moved = HashMap::new();
+    if is_ignored(directory, to, options)
+        || !move_node_ids(
+            &mut from_ids,
+            &from_owner.relative_path,
+            &mut moved,
+            &to_owner.relative_path,
+        )
+    {
+        handle_file_deleted(
+            client,
+            server,
+            fs_root_id,
+            directory,
+            from,
+            options,
+            file_states,
+        )
+        .await;
+        handle_file_created(
+            client,
+            server,
+            fs_root_id,
+            directory,
+            to,
+            options,
+            file_states,
+            use_paths,
+            push_only,
+            pull_only,
+            context,
+        )
+        .await;
+        return;
+    }
+
+    // Push the destination schema first so the moved nodes are never
+    // missing from the tree, then the source if it's another document
+    let same_owner = from_owner.document_id == to_owner.document_id;
+    if same_owner {
+        from_ids.extend(moved);
+        push_owner_schema(client, server, &to_owner, options, &from_ids).await;
+    } else {
+        let mut to_ids = load_existing_node_ids(&to_owner.directory);
+        to_ids.extend(moved);
+        push_owner_schema(client, server, &to_owner, options, &to_ids).await;
+        push_owner_schema(client, server, &from_owner, options, &from_ids).await;
+    }
+
+    // Restart sync tasks for moved files at their new paths
+    let prefix = format!("{}/", from_rel);
+    let mut states = file_states.write().await;
+    if let Some(replaced) = states.remove(&to_rel) {
+        for handle in replaced.task_handles {
+            handle.abort();
+        }
+    }
+    let moved_paths: Vec<String> = states
+        .keys()
+        .filter(|path| **path == from_rel || path.starts_with(&prefix))
+        .cloned()
+        .collect();
+    for old_path in moved_paths {
+        let Some(mut file_state) = states.remove(&old_path) else {
+            continue;
+        };
+        for handle in file_state.task_handles.drain(..) {
+            handle.abort();
+        }
+        let new_path = format!("{}{}", to_rel, &old_path[from_rel.len()..]);
+        if file_state.use_paths {
+            file_state.identifier = new_path.clone();
+        }
+        file_state.relative_path = new_path.clone();
+        file_state.task_handles = spawn_file_sync_tasks(
+            client.clone(),
+            server.to_string(),
+            file_state.identifier.clone(),
+            directory.join(&new_path),
+            file_state.state.clone(),
+            file_state.use_paths,
+            push_only,
+            pull_only,
+            false, // force_push: directory mode doesn't support force-push
+            context,
+        );
+        info!("Renamed {} -> {}", old_path, new_path);
+        states.insert(new_path, file_state);
+    }
+}
+
+/// Scan an owning document's directory with `node_ids`, push the schema,
+/// and record it locally so later scans keep the node_ids.
+async fn push_owner_schema(
+    client: &Client,
+    server: &str,
+    owner: &OwningDocument,
+    options: &ScanOptions,
+    node_ids: &HashMap<String, String>,
+) {
+    let json = match scan_directory_with_node_ids(&owner.directory, options, node_ids)
+        .map_err(|e| e.to_string())
+        .and_then(|schema| schema_to_json(&schema).map_err(|e| e.to_string()))
+    {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("Failed to scan {}: {}", owner.directory.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = push_schema_to_server(client, server, &owner.document_id, &json).await {
+        warn!("Failed to push updated schema: {}", e);
+        return;
+    }
+    if let Err(e) = write_schema_file(&owner.directory, &json).await {
+        warn!("Failed to write schema file: {}", e);
+    }
+}
+
 /// Handle a file modification event in directory sync mode.
 ///
 /// Modified files are handled by per-file watchers, so this just updates
@@ -1442,6 +1647,20 @@ pub async fn sync_schema(
     initial_sync_strategy: &str,
     server_has_content: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
+    // A sparse checkout keeps the entries it leaves out from the last known
+    // schema; on a first sync that is the server's
+    if server_has_content
+        && !directory.join(SCHEMA_FILENAME).exists()
+        && !SparseFilter::load(directory).is_full()
+    {
+        let head_url = format!("{}/docs/{}/head", server, encode_node_id(fs_root_id));
+        let resp = client.get(&head_url).send().await?;
+        if resp.status().is_success() {
+            let head: HeadResponse = resp.json().await?;
+            write_schema_file(directory, &head.content).await?;
+        }
+    }
+
     // Scan directory and generate FS schema
     info!("Scanning directory...");
     let schema = scan_directory(directory, options).map_err(|e| format!("Scan error: {}", e))?;
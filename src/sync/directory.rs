@@ -2,6 +2,8 @@
 
 use crate::fs::{DirEntry, DocEntry, Entry, FsSchema};
 use crate::sync::content_type::{detect_from_path, is_allowed_extension, is_binary_content};
+use crate::sync::ignore_file::{is_hidden_name, IgnoreRules};
+use crate::sync::sparse::SparseFilter;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
@@ -45,7 +47,7 @@ fn extract_node_ids(entry: &Entry, prefix: &str) -> HashMap<String, String> {
 }
 
 /// Load existing node_ids from .commonplace.json if present.
-fn load_existing_node_ids(directory: &Path) -> HashMap<String, String> {
+pub fn load_existing_node_ids(directory: &Path) -> HashMap<String, String> {
     let schema_path = directory.join(SCHEMA_FILENAME);
     if !schema_path.exists() {
         return HashMap::new();
@@ -66,6 +68,30 @@ fn load_existing_node_ids(directory: &Path) -> HashMap<String, String> {
     }
 }
 
+/// Move the node_ids recorded at `from` (and below it, for a directory) in
+/// `source` to `to` in `target`.
+///
+/// Returns whether anything was moved.
+pub fn move_node_ids(
+    source: &mut HashMap<String, String>,
+    from: &str,
+    target: &mut HashMap<String, String>,
+    to: &str,
+) -> bool {
+    let prefix = format!("{}/", from);
+    let moved: Vec<String> = source
+        .keys()
+        .filter(|path| *path == from || path.starts_with(&prefix))
+        .cloned()
+        .collect();
+    for path in &moved {
+        if let Some(node_id) = source.remove(path) {
+            target.insert(format!("{}{}", to, &path[from.len()..]), node_id);
+        }
+    }
+    !moved.is_empty()
+}
+
 /// Normalize a path to use forward slashes regardless of OS.
 ///
 /// Schema paths always use forward slashes, so relative paths must be
@@ -100,8 +126,12 @@ pub enum ScanError {
 pub struct ScanOptions {
     /// Include hidden files (starting with '.')
     pub include_hidden: bool,
-    /// Custom ignore patterns (glob-style)
+    /// Custom ignore patterns (gitignore syntax), applied at the scan root
+    /// beneath any `.commonplaceignore` files
     pub ignore_patterns: Vec<String>,
+    /// Sparse-checkout patterns to use instead of the `.commonplace-sparse`
+    /// file, relative to the scan root (for previewing a sparse checkout)
+    pub include_patterns: Option<Vec<String>>,
 }
 
 /// Result of scanning a file.
@@ -123,16 +153,43 @@ pub struct ScannedFile {
 /// and builds an FsSchema that can be serialized to JSON.
 ///
 /// If a `.commonplace.json` file exists in the directory, existing
-/// node_ids will be preserved for files that still exist.
+/// node_ids will be preserved for files that still exist. Paths excluded by
+/// `.commonplaceignore` files are left out.
 pub fn scan_directory(path: &Path, options: &ScanOptions) -> Result<FsSchema, ScanError> {
     if !path.is_dir() {
         return Err(ScanError::NotDirectory(path.display().to_string()));
     }
 
     // Load existing node_ids to preserve them
-    let existing_node_ids = load_existing_node_ids(path);
+    scan_directory_with_node_ids(path, options, &load_existing_node_ids(path))
+}
+
+/// Scan a directory like [`scan_directory`], using the given path -> node_id
+/// map instead of the one in `.commonplace.json`.
+pub fn scan_directory_with_node_ids(
+    path: &Path,
+    options: &ScanOptions,
+    existing_node_ids: &HashMap<String, String>,
+) -> Result<FsSchema, ScanError> {
+    if !path.is_dir() {
+        return Err(ScanError::NotDirectory(path.display().to_string()));
+    }
 
-    let root_entry = scan_dir_recursive(path, path, options, &existing_node_ids)?;
+    let mut ignore = IgnoreRules::new(path, options);
+    let mut root_entry = scan_dir_recursive(path, path, options, &mut ignore, existing_node_ids)?;
+
+    // A sparse checkout only sees part of the tree: keep the entries it
+    // doesn't check out from the last known schema, so pushing the scan
+    // doesn't delete them on the server
+    if !ignore.sparse().is_full() {
+        if let (Some(Entry::Dir(existing)), Entry::Dir(scanned)) =
+            (load_existing_root(path), &mut root_entry)
+        {
+            if let (Some(existing), Some(scanned)) = (existing.entries, scanned.entries.as_mut()) {
+                graft_sparse_entries(scanned, &existing, "", ignore.sparse());
+            }
+        }
+    }
 
     Ok(FsSchema {
         version: 1,
@@ -140,11 +197,84 @@ pub fn scan_directory(path: &Path, options: &ScanOptions) -> Result<FsSchema, Sc
     })
 }
 
+/// The root entry of the `.commonplace.json` in `directory`, if any.
+fn load_existing_root(directory: &Path) -> Option<Entry> {
+    let content = fs::read_to_string(directory.join(SCHEMA_FILENAME)).ok()?;
+    serde_json::from_str::<FsSchema>(&content).ok()?.root
+}
+
+/// Copy the entries of `existing` that `sparse` doesn't check out into the
+/// scanned `entries` of the directory at `dir_relative`.
+///
+/// Returns whether anything was copied.
+fn graft_sparse_entries(
+    entries: &mut HashMap<String, Entry>,
+    existing: &HashMap<String, Entry>,
+    dir_relative: &str,
+    sparse: &SparseFilter,
+) -> bool {
+    let mut grafted = false;
+    for (name, entry) in existing {
+        let relative_path = if dir_relative.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", dir_relative, name)
+        };
+        match entry {
+            Entry::Doc(_) => {
+                if !sparse.includes(&relative_path) && !entries.contains_key(name) {
+                    entries.insert(name.clone(), entry.clone());
+                    grafted = true;
+                }
+            }
+            Entry::Dir(dir) => {
+                if !sparse.may_contain(&relative_path) {
+                    // Not checked out at all
+                    entries.insert(name.clone(), entry.clone());
+                    grafted = true;
+                } else if sparse.includes(&relative_path) || dir.node_id.is_some() {
+                    // Checked out (or scanned by its own document's sync), so
+                    // whatever the scan found is authoritative
+                } else if let Some(ref existing_sub) = dir.entries {
+                    // Partially checked out inline directory
+                    let scanned = entries.get_mut(name).and_then(|e| match e {
+                        Entry::Dir(d) => d.entries.as_mut(),
+                        Entry::Doc(_) => None,
+                    });
+                    match scanned {
+                        Some(scanned) => {
+                            grafted |=
+                                graft_sparse_entries(scanned, existing_sub, &relative_path, sparse);
+                        }
+                        None => {
+                            let mut sub = HashMap::new();
+                            if graft_sparse_entries(&mut sub, existing_sub, &relative_path, sparse)
+                            {
+                                entries.insert(
+                                    name.clone(),
+                                    Entry::Dir(DirEntry {
+                                        entries: Some(sub),
+                                        node_id: None,
+                                        content_type: None,
+                                    }),
+                                );
+                                grafted = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    grafted
+}
+
 /// Recursively scan a directory and build an Entry tree.
 fn scan_dir_recursive(
     root: &Path,
     current: &Path,
     options: &ScanOptions,
+    ignore: &mut IgnoreRules,
     existing_node_ids: &HashMap<String, String>,
 ) -> Result<Entry, ScanError> {
     let mut entries: HashMap<String, Entry> = HashMap::new();
@@ -165,16 +295,16 @@ fn scan_dir_recursive(
 
         // Handle symlinks - convert to commonplace-linked files
         if file_type.is_symlink() {
+            let entry_path = entry.path();
+
             // Apply the same hidden/ignore filters as for regular files
-            if !options.include_hidden && name.starts_with('.') {
+            if !options.include_hidden && is_hidden_name(&name) {
                 continue;
             }
-            if should_ignore(&name, &options.ignore_patterns) {
+            if ignore.is_ignored(&entry_path, false) {
                 continue;
             }
 
-            let entry_path = entry.path();
-
             // Compute relative path for this entry
             let symlink_relative = if dir_relative.is_empty() {
                 name.clone()
@@ -238,17 +368,17 @@ fn scan_dir_recursive(
         }
 
         // Skip hidden files unless configured to include them
-        if !options.include_hidden && name.starts_with('.') {
+        if !options.include_hidden && is_hidden_name(&name) {
             continue;
         }
 
-        // Skip ignored patterns
-        if should_ignore(&name, &options.ignore_patterns) {
+        let entry_path = entry.path();
+
+        // Skip paths excluded by ignore patterns or .commonplaceignore
+        if ignore.is_ignored(&entry_path, file_type.is_dir()) {
             continue;
         }
 
-        let entry_path = entry.path();
-
         // Compute relative path for this entry
         let relative_path = if dir_relative.is_empty() {
             name.clone()
@@ -271,7 +401,8 @@ fn scan_dir_recursive(
                 );
             } else {
                 // No existing node_id - scan inline as usual
-                let sub_entry = scan_dir_recursive(root, &entry_path, options, existing_node_ids)?;
+                let sub_entry =
+                    scan_dir_recursive(root, &entry_path, options, ignore, existing_node_ids)?;
                 entries.insert(name, sub_entry);
             }
         } else if file_type.is_file() {
@@ -318,16 +449,6 @@ fn scan_dir_recursive(
     }))
 }
 
-/// Check if a filename matches any ignore pattern.
-fn should_ignore(name: &str, patterns: &[String]) -> bool {
-    for pattern in patterns {
-        if pattern_matches(pattern, name) {
-            return true;
-        }
-    }
-    false
-}
-
 /// Result of resolving a symlink.
 enum SymlinkResolution {
     /// Target is within workspace, use this relative path for node_id lookup
@@ -390,26 +511,6 @@ fn resolve_symlink(root: &Path, symlink_path: &Path) -> SymlinkResolution {
     SymlinkResolution::WithinWorkspace { target_relative }
 }
 
-/// Simple glob pattern matching (supports * and ?).
-fn pattern_matches(pattern: &str, name: &str) -> bool {
-    // Simple implementation: exact match or wildcard patterns
-    if pattern == name {
-        return true;
-    }
-
-    // Handle simple * wildcards
-    if pattern.contains('*') {
-        let parts: Vec<&str> = pattern.split('*').collect();
-        if parts.len() == 2 {
-            let prefix = parts[0];
-            let suffix = parts[1];
-            return name.starts_with(prefix) && name.ends_with(suffix);
-        }
-    }
-
-    false
-}
-
 /// Scan a directory and collect all file contents.
 ///
 /// Returns a list of scanned files with their contents ready for upload.
@@ -422,7 +523,8 @@ pub fn scan_directory_with_contents(
     }
 
     let mut files = Vec::new();
-    scan_files_recursive(path, path, options, &mut files)?;
+    let mut ignore = IgnoreRules::new(path, options);
+    scan_files_recursive(path, path, options, &mut ignore, &mut files)?;
     Ok(files)
 }
 
@@ -431,6 +533,7 @@ fn scan_files_recursive(
     root: &Path,
     current: &Path,
     options: &ScanOptions,
+    ignore: &mut IgnoreRules,
     files: &mut Vec<ScannedFile>,
 ) -> Result<(), ScanError> {
     let read_dir = fs::read_dir(current)?;
@@ -448,19 +551,19 @@ fn scan_files_recursive(
         }
 
         // Skip hidden files unless configured
-        if !options.include_hidden && name.starts_with('.') {
+        if !options.include_hidden && is_hidden_name(&name) {
             continue;
         }
 
-        // Skip ignored patterns
-        if should_ignore(&name, &options.ignore_patterns) {
+        let entry_path = entry.path();
+
+        // Skip paths excluded by ignore patterns or .commonplaceignore
+        if ignore.is_ignored(&entry_path, file_type.is_dir()) {
             continue;
         }
 
-        let entry_path = entry.path();
-
         if file_type.is_dir() {
-            scan_files_recursive(root, &entry_path, options, files)?;
+            scan_files_recursive(root, &entry_path, options, ignore, files)?;
         } else if file_type.is_file() {
             // Skip files with disallowed extensions
             if !is_allowed_extension(&entry_path) {
@@ -615,6 +718,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_scan_skips_conflict_copies() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("notes.txt");
+        fs::write(&file, b"kept").unwrap();
+        let copy = crate::sync::conflict_copy_path(&file, "alice", chrono::Utc::now());
+        fs::write(&copy, b"lost").unwrap();
+
+        let options = crate::sync::directory_scan_options(false, Vec::new());
+        let files = scan_directory_with_contents(temp.path(), &options).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.relative_path.as_str()).collect();
+        assert_eq!(paths, vec!["notes.txt"]);
+    }
+
     #[test]
     fn test_scan_directory_with_contents() {
         let temp = create_test_directory();
@@ -660,11 +777,87 @@ mod tests {
 
     #[test]
     fn test_pattern_matching() {
-        assert!(pattern_matches("*.txt", "readme.txt"));
-        assert!(pattern_matches("*.txt", "notes.txt"));
-        assert!(!pattern_matches("*.txt", "readme.md"));
-        assert!(pattern_matches("exact", "exact"));
-        assert!(!pattern_matches("exact", "inexact"));
+        let temp = TempDir::new().unwrap();
+        let options = ScanOptions {
+            ignore_patterns: vec!["*.txt".to_string(), "exact".to_string()],
+            ..Default::default()
+        };
+        let mut ignore = IgnoreRules::new(temp.path(), &options);
+        assert!(ignore.is_ignored(Path::new("readme.txt"), false));
+        assert!(ignore.is_ignored(Path::new("notes.txt"), false));
+        assert!(!ignore.is_ignored(Path::new("readme.md"), false));
+        assert!(ignore.is_ignored(Path::new("exact"), false));
+        assert!(!ignore.is_ignored(Path::new("inexact"), false));
+    }
+
+    #[test]
+    fn test_scan_honors_commonplaceignore() {
+        let temp = create_test_directory();
+        fs::write(temp.path().join(".commonplaceignore"), "*.json\nnotes/\n").unwrap();
+
+        let schema = scan_directory(temp.path(), &ScanOptions::default()).unwrap();
+        if let Some(Entry::Dir(dir)) = &schema.root {
+            let entries = dir.entries.as_ref().unwrap();
+            assert!(entries.contains_key("readme.txt"));
+            assert!(!entries.contains_key("data.json"));
+            assert!(!entries.contains_key("notes"));
+            // The ignore file itself syncs even though it is hidden
+            assert!(entries.contains_key(".commonplaceignore"));
+        }
+
+        let files = scan_directory_with_contents(temp.path(), &ScanOptions::default()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.relative_path.as_str()).collect();
+        assert!(paths.contains(&".commonplaceignore"));
+        assert!(!paths.iter().any(|p| p.contains("idea.md")));
+    }
+
+    #[test]
+    fn test_sparse_scan_keeps_entries_not_checked_out() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        // The last known schema has more than the sparse checkout materializes
+        let existing = serde_json::json!({
+            "version": 1,
+            "root": {"type": "dir", "entries": {
+                "top.txt": {"type": "doc", "node_id": "top"},
+                "gone.md": {"type": "doc", "node_id": "gone"},
+                "archive": {"type": "dir", "node_id": "archive-doc"},
+                "projects": {"type": "dir", "entries": {
+                    "a": {"type": "dir", "entries": {
+                        "docs": {"type": "dir", "entries": {
+                            "x.md": {"type": "doc", "node_id": "x"}
+                        }},
+                        "src.txt": {"type": "doc", "node_id": "src"}
+                    }}
+                }}
+            }}
+        });
+        fs::write(root.join(SCHEMA_FILENAME), existing.to_string()).unwrap();
+        fs::create_dir_all(root.join("projects/a/docs")).unwrap();
+        fs::write(root.join("projects/a/docs/x.md"), "x").unwrap();
+        fs::write(root.join("projects/a/docs/new.md"), "new").unwrap();
+
+        let options = ScanOptions {
+            include_patterns: Some(vec!["*.md".to_string(), "projects/*/docs".to_string()]),
+            ..Default::default()
+        };
+        let schema = scan_directory(root, &options).unwrap();
+        let ids = extract_node_ids(schema.root.as_ref().unwrap(), "");
+
+        // Outside the checkout: kept as they were
+        assert_eq!(ids.get("top.txt").map(String::as_str), Some("top"));
+        assert_eq!(ids.get("archive").map(String::as_str), Some("archive-doc"));
+        assert_eq!(
+            ids.get("projects/a/src.txt").map(String::as_str),
+            Some("src")
+        );
+        // Inside the checkout: the scan wins, including local deletions
+        assert_eq!(
+            ids.get("projects/a/docs/x.md").map(String::as_str),
+            Some("x")
+        );
+        assert!(ids.contains_key("projects/a/docs/new.md"));
+        assert!(!ids.contains_key("gone.md"));
     }
 
     #[test]
@@ -857,6 +1050,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_scan_keeps_node_ids_across_rename() {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir(temp.path().join("renamed")).unwrap();
+        File::create(temp.path().join("renamed/nested.txt"))
+            .unwrap()
+            .write_all(b"nested content")
+            .unwrap();
+
+        let mut node_ids = HashMap::from([
+            (
+                "subdir/nested.txt".to_string(),
+                "uuid-for-nested".to_string(),
+            ),
+            (
+                "subdirectory.txt".to_string(),
+                "uuid-for-sibling".to_string(),
+            ),
+        ]);
+        let mut moved = HashMap::new();
+        assert!(move_node_ids(
+            &mut node_ids,
+            "subdir",
+            &mut moved,
+            "renamed"
+        ));
+        assert!(!move_node_ids(
+            &mut node_ids,
+            "missing",
+            &mut moved,
+            "renamed"
+        ));
+        // Only the directory and its children move, not siblings sharing a prefix
+        assert_eq!(node_ids.len(), 1);
+        node_ids.extend(moved);
+
+        let schema =
+            scan_directory_with_node_ids(temp.path(), &ScanOptions::default(), &node_ids).unwrap();
+        let Some(Entry::Dir(root)) = &schema.root else {
+            panic!("Expected root to be a Dir");
+        };
+        let Some(Entry::Dir(renamed)) = root.entries.as_ref().unwrap().get("renamed") else {
+            panic!("Expected renamed to be a Dir");
+        };
+        let Some(Entry::Doc(doc)) = renamed.entries.as_ref().unwrap().get("nested.txt") else {
+            panic!("Expected nested.txt to be a Doc");
+        };
+        assert_eq!(doc.node_id.as_deref(), Some("uuid-for-nested"));
+    }
+
     #[test]
     fn test_scan_preserves_shared_node_ids() {
         let temp = TempDir::new().unwrap();
@@ -0,0 +1,559 @@
+//! Dry-run plans for the initial sync.
+//!
+//! `commonplace-sync --dry-run` works out what starting a sync with the given
+//! `--initial-sync` strategy would do to each file, using only read requests,
+//! and prints that plan instead of carrying it out. The decisions mirror
+//! `initial_sync` and the offline-edit merge in file mode, and
+//! `handle_schema_change`, `sync_schema` and `sync_single_file` in directory
+//! mode. Where a sync state file records the last sync, files changed on both
+//! sides since then are flagged as conflicts.
+
+use crate::diff::{summarize_diff, DiffSummary};
+use crate::sync::content_type::{content_to_bytes, detect_from_path, is_binary_content};
+use crate::sync::dir_sync::check_server_has_content;
+use crate::sync::directory::{scan_directory_with_contents, ScanOptions};
+use crate::sync::sparse::SparseFilter;
+use crate::sync::state_file::{compute_content_hash, FileState, SyncStateFile};
+use crate::sync::status::{content_matches, fetch_heads};
+use crate::sync::types::HeadResponse;
+use crate::sync::urls::encode_node_id;
+use crate::sync::uuid_map::build_uuid_map_recursive;
+use reqwest::Client;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// What the initial sync would do to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PlanAction {
+    /// Both sides changed since the last sync; one side's changes are lost
+    /// (or kept only in a conflict copy)
+    Conflict,
+    /// Removed from the server
+    Delete,
+    /// Local content replaces or merges into the server's
+    Push,
+    /// Server content replaces the local file
+    Pull,
+    /// Created on one side from the other
+    Create,
+    /// Left alone at startup although the sides differ
+    Skip,
+    /// Already in sync
+    Unchanged,
+}
+
+impl fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlanAction::Conflict => "conflict",
+            PlanAction::Delete => "delete",
+            PlanAction::Push => "push",
+            PlanAction::Pull => "pull",
+            PlanAction::Create => "create",
+            PlanAction::Skip => "skip",
+            PlanAction::Unchanged => "unchanged",
+        };
+        f.pad(name)
+    }
+}
+
+/// The side of the sync a planned action changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanSide {
+    Local,
+    Server,
+}
+
+/// One file in a dry-run plan.
+#[derive(Debug, Clone)]
+pub struct PlanEntry {
+    /// Path relative to the synced directory (the file name in file mode)
+    pub path: String,
+    pub action: PlanAction,
+    /// The side that would change, if any
+    pub side: Option<PlanSide>,
+    /// Size of the text change on that side
+    pub diff: Option<DiffSummary>,
+    pub note: Option<String>,
+}
+
+impl PlanEntry {
+    fn new(path: &str, action: PlanAction, side: Option<PlanSide>) -> Self {
+        Self {
+            path: path.to_string(),
+            action,
+            side,
+            diff: None,
+            note: None,
+        }
+    }
+
+    fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+/// A file as each side of the sync sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSides<'a> {
+    /// The local path, for content type detection
+    pub path: &'a Path,
+    /// Local content, if the file exists
+    pub local: Option<&'a [u8]>,
+    /// Whether the server schema lists the file (always true in file mode)
+    pub on_server: bool,
+    /// The server HEAD, if the document exists
+    pub head: Option<&'a HeadResponse>,
+    /// State recorded at the last sync
+    pub base: Option<&'a FileState>,
+    /// Commit of the last sync
+    pub base_cid: Option<&'a str>,
+}
+
+impl FileSides<'_> {
+    /// Whether the local file changed since the last sync (`None` without a
+    /// recorded sync).
+    fn local_changed(&self) -> Option<bool> {
+        let (base, local) = (self.base?, self.local?);
+        Some(base.hash != compute_content_hash(local))
+    }
+
+    /// Whether the server moved on since the last sync (`None` without a
+    /// recorded sync).
+    fn server_changed(&self) -> Option<bool> {
+        let base_cid = self.base_cid?;
+        Some(self.head?.cid.as_deref() != Some(base_cid))
+    }
+
+    fn server_bytes(&self) -> Vec<u8> {
+        self.head
+            .map(|head| content_to_bytes(self.path, &head.content))
+            .unwrap_or_default()
+    }
+
+    fn is_binary(&self) -> bool {
+        detect_from_path(self.path).is_binary || self.local.is_some_and(is_binary_content)
+    }
+}
+
+/// Plan a file in file mode.
+///
+/// Offline edits recorded against the state file are pushed (merged through
+/// the CRDT, or resolved with a conflict copy when they can't be merged);
+/// otherwise the server HEAD is written over the local file.
+pub fn plan_file(name: &str, sides: &FileSides, force_push: bool) -> PlanEntry {
+    let Some(local) = sides.local else {
+        return PlanEntry::new(name, PlanAction::Create, Some(PlanSide::Local));
+    };
+    let offline_edit = sides.base_cid.is_some() && sides.local_changed().unwrap_or(true);
+    if offline_edit {
+        let server_moved = sides.server_changed().unwrap_or(false);
+        if server_moved && (sides.is_binary() || force_push) {
+            let (side, winner) = if force_push {
+                (PlanSide::Server, "local")
+            } else {
+                (PlanSide::Local, "server")
+            };
+            return PlanEntry::new(name, PlanAction::Conflict, Some(side)).with_note(format!(
+                "changed on both sides since the last sync; {} version wins, the other is kept as a conflict copy",
+                winner
+            ));
+        }
+        let entry = PlanEntry::new(name, PlanAction::Push, Some(PlanSide::Server));
+        return if server_moved {
+            entry.with_note("offline edits merge with server edits since the last sync")
+        } else {
+            entry.with_note("offline edits")
+        };
+    }
+    if content_matches(
+        sides.path,
+        local,
+        sides.head.map(|h| h.content.as_str()).unwrap_or(""),
+    ) {
+        return PlanEntry::new(name, PlanAction::Unchanged, None);
+    }
+    let entry = PlanEntry::new(name, PlanAction::Pull, Some(PlanSide::Local));
+    if sides.base.is_none() {
+        entry.with_note("no sync state; local content is replaced by the server's")
+    } else {
+        entry
+    }
+}
+
+/// Plan a file in directory mode with `strategy` (`local`, `server` or
+/// `skip`).
+pub fn plan_directory_file(
+    path: &str,
+    sides: &FileSides,
+    strategy: &str,
+    server_has_content: bool,
+) -> PlanEntry {
+    if !server_has_content {
+        // The local schema is pushed and every local file uploaded
+        return PlanEntry::new(path, PlanAction::Create, Some(PlanSide::Server))
+            .with_note("server schema is empty");
+    }
+    let Some(local) = sides.local else {
+        return match strategy {
+            "local" => PlanEntry::new(path, PlanAction::Delete, Some(PlanSide::Server))
+                .with_note("not in the local schema pushed to the server"),
+            "server" => PlanEntry::new(path, PlanAction::Create, Some(PlanSide::Local)),
+            _ if sides.base.is_some() => PlanEntry::new(path, PlanAction::Skip, None)
+                .with_note("deleted locally; stays on the server"),
+            _ => PlanEntry::new(path, PlanAction::Skip, None)
+                .with_note("only on the server; pulled when the server schema next changes"),
+        };
+    };
+    if !sides.on_server {
+        return PlanEntry::new(path, PlanAction::Create, Some(PlanSide::Server));
+    }
+    let server_content = sides.head.map(|h| h.content.as_str()).unwrap_or("");
+    if server_content.is_empty() {
+        return PlanEntry::new(path, PlanAction::Push, Some(PlanSide::Server))
+            .with_note("server document is empty");
+    }
+    if content_matches(sides.path, local, server_content) {
+        return PlanEntry::new(path, PlanAction::Unchanged, None);
+    }
+
+    let (action, side, winner) = if strategy == "server" {
+        (PlanAction::Pull, PlanSide::Local, "server")
+    } else {
+        (PlanAction::Push, PlanSide::Server, "local")
+    };
+    match (sides.local_changed(), sides.server_changed()) {
+        (Some(true), Some(true)) => PlanEntry::new(path, PlanAction::Conflict, Some(side))
+            .with_note(format!(
+                "changed on both sides since the last sync; {} version wins",
+                winner
+            )),
+        (Some(false), Some(true)) if action == PlanAction::Push => {
+            PlanEntry::new(path, action, Some(side))
+                .with_note("local copy is older than the server's; pushing it reverts server edits")
+        }
+        (Some(true), Some(false)) if action == PlanAction::Pull => {
+            PlanEntry::new(path, action, Some(side))
+                .with_note("discards local edits made since the last sync")
+        }
+        _ => PlanEntry::new(path, action, Some(side)),
+    }
+}
+
+/// Fill in the size of the text change an entry makes.
+fn summarize(entry: &mut PlanEntry, sides: &FileSides) {
+    let (Some(local), Some(side)) = (sides.local, entry.side) else {
+        return;
+    };
+    if sides.head.is_none() || sides.is_binary() {
+        return;
+    }
+    let server = sides.server_bytes();
+    let (Ok(local), Ok(server)) = (std::str::from_utf8(local), std::str::from_utf8(&server)) else {
+        return;
+    };
+    entry.diff = Some(match side {
+        PlanSide::Local => summarize_diff(local, server),
+        PlanSide::Server => summarize_diff(server, local),
+    });
+}
+
+/// Plan the initial sync of a single file.
+pub async fn plan_file_sync(
+    client: &Client,
+    server: &str,
+    node_id: &str,
+    file: &Path,
+    force_push: bool,
+) -> Result<Vec<PlanEntry>, Box<dyn std::error::Error>> {
+    let head_url = format!("{}/docs/{}/head", server, encode_node_id(node_id));
+    let resp = client.get(&head_url).send().await?;
+    if !resp.status().is_success() {
+        return Err(format!("Failed to get HEAD: {}", resp.status()).into());
+    }
+    let head: HeadResponse = resp.json().await?;
+
+    let local = tokio::fs::read(file).await.ok();
+    let state = SyncStateFile::load(&SyncStateFile::state_file_path(file))
+        .await
+        .ok()
+        .flatten();
+    let name = file
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let sides = FileSides {
+        path: file,
+        local: local.as_deref(),
+        on_server: true,
+        head: Some(&head),
+        base: state.as_ref().and_then(|s| s.files.get(&name)),
+        base_cid: state.as_ref().and_then(|s| s.last_synced_cid.as_deref()),
+    };
+    let mut entry = plan_file(&name, &sides, force_push);
+    summarize(&mut entry, &sides);
+    Ok(vec![entry])
+}
+
+/// Plan the initial sync of a directory with `strategy`.
+pub async fn plan_directory_sync(
+    client: &Client,
+    server: &str,
+    fs_root_id: &str,
+    directory: &Path,
+    options: &ScanOptions,
+    strategy: &str,
+) -> Result<Vec<PlanEntry>, Box<dyn std::error::Error>> {
+    let local_paths: Vec<String> = scan_directory_with_contents(directory, options)
+        .map_err(|e| format!("Scan error: {}", e))?
+        .into_iter()
+        .map(|file| file.relative_path)
+        .collect();
+
+    // Server files a sparse checkout leaves out are neither pulled nor deleted
+    let sparse = match &options.include_patterns {
+        Some(patterns) => SparseFilter::new(patterns),
+        None => SparseFilter::load(directory),
+    };
+    let server_has_content = check_server_has_content(client, server, fs_root_id).await;
+    let server_docs: HashMap<String, String> = if server_has_content {
+        build_uuid_map_recursive(client, server, fs_root_id)
+            .await
+            .into_iter()
+            .filter(|(path, _)| crate::sync::content_type::is_allowed_extension(Path::new(path)))
+            .filter(|(path, _)| sparse.includes(path))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let heads = fetch_heads(client, server, server_docs.values().cloned().collect()).await?;
+    let state = SyncStateFile::load(&SyncStateFile::state_file_path(directory))
+        .await
+        .ok()
+        .flatten();
+
+    let paths: BTreeSet<&String> = local_paths.iter().chain(server_docs.keys()).collect();
+    let mut plan = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file_path = directory.join(path);
+        let local = if local_paths.contains(path) {
+            tokio::fs::read(&file_path).await.ok()
+        } else {
+            None
+        };
+        let base = state.as_ref().and_then(|s| s.files.get(path));
+        let sides = FileSides {
+            path: &file_path,
+            local: local.as_deref(),
+            on_server: server_docs.contains_key(path),
+            head: server_docs.get(path).and_then(|id| heads.get(id)),
+            base,
+            base_cid: base.and_then(|b| b.cid.as_deref()),
+        };
+        let mut entry = plan_directory_file(path, &sides, strategy, server_has_content);
+        summarize(&mut entry, &sides);
+        plan.push(entry);
+    }
+    Ok(plan)
+}
+
+/// Print a plan, one line per file that would change, then totals.
+pub fn print_plan(plan: &[PlanEntry]) {
+    let mut entries: Vec<&PlanEntry> = plan
+        .iter()
+        .filter(|entry| entry.action != PlanAction::Unchanged)
+        .collect();
+    entries.sort_by(|a, b| a.action.cmp(&b.action).then_with(|| a.path.cmp(&b.path)));
+
+    println!("Dry run: nothing is changed locally or on the server.");
+    if entries.is_empty() {
+        println!("Nothing to do: everything is in sync.");
+        return;
+    }
+    let width = entries.iter().map(|e| e.path.len()).max().unwrap_or(0);
+    for entry in &entries {
+        let mut details = Vec::new();
+        match entry.side {
+            Some(PlanSide::Local) => details.push("local".to_string()),
+            Some(PlanSide::Server) => details.push("server".to_string()),
+            None => {}
+        }
+        if let Some(diff) = &entry.diff {
+            details.push(format!(
+                "+{} -{} chars",
+                diff.chars_inserted, diff.chars_deleted
+            ));
+        }
+        if let Some(note) = &entry.note {
+            details.push(note.clone());
+        }
+        println!(
+            "  {:<10}{:<width$}  ({})",
+            entry.action,
+            entry.path,
+            details.join("; "),
+            width = width
+        );
+    }
+
+    let mut counts: Vec<(PlanAction, usize)> = Vec::new();
+    for entry in plan {
+        match counts
+            .iter_mut()
+            .find(|(action, _)| *action == entry.action)
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((entry.action, 1)),
+        }
+    }
+    counts.sort();
+    let totals: Vec<String> = counts
+        .iter()
+        .map(|(action, count)| format!("{} {}", count, action))
+        .collect();
+    println!("{}", totals.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(content: &str, cid: &str) -> HeadResponse {
+        HeadResponse {
+            cid: Some(cid.to_string()),
+            content: content.to_string(),
+            state: None,
+        }
+    }
+
+    fn base(content: &[u8], cid: &str) -> FileState {
+        FileState {
+            hash: compute_content_hash(content),
+            last_modified: None,
+            cid: Some(cid.to_string()),
+        }
+    }
+
+    fn sides<'a>(
+        local: Option<&'a [u8]>,
+        head: Option<&'a HeadResponse>,
+        base: Option<&'a FileState>,
+    ) -> FileSides<'a> {
+        FileSides {
+            path: Path::new("notes.txt"),
+            local,
+            on_server: head.is_some(),
+            head,
+            base,
+            base_cid: base.and_then(|b| b.cid.as_deref()),
+        }
+    }
+
+    #[test]
+    fn test_plan_directory_file_by_strategy() {
+        let server = head("server text", "c2");
+        let synced = base(b"old text", "c1");
+        let both_changed = sides(Some(b"local text"), Some(&server), Some(&synced));
+
+        let entry = plan_directory_file("a.txt", &both_changed, "skip", true);
+        assert_eq!(entry.action, PlanAction::Conflict);
+        assert_eq!(entry.side, Some(PlanSide::Server));
+        let entry = plan_directory_file("a.txt", &both_changed, "server", true);
+        assert_eq!(entry.action, PlanAction::Conflict);
+        assert_eq!(entry.side, Some(PlanSide::Local));
+
+        let no_state = sides(Some(b"local text"), Some(&server), None);
+        assert_eq!(
+            plan_directory_file("a.txt", &no_state, "local", true).action,
+            PlanAction::Push
+        );
+        assert_eq!(
+            plan_directory_file("a.txt", &no_state, "server", true).action,
+            PlanAction::Pull
+        );
+
+        let same = sides(Some(b"server text\n"), Some(&server), None);
+        assert_eq!(
+            plan_directory_file("a.txt", &same, "local", true).action,
+            PlanAction::Unchanged
+        );
+
+        let server_only = sides(None, Some(&server), None);
+        assert_eq!(
+            plan_directory_file("a.txt", &server_only, "local", true).action,
+            PlanAction::Delete
+        );
+        assert_eq!(
+            plan_directory_file("a.txt", &server_only, "server", true).action,
+            PlanAction::Create
+        );
+        assert_eq!(
+            plan_directory_file("a.txt", &server_only, "skip", true).action,
+            PlanAction::Skip
+        );
+
+        let local_only = sides(Some(b"new"), None, None);
+        let entry = plan_directory_file("a.txt", &local_only, "skip", true);
+        assert_eq!(
+            (entry.action, entry.side),
+            (PlanAction::Create, Some(PlanSide::Server))
+        );
+    }
+
+    #[test]
+    fn test_plan_file_offline_edits() {
+        let synced = base(b"old text", "c1");
+
+        // Server unchanged: offline edits are pushed
+        let server = head("old text", "c1");
+        let entry = plan_file(
+            "notes.txt",
+            &sides(Some(b"new text"), Some(&server), Some(&synced)),
+            false,
+        );
+        assert_eq!(entry.action, PlanAction::Push);
+
+        // Text merges even when the server moved on...
+        let server = head("server text", "c2");
+        let edited = sides(Some(b"new text"), Some(&server), Some(&synced));
+        assert_eq!(
+            plan_file("notes.txt", &edited, false).action,
+            PlanAction::Push
+        );
+        // ...but --force-push can't merge
+        let entry = plan_file("notes.txt", &edited, true);
+        assert_eq!(
+            (entry.action, entry.side),
+            (PlanAction::Conflict, Some(PlanSide::Server))
+        );
+
+        // No local edits: the server version is written locally
+        let unedited = sides(Some(b"old text"), Some(&server), Some(&synced));
+        assert_eq!(
+            plan_file("notes.txt", &unedited, false).action,
+            PlanAction::Pull
+        );
+        assert_eq!(
+            plan_file("notes.txt", &sides(None, Some(&server), None), false).action,
+            PlanAction::Create
+        );
+    }
+
+    #[test]
+    fn test_summarize_direction() {
+        let server = head("hello", "c1");
+        let sides = sides(Some(b"hello world"), Some(&server), None);
+        let mut entry = PlanEntry::new("a.txt", PlanAction::Push, Some(PlanSide::Server));
+        summarize(&mut entry, &sides);
+        let diff = entry.diff.unwrap();
+        assert_eq!((diff.chars_inserted, diff.chars_deleted), (6, 0));
+
+        let mut entry = PlanEntry::new("a.txt", PlanAction::Pull, Some(PlanSide::Local));
+        summarize(&mut entry, &sides);
+        let diff = entry.diff.unwrap();
+        assert_eq!((diff.chars_inserted, diff.chars_deleted), (0, 6));
+    }
+}
@@ -2,16 +2,21 @@
 //!
 //! This module contains functions for syncing a single file with a server document.
 
+use crate::sync::context::SyncContext;
 use crate::sync::dir_sync::find_owning_document;
 use crate::sync::directory::{scan_directory, schema_to_json, ScanOptions};
+use crate::sync::journal::{record_offline_edit, CommitJournal};
+use crate::sync::state::UploadGuard;
 use crate::sync::state_file::compute_content_hash;
+use crate::sync::text_sync::is_text_syncable;
 use crate::sync::uuid_map::fetch_node_id_from_schema;
+use crate::sync::ws::ws_sync_task;
 use crate::sync::{
     build_edit_url, build_head_url, build_replace_url, create_yjs_text_update, detect_from_path,
-    encode_node_id, file_watcher_task, is_binary_content, looks_like_base64_binary,
+    encode_node_id, file_watcher_task, flush_journal, is_binary_content, looks_like_base64_binary,
     push_json_content, push_jsonl_content, push_schema_to_server, refresh_from_head, sse_task,
-    EditRequest, EditResponse, FileEvent, HeadResponse, ReplaceResponse, SyncState,
-    PENDING_WRITE_TIMEOUT,
+    EditRequest, EditResponse, FileEvent, FlushOutcome, HeadResponse, PushResponse,
+    ReplaceResponse, SyncState, PENDING_WRITE_TIMEOUT,
 };
 use reqwest::Client;
 use std::path::{Path, PathBuf};
@@ -26,10 +31,13 @@ use tracing::{debug, error, info, warn};
 pub const BARRIER_RETRY_COUNT: u32 = 5;
 /// Delay between retries when checking for stable content
 pub const BARRIER_RETRY_DELAY: Duration = Duration::from_millis(50);
+/// How often journaled offline edits are pushed again while the server is
+/// unreachable
+pub const JOURNAL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 /// Ensures text content ends with a trailing newline.
 /// This is important for text files (especially JSON) to maintain proper formatting.
-fn ensure_trailing_newline(content: &str) -> String {
+pub(crate) fn ensure_trailing_newline(content: &str) -> String {
     if content.ends_with('\n') {
         content.to_string()
     } else {
@@ -48,8 +56,27 @@ pub async fn upload_task(
     mut rx: mpsc::Receiver<FileEvent>,
     use_paths: bool,
     force_push: bool,
+    journal: Option<Arc<CommitJournal>>,
 ) {
-    while let Some(event) = rx.recv().await {
+    // Edits that can't reach the server are journaled and pushed as history
+    // later; only the ID-based API takes pushes, and force-push overwrites
+    let journal = journal.filter(|_| !use_paths && !force_push);
+
+    loop {
+        // While edits wait in the journal, retry them with the file's
+        // current content every so often
+        let retry = journal_pending(journal.as_deref(), &identifier);
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            _ = sleep(JOURNAL_RETRY_INTERVAL), if retry => match tokio::fs::read(&file_path).await {
+                Ok(raw) => FileEvent::Modified(raw),
+                Err(_) => continue,
+            },
+        };
+
         // Extract captured content from the event
         // The watcher captures content at notification time to prevent race conditions
         // where SSE might overwrite the file between event dispatch and us reading it.
@@ -209,7 +236,9 @@ pub async fn upload_task(
                 should_refresh = s.needs_head_refresh;
                 s.needs_head_refresh = false;
 
-                if content == s.last_written_content {
+                // With edits journaled, content matching the last synced
+                // state is a revert that still has to go up after them
+                if content == s.last_written_content && !retry {
                     debug!("Ignoring echo: content matches last written");
                     echo_detected = true;
                 }
@@ -231,6 +260,9 @@ pub async fn upload_task(
             continue;
         }
 
+        let upload_in_flight = state.read().await.upload_in_flight.clone();
+        let _in_flight = UploadGuard::start(&upload_in_flight);
+
         if is_json {
             let json_upload_succeeded =
                 match push_json_content(&client, &server, &identifier, &content, &state, use_paths)
@@ -337,66 +369,52 @@ pub async fn upload_task(
 
         // Track upload success - only refresh if upload succeeded
         let mut upload_succeeded = false;
+        let journaling = journal.as_deref().filter(|_| !is_binary);
 
-        match parent_cid {
-            Some(parent) => {
-                // Normal case: use replace endpoint
-                // For force-push, parent is HEAD's cid so this is a simple replace
-                let replace_url = build_replace_url(&server, &identifier, &parent, use_paths);
-
-                match client
-                    .post(&replace_url)
-                    .header("content-type", "text/plain")
-                    .body(content.clone())
-                    .send()
-                    .await
+        match (parent_cid, journaling) {
+            (Some(parent), Some(journal)) if journal.has_pending(&identifier) => {
+                // Keep the order: this edit goes after the ones still waiting
+                record_offline_edit(journal, &identifier, &parent, &content);
+                upload_succeeded = match flush_journal(journal, &client, &server, &identifier).await
                 {
-                    Ok(resp) => {
-                        if resp.status().is_success() {
-                            match resp.json::<ReplaceResponse>().await {
-                                Ok(result) => {
-                                    info!(
-                                        "Uploaded: {} chars inserted, {} deleted (cid: {})",
-                                        result.summary.chars_inserted,
-                                        result.summary.chars_deleted,
-                                        &result.cid[..8.min(result.cid.len())]
-                                    );
-
-                                    // Update state and persist to state file
-                                    // Hash the raw file bytes, not the (possibly base64) content
-                                    let cid = result.cid.clone();
-                                    let file_bytes = tokio::fs::read(&file_path).await.ok();
-                                    let content_hash = file_bytes
-                                        .as_ref()
-                                        .map(|b| compute_content_hash(b))
-                                        .unwrap_or_default();
-                                    let file_name = file_path
-                                        .file_name()
-                                        .map(|n| n.to_string_lossy().to_string())
-                                        .unwrap_or_else(|| "file".to_string());
-
-                                    let mut s = state.write().await;
-                                    s.last_written_cid = Some(result.cid);
-                                    s.last_written_content = content;
-                                    s.mark_synced(&cid, &content_hash, &file_name).await;
-                                    upload_succeeded = true;
-                                }
-                                Err(e) => {
-                                    error!("Failed to parse replace response: {}", e);
-                                }
-                            }
-                        } else {
-                            let status = resp.status();
-                            let body = resp.text().await.unwrap_or_default();
-                            error!("Upload failed: {} - {}", status, body);
-                        }
+                    FlushOutcome::Pushed { response, content } => {
+                        finish_push(&state, &file_path, response, content).await;
+                        true
                     }
-                    Err(e) => {
-                        error!("Upload request failed: {}", e);
+                    FlushOutcome::Rejected => {
+                        upload_replace(
+                            &client,
+                            &server,
+                            &identifier,
+                            &file_path,
+                            &state,
+                            &parent,
+                            content,
+                            use_paths,
+                            None,
+                        )
+                        .await
                     }
-                }
+                    FlushOutcome::Empty | FlushOutcome::Unreachable => false,
+                };
             }
-            None => {
+            (Some(parent), _) => {
+                // Normal case: use replace endpoint
+                // For force-push, parent is HEAD's cid so this is a simple replace
+                upload_succeeded = upload_replace(
+                    &client,
+                    &server,
+                    &identifier,
+                    &file_path,
+                    &state,
+                    &parent,
+                    content,
+                    use_paths,
+                    journaling,
+                )
+                .await;
+            }
+            (None, _) => {
                 // First commit: use edit endpoint with generated Yjs update
                 info!("Creating initial commit...");
                 let update = create_yjs_text_update(&content);
@@ -483,6 +501,111 @@ pub async fn upload_task(
     }
 }
 
+/// Upload `content` with the replace endpoint, diffed against `parent`.
+///
+/// With a `journal`, an edit that can't reach the server is journaled to be
+/// pushed as history later. Returns whether the upload succeeded.
+#[allow(clippy::too_many_arguments)]
+async fn upload_replace(
+    client: &Client,
+    server: &str,
+    identifier: &str,
+    file_path: &Path,
+    state: &Arc<RwLock<SyncState>>,
+    parent: &str,
+    content: String,
+    use_paths: bool,
+    journal: Option<&CommitJournal>,
+) -> bool {
+    let replace_url = build_replace_url(server, identifier, parent, use_paths);
+
+    match client
+        .post(&replace_url)
+        .header("content-type", "text/plain")
+        .body(content.clone())
+        .send()
+        .await
+    {
+        Ok(resp) => {
+            if resp.status().is_success() {
+                match resp.json::<ReplaceResponse>().await {
+                    Ok(result) => {
+                        info!(
+                            "Uploaded: {} chars inserted, {} deleted (cid: {})",
+                            result.summary.chars_inserted,
+                            result.summary.chars_deleted,
+                            &result.cid[..8.min(result.cid.len())]
+                        );
+
+                        // Update state and persist to state file
+                        // Hash the raw file bytes, not the (possibly base64) content
+                        let cid = result.cid.clone();
+                        let file_bytes = tokio::fs::read(file_path).await.ok();
+                        let content_hash = file_bytes
+                            .as_ref()
+                            .map(|b| compute_content_hash(b))
+                            .unwrap_or_default();
+
+                        let mut s = state.write().await;
+                        s.last_written_cid = Some(result.cid);
+                        s.last_written_content = content;
+                        s.mark_synced(&cid, &content_hash, &synced_file_name(file_path))
+                            .await;
+                        return true;
+                    }
+                    Err(e) => {
+                        error!("Failed to parse replace response: {}", e);
+                    }
+                }
+            } else {
+                let status = resp.status();
+                let body = resp.text().await.unwrap_or_default();
+                error!("Upload failed: {} - {}", status, body);
+            }
+        }
+        Err(e) => match journal {
+            Some(journal) => {
+                debug!("Upload request failed: {}", e);
+                record_offline_edit(journal, identifier, parent, &content);
+            }
+            None => error!("Upload request failed: {}", e),
+        },
+    }
+    false
+}
+
+/// Record journaled edits the server accepted: the file is synced up to
+/// `content`, the last of them.
+async fn finish_push(
+    state: &Arc<RwLock<SyncState>>,
+    file_path: &Path,
+    response: PushResponse,
+    content: String,
+) {
+    let content_hash = compute_content_hash(content.as_bytes());
+    let mut s = state.write().await;
+    s.last_written_cid = Some(response.cid.clone());
+    s.last_written_content = content;
+    s.mark_synced(&response.cid, &content_hash, &synced_file_name(file_path))
+        .await;
+    if response.merge_cid.is_some() {
+        // Merged with server edits the file doesn't have yet
+        s.needs_head_refresh = true;
+    }
+}
+
+/// Whether edits of `identifier` are waiting in `journal`.
+fn journal_pending(journal: Option<&CommitJournal>, identifier: &str) -> bool {
+    journal.is_some_and(|journal| journal.has_pending(identifier))
+}
+
+fn synced_file_name(file_path: &Path) -> String {
+    file_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "file".to_string())
+}
+
 /// Perform initial sync: fetch HEAD and write to local file
 pub async fn initial_sync(
     client: &Client,
@@ -617,6 +740,7 @@ pub async fn sync_single_file(
             let options = ScanOptions {
                 include_hidden: false,
                 ignore_patterns: vec![],
+                include_patterns: None,
             };
             if let Ok(schema) = scan_directory(&owning.directory, &options) {
                 if let Ok(json) = schema_to_json(&schema) {
@@ -625,9 +749,10 @@ pub async fn sync_single_file(
                         owning.directory.display(),
                         owning.document_id
                     );
-                    if let Err(e) =
-                        push_schema_to_server(client, server, &owning.document_id, &json).await
-                    {
+                    let pushed = push_schema_to_server(client, server, &owning.document_id, &json)
+                        .await
+                        .map_err(|e| e.to_string());
+                    if let Err(e) = pushed {
                         warn!("Failed to push subdirectory schema: {}", e);
                     } else {
                         // Wait for server to reconcile and create the document
@@ -863,6 +988,9 @@ pub async fn sync_single_file(
 /// Spawn sync tasks (watcher, upload, SSE) for a single file.
 /// Returns the task handles so they can be aborted on file deletion.
 ///
+/// With the WebSocket transport in `context`, text files addressed by node
+/// ID sync through [`ws_sync_task`] in place of the upload and SSE tasks.
+///
 /// - push_only: Skip SSE subscription (only push local changes)
 /// - pull_only: Skip file watcher (only pull server changes)
 /// - force_push: Always fetch HEAD before upload to ensure local replaces server
@@ -877,13 +1005,39 @@ pub fn spawn_file_sync_tasks(
     push_only: bool,
     pull_only: bool,
     force_push: bool,
+    context: &SyncContext,
 ) -> Vec<JoinHandle<()>> {
     let (file_tx, file_rx) = mpsc::channel::<FileEvent>(100);
     let mut handles = Vec::new();
 
+    let ws = context
+        .ws
+        .clone()
+        .filter(|_| !use_paths && !force_push && is_text_syncable(&file_path));
+    if let Some(transport) = ws {
+        let rx = if pull_only {
+            None
+        } else {
+            handles.push(tokio::spawn(file_watcher_task(
+                file_path.clone(),
+                file_tx,
+                context.watcher.clone(),
+            )));
+            Some(file_rx)
+        };
+        handles.push(tokio::spawn(ws_sync_task(
+            server, identifier, file_path, state, rx, push_only, transport,
+        )));
+        return handles;
+    }
+
     // File watcher and upload tasks (skip if pull-only)
     if !pull_only {
-        handles.push(tokio::spawn(file_watcher_task(file_path.clone(), file_tx)));
+        handles.push(tokio::spawn(file_watcher_task(
+            file_path.clone(),
+            file_tx,
+            context.watcher.clone(),
+        )));
         handles.push(tokio::spawn(upload_task(
             client.clone(),
             server.clone(),
@@ -893,6 +1047,7 @@ pub fn spawn_file_sync_tasks(
             file_rx,
             use_paths,
             force_push,
+            context.journal.clone(),
         )));
     }
 
@@ -0,0 +1,269 @@
+//! `.commonplaceignore` files.
+//!
+//! Any synced directory may contain a `.commonplaceignore` file with gitignore
+//! syntax (negation, directory-only patterns, anchored paths). Patterns are
+//! relative to the directory holding the file, and rules in deeper files take
+//! precedence over those in enclosing directories. As with gitignore, a file
+//! below an ignored directory cannot be re-included. The files themselves are
+//! synced like any other document so everyone sharing a directory shares its
+//! rules.
+//!
+//! The `--ignore` patterns from [`ScanOptions`] apply at the scan root with
+//! the lowest precedence. In a sparse checkout, paths outside the checked-out
+//! subtrees are ignored too (see [`crate::sync::sparse`]).
+
+use crate::sync::dir_sync::SCHEMA_FILENAME;
+use crate::sync::directory::ScanOptions;
+use crate::sync::sparse::SparseFilter;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+
+/// Name of the per-directory ignore file.
+pub const IGNORE_FILENAME: &str = ".commonplaceignore";
+
+/// Whether a file name is hidden.
+///
+/// `.commonplaceignore` is never hidden, so it syncs even when hidden files
+/// are excluded.
+pub fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.') && name != IGNORE_FILENAME
+}
+
+/// Whether `path` is a `.commonplaceignore` file.
+pub fn is_ignore_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == IGNORE_FILENAME)
+}
+
+/// The ignore rules for a synced directory tree.
+///
+/// `.commonplaceignore` files are read on first use and cached; call
+/// [`IgnoreRules::reload`] after one changes.
+pub struct IgnoreRules {
+    root: PathBuf,
+    canonical_root: Option<PathBuf>,
+    /// Rules from `--ignore` patterns, rooted at `root`
+    patterns: Gitignore,
+    /// Rules from enclosing synced directories, innermost first
+    enclosing: Vec<Gitignore>,
+    /// Rules per directory relative to `root` (`""` for the root itself)
+    cache: HashMap<PathBuf, Gitignore>,
+    /// The subtrees checked out, for a sparse checkout
+    sparse: SparseFilter,
+}
+
+impl IgnoreRules {
+    /// Rules for the tree at `root`.
+    ///
+    /// When `root` is a node-backed subdirectory of a larger synced tree, the
+    /// `.commonplaceignore` files of the enclosing synced directories (those
+    /// with a `.commonplace.json`) apply too.
+    pub fn new(root: &Path, options: &ScanOptions) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in &options.ignore_patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                warn!("Invalid ignore pattern {:?}: {}", pattern, e);
+            }
+        }
+        let patterns = builder.build().unwrap_or_else(|e| {
+            warn!("Failed to build ignore patterns: {}", e);
+            Gitignore::empty()
+        });
+
+        let mut enclosing = Vec::new();
+        let mut dir = root.parent();
+        while let Some(parent) = dir.filter(|d| d.join(SCHEMA_FILENAME).is_file()) {
+            enclosing.push(load_ignore_file(parent));
+            dir = parent.parent();
+        }
+
+        Self {
+            root: root.to_path_buf(),
+            canonical_root: root.canonicalize().ok(),
+            patterns,
+            enclosing,
+            cache: HashMap::new(),
+            sparse: match &options.include_patterns {
+                Some(patterns) => SparseFilter::new(patterns),
+                None => SparseFilter::load(root),
+            },
+        }
+    }
+
+    /// The subtrees of the root that are checked out.
+    pub fn sparse(&self) -> &SparseFilter {
+        &self.sparse
+    }
+
+    /// Forget cached `.commonplaceignore` files so they are read again.
+    pub fn reload(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Whether `path` (absolute, or relative to the root) is ignored.
+    ///
+    /// Paths outside the root are never ignored.
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let Some(relative) = self.relative(path) else {
+            return false;
+        };
+        let components: Vec<Component> = relative.components().collect();
+        // An ignored directory ignores everything below it
+        let mut prefix = PathBuf::new();
+        for (i, component) in components.iter().enumerate() {
+            prefix.push(component);
+            let last = i + 1 == components.len();
+            if self.matches(&prefix, !last || is_dir) {
+                return true;
+            }
+        }
+        if self.sparse.is_full() {
+            return false;
+        }
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if is_dir {
+            !self.sparse.may_contain(&relative)
+        } else {
+            !self.sparse.includes(&relative)
+        }
+    }
+
+    /// Whether `relative` itself matches, without looking at its parents.
+    fn matches(&mut self, relative: &Path, is_dir: bool) -> bool {
+        let path = self.root.join(relative);
+        let mut dir = relative.parent();
+        while let Some(d) = dir {
+            let rules = self.rules_for(d);
+            match rules.matched(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+            dir = d.parent();
+        }
+        for rules in self.enclosing.iter().chain(std::iter::once(&self.patterns)) {
+            match rules.matched(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    fn rules_for(&mut self, relative_dir: &Path) -> &Gitignore {
+        let root = &self.root;
+        self.cache
+            .entry(relative_dir.to_path_buf())
+            .or_insert_with(|| load_ignore_file(&root.join(relative_dir)))
+    }
+
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        if path.is_relative() && !path.starts_with(&self.root) {
+            return Some(path.to_path_buf());
+        }
+        if let Ok(rel) = path.strip_prefix(&self.root) {
+            return Some(rel.to_path_buf());
+        }
+        // The path may be canonical (as from the watcher) while the root is not
+        let canonical_root = self.canonical_root.as_ref()?;
+        if let Ok(rel) = path.strip_prefix(canonical_root) {
+            return Some(rel.to_path_buf());
+        }
+        let parent = path.parent()?.canonicalize().ok()?;
+        let rel = parent.strip_prefix(canonical_root).ok()?;
+        Some(rel.join(path.file_name()?))
+    }
+}
+
+/// Load the `.commonplaceignore` in `dir`, or empty rules if there is none.
+fn load_ignore_file(dir: &Path) -> Gitignore {
+    let path = dir.join(IGNORE_FILENAME);
+    if !path.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&path) {
+        warn!("Error in {}: {}", path.display(), e);
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("Failed to load {}: {}", path.display(), e);
+        Gitignore::empty()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_gitignore_semantics() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("build/keep")).unwrap();
+        fs::create_dir_all(root.join("docs/drafts")).unwrap();
+        fs::write(
+            root.join(IGNORE_FILENAME),
+            "*.log\n!important.log\nbuild/\n/top.txt\n",
+        )
+        .unwrap();
+        fs::write(root.join("docs").join(IGNORE_FILENAME), "drafts/\n!*.log\n").unwrap();
+
+        let mut rules = IgnoreRules::new(root, &ScanOptions::default());
+        assert!(rules.is_ignored(Path::new("debug.log"), false));
+        assert!(!rules.is_ignored(Path::new("important.log"), false));
+        // Directory-only pattern
+        assert!(rules.is_ignored(Path::new("build"), true));
+        assert!(rules.is_ignored(&root.join("build/keep/a.txt"), false));
+        // Anchored pattern only matches at its own level
+        assert!(rules.is_ignored(Path::new("top.txt"), false));
+        assert!(!rules.is_ignored(Path::new("docs/top.txt"), false));
+        // Deeper files take precedence
+        assert!(!rules.is_ignored(Path::new("docs/notes.log"), false));
+        assert!(rules.is_ignored(Path::new("docs/drafts/a.md"), false));
+        assert!(!rules.is_ignored(Path::new("docs/a.md"), false));
+    }
+
+    #[test]
+    fn test_patterns_and_reload() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        let options = ScanOptions {
+            ignore_patterns: vec!["*.tmp".to_string()],
+            ..Default::default()
+        };
+        let mut rules = IgnoreRules::new(root, &options);
+        assert!(rules.is_ignored(Path::new("a/b.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("a.txt"), false));
+
+        fs::write(root.join(IGNORE_FILENAME), "a.txt\n!keep.tmp\n").unwrap();
+        assert!(!rules.is_ignored(Path::new("a.txt"), false));
+        rules.reload();
+        assert!(rules.is_ignored(Path::new("a.txt"), false));
+        assert!(!rules.is_ignored(Path::new("keep.tmp"), false));
+    }
+
+    #[test]
+    fn test_enclosing_synced_directory_rules_apply() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join(SCHEMA_FILENAME), "{}").unwrap();
+        fs::write(root.join(IGNORE_FILENAME), "*.bak\n").unwrap();
+
+        let mut rules = IgnoreRules::new(&root.join("sub"), &ScanOptions::default());
+        assert!(rules.is_ignored(Path::new("x.bak"), false));
+        assert!(!rules.is_ignored(Path::new("x.txt"), false));
+    }
+
+    #[test]
+    fn test_ignore_file_is_not_hidden() {
+        assert!(!is_hidden_name(IGNORE_FILENAME));
+        assert!(is_hidden_name(".env"));
+        assert!(is_ignore_file(Path::new("a/.commonplaceignore")));
+    }
+}
@@ -0,0 +1,339 @@
+//! Local journal of edits made while the server is unreachable.
+//!
+//! When an upload can't reach the server, the edit is recorded as a commit
+//! whose parent is the previous journaled commit, or the document's last
+//! synced commit for the first one. The chain lives in a redb file beside the
+//! synced file, so it survives restarts. Once the server answers again the
+//! whole chain is pushed to `POST /docs/:id/push`, which stores every commit
+//! as history and merges the chain with whatever the server has meanwhile.
+
+use crate::sync::{encode_node_id, PushRequest, PushResponse};
+use redb::{Database, ReadableTable, TableDefinition};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Journaled commits keyed by document and sequence number.
+const JOURNAL_TABLE: TableDefinition<(&str, u64), &str> = TableDefinition::new("journal");
+
+/// Open the journal for `target`, logging why if it can't be: offline edits
+/// then keep retrying as single uploads instead.
+pub fn open_journal(target: &Path) -> Option<Arc<CommitJournal>> {
+    match CommitJournal::open(CommitJournal::journal_path(target)) {
+        Ok(journal) => Some(Arc::new(journal)),
+        Err(e) => {
+            warn!(
+                "Failed to open journal, offline edits won't keep history: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// An edit made while the server was unreachable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalCommit {
+    /// Local id, named by the next commit's parent
+    pub id: String,
+    /// The previous journaled commit, or the server commit the chain starts from
+    pub parent: String,
+    /// Full file content after the edit
+    pub content: String,
+    /// When the edit was made, in Unix milliseconds
+    pub timestamp: u64,
+}
+
+/// Journaled commits, in order, for each document.
+#[derive(Debug)]
+pub struct CommitJournal {
+    db: Database,
+}
+
+fn db_error(e: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("Journal error: {}", e))
+}
+
+impl CommitJournal {
+    /// Derive the journal path for a sync target, next to its state file.
+    ///
+    /// For a target "notes/readme.txt", returns
+    /// "notes/.readme.txt.commonplace-journal.redb".
+    pub fn journal_path(target: &Path) -> PathBuf {
+        let file_name = target
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("sync");
+        let journal_name = format!(".{}.commonplace-journal.redb", file_name);
+
+        match target.parent() {
+            Some(parent) => parent.join(&journal_name),
+            None => PathBuf::from(&journal_name),
+        }
+    }
+
+    /// Create or open a journal at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let db = Database::create(path).map_err(db_error)?;
+        Ok(Self { db })
+    }
+
+    /// Journal an edit of `doc_id` to `content`.
+    ///
+    /// The commit follows the last one journaled, or `synced_cid` if there is
+    /// none. Returns `None` if the content is what the last commit holds.
+    pub fn record(
+        &self,
+        doc_id: &str,
+        synced_cid: &str,
+        content: &str,
+    ) -> io::Result<Option<JournalCommit>> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        let commit = {
+            let mut table = txn.open_table(JOURNAL_TABLE).map_err(db_error)?;
+            let last = table
+                .range((doc_id, 0)..=(doc_id, u64::MAX))
+                .map_err(db_error)?
+                .next_back()
+                .transpose()
+                .map_err(db_error)?
+                .map(|(key, value)| {
+                    serde_json::from_str::<JournalCommit>(value.value())
+                        .map(|commit| (key.value().1, commit))
+                })
+                .transpose()?;
+
+            let (seq, parent) = match last {
+                Some((_, tip)) if tip.content == content => return Ok(None),
+                Some((seq, tip)) => (seq + 1, tip.id),
+                None => (0, synced_cid.to_string()),
+            };
+            let commit = JournalCommit {
+                id: uuid::Uuid::new_v4().to_string(),
+                parent,
+                content: content.to_string(),
+                timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64,
+            };
+            let json = serde_json::to_string(&commit)?;
+            table
+                .insert((doc_id, seq), json.as_str())
+                .map_err(db_error)?;
+            commit
+        };
+        txn.commit().map_err(db_error)?;
+        Ok(Some(commit))
+    }
+
+    /// The commits journaled for `doc_id`, oldest first.
+    pub fn commits(&self, doc_id: &str) -> io::Result<Vec<JournalCommit>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = match txn.open_table(JOURNAL_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(db_error(e)),
+        };
+        let mut commits = Vec::new();
+        for entry in table
+            .range((doc_id, 0)..=(doc_id, u64::MAX))
+            .map_err(db_error)?
+        {
+            let (_, value) = entry.map_err(db_error)?;
+            commits.push(serde_json::from_str(value.value())?);
+        }
+        Ok(commits)
+    }
+
+    /// Whether any commits are waiting for `doc_id`.
+    pub fn has_pending(&self, doc_id: &str) -> bool {
+        self.commits(doc_id)
+            .map(|commits| !commits.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Forget the commits journaled for `doc_id`.
+    pub fn clear(&self, doc_id: &str) -> io::Result<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(JOURNAL_TABLE).map_err(db_error)?;
+            table
+                .retain_in((doc_id, 0)..=(doc_id, u64::MAX), |_, _| false)
+                .map_err(db_error)?;
+        }
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Journal an edit, logging failures.
+pub fn record_offline_edit(journal: &CommitJournal, doc_id: &str, synced_cid: &str, content: &str) {
+    match journal.record(doc_id, synced_cid, content) {
+        Ok(Some(commit)) => info!(
+            "Server unreachable; journaled edit {} of {}",
+            &commit.id[..8],
+            doc_id
+        ),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to journal edit of {}: {}", doc_id, e),
+    }
+}
+
+/// What became of the journaled commits for a document.
+pub enum FlushOutcome {
+    /// Nothing was journaled
+    Empty,
+    /// The commits are on the server; `content` is what the last one holds
+    Pushed {
+        response: PushResponse,
+        content: String,
+    },
+    /// The server is still out of reach; the commits stay journaled
+    Unreachable,
+    /// The server refused the commits and they were dropped; the file's
+    /// content has to be uploaded as a single edit instead
+    Rejected,
+}
+
+/// Push the commits journaled for `node_id` to the server.
+pub async fn flush_journal(
+    journal: &CommitJournal,
+    client: &Client,
+    server: &str,
+    node_id: &str,
+) -> FlushOutcome {
+    let commits = match journal.commits(node_id) {
+        Ok(commits) => commits,
+        Err(e) => {
+            warn!("Failed to read journal for {}: {}", node_id, e);
+            return FlushOutcome::Empty;
+        }
+    };
+    let (Some(first), Some(last)) = (commits.first(), commits.last()) else {
+        return FlushOutcome::Empty;
+    };
+    let content = last.content.clone();
+    let request = PushRequest {
+        base: first.parent.clone(),
+        commits: commits.clone(),
+    };
+
+    let url = format!(
+        "{}/docs/{}/push?author=sync-client",
+        server,
+        encode_node_id(node_id)
+    );
+    let resp = match client.post(&url).json(&request).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            debug!("Server still unreachable for {}: {}", node_id, e);
+            return FlushOutcome::Unreachable;
+        }
+    };
+
+    let status = resp.status();
+    if status.is_server_error() {
+        warn!("Push of journaled edits failed: {}", status);
+        return FlushOutcome::Unreachable;
+    }
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        warn!(
+            "Server refused {} journaled edits of {}: {} - {}",
+            commits.len(),
+            node_id,
+            status,
+            body
+        );
+        if let Err(e) = journal.clear(node_id) {
+            warn!("Failed to clear journal for {}: {}", node_id, e);
+        }
+        return FlushOutcome::Rejected;
+    }
+
+    match resp.json::<PushResponse>().await {
+        Ok(response) => {
+            info!(
+                "Pushed {} journaled edits of {} (head: {})",
+                response.commits.len(),
+                node_id,
+                &response.cid[..8.min(response.cid.len())]
+            );
+            if let Err(e) = journal.clear(node_id) {
+                warn!("Failed to clear journal for {}: {}", node_id, e);
+            }
+            FlushOutcome::Pushed { response, content }
+        }
+        Err(e) => {
+            // Pushing again is harmless: the server recognises a chain it
+            // already holds
+            warn!("Failed to parse push response: {}", e);
+            FlushOutcome::Unreachable
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_path() {
+        assert_eq!(
+            CommitJournal::journal_path(Path::new("notes/readme.txt")),
+            PathBuf::from("notes/.readme.txt.commonplace-journal.redb")
+        );
+    }
+
+    #[test]
+    fn test_record_chains_commits() {
+        let temp = TempDir::new().unwrap();
+        let journal = CommitJournal::open(temp.path().join("journal.redb")).unwrap();
+
+        let first = journal.record("doc", "base", "one\n").unwrap().unwrap();
+        assert_eq!(first.parent, "base");
+        // Later edits follow the journaled tip, not the stale synced commit
+        let second = journal
+            .record("doc", "base", "one\ntwo\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.parent, first.id);
+        // Unchanged content is not a new commit
+        assert!(journal
+            .record("doc", "base", "one\ntwo\n")
+            .unwrap()
+            .is_none());
+
+        journal.record("other", "base2", "x").unwrap().unwrap();
+        assert_eq!(journal.commits("doc").unwrap(), vec![first, second]);
+        assert_eq!(journal.commits("other").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_clear_keeps_other_documents() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("journal.redb");
+        {
+            let journal = CommitJournal::open(&path).unwrap();
+            assert!(!journal.has_pending("doc"));
+            journal.record("doc", "base", "a").unwrap();
+            journal.record("other", "base", "b").unwrap();
+        }
+
+        // Survives reopening
+        let journal = CommitJournal::open(&path).unwrap();
+        assert!(journal.has_pending("doc"));
+        journal.clear("doc").unwrap();
+        assert!(!journal.has_pending("doc"));
+        assert!(journal.has_pending("other"));
+
+        // A new chain starts from the synced commit again
+        let commit = journal.record("doc", "newer", "c").unwrap().unwrap();
+        assert_eq!(commit.parent, "newer");
+    }
+}
@@ -7,17 +7,29 @@ use std::path::Path;
 use tracing::{error, info};
 
 pub mod client;
+pub mod conflict;
 pub mod content_type;
+pub mod context;
+pub mod daemon;
 pub mod dir_sync;
 pub mod directory;
+pub mod dry_run;
 pub mod file_sync;
+pub mod ignore_file;
+pub mod journal;
+pub mod mqtt;
+pub mod runner;
+pub mod sparse;
 pub mod sse;
 pub mod state;
 pub mod state_file;
+pub mod status;
+pub mod text_sync;
 pub mod types;
 pub mod urls;
 pub mod uuid_map;
 pub mod watcher;
+pub mod ws;
 pub mod yjs;
 
 /// Lock file name for sync process
@@ -56,10 +68,15 @@ pub fn acquire_sync_lock(directory: &Path) -> io::Result<File> {
 pub use client::{
     fork_node, push_file_content, push_json_content, push_jsonl_content, push_schema_to_server,
 };
+pub use daemon::{
+    query_daemon, serve_daemon_socket, DaemonConfig, DaemonRequest, DaemonResponse, RootInfo,
+    RootSpec, RootState, SyncDaemon, ROOT_RETRY_DELAY,
+};
 pub use dir_sync::{
     check_server_has_content, directory_sse_task, ensure_fs_root_exists, handle_file_created,
-    handle_file_deleted, handle_file_modified, handle_schema_change, push_nested_schemas,
-    subdir_sse_task, sync_schema, write_nested_schemas, write_schema_file, SCHEMA_FILENAME,
+    handle_file_deleted, handle_file_modified, handle_file_renamed, handle_schema_change,
+    push_nested_schemas, subdir_sse_task, sync_schema, write_nested_schemas, write_schema_file,
+    SCHEMA_FILENAME,
 };
 pub use file_sync::{
     initial_sync, spawn_file_sync_tasks, sync_single_file, upload_task, BARRIER_RETRY_COUNT,
@@ -71,26 +88,49 @@ pub use uuid_map::{
     get_all_node_backed_dir_ids,
 };
 
+pub use conflict::{
+    conflict_copy_path, is_conflict_copy, local_author, write_conflict_copy, ConflictRecord,
+    ConflictSide, CONFLICT_COPY_PATTERN,
+};
 pub use content_type::{
     detect_from_path, is_allowed_extension, is_binary_content, looks_like_base64_binary,
     ContentTypeInfo,
 };
+pub use context::SyncContext;
 pub use directory::{
-    scan_directory, scan_directory_with_contents, schema_to_json, ScanError, ScanOptions,
-    ScannedFile,
+    scan_directory, scan_directory_with_contents, scan_directory_with_node_ids, schema_to_json,
+    ScanError, ScanOptions, ScannedFile,
+};
+pub use dry_run::{
+    plan_directory_sync, plan_file_sync, print_plan, PlanAction, PlanEntry, PlanSide,
 };
+pub use ignore_file::{is_hidden_name, is_ignore_file, IgnoreRules, IGNORE_FILENAME};
+pub use journal::{flush_journal, open_journal, CommitJournal, FlushOutcome, JournalCommit};
+pub use mqtt::mqtt_sync_task;
+pub use runner::{
+    directory_scan_options, flush_directory_journal, merge_offline_edits, run_directory_sync,
+    run_file_sync, spawn_directory_checkpoints, start_control_socket, stop_control_socket,
+};
+pub use sparse::{read_sparse_file, write_sparse_file, SparseFilter, SPARSE_FILENAME};
 pub use sse::{handle_server_edit, refresh_from_head, sse_task, PENDING_WRITE_TIMEOUT};
 pub use state::{PendingWrite, SyncState};
+pub use status::{
+    checkpoint_directory_state, collect_status, control_socket_path, find_sync_target,
+    query_control_socket, serve_control_socket, ControlSource, FileStatus, StatusEntry,
+    StatusOptions, StatusReport, SyncProcessStatus, STATE_CHECKPOINT_INTERVAL,
+};
+pub use text_sync::is_text_syncable;
 pub use types::{
     CommitData, DirEvent, EditEventData, EditRequest, EditResponse, FileEvent, FileSyncState,
-    ForkResponse, HeadResponse, ReplaceResponse, ReplaceSummary,
+    ForkResponse, HeadResponse, PushRequest, PushResponse, ReplaceResponse, ReplaceSummary,
 };
 pub use urls::{
-    build_edit_url, build_fork_url, build_head_url, build_replace_url, build_sse_url,
+    build_edit_url, build_fork_url, build_head_url, build_replace_url, build_sse_url, build_ws_url,
     encode_node_id, encode_path, normalize_path,
 };
-pub use watcher::{directory_watcher_task, file_watcher_task};
+pub use watcher::{directory_watcher_task, file_watcher_task, SharedWatcher, Subscription};
+pub use ws::{connect_ws, probe_ws, ws_sync_task, WsTransport, WsTransportError};
 pub use yjs::{
-    base64_decode, base64_encode, create_yjs_json_update, create_yjs_jsonl_update,
+    apply_text_diff, base64_decode, base64_encode, create_yjs_json_update, create_yjs_jsonl_update,
     create_yjs_text_diff_update, create_yjs_text_update, json_value_to_any, TEXT_ROOT_NAME,
 };
@@ -0,0 +1,264 @@
+//! MQTT transport for the sync client.
+//!
+//! With `commonplace-sync --mqtt-broker`, a text file syncs with the document
+//! at `--path` in the store's fs-root by talking to the broker alone, so no
+//! HTTP server is needed. The client catches up with a `negotiate` request on
+//! `{path}/sync/{client-id}`, then follows `{path}/edits` for live updates
+//! and publishes its own edits there. The retained `{path}/head`
+//! announcements name the commits; a head nobody sent us an edit for (e.g.
+//! one made while disconnected) triggers another catch-up from the last head
+//! we hold.
+
+use crate::mqtt::{EditMessage, HeadMessage, MqttClient, MqttError, SyncMessage, Topic};
+use crate::sync::text_sync::{merge_local_edit, merge_remote_updates, next_event, TextDoc};
+use crate::sync::{is_binary_content, FileEvent, SyncState};
+use rumqttc::QoS;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// How long to wait for the store to answer a catch-up before asking again
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ask for a snapshot instead of the commits when more than this many are
+/// missing
+const SNAPSHOT_AFTER: usize = 100;
+
+/// A catch-up request awaiting its `done`.
+struct CatchUp {
+    req: String,
+    /// Updates received so far, including live edits, in arrival order
+    updates: Vec<Vec<u8>>,
+    /// Last commit streamed
+    head: Option<String>,
+    deadline: Instant,
+}
+
+/// Task that syncs a single text file over MQTT.
+///
+/// `path` is the file's path in the store's fs-root. `rx` carries the file
+/// watcher's events and is `None` in pull-only mode; in push-only mode
+/// remote changes are merged into the document but never written to the
+/// file. Returns only if the client goes away.
+pub async fn mqtt_sync_task(
+    client: Arc<MqttClient>,
+    path: String,
+    file_path: PathBuf,
+    state: Arc<RwLock<SyncState>>,
+    mut rx: Option<mpsc::Receiver<FileEvent>>,
+    push_only: bool,
+    author: String,
+) -> Result<(), MqttError> {
+    let pull_only = rx.is_none();
+    let edits_topic = Topic::edits(&path).to_topic_string();
+    let head_topic = Topic::head_topic_str(&path);
+    let sync_topic = Topic::sync(&path, client.client_id()).to_topic_string();
+
+    // Subscribe before asking, so nothing published meanwhile is missed
+    let mut messages = client.subscribe_messages();
+    for topic in [&edits_topic, &head_topic, &sync_topic] {
+        client.subscribe(topic, QoS::AtLeastOnce).await?;
+    }
+
+    let mut doc = TextDoc::new();
+    // Latest commit whose changes the document holds
+    let mut head: Option<String> = None;
+    // Edits received whose head announcement hasn't arrived yet
+    let mut unheaded_edits = 0usize;
+    info!("Catching up with {} over MQTT", path);
+    let mut catch_up = Some(request_catch_up(&client, &sync_topic, None).await?);
+
+    loop {
+        let deadline = catch_up.as_ref().map(|c| c.deadline);
+        tokio::select! {
+            received = messages.recv() => {
+                let msg = match received {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("MQTT sync for {} lagged by {} messages; catching up", path, n);
+                        if catch_up.is_none() {
+                            catch_up = Some(request_catch_up(&client, &sync_topic, head.clone()).await?);
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+
+                let outgoing = if msg.topic == sync_topic {
+                    let Ok(reply) = serde_json::from_slice::<SyncMessage>(&msg.payload) else {
+                        continue;
+                    };
+                    let Some(pending) = catch_up.as_mut().filter(|c| c.req == reply.req()) else {
+                        // Our own request, or a reply to an abandoned one
+                        continue;
+                    };
+                    match reply {
+                        SyncMessage::Commit { id: commit, data, .. }
+                        | SyncMessage::Snapshot { commit, data, .. } => {
+                            match crate::b64::decode(&data) {
+                                Ok(update) => pending.updates.push(update),
+                                Err(e) => warn!("Invalid update in commit {}: {}", commit, e),
+                            }
+                            pending.head = Some(commit);
+                            continue;
+                        }
+                        SyncMessage::Error { message, .. } => {
+                            warn!("Catch-up for {} failed: {}", path, message);
+                            continue;
+                        }
+                        SyncMessage::Done { .. } => {
+                            let Some(pending) = catch_up.take() else {
+                                continue;
+                            };
+                            let outgoing = merge_remote_updates(&file_path, &state, &mut doc, &pending.updates, pull_only, push_only).await;
+                            if let Some(commit) = pending.head {
+                                record_head(&state, &file_path, &commit).await;
+                                head = Some(commit);
+                            }
+                            debug!("Caught up with {} at {:?}", path, head);
+                            outgoing
+                        }
+                        _ => continue,
+                    }
+                } else if msg.topic == edits_topic {
+                    let update = match serde_json::from_slice::<EditMessage>(&msg.payload)
+                        .map_err(|e| e.to_string())
+                        .and_then(|edit| crate::b64::decode(&edit.update).map_err(|e| e.to_string()))
+                    {
+                        Ok(update) => update,
+                        Err(e) => {
+                            warn!("Ignoring invalid edit for {}: {}", path, e);
+                            continue;
+                        }
+                    };
+                    unheaded_edits += 1;
+                    if !doc.synced {
+                        // Applied along with the history once it's in
+                        if let Some(pending) = catch_up.as_mut() {
+                            pending.updates.push(update);
+                        }
+                        continue;
+                    }
+                    merge_remote_updates(&file_path, &state, &mut doc, &[update], pull_only, push_only).await
+                } else if msg.topic == head_topic {
+                    let Ok(announced) = serde_json::from_slice::<HeadMessage>(&msg.payload) else {
+                        continue;
+                    };
+                    if head.as_deref() == Some(announced.cid.as_str()) {
+                        continue;
+                    }
+                    if unheaded_edits > 0 {
+                        unheaded_edits -= 1;
+                        record_head(&state, &file_path, &announced.cid).await;
+                        head = Some(announced.cid);
+                    } else if catch_up.is_none() {
+                        info!("{} moved to {} without us; catching up", path, announced.cid);
+                        catch_up = Some(request_catch_up(&client, &sync_topic, head.clone()).await?);
+                    }
+                    continue;
+                } else {
+                    continue;
+                };
+
+                for update in outgoing {
+                    publish_edit(&client, &edits_topic, &update, &author).await?;
+                }
+            }
+            _ = sleep_until(deadline) => {
+                warn!(
+                    "No catch-up reply for {} after {:?} (is it in the store's fs-root?); asking again",
+                    path, CATCH_UP_TIMEOUT
+                );
+                catch_up = Some(request_catch_up(&client, &sync_topic, head.clone()).await?);
+            }
+            event = next_event(&mut rx) => {
+                let Some(FileEvent::Modified(raw)) = event else {
+                    rx = None;
+                    continue;
+                };
+                // Until the history is in there is nothing to diff against;
+                // the first merge picks the edit up from the file
+                if !doc.synced {
+                    continue;
+                }
+                if is_binary_content(&raw) {
+                    warn!("{} now holds binary content; not syncing it over MQTT", file_path.display());
+                    continue;
+                }
+                let content = String::from_utf8_lossy(&raw).to_string();
+                if let Some(update) = merge_local_edit(&state, &mut doc, content).await {
+                    publish_edit(&client, &edits_topic, &update, &author).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Ask the store for the commits after `head` (everything if `None`).
+async fn request_catch_up(
+    client: &MqttClient,
+    sync_topic: &str,
+    head: Option<String>,
+) -> Result<CatchUp, MqttError> {
+    let req = uuid::Uuid::new_v4().to_string();
+    let request = SyncMessage::Negotiate {
+        req: req.clone(),
+        want: "HEAD".to_string(),
+        have: head.into_iter().collect(),
+        bloom: None,
+        snapshot_after: Some(SNAPSHOT_AFTER),
+    };
+    client
+        .publish(sync_topic, &serde_json::to_vec(&request)?, QoS::AtLeastOnce)
+        .await?;
+    Ok(CatchUp {
+        req,
+        updates: Vec::new(),
+        head: None,
+        deadline: Instant::now() + CATCH_UP_TIMEOUT,
+    })
+}
+
+/// Publish a local update to the edits port.
+async fn publish_edit(
+    client: &MqttClient,
+    edits_topic: &str,
+    update: &[u8],
+    author: &str,
+) -> Result<(), MqttError> {
+    let edit = EditMessage {
+        update: crate::b64::encode(update),
+        // Left to the store, which chains the commit onto its current head;
+        // naming the head we last saw would fork history whenever another
+        // client's commit landed in between
+        parents: Vec::new(),
+        author: author.to_string(),
+        message: None,
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+    };
+    client
+        .publish(edits_topic, &serde_json::to_vec(&edit)?, QoS::AtLeastOnce)
+        .await
+}
+
+/// Record that the file holds `commit`.
+async fn record_head(state: &Arc<RwLock<SyncState>>, file_path: &Path, commit: &str) {
+    let mut s = state.write().await;
+    s.last_written_cid = Some(commit.to_string());
+    s.checkpoint(file_path).await;
+}
+
+/// Sleep until `deadline`; never resolves without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
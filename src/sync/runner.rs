@@ -0,0 +1,991 @@
+//! Running a sync of one file or directory.
+//!
+//! `commonplace-sync` runs one of these until Ctrl+C; `commonplace-syncd`
+//! runs many side by side and stops each when its root is removed or paused.
+//! Either way the runner cleans up after itself: its tasks are aborted, the
+//! sync state is checkpointed and its control socket is removed.
+
+use crate::sync::context::SyncContext;
+use crate::sync::file_sync::ensure_trailing_newline;
+use crate::sync::journal::CommitJournal;
+use crate::sync::state_file::{compute_content_hash, SyncStateFile};
+use crate::sync::status::content_matches;
+use crate::sync::{
+    build_head_url, build_replace_url, build_uuid_map_recursive, check_server_has_content,
+    checkpoint_directory_state, control_socket_path, detect_from_path, directory_sse_task,
+    directory_watcher_task, encode_node_id, ensure_fs_root_exists, flush_journal,
+    get_all_node_backed_dir_ids, handle_file_created, handle_file_deleted, handle_file_modified,
+    handle_file_renamed, handle_schema_change, initial_sync, is_binary_content, open_journal,
+    scan_directory_with_contents, serve_control_socket, spawn_file_sync_tasks, subdir_sse_task,
+    sync_schema, sync_single_file, write_conflict_copy, ConflictRecord, ConflictSide,
+    ControlSource, DirEvent, FileSyncState, FlushOutcome, HeadResponse, ReplaceResponse,
+    ScanOptions, ScannedFile, SyncState, CONFLICT_COPY_PATTERN, SCHEMA_FILENAME, SPARSE_FILENAME,
+    STATE_CHECKPOINT_INTERVAL,
+};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+/// Scan options for syncing a directory: the given ones, plus the files sync
+/// keeps in the directory for itself.
+pub fn directory_scan_options(
+    include_hidden: bool,
+    mut ignore_patterns: Vec<String>,
+) -> ScanOptions {
+    ignore_patterns.push(SCHEMA_FILENAME.to_string());
+    ignore_patterns.push(".commonplace-sync.lock".to_string()); // Ignore lock file
+    ignore_patterns.push(".commonplace-synced-dirs.json".to_string()); // Ignore synced dirs state
+    ignore_patterns.push(SPARSE_FILENAME.to_string()); // Local sparse-checkout selection
+    ignore_patterns.push(CONFLICT_COPY_PATTERN.to_string()); // Local conflict copies
+    ScanOptions {
+        include_hidden,
+        ignore_patterns,
+        include_patterns: None,
+    }
+}
+
+/// Handle an offline edit to a file whose content can't be merged (binary
+/// content, or a `--force-push` target).
+///
+/// If the server is still at `last_cid` there is no conflict and local content
+/// is pushed as usual. Otherwise one side wins and the other is written to a
+/// conflict copy beside the file and recorded in the state file: with
+/// `force_push` the local version wins, otherwise the server version does.
+///
+/// Returns the parent commit to push local content against, or `None` if the
+/// server version was kept and nothing should be pushed.
+///
+/// `record_path` is the file's path relative to the state file, as the
+/// conflict is recorded under.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_unmergeable_offline_edit(
+    head: &HeadResponse,
+    file: &Path,
+    record_path: &str,
+    state_file: &mut SyncStateFile,
+    state_file_path: &Path,
+    last_cid: &str,
+    local_content: &[u8],
+    is_binary: bool,
+    force_push: bool,
+    author: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if head.cid.as_deref() == Some(last_cid) {
+        return Ok(Some(last_cid.to_string()));
+    }
+
+    let (loser_author, loser_content, kept) = if force_push {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        let server_content = if is_binary {
+            STANDARD
+                .decode(&head.content)
+                .unwrap_or_else(|_| head.content.clone().into_bytes())
+        } else {
+            head.content.clone().into_bytes()
+        };
+        ("server".to_string(), server_content, ConflictSide::Local)
+    } else {
+        (
+            author.to_string(),
+            local_content.to_vec(),
+            ConflictSide::Server,
+        )
+    };
+    let copy = write_conflict_copy(file, &loser_author, &loser_content)
+        .await
+        .map_err(|e| format!("Failed to write conflict copy: {}", e))?;
+    warn!(
+        "Conflict: {} changed both locally and on the server; kept the {} version and saved the other as {}",
+        record_path,
+        if kept == ConflictSide::Local { "local" } else { "server" },
+        copy.display()
+    );
+
+    state_file.record_conflict(ConflictRecord {
+        path: record_path.to_string(),
+        copy: Path::new(record_path)
+            .with_file_name(copy.file_name().unwrap_or_default())
+            .to_string_lossy()
+            .to_string(),
+        author: loser_author,
+        kept,
+        base_cid: Some(last_cid.to_string()),
+        server_cid: head.cid.clone(),
+        detected_at: chrono::Utc::now().to_rfc3339(),
+    });
+    if let Err(e) = state_file.save(state_file_path).await {
+        warn!("Failed to save state file: {}", e);
+    }
+
+    Ok(match kept {
+        // Replace HEAD outright rather than merging into it
+        ConflictSide::Local => head.cid.clone().or(Some(last_cid.to_string())),
+        // initial_sync writes the server version over the local file
+        ConflictSide::Server => None,
+    })
+}
+
+/// Push the edits journaled for each file of a synced directory, as file mode
+/// does for its file at startup.
+///
+/// A pushed chain is recorded as the file's last sync. If the server had moved
+/// on and the file still holds the last journaled edit, the merged content is
+/// written locally and into `files`, so the initial sync doesn't push over it.
+#[allow(clippy::too_many_arguments)]
+pub async fn flush_directory_journal(
+    journal: &CommitJournal,
+    client: &Client,
+    server: &str,
+    fs_root_id: &str,
+    directory: &Path,
+    files: &mut [ScannedFile],
+    uuid_map: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let state_file_path = SyncStateFile::state_file_path(directory);
+    let mut state_file = SyncStateFile::load_or_create(directory, server, fs_root_id).await?;
+    let mut pushed = false;
+
+    for file in files.iter_mut() {
+        let Some(node_id) = uuid_map.get(&file.relative_path) else {
+            continue;
+        };
+        match flush_journal(journal, client, server, node_id).await {
+            FlushOutcome::Pushed { response, content } => {
+                let mut synced = content.into_bytes();
+                if response.merge_cid.is_some() && file.content.as_bytes() == synced {
+                    let head_url = build_head_url(server, node_id, false);
+                    let head: HeadResponse = client.get(&head_url).send().await?.json().await?;
+                    synced = ensure_trailing_newline(&head.content).into_bytes();
+                    tokio::fs::write(directory.join(&file.relative_path), &synced).await?;
+                    file.content = head.content;
+                }
+                state_file.record_file(
+                    &file.relative_path,
+                    compute_content_hash(&synced),
+                    Some(response.cid),
+                );
+                pushed = true;
+            }
+            FlushOutcome::Unreachable => {
+                return Err(format!(
+                    "Failed to push journaled offline edits of {}",
+                    file.relative_path
+                )
+                .into());
+            }
+            // Dropped edits are still in the file and go up as one later
+            FlushOutcome::Empty | FlushOutcome::Rejected => {}
+        }
+    }
+
+    if pushed {
+        state_file.save(&state_file_path).await?;
+    }
+    Ok(())
+}
+
+/// Merge edits made to a synced directory while sync wasn't running.
+///
+/// A file whose hash differs from the one recorded at its last sync was
+/// edited offline, and is checked against the commit it was last synced at,
+/// as in file mode: text merges with the server's changes through the CRDT,
+/// and binary content changed on both sides keeps the server version and
+/// leaves the local one in a conflict copy. The result is written locally and
+/// into `files`, so the initial sync that follows has nothing left to push.
+pub async fn merge_offline_edits(
+    client: &Client,
+    server: &str,
+    directory: &Path,
+    files: &mut [ScannedFile],
+    uuid_map: &HashMap<String, String>,
+    use_paths: bool,
+    author: &str,
+) {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let state_file_path = SyncStateFile::state_file_path(directory);
+    let mut state_file = match SyncStateFile::load(&state_file_path).await {
+        Ok(Some(state_file)) => state_file,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Failed to load state file: {}", e);
+            return;
+        }
+    };
+
+    // Conflicts whose copy was deleted have been dealt with
+    let state_dir = state_file_path
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    if state_file.prune_resolved_conflicts(&state_dir) {
+        if let Err(e) = state_file.save(&state_file_path).await {
+            warn!("Failed to save state file: {}", e);
+        }
+    }
+
+    let dir_name = directory.file_name().unwrap_or_default().to_string_lossy();
+    for file in files.iter_mut() {
+        let Some(last_cid) = state_file
+            .files
+            .get(&file.relative_path)
+            .and_then(|f| f.cid.clone())
+        else {
+            continue;
+        };
+        let local_content = if file.is_binary {
+            STANDARD
+                .decode(&file.content)
+                .unwrap_or_else(|_| file.content.clone().into_bytes())
+        } else {
+            file.content.clone().into_bytes()
+        };
+        if !state_file.has_file_changed(&file.relative_path, &compute_content_hash(&local_content))
+        {
+            continue;
+        }
+        let identifier = if use_paths {
+            file.relative_path.clone()
+        } else if let Some(uuid) = uuid_map.get(&file.relative_path) {
+            uuid.clone()
+        } else {
+            continue;
+        };
+        info!(
+            "Detected offline local changes to {} (last synced at {})",
+            file.relative_path, last_cid
+        );
+
+        let file_path = directory.join(&file.relative_path);
+        let record_path = format!("{}/{}", dir_name, file.relative_path);
+        let head_url = build_head_url(server, &identifier, use_paths);
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            if file.is_binary {
+                let head: HeadResponse = client.get(&head_url).send().await?.json().await?;
+                let parent_cid = resolve_unmergeable_offline_edit(
+                    &head,
+                    &file_path,
+                    &record_path,
+                    &mut state_file,
+                    &state_file_path,
+                    &last_cid,
+                    &local_content,
+                    true,
+                    false,
+                    author,
+                )
+                .await?;
+                // Otherwise the initial sync pushes the local version
+                if parent_cid.is_none() {
+                    tokio::fs::write(&file_path, STANDARD.decode(&head.content)?).await?;
+                    file.content = head.content;
+                }
+                return Ok(());
+            }
+
+            // The server computes a diff from the state at last_cid, which
+            // merges with whatever changed on the server since
+            let replace_url = build_replace_url(server, &identifier, &last_cid, use_paths);
+            let resp = client
+                .post(&replace_url)
+                .header("content-type", "text/plain")
+                .body(file.content.clone())
+                .send()
+                .await?;
+            let status = resp.status();
+            if !status.is_success() {
+                let body = resp.text().await.unwrap_or_default();
+                return Err(format!("{} - {}", status, body).into());
+            }
+            let result: ReplaceResponse = resp.json().await?;
+            info!(
+                "Merged offline changes to {}: {} chars inserted, {} deleted (new cid: {})",
+                file.relative_path,
+                result.summary.chars_inserted,
+                result.summary.chars_deleted,
+                &result.cid[..8.min(result.cid.len())]
+            );
+
+            let head: HeadResponse = client.get(&head_url).send().await?.json().await?;
+            if !content_matches(&file_path, file.content.as_bytes(), &head.content) {
+                tokio::fs::write(&file_path, ensure_trailing_newline(&head.content)).await?;
+                file.content = head.content;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                "Failed to merge offline changes to {}: {}",
+                file.relative_path, e
+            );
+        }
+    }
+}
+
+/// Sync a single file until `shutdown` completes.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_file_sync(
+    client: Client,
+    server: String,
+    node_id: String,
+    file: PathBuf,
+    push_only: bool,
+    pull_only: bool,
+    force_push: bool,
+    author: String,
+    mut context: SyncContext,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mode = if force_push {
+        "force-push"
+    } else if push_only {
+        "push-only"
+    } else if pull_only {
+        "pull-only"
+    } else {
+        "bidirectional"
+    };
+    info!(
+        "Starting sync (file mode, {}): server={}, node={}, file={}",
+        mode,
+        server,
+        node_id,
+        file.display()
+    );
+
+    // Verify document exists
+    let doc_url = format!("{}/docs/{}/info", server, encode_node_id(&node_id));
+    let resp = client.get(&doc_url).send().await?;
+    if !resp.status().is_success() {
+        error!("Document {} not found on server", node_id);
+        return Err(format!("Document {} not found", node_id).into());
+    }
+    info!("Connected to document {}", node_id);
+
+    // Load or create state file for offline change detection
+    let state_file_path = SyncStateFile::state_file_path(&file);
+    let mut state_file = SyncStateFile::load_or_create(&file, &server, &node_id)
+        .await
+        .map_err(|e| format!("Failed to load state file: {}", e))?;
+
+    // Conflicts whose copy was deleted have been dealt with
+    let state_dir = state_file_path
+        .parent()
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    if state_file.prune_resolved_conflicts(&state_dir) {
+        if let Err(e) = state_file.save(&state_file_path).await {
+            warn!("Failed to save state file: {}", e);
+        }
+    }
+
+    // Edits journaled while the server was unreachable go up first, as
+    // history; the offline-change check below then only sees what's newer
+    context.journal = open_journal(&file);
+    if let Some(journal) = context.journal.as_deref().filter(|_| !force_push) {
+        match flush_journal(journal, &client, &server, &node_id).await {
+            FlushOutcome::Pushed { response, content } => {
+                let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                state_file.mark_synced(response.cid.clone());
+                state_file.record_file(
+                    &file_name,
+                    compute_content_hash(content.as_bytes()),
+                    Some(response.cid),
+                );
+                if let Err(e) = state_file.save(&state_file_path).await {
+                    warn!("Failed to save state file: {}", e);
+                }
+            }
+            FlushOutcome::Unreachable => {
+                return Err("Failed to push journaled offline edits".into());
+            }
+            // Dropped edits are still in the file and go up as one below
+            FlushOutcome::Empty | FlushOutcome::Rejected => {}
+        }
+    }
+
+    // Check for offline local changes and merge them before initial_sync
+    if let Some(last_cid) = state_file.last_synced_cid.clone() {
+        if file.exists() {
+            let current_content = tokio::fs::read(&file).await?;
+            let current_hash = compute_content_hash(&current_content);
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+
+            if state_file.has_file_changed(&file_name, &current_hash) {
+                info!(
+                    "Detected offline local changes (last synced at {})",
+                    last_cid
+                );
+
+                let content_info = detect_from_path(&file);
+                let is_binary = content_info.is_binary || is_binary_content(&current_content);
+                let content_str = if is_binary {
+                    use base64::{engine::general_purpose::STANDARD, Engine};
+                    STANDARD.encode(&current_content)
+                } else {
+                    String::from_utf8_lossy(&current_content).to_string()
+                };
+
+                // Binary content and force-push targets can't be merged, so
+                // check whether the server moved on too
+                let parent_cid = if is_binary || force_push {
+                    let head_url = format!("{}/docs/{}/head", server, encode_node_id(&node_id));
+                    let head: HeadResponse = client.get(&head_url).send().await?.json().await?;
+                    resolve_unmergeable_offline_edit(
+                        &head,
+                        &file,
+                        &file_name,
+                        &mut state_file,
+                        &state_file_path,
+                        &last_cid,
+                        &current_content,
+                        is_binary,
+                        force_push,
+                        &author,
+                    )
+                    .await?
+                } else {
+                    Some(last_cid.clone())
+                };
+
+                if let Some(parent_cid) = parent_cid {
+                    // Push offline changes using replace endpoint with last_synced_cid as parent.
+                    // The server computes a diff from historical state and creates a CRDT update,
+                    // which automatically merges with any concurrent server changes.
+                    let replace_url = build_replace_url(&server, &node_id, &parent_cid, false);
+                    info!("Pushing offline changes via CRDT merge...");
+
+                    match client
+                        .post(&replace_url)
+                        .header("content-type", "text/plain")
+                        .body(content_str)
+                        .send()
+                        .await
+                    {
+                        Ok(resp) => {
+                            if resp.status().is_success() {
+                                match resp.json::<ReplaceResponse>().await {
+                                    Ok(result) => {
+                                        info!(
+                                            "Merged offline changes: {} chars inserted, {} deleted (new cid: {})",
+                                            result.summary.chars_inserted,
+                                            result.summary.chars_deleted,
+                                            &result.cid[..8.min(result.cid.len())]
+                                        );
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed to parse replace response: {}", e);
+                                    }
+                                }
+                            } else {
+                                let status = resp.status();
+                                let body = resp.text().await.unwrap_or_default();
+                                warn!("Failed to push offline changes: {} - {}", status, body);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Failed to push offline changes: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Initialize shared state with loaded state file
+    let state = Arc::new(RwLock::new(SyncState::with_state_file(
+        state_file,
+        state_file_path,
+    )));
+
+    // Perform initial sync
+    initial_sync(&client, &server, &node_id, &file, &state).await?;
+
+    if pull_only {
+        info!("Pull-only mode: skipping file watcher");
+    }
+    if push_only {
+        info!("Push-only mode: skipping SSE subscription");
+    }
+
+    // Start the watcher, upload and SSE tasks, or their WebSocket
+    // replacement. File mode always uses the ID-based API.
+    let sync_handles = spawn_file_sync_tasks(
+        client.clone(),
+        server.clone(),
+        node_id.clone(),
+        file.clone(),
+        state.clone(),
+        false,
+        push_only,
+        pull_only,
+        force_push,
+        &context,
+    );
+
+    // Report to commonplace-status: keep the state file current with server
+    // edits written locally, and answer queries about in-flight work
+    let control_handle = start_control_socket(
+        &file,
+        ControlSource::File {
+            path: file.clone(),
+            state: state.clone(),
+        },
+    )
+    .await;
+    let checkpoint_handle = tokio::spawn({
+        let state = state.clone();
+        let file = file.clone();
+        async move {
+            loop {
+                sleep(STATE_CHECKPOINT_INTERVAL).await;
+                state.write().await.checkpoint(&file).await;
+            }
+        }
+    });
+
+    shutdown.await;
+    info!("Stopping sync of {}...", file.display());
+
+    // Cancel tasks
+    for handle in sync_handles {
+        handle.abort();
+    }
+    checkpoint_handle.abort();
+    state.write().await.checkpoint(&file).await;
+    stop_control_socket(&file, control_handle);
+    Ok(())
+}
+
+/// Sync a directory until `shutdown` completes.
+///
+/// `shutdown` is first polled once the initial sync is done and every task
+/// is running, so it may start work that expects a synced directory.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_directory_sync(
+    client: Client,
+    server: String,
+    fs_root_id: String,
+    directory: PathBuf,
+    options: ScanOptions,
+    initial_sync_strategy: String,
+    use_paths: bool,
+    push_only: bool,
+    pull_only: bool,
+    author: String,
+    mut context: SyncContext,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mode = if push_only {
+        "push-only"
+    } else if pull_only {
+        "pull-only"
+    } else {
+        "bidirectional"
+    };
+    info!(
+        "Starting sync (directory mode, {}): server={}, fs-root={}, directory={}, use_paths={}",
+        mode,
+        server,
+        fs_root_id,
+        directory.display(),
+        use_paths
+    );
+
+    // Verify directory exists
+    if !directory.is_dir() {
+        error!("Not a directory: {}", directory.display());
+        return Err(format!("Not a directory: {}", directory.display()).into());
+    }
+
+    // Verify fs-root document exists (or create it)
+    ensure_fs_root_exists(&client, &server, &fs_root_id).await?;
+
+    // Check if server has existing schema
+    let server_has_content = check_server_has_content(&client, &server, &fs_root_id).await;
+
+    // If strategy is "server" and server has content, pull server files first
+    // This creates the temporary file_states that handle_schema_change needs
+    let file_states: Arc<RwLock<HashMap<String, FileSyncState>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+
+    if initial_sync_strategy == "server" && server_has_content {
+        info!("Pulling server schema first (initial-sync=server)...");
+        // Don't spawn tasks here - the main loop will spawn them for all files
+        handle_schema_change(
+            &client,
+            &server,
+            &fs_root_id,
+            &directory,
+            &file_states,
+            false,
+            use_paths,
+            push_only,
+            pull_only,
+            &context,
+        )
+        .await?;
+        info!("Server files pulled to local directory");
+    }
+
+    // Synchronize schema between local and server
+    sync_schema(
+        &client,
+        &server,
+        &fs_root_id,
+        &directory,
+        &options,
+        &initial_sync_strategy,
+        server_has_content,
+    )
+    .await?;
+
+    // Scan files with contents and push each one
+    info!("Syncing file contents...");
+    let mut files = scan_directory_with_contents(&directory, &options)
+        .map_err(|e| format!("Scan error: {}", e))?;
+
+    // Wait for reconciler to process the schema and create documents
+    sleep(Duration::from_millis(100)).await;
+
+    // When not using paths, fetch the updated schema to get UUIDs assigned by reconciler
+    // Build a map of relative_path -> node_id for UUID resolution
+    // This recursively follows node-backed directories to get all UUIDs
+    let uuid_map = if !use_paths {
+        let map = build_uuid_map_recursive(&client, &server, &fs_root_id).await;
+        info!(
+            "Resolved {} UUIDs from server schema for initial sync",
+            map.len()
+        );
+        for (path, uuid) in &map {
+            debug!("  UUID map: {} -> {}", path, uuid);
+        }
+        map
+    } else {
+        std::collections::HashMap::new()
+    };
+
+    // Edits journaled while the server was unreachable go up first, as
+    // history; only the ID-based API takes pushes, and an ephemeral
+    // directory has nothing to journal
+    if !use_paths && !context.ephemeral {
+        context.journal = open_journal(&directory);
+    }
+    if let Some(journal) = context.journal.as_deref() {
+        flush_directory_journal(
+            journal,
+            &client,
+            &server,
+            &fs_root_id,
+            &directory,
+            &mut files,
+            &uuid_map,
+        )
+        .await?;
+    }
+
+    // Edits made while sync wasn't running merge with the server's, rather
+    // than the initial sync pushing over them
+    if initial_sync_strategy == "skip" && !pull_only {
+        merge_offline_edits(
+            &client, &server, &directory, &mut files, &uuid_map, use_paths, &author,
+        )
+        .await;
+    }
+
+    // Sync each file
+    for file in &files {
+        let file_path = directory.join(&file.relative_path);
+        if let Err(e) = sync_single_file(
+            &client,
+            &server,
+            &fs_root_id,
+            &directory,
+            file,
+            &file_path,
+            &uuid_map,
+            &initial_sync_strategy,
+            &file_states,
+            use_paths,
+        )
+        .await
+        {
+            warn!("Failed to sync file {}: {}", file.relative_path, e);
+        }
+    }
+
+    info!("Initial sync complete: {} files synced", files.len());
+
+    // The event handler takes the context; keep what's needed afterwards
+    let ephemeral = context.ephemeral;
+
+    // Start directory watcher (skip if pull-only)
+    let (dir_tx, mut dir_rx) = mpsc::channel::<DirEvent>(100);
+    let watcher_handle = if !pull_only {
+        Some(tokio::spawn(directory_watcher_task(
+            directory.clone(),
+            dir_tx,
+            options.clone(),
+            context.watcher.clone(),
+        )))
+    } else {
+        info!("Pull-only mode: skipping directory watcher");
+        None
+    };
+
+    // Start SSE task for fs-root (skip if push-only)
+    let sse_handle = if !push_only {
+        Some(tokio::spawn(directory_sse_task(
+            client.clone(),
+            server.clone(),
+            fs_root_id.clone(),
+            directory.clone(),
+            file_states.clone(),
+            use_paths,
+            push_only,
+            pull_only,
+            context.clone(),
+        )))
+    } else {
+        info!("Push-only mode: skipping SSE subscription");
+        None
+    };
+
+    // Start SSE tasks for all node-backed subdirectories (skip if push-only)
+    // This allows files created in subdirectories to propagate to other sync clients
+    let mut subdir_sse_handles = Vec::new();
+    if !push_only {
+        let node_backed_subdirs = get_all_node_backed_dir_ids(&client, &server, &fs_root_id).await;
+        info!(
+            "Found {} node-backed subdirectories to watch",
+            node_backed_subdirs.len()
+        );
+        for (subdir_path, subdir_node_id) in node_backed_subdirs {
+            info!(
+                "Spawning SSE task for node-backed subdir: {} ({})",
+                subdir_path, subdir_node_id
+            );
+            subdir_sse_handles.push(tokio::spawn(subdir_sse_task(
+                client.clone(),
+                server.clone(),
+                fs_root_id.clone(),
+                subdir_path,
+                subdir_node_id,
+                directory.clone(),
+                file_states.clone(),
+                use_paths,
+                push_only,
+                pull_only,
+                context.clone(),
+            )));
+        }
+    }
+
+    // Start file sync tasks for each file and store handles in FileSyncState
+    {
+        let mut states = file_states.write().await;
+        for (relative_path, file_state) in states.iter_mut() {
+            let file_path = directory.join(relative_path);
+
+            // Spawn sync tasks and store handles in FileSyncState for cleanup on deletion
+            file_state.task_handles = spawn_file_sync_tasks(
+                client.clone(),
+                server.clone(),
+                file_state.identifier.clone(),
+                file_path,
+                file_state.state.clone(),
+                file_state.use_paths,
+                push_only,
+                pull_only,
+                false, // force_push: directory mode doesn't support force-push
+                &context,
+            );
+        }
+    }
+
+    // Handle directory-level events (file creation/deletion)
+    let dir_event_handle = tokio::spawn({
+        let client = client.clone();
+        let server = server.clone();
+        let fs_root_id = fs_root_id.clone();
+        let directory = directory.clone();
+        let options = options.clone();
+        let file_states = file_states.clone();
+        async move {
+            while let Some(event) = dir_rx.recv().await {
+                match event {
+                    DirEvent::Created(path) => {
+                        handle_file_created(
+                            &client,
+                            &server,
+                            &fs_root_id,
+                            &directory,
+                            &path,
+                            &options,
+                            &file_states,
+                            use_paths,
+                            push_only,
+                            pull_only,
+                            &context,
+                        )
+                        .await;
+                    }
+                    DirEvent::Modified(path) => {
+                        handle_file_modified(
+                            &client,
+                            &server,
+                            &fs_root_id,
+                            &directory,
+                            &path,
+                            &options,
+                        )
+                        .await;
+                    }
+                    DirEvent::Deleted(path) => {
+                        handle_file_deleted(
+                            &client,
+                            &server,
+                            &fs_root_id,
+                            &directory,
+                            &path,
+                            &options,
+                            &file_states,
+                        )
+                        .await;
+                    }
+                    DirEvent::Renamed(from, to) => {
+                        handle_file_renamed(
+                            &client,
+                            &server,
+                            &fs_root_id,
+                            &directory,
+                            &from,
+                            &to,
+                            &options,
+                            &file_states,
+                            use_paths,
+                            push_only,
+                            pull_only,
+                            &context,
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    });
+
+    // Report to commonplace-status (an ephemeral directory is private)
+    let reporting = if !ephemeral {
+        let control_handle = start_control_socket(
+            &directory,
+            ControlSource::Directory {
+                path: directory.clone(),
+                file_states: file_states.clone(),
+            },
+        )
+        .await;
+        let checkpoint_handle =
+            spawn_directory_checkpoints(&directory, &server, &fs_root_id, &file_states);
+        Some((control_handle, checkpoint_handle))
+    } else {
+        None
+    };
+
+    shutdown.await;
+    info!("Stopping sync of {}...", directory.display());
+
+    // Cancel all tasks
+    if let Some(handle) = watcher_handle {
+        handle.abort();
+    }
+    if let Some(handle) = sse_handle {
+        handle.abort();
+    }
+    for handle in subdir_sse_handles {
+        handle.abort();
+    }
+    dir_event_handle.abort();
+
+    // Abort all per-file sync tasks
+    {
+        let states = file_states.read().await;
+        for file_state in states.values() {
+            for handle in &file_state.task_handles {
+                handle.abort();
+            }
+        }
+    }
+
+    if let Some((control_handle, checkpoint_handle)) = reporting {
+        checkpoint_handle.abort();
+        if let Err(e) =
+            checkpoint_directory_state(&directory, &server, &fs_root_id, &file_states).await
+        {
+            warn!("Failed to save sync state: {}", e);
+        }
+        stop_control_socket(&directory, control_handle);
+    }
+    Ok(())
+}
+
+/// Serve the control socket `commonplace-status` queries for `target`.
+///
+/// Sync carries on without it if it can't be bound.
+pub async fn start_control_socket(target: &Path, source: ControlSource) -> Option<JoinHandle<()>> {
+    let socket_path = control_socket_path(target);
+    match serve_control_socket(&socket_path, source).await {
+        Ok(handle) => {
+            debug!("Control socket at {}", socket_path.display());
+            Some(handle)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to start control socket at {}: {}",
+                socket_path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Stop serving the control socket for `target` and remove it.
+pub fn stop_control_socket(target: &Path, handle: Option<JoinHandle<()>>) {
+    if let Some(handle) = handle {
+        handle.abort();
+        let _ = std::fs::remove_file(control_socket_path(target));
+    }
+}
+
+/// Periodically save the per-file sync state of a directory sync, so
+/// `commonplace-status` can tell local changes from server ones.
+pub fn spawn_directory_checkpoints(
+    directory: &Path,
+    server: &str,
+    fs_root_id: &str,
+    file_states: &Arc<RwLock<HashMap<String, FileSyncState>>>,
+) -> JoinHandle<()> {
+    let directory = directory.to_path_buf();
+    let server = server.to_string();
+    let fs_root_id = fs_root_id.to_string();
+    let file_states = file_states.clone();
+    tokio::spawn(async move {
+        loop {
+            sleep(STATE_CHECKPOINT_INTERVAL).await;
+            if let Err(e) =
+                checkpoint_directory_state(&directory, &server, &fs_root_id, &file_states).await
+            {
+                warn!("Failed to save sync state: {}", e);
+            }
+        }
+    })
+}
@@ -0,0 +1,235 @@
+//! Sparse directory sync.
+//!
+//! A synced directory with a `.commonplace-sparse` file only checks out the
+//! subtrees its patterns select; the rest of the server schema is kept intact
+//! but never materialized locally. `commonplace-sync --include` writes the
+//! file; delete it to go back to a full checkout.
+//!
+//! Each line of the file is a pattern selecting the subtree at every path it
+//! matches, relative to the synced directory. Path components may use `*`,
+//! `?` and `[...]` wildcards: `notes` selects everything under `notes/`,
+//! `projects/*/docs` the `docs` folder of every project and `*.md` the
+//! Markdown files at the top level. Blank lines and lines starting with `#`
+//! are skipped.
+//!
+//! Local paths outside the selection are treated like ignored ones: they
+//! aren't scanned, watched or pushed, and schemas scanned from a sparse
+//! checkout keep the entries for them from the last known schema.
+
+use crate::sync::ignore_file::is_ignore_file;
+use globset::{Glob, GlobMatcher};
+use std::io;
+use std::path::{Component, Path};
+use tracing::warn;
+
+/// Name of the sparse-checkout file in a synced directory.
+pub const SPARSE_FILENAME: &str = ".commonplace-sparse";
+
+/// Which paths of a synced directory are checked out.
+#[derive(Debug, Clone, Default)]
+pub struct SparseFilter {
+    /// Patterns as per-component matchers; none means a full checkout
+    patterns: Vec<Vec<GlobMatcher>>,
+    /// Path of the filtered root below the directory the patterns are
+    /// relative to
+    prefix: Vec<String>,
+}
+
+impl SparseFilter {
+    /// A filter selecting the subtrees matched by `patterns`. An empty list
+    /// selects everything.
+    pub fn new(patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .filter_map(|pattern| {
+                let components: Result<Vec<GlobMatcher>, _> = pattern
+                    .trim_matches('/')
+                    .split('/')
+                    .filter(|c| !c.is_empty() && *c != ".")
+                    .map(|c| Glob::new(c).map(|glob| glob.compile_matcher()))
+                    .collect();
+                match components {
+                    Ok(components) if !components.is_empty() => Some(components),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("Invalid include pattern {:?}: {}", pattern, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self {
+            patterns,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// The filter for a tree rooted at `root`: the patterns in the nearest
+    /// `.commonplace-sparse` at or above it, or a full checkout if there is
+    /// none.
+    ///
+    /// `root` may be a node-backed subdirectory of the synced directory that
+    /// holds the file.
+    pub fn load(root: &Path) -> Self {
+        for dir in root.ancestors() {
+            let Some(patterns) = read_sparse_file(dir) else {
+                continue;
+            };
+            let mut filter = Self::new(&patterns);
+            filter.prefix = root
+                .strip_prefix(dir)
+                .map(|rel| {
+                    rel.components()
+                        .filter_map(|c| match c {
+                            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            return filter;
+        }
+        Self::default()
+    }
+
+    /// Whether every path is checked out.
+    pub fn is_full(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the file (or subtree) at `relative` is checked out.
+    pub fn includes(&self, relative: &str) -> bool {
+        if self.is_full() {
+            return true;
+        }
+        let components = self.components(relative);
+        if is_ignore_file(Path::new(relative)) {
+            // Ignore files apply to whatever is checked out beside them
+            return self.may_contain_components(&components[..components.len() - 1]);
+        }
+        self.patterns.iter().any(|pattern| {
+            pattern.len() <= components.len()
+                && pattern
+                    .iter()
+                    .zip(&components)
+                    .all(|(glob, name)| glob.is_match(name))
+        })
+    }
+
+    /// Whether anything at or below the directory `relative` is checked out.
+    pub fn may_contain(&self, relative: &str) -> bool {
+        self.is_full() || self.may_contain_components(&self.components(relative))
+    }
+
+    fn may_contain_components(&self, components: &[String]) -> bool {
+        self.patterns.iter().any(|pattern| {
+            pattern
+                .iter()
+                .zip(components)
+                .all(|(glob, name)| glob.is_match(name))
+        })
+    }
+
+    fn components(&self, relative: &str) -> Vec<String> {
+        self.prefix
+            .iter()
+            .cloned()
+            .chain(
+                relative
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_string),
+            )
+            .collect()
+    }
+}
+
+/// Parse the patterns in a sparse-checkout file.
+pub fn parse_sparse_patterns(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The patterns in `directory`'s sparse-checkout file, if it has one.
+pub fn read_sparse_file(directory: &Path) -> Option<Vec<String>> {
+    let content = std::fs::read_to_string(directory.join(SPARSE_FILENAME)).ok()?;
+    Some(parse_sparse_patterns(&content))
+}
+
+/// Make `directory` a sparse checkout of `patterns`.
+pub async fn write_sparse_file(directory: &Path, patterns: &[String]) -> io::Result<()> {
+    let mut content =
+        String::from("# Paths checked out by commonplace-sync (one pattern per line)\n");
+    for pattern in patterns {
+        content.push_str(pattern);
+        content.push('\n');
+    }
+    tokio::fs::write(directory.join(SPARSE_FILENAME), content).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn filter(patterns: &[&str]) -> SparseFilter {
+        SparseFilter::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_patterns_select_subtrees() {
+        let sparse = filter(&["notes", "projects/*/docs/", "*.md"]);
+        assert!(sparse.includes("notes/a.txt"));
+        assert!(sparse.includes("notes/deep/b.txt"));
+        assert!(sparse.includes("projects/x/docs/c.txt"));
+        assert!(sparse.includes("readme.md"));
+        assert!(!sparse.includes("notes-old/a.txt"));
+        assert!(!sparse.includes("projects/x/src/main.txt"));
+        assert!(!sparse.includes("other/readme.md"));
+
+        assert!(sparse.may_contain(""));
+        assert!(sparse.may_contain("projects"));
+        assert!(sparse.may_contain("projects/x"));
+        assert!(!sparse.may_contain("projects/x/src"));
+        assert!(!sparse.may_contain("other"));
+
+        // Ignore files follow their directory
+        assert!(sparse.includes(".commonplaceignore"));
+        assert!(sparse.includes("projects/.commonplaceignore"));
+        assert!(!sparse.includes("other/.commonplaceignore"));
+    }
+
+    #[test]
+    fn test_empty_is_full_checkout() {
+        let sparse = filter(&[]);
+        assert!(sparse.is_full());
+        assert!(sparse.includes("anything/at/all.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_load_from_enclosing_directory() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("projects/x")).unwrap();
+        assert!(SparseFilter::load(root).is_full());
+
+        write_sparse_file(root, &["projects/x/docs".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_sparse_file(root).unwrap(),
+            vec!["projects/x/docs".to_string()]
+        );
+
+        // A node-backed subdirectory sees the patterns relative to itself
+        let sub = SparseFilter::load(&root.join("projects/x"));
+        assert!(sub.includes("docs/a.txt"));
+        assert!(!sub.includes("src/a.txt"));
+        assert!(sub.may_contain(""));
+        assert!(!SparseFilter::load(&root.join("projects")).includes("y/docs/a.txt"));
+    }
+}
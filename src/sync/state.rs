@@ -3,8 +3,11 @@
 //! This module contains the `SyncState` struct that tracks synchronization state
 //! between file watchers and SSE tasks, including echo detection and write barriers.
 
-use crate::sync::state_file::SyncStateFile;
-use std::path::PathBuf;
+use crate::sync::content_type::content_to_bytes;
+use crate::sync::state_file::{compute_content_hash, SyncStateFile};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tracing::warn;
 
 /// Pending write from server - used for barrier-based echo detection.
@@ -51,6 +54,9 @@ pub struct SyncState {
     pub state_file: Option<SyncStateFile>,
     /// Path to save the state file
     pub state_file_path: Option<PathBuf>,
+    /// Set by the upload task while it pushes a local change, so the control
+    /// socket can report it without waiting on this lock
+    pub upload_in_flight: Arc<AtomicBool>,
 }
 
 impl SyncState {
@@ -64,6 +70,7 @@ impl SyncState {
             needs_head_refresh: false,
             state_file: None,
             state_file_path: None,
+            upload_in_flight: Arc::default(),
         }
     }
 
@@ -79,6 +86,7 @@ impl SyncState {
             needs_head_refresh: false,
             state_file: Some(state_file),
             state_file_path: Some(state_file_path),
+            upload_in_flight: Arc::default(),
         }
     }
 
@@ -88,7 +96,11 @@ impl SyncState {
     pub async fn mark_synced(&mut self, cid: &str, content_hash: &str, relative_path: &str) {
         if let Some(ref mut state_file) = self.state_file {
             state_file.mark_synced(cid.to_string());
-            state_file.update_file(relative_path, content_hash.to_string());
+            state_file.record_file(
+                relative_path,
+                content_hash.to_string(),
+                Some(cid.to_string()),
+            );
 
             // Save to disk
             if let Some(ref path) = self.state_file_path {
@@ -98,6 +110,49 @@ impl SyncState {
             }
         }
     }
+
+    /// Save the last written commit to the state file if it has moved on
+    /// since the last save.
+    ///
+    /// Uploads and the initial sync record themselves through
+    /// [`mark_synced`](Self::mark_synced); server edits written to the local
+    /// file are only recorded here.
+    pub async fn checkpoint(&mut self, file_path: &Path) {
+        let Some(cid) = self.last_written_cid.clone() else {
+            return;
+        };
+        match self.state_file {
+            Some(ref state_file) if state_file.last_synced_cid.as_deref() != Some(&cid) => {}
+            _ => return,
+        }
+        let hash = compute_content_hash(&content_to_bytes(file_path, &self.last_written_content));
+        let file_name = file_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+        self.mark_synced(&cid, &hash, &file_name).await;
+    }
+
+    /// Whether the upload task is pushing a local change.
+    pub fn is_uploading(&self) -> bool {
+        self.upload_in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Marks an upload as in flight until dropped.
+pub struct UploadGuard(Arc<AtomicBool>);
+
+impl UploadGuard {
+    pub fn start(flag: &Arc<AtomicBool>) -> Self {
+        flag.store(true, Ordering::Relaxed);
+        Self(flag.clone())
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
 impl Default for SyncState {
@@ -4,6 +4,7 @@
 //! syncing it back to the server. For a target "notes/", the state file
 //! is ".notes.commonplace-sync.json" in the parent directory.
 
+use crate::sync::conflict::ConflictRecord;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
@@ -29,6 +30,10 @@ pub struct SyncStateFile {
     /// Used to distinguish "deleted locally" from "not yet synced".
     #[serde(default)]
     pub directories: std::collections::HashSet<String>,
+    /// Divergent edits resolved with a conflict copy, oldest first.
+    /// Dropped once the conflict copy is deleted.
+    #[serde(default)]
+    pub conflicts: Vec<ConflictRecord>,
 }
 
 /// State for a single file.
@@ -38,6 +43,10 @@ pub struct FileState {
     pub hash: String,
     /// Last modified time (RFC 3339)
     pub last_modified: Option<String>,
+    /// Commit the file was last synced at, where it has its own document
+    /// (directory mode); file mode uses `last_synced_cid`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
 }
 
 impl SyncStateFile {
@@ -50,6 +59,7 @@ impl SyncStateFile {
             last_synced_at: None,
             files: HashMap::new(),
             directories: std::collections::HashSet::new(),
+            conflicts: Vec::new(),
         }
     }
 
@@ -102,12 +112,18 @@ impl SyncStateFile {
 
     /// Update file state after a successful sync.
     pub fn update_file(&mut self, relative_path: &str, hash: String) {
+        self.record_file(relative_path, hash, None);
+    }
+
+    /// Update file state after a successful sync at commit `cid`.
+    pub fn record_file(&mut self, relative_path: &str, hash: String, cid: Option<String>) {
         let now = chrono::Utc::now().to_rfc3339();
         self.files.insert(
             relative_path.to_string(),
             FileState {
                 hash,
                 last_modified: Some(now),
+                cid,
             },
         );
     }
@@ -136,6 +152,22 @@ impl SyncStateFile {
         self.directories.remove(relative_path);
     }
 
+    /// Record a divergent edit resolved with a conflict copy.
+    pub fn record_conflict(&mut self, conflict: ConflictRecord) {
+        self.conflicts.push(conflict);
+    }
+
+    /// Drop conflicts whose conflict copy no longer exists in `dir` (the
+    /// directory holding the state file): deleting the copy resolves them.
+    ///
+    /// Returns whether any were dropped.
+    pub fn prune_resolved_conflicts(&mut self, dir: &Path) -> bool {
+        let before = self.conflicts.len();
+        self.conflicts
+            .retain(|conflict| dir.join(&conflict.copy).exists());
+        self.conflicts.len() != before
+    }
+
     /// Check if a directory was previously synced/created locally.
     ///
     /// Returns true if the directory is tracked, meaning if it's missing
@@ -306,6 +338,37 @@ mod tests {
         assert_eq!(loaded.files["test.txt"].hash, "abc123");
     }
 
+    #[tokio::test]
+    async fn test_conflicts_persist_until_copy_deleted() {
+        use crate::sync::conflict::{ConflictRecord, ConflictSide};
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let copy = "photo.conflict-alice-20250102T030405Z.png";
+        fs::write(dir.path().join(copy), b"local").await.unwrap();
+
+        let mut state =
+            SyncStateFile::new("http://localhost:3000".to_string(), "doc-123".to_string());
+        state.record_conflict(ConflictRecord {
+            path: "photo.png".to_string(),
+            copy: copy.to_string(),
+            author: "alice".to_string(),
+            kept: ConflictSide::Server,
+            base_cid: Some("Qm1".to_string()),
+            server_cid: Some("Qm2".to_string()),
+            detected_at: chrono::Utc::now().to_rfc3339(),
+        });
+        state.save(&path).await.unwrap();
+
+        let mut loaded = SyncStateFile::load(&path).await.unwrap().unwrap();
+        assert_eq!(loaded.conflicts, state.conflicts);
+        assert!(!loaded.prune_resolved_conflicts(dir.path()));
+
+        fs::remove_file(dir.path().join(copy)).await.unwrap();
+        assert!(loaded.prune_resolved_conflicts(dir.path()));
+        assert!(loaded.conflicts.is_empty());
+    }
+
     #[tokio::test]
     async fn test_load_nonexistent() {
         let dir = tempdir().unwrap();
@@ -0,0 +1,1180 @@
+//! Working-tree status of a synced checkout, and the control socket a running
+//! `commonplace-sync` answers status queries on.
+//!
+//! `commonplace-status` compares each tracked file against the state recorded
+//! at its last sync (the `SyncStateFile` hash and commit) and the server HEAD:
+//! a local change since then is unpushed, a server change is still to be
+//! pulled, and both is a conflict. A running sync checkpoints its per-file
+//! state every [`STATE_CHECKPOINT_INTERVAL`] so the comparison stays current,
+//! and, on Unix, reports what it is pushing or pulling over a socket beside the
+//! state file.
+
+use crate::fs::{Entry, FsSchema};
+use crate::sync::conflict::ConflictSide;
+use crate::sync::content_type::{content_to_bytes, is_allowed_extension};
+use crate::sync::dir_sync::SCHEMA_FILENAME;
+use crate::sync::directory::ScanOptions;
+use crate::sync::ignore_file::{is_hidden_name, IgnoreRules};
+use crate::sync::sparse::SparseFilter;
+use crate::sync::state::SyncState;
+use crate::sync::state_file::{
+    compute_content_hash, load_synced_directories, FileState, SyncStateFile,
+};
+use crate::sync::types::{FileSyncState, HeadResponse};
+use crate::sync::urls::encode_node_id;
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+#[cfg(unix)]
+use tracing::debug;
+use tracing::warn;
+
+/// How often a running sync saves its per-file state to the state file.
+pub const STATE_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to wait for a running sync to answer on its control socket.
+#[cfg(unix)]
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Server HEAD requests made at once.
+const HEAD_CONCURRENCY: usize = 8;
+
+/// Path of the control socket for a sync target, beside its state file.
+///
+/// For a target "notes/", returns ".notes.commonplace-sync.sock".
+pub fn control_socket_path(target: &Path) -> PathBuf {
+    SyncStateFile::state_file_path(target).with_extension("sock")
+}
+
+/// What a running sync is doing with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Activity {
+    /// Pushing a local change to the server
+    Pushing,
+    /// Writing a server edit to the local file
+    Pulling,
+    /// A server edit waiting for a local change to be pushed first
+    PullDeferred,
+}
+
+/// A file a running sync is busy with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InFlight {
+    pub path: String,
+    pub activity: Activity,
+}
+
+/// What a running sync reports over its control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProcessStatus {
+    pub pid: u32,
+    /// The synced file or directory
+    pub target: String,
+    /// Number of files being synced
+    pub files: usize,
+    /// Files with a push or pull in progress, by path
+    pub in_flight: Vec<InFlight>,
+}
+
+/// The sync state a control socket reports on.
+#[derive(Clone)]
+pub enum ControlSource {
+    File {
+        path: PathBuf,
+        state: Arc<RwLock<SyncState>>,
+    },
+    Directory {
+        path: PathBuf,
+        file_states: Arc<RwLock<HashMap<String, FileSyncState>>>,
+    },
+}
+
+#[cfg(unix)]
+impl ControlSource {
+    async fn status(&self) -> SyncProcessStatus {
+        let (target, files, in_flight) = match self {
+            ControlSource::File { path, state } => {
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let in_flight = activity(&*state.read().await)
+                    .map(|activity| InFlight {
+                        path: name,
+                        activity,
+                    })
+                    .into_iter()
+                    .collect();
+                (path, 1, in_flight)
+            }
+            ControlSource::Directory { path, file_states } => {
+                let states = file_states.read().await;
+                let mut in_flight = Vec::new();
+                for (relative_path, file_state) in states.iter() {
+                    if let Some(activity) = activity(&*file_state.state.read().await) {
+                        in_flight.push(InFlight {
+                            path: relative_path.clone(),
+                            activity,
+                        });
+                    }
+                }
+                in_flight.sort_by(|a, b| a.path.cmp(&b.path));
+                (path, states.len(), in_flight)
+            }
+        };
+        SyncProcessStatus {
+            pid: std::process::id(),
+            target: target.display().to_string(),
+            files,
+            in_flight,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn activity(state: &SyncState) -> Option<Activity> {
+    if state.is_uploading() {
+        Some(Activity::Pushing)
+    } else if state.pending_write.is_some() {
+        Some(Activity::Pulling)
+    } else if state.needs_head_refresh {
+        Some(Activity::PullDeferred)
+    } else {
+        None
+    }
+}
+
+/// Answer status queries on a Unix socket at `socket_path`.
+///
+/// The protocol is line-based: a client sends `status` and gets one line of
+/// JSON ([`SyncProcessStatus`]) back. A socket left behind by a sync that
+/// didn't shut down cleanly is replaced; one that still answers is an error.
+#[cfg(unix)]
+pub async fn serve_control_socket(
+    socket_path: &Path,
+    source: ControlSource,
+) -> io::Result<JoinHandle<()>> {
+    if UnixStream::connect(socket_path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!(
+                "another sync is already running ({})",
+                socket_path.display()
+            ),
+        ));
+    }
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Control socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            let source = source.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_control_connection(stream, &source).await {
+                    debug!("Control connection closed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// Control sockets are Unix sockets; elsewhere a sync runs without one.
+#[cfg(not(unix))]
+pub async fn serve_control_socket(
+    _socket_path: &Path,
+    _source: ControlSource,
+) -> io::Result<JoinHandle<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "control sockets need Unix sockets",
+    ))
+}
+
+#[cfg(unix)]
+async fn handle_control_connection(stream: UnixStream, source: &ControlSource) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.trim() {
+            "status" => serde_json::to_value(source.status().await)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            other => serde_json::json!({ "error": format!("unknown command: {}", other) }),
+        };
+        write.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Ask the sync running for a target what it is doing.
+///
+/// Returns `None` if no sync is running for it.
+#[cfg(unix)]
+pub async fn query_control_socket(socket_path: &Path) -> io::Result<Option<SyncProcessStatus>> {
+    let query = async {
+        let stream = match UnixStream::connect(socket_path).await {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let (read, mut write) = stream.into_split();
+        write.write_all(b"status\n").await?;
+        let line = BufReader::new(read)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no reply"))?;
+        serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    tokio::time::timeout(CONTROL_TIMEOUT, query)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "sync did not answer"))?
+}
+
+/// Without Unix sockets no sync answers, so none is ever found running.
+#[cfg(not(unix))]
+pub async fn query_control_socket(_socket_path: &Path) -> io::Result<Option<SyncProcessStatus>> {
+    Ok(None)
+}
+
+/// Save the per-file sync state of a directory sync to its state file.
+///
+/// Records the content hash and commit each file was last synced at, and the
+/// directories synced so far. Conflicts already recorded are kept, and so are
+/// files not being synced right now that still exist on disk. The file is
+/// only rewritten when something changed.
+pub async fn checkpoint_directory_state(
+    directory: &Path,
+    server: &str,
+    node_id: &str,
+    file_states: &Arc<RwLock<HashMap<String, FileSyncState>>>,
+) -> io::Result<()> {
+    let path = SyncStateFile::state_file_path(directory);
+    let mut state = SyncStateFile::load(&path)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| SyncStateFile::new(server.to_string(), node_id.to_string()));
+    let mut changed = state.server != server || state.node_id != node_id;
+    state.server = server.to_string();
+    state.node_id = node_id.to_string();
+
+    {
+        let states = file_states.read().await;
+        // Drop only files that are gone from disk: one whose sync hasn't
+        // started (or failed) keeps its last-known commit for next time
+        let mut deleted = Vec::new();
+        for relative_path in state.files.keys() {
+            if !states.contains_key(relative_path)
+                && !tokio::fs::try_exists(directory.join(relative_path))
+                    .await
+                    .unwrap_or(true)
+            {
+                deleted.push(relative_path.clone());
+            }
+        }
+        for relative_path in &deleted {
+            state.files.remove(relative_path);
+        }
+        changed |= !deleted.is_empty();
+
+        for (relative_path, file_state) in states.iter() {
+            let sync_state = file_state.state.read().await;
+            let Some(cid) = sync_state.last_written_cid.clone() else {
+                continue;
+            };
+            let recorded = state
+                .files
+                .get(relative_path)
+                .and_then(|f| f.cid.as_deref());
+            if recorded == Some(cid.as_str()) {
+                continue;
+            }
+            let bytes = content_to_bytes(
+                &directory.join(relative_path),
+                &sync_state.last_written_content,
+            );
+            state.record_file(relative_path, compute_content_hash(&bytes), Some(cid));
+            changed = true;
+        }
+    }
+
+    let directories = load_synced_directories(directory).await;
+    if directories != state.directories {
+        state.directories = directories;
+        changed = true;
+    }
+
+    if changed {
+        state.last_synced_at = Some(chrono::Utc::now().to_rfc3339());
+        state.save(&path).await?;
+    }
+    Ok(())
+}
+
+/// Where a file in a synced checkout stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// Changed locally, not yet pushed
+    Modified,
+    /// Deleted locally, still on the server
+    Deleted,
+    /// Changed on the server, not yet written locally
+    PendingPull,
+    /// Changed on both sides, or resolved with a conflict copy
+    Conflicted,
+    /// Not tracked by the sync
+    Untracked,
+    /// Excluded by `.commonplaceignore`, `--ignore` or its file type
+    Ignored,
+}
+
+/// A file that isn't in sync.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusEntry {
+    pub path: String,
+    pub status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl StatusEntry {
+    fn new(path: impl Into<String>, status: FileStatus) -> Self {
+        Self {
+            path: path.into(),
+            status,
+            detail: None,
+        }
+    }
+
+    fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Status of a synced file or directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    pub target: PathBuf,
+    pub server: Option<String>,
+    pub node_id: Option<String>,
+    pub last_synced_at: Option<String>,
+    /// Files not in sync, ordered by status then path
+    pub entries: Vec<StatusEntry>,
+    /// Why server heads weren't compared, when they weren't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_error: Option<String>,
+    /// The sync running for the target, if any
+    pub process: Option<SyncProcessStatus>,
+}
+
+impl StatusReport {
+    /// Whether everything tracked is in sync (ignored files don't count).
+    pub fn is_clean(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.status == FileStatus::Ignored)
+    }
+}
+
+/// Prints like `git status`.
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "On {}", self.target.display())?;
+        if let Some(server) = &self.server {
+            writeln!(f, "Synced with {}", server)?;
+        }
+        match &self.last_synced_at {
+            Some(at) => writeln!(f, "Last synced at {}", at)?,
+            None => writeln!(f, "Never synced")?,
+        }
+        match &self.process {
+            Some(process) if process.in_flight.is_empty() => {
+                writeln!(f, "Sync running (pid {}), idle", process.pid)?;
+            }
+            Some(process) => {
+                writeln!(
+                    f,
+                    "Sync running (pid {}), {} in flight:",
+                    process.pid,
+                    process.in_flight.len()
+                )?;
+                for item in &process.in_flight {
+                    let activity = serde_json::to_value(item.activity)
+                        .ok()
+                        .and_then(|v| v.as_str().map(str::to_string))
+                        .unwrap_or_default();
+                    writeln!(f, "        {:<14}{}", format!("{}:", activity), item.path)?;
+                }
+            }
+            None => writeln!(f, "No sync running")?,
+        }
+        if let Some(e) = &self.server_error {
+            writeln!(f, "Server not checked: {}", e)?;
+        }
+
+        let sections = [
+            (FileStatus::Conflicted, "Conflicts:", "conflicted:"),
+            (FileStatus::Modified, "Changes not pushed:", "modified:"),
+            (FileStatus::Deleted, "Deleted locally:", "deleted:"),
+            (FileStatus::PendingPull, "Changes not pulled:", "incoming:"),
+            (FileStatus::Untracked, "Untracked files:", ""),
+            (FileStatus::Ignored, "Ignored files:", ""),
+        ];
+        for (status, heading, label) in sections {
+            let entries: Vec<_> = self
+                .entries
+                .iter()
+                .filter(|entry| entry.status == status)
+                .collect();
+            if entries.is_empty() {
+                continue;
+            }
+            writeln!(f)?;
+            writeln!(f, "{}", heading)?;
+            for entry in entries {
+                let line = if label.is_empty() {
+                    entry.path.clone()
+                } else {
+                    format!("{:<14}{}", label, entry.path)
+                };
+                match &entry.detail {
+                    Some(detail) => writeln!(f, "        {} ({})", line, detail)?,
+                    None => writeln!(f, "        {}", line)?,
+                }
+            }
+        }
+
+        if self.is_clean() {
+            writeln!(f)?;
+            writeln!(f, "Everything in sync")?;
+        }
+        Ok(())
+    }
+}
+
+/// Options for [`collect_status`].
+#[derive(Debug, Clone, Default)]
+pub struct StatusOptions {
+    /// Server to compare against (default: the one in the state file)
+    pub server: Option<String>,
+    /// Don't contact the server; only local changes are reported
+    pub offline: bool,
+    /// `--ignore` patterns the sync was started with
+    pub ignore_patterns: Vec<String>,
+}
+
+/// The server side of a tracked file, when it was fetched.
+#[derive(Debug, Clone)]
+pub struct ServerSide<'a> {
+    /// The server's HEAD commit
+    pub cid: Option<&'a str>,
+    /// Whether the server content matches the local file
+    pub matches_local: bool,
+}
+
+/// Classify a tracked file from its local content hash (`None` if it is
+/// missing), its state at the last sync and the server side.
+///
+/// Returns `None` when the file is in sync.
+pub fn classify(
+    local_hash: Option<&str>,
+    base: Option<&FileState>,
+    base_cid: Option<&str>,
+    server: Option<&ServerSide>,
+) -> Option<FileStatus> {
+    let Some(local_hash) = local_hash else {
+        // Missing locally: deleted if it was synced, otherwise not pulled yet
+        return Some(if base.is_some() {
+            FileStatus::Deleted
+        } else {
+            FileStatus::PendingPull
+        });
+    };
+    let local_changed = base.is_none_or(|base| base.hash != local_hash);
+    let Some(server) = server else {
+        // Offline: only local changes can be seen
+        return (base.is_some() && local_changed).then_some(FileStatus::Modified);
+    };
+    if server.matches_local {
+        return None;
+    }
+    if base.is_none() {
+        return Some(FileStatus::Modified);
+    }
+    let server_changed = base_cid.is_none_or(|cid| server.cid != Some(cid));
+    match (local_changed, server_changed) {
+        (true, true) => Some(FileStatus::Conflicted),
+        (false, true) => Some(FileStatus::PendingPull),
+        _ => Some(FileStatus::Modified),
+    }
+}
+
+/// Whether local bytes hold the same content as the server.
+///
+/// Tolerates a trailing newline and, for JSON, formatting differences.
+pub(crate) fn content_matches(path: &Path, local: &[u8], server: &str) -> bool {
+    let server = content_to_bytes(path, server);
+    if local == server.as_slice() {
+        return true;
+    }
+    let trim = |b: &[u8]| -> Vec<u8> {
+        let mut b = b.to_vec();
+        while b.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+            b.pop();
+        }
+        b
+    };
+    if trim(local) == trim(&server) {
+        return true;
+    }
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    if is_json {
+        if let (Ok(a), Ok(b)) = (
+            serde_json::from_slice::<serde_json::Value>(local),
+            serde_json::from_slice::<serde_json::Value>(&server),
+        ) {
+            return a == b;
+        }
+    }
+    false
+}
+
+/// Documents listed in a synced directory's schema, by relative path, with
+/// their node IDs where assigned.
+///
+/// Follows node-backed subdirectories into their own `.commonplace.json`.
+pub fn schema_documents(directory: &Path) -> BTreeMap<String, Option<String>> {
+    let mut docs = BTreeMap::new();
+    collect_schema_documents(directory, "", &mut docs);
+    docs
+}
+
+fn collect_schema_documents(dir: &Path, prefix: &str, docs: &mut BTreeMap<String, Option<String>>) {
+    let Ok(content) = std::fs::read_to_string(dir.join(SCHEMA_FILENAME)) else {
+        return;
+    };
+    let schema: FsSchema = match serde_json::from_str(&content) {
+        Ok(schema) => schema,
+        Err(e) => {
+            warn!("Invalid schema in {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    if let Some(root) = schema.root {
+        collect_entry(dir, prefix, &root, docs);
+    }
+}
+
+fn collect_entry(
+    dir: &Path,
+    prefix: &str,
+    entry: &Entry,
+    docs: &mut BTreeMap<String, Option<String>>,
+) {
+    let Entry::Dir(dir_entry) = entry else {
+        return;
+    };
+    let Some(entries) = &dir_entry.entries else {
+        return;
+    };
+    for (name, child) in entries {
+        let path = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        match child {
+            Entry::Doc(doc) => {
+                docs.insert(path, doc.node_id.clone());
+            }
+            Entry::Dir(sub) if sub.entries.is_some() => {
+                collect_entry(&dir.join(name), &path, child, docs);
+            }
+            Entry::Dir(sub) if sub.node_id.is_some() => {
+                collect_schema_documents(&dir.join(name), &path, docs);
+            }
+            Entry::Dir(_) => {}
+        }
+    }
+}
+
+/// Files below `root` that aren't tracked, and the top-most ignored entries.
+///
+/// Hidden files are skipped, as the sync skips them.
+pub fn untracked_and_ignored(
+    root: &Path,
+    tracked: &dyn Fn(&str) -> bool,
+    rules: &mut IgnoreRules,
+) -> Vec<StatusEntry> {
+    let mut entries = Vec::new();
+    walk_untracked(root, root, tracked, rules, &mut entries);
+    entries
+}
+
+fn walk_untracked(
+    root: &Path,
+    dir: &Path,
+    tracked: &dyn Fn(&str) -> bool,
+    rules: &mut IgnoreRules,
+    out: &mut Vec<StatusEntry>,
+) {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut children: Vec<_> = read_dir.filter_map(Result::ok).collect();
+    children.sort_by_key(|e| e.file_name());
+    for child in children {
+        let name = child.file_name().to_string_lossy().to_string();
+        if is_hidden_name(&name) {
+            continue;
+        }
+        let Ok(file_type) = child.file_type() else {
+            continue;
+        };
+        if file_type.is_symlink() {
+            continue;
+        }
+        let path = child.path();
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let is_dir = file_type.is_dir();
+        if rules.is_ignored(&path, is_dir) {
+            let display = if is_dir {
+                format!("{}/", relative)
+            } else {
+                relative
+            };
+            out.push(StatusEntry::new(display, FileStatus::Ignored));
+        } else if is_dir {
+            walk_untracked(root, &path, tracked, rules, out);
+        } else if !is_allowed_extension(&path) {
+            out.push(
+                StatusEntry::new(relative, FileStatus::Ignored).with_detail("file type not synced"),
+            );
+        } else if !tracked(&relative) {
+            out.push(StatusEntry::new(relative, FileStatus::Untracked));
+        }
+    }
+}
+
+/// Fetch the server HEADs of `node_ids`.
+///
+/// Documents the server doesn't have are left out; an unreachable server is
+/// an error.
+pub(crate) async fn fetch_heads(
+    client: &Client,
+    server: &str,
+    node_ids: Vec<String>,
+) -> Result<HashMap<String, HeadResponse>, String> {
+    let results: Vec<_> = stream::iter(node_ids)
+        .map(|node_id| async move {
+            let url = format!("{}/docs/{}/head", server, encode_node_id(&node_id));
+            let result = match client.get(&url).send().await {
+                Ok(resp) if resp.status().is_success() => resp
+                    .json::<HeadResponse>()
+                    .await
+                    .map(Some)
+                    .map_err(|e| e.to_string()),
+                Ok(resp) if resp.status() == reqwest::StatusCode::NOT_FOUND => Ok(None),
+                Ok(resp) => Err(format!("HTTP {}", resp.status())),
+                Err(e) => Err(e.to_string()),
+            };
+            (node_id, result)
+        })
+        .buffer_unordered(HEAD_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut heads = HashMap::new();
+    for (node_id, result) in results {
+        match result {
+            Ok(Some(head)) => {
+                heads.insert(node_id, head);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed to fetch HEAD of {}: {}", node_id, e)),
+        }
+    }
+    Ok(heads)
+}
+
+/// Collect the status of a synced file or directory.
+///
+/// `target` is what `commonplace-sync` was started on; its state file must
+/// exist.
+pub async fn collect_status(
+    client: &Client,
+    target: &Path,
+    options: &StatusOptions,
+) -> Result<StatusReport, String> {
+    let state_path = SyncStateFile::state_file_path(target);
+    let state = SyncStateFile::load(&state_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", state_path.display(), e))?
+        .ok_or_else(|| {
+            format!(
+                "{} is not synced (no {})",
+                target.display(),
+                state_path.display()
+            )
+        })?;
+    let server = options
+        .server
+        .clone()
+        .unwrap_or_else(|| state.server.clone());
+
+    // Tracked files: relative path -> (absolute path, node ID)
+    let is_directory = target.is_dir();
+    // Paths outside a sparse checkout aren't synced here
+    let sparse = if is_directory {
+        SparseFilter::load(target)
+    } else {
+        SparseFilter::default()
+    };
+    let tracked: BTreeMap<String, (PathBuf, Option<String>)> = if is_directory {
+        let mut tracked: BTreeMap<_, _> = schema_documents(target)
+            .into_iter()
+            .map(|(path, node_id)| (path.clone(), (target.join(&path), node_id)))
+            .collect();
+        for path in state.files.keys() {
+            tracked
+                .entry(path.clone())
+                .or_insert_with(|| (target.join(path), None));
+        }
+        tracked.retain(|path, _| sparse.includes(path));
+        tracked
+    } else {
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        BTreeMap::from([(name, (target.to_path_buf(), Some(state.node_id.clone())))])
+    };
+
+    let (heads, server_error) = if options.offline {
+        (None, None)
+    } else {
+        let node_ids = tracked
+            .values()
+            .filter_map(|(_, node_id)| node_id.clone())
+            .collect();
+        match fetch_heads(client, &server, node_ids).await {
+            Ok(heads) => (Some(heads), None),
+            Err(e) => (None, Some(e)),
+        }
+    };
+
+    let mut entries = Vec::new();
+
+    // Synced directories deleted locally; their files aren't listed again
+    let mut deleted_dirs: Vec<&String> = state
+        .directories
+        .iter()
+        .filter(|dir| is_directory && sparse.may_contain(dir) && !target.join(dir).is_dir())
+        .collect();
+    deleted_dirs.sort();
+    deleted_dirs.dedup_by(|b, a| b.starts_with(&format!("{}/", a)));
+    for dir in &deleted_dirs {
+        entries.push(StatusEntry::new(format!("{}/", dir), FileStatus::Deleted));
+    }
+
+    for (relative_path, (path, node_id)) in &tracked {
+        if deleted_dirs
+            .iter()
+            .any(|dir| relative_path.starts_with(&format!("{}/", dir)))
+        {
+            continue;
+        }
+        let local = tokio::fs::read(path).await.ok();
+        let local_hash = local.as_deref().map(compute_content_hash);
+        let base = state.files.get(relative_path);
+        let base_cid = base.and_then(|b| b.cid.as_deref()).or(if is_directory {
+            None
+        } else {
+            state.last_synced_cid.as_deref()
+        });
+        let head = heads
+            .as_ref()
+            .zip(node_id.as_ref())
+            .and_then(|(heads, id)| heads.get(id));
+        let server_side = match (&heads, head) {
+            (Some(_), Some(head)) => Some(ServerSide {
+                cid: head.cid.as_deref(),
+                matches_local: local
+                    .as_deref()
+                    .is_some_and(|bytes| content_matches(path, bytes, &head.content)),
+            }),
+            _ => None,
+        };
+        // A file the server never had is a new, unpushed file
+        if heads.is_some() && head.is_none() && local.is_some() {
+            entries.push(
+                StatusEntry::new(relative_path, FileStatus::Modified).with_detail("new file"),
+            );
+            continue;
+        }
+        if let Some(status) = classify(local_hash.as_deref(), base, base_cid, server_side.as_ref())
+        {
+            entries.push(StatusEntry::new(relative_path, status));
+        }
+    }
+
+    // Conflicts resolved with a copy that is still around
+    let state_dir = state_path.parent().unwrap_or(Path::new("."));
+    for conflict in &state.conflicts {
+        if !state_dir.join(&conflict.copy).exists() {
+            continue;
+        }
+        let path = if is_directory {
+            Path::new(&conflict.path)
+                .strip_prefix(target.file_name().unwrap_or_default())
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|_| conflict.path.clone())
+        } else {
+            conflict.path.clone()
+        };
+        let kept = match conflict.kept {
+            ConflictSide::Local => "local",
+            ConflictSide::Server => "server",
+        };
+        entries.retain(|entry| entry.path != path);
+        entries.push(
+            StatusEntry::new(path, FileStatus::Conflicted).with_detail(format!(
+                "kept {} version; {}'s version is in {}",
+                kept, conflict.author, conflict.copy
+            )),
+        );
+    }
+
+    if is_directory {
+        let scan_options = ScanOptions {
+            ignore_patterns: options.ignore_patterns.clone(),
+            ..Default::default()
+        };
+        let mut rules = IgnoreRules::new(target, &scan_options);
+        entries.extend(untracked_and_ignored(
+            target,
+            &|path| tracked.contains_key(path),
+            &mut rules,
+        ));
+    }
+
+    entries.sort_by(|a, b| a.status.cmp(&b.status).then_with(|| a.path.cmp(&b.path)));
+
+    let process = query_control_socket(&control_socket_path(target))
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to query running sync: {}", e);
+            None
+        });
+
+    Ok(StatusReport {
+        target: target.to_path_buf(),
+        server: Some(server),
+        node_id: Some(state.node_id),
+        last_synced_at: state.last_synced_at,
+        entries,
+        server_error,
+        process,
+    })
+}
+
+/// Find the sync target `path` belongs to: the file or directory a state
+/// file was written for, starting at `path` and walking up.
+pub fn find_sync_target(path: &Path) -> Option<PathBuf> {
+    let path = path.canonicalize().ok()?;
+    path.ancestors()
+        .find(|candidate| SyncStateFile::state_file_path(candidate).is_file())
+        .map(Path::to_path_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn base(hash: &str, cid: &str) -> FileState {
+        FileState {
+            hash: hash.to_string(),
+            last_modified: None,
+            cid: Some(cid.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let base = base("h1", "c1");
+        let server = |cid, matches_local| ServerSide {
+            cid: Some(cid),
+            matches_local,
+        };
+
+        assert_eq!(
+            classify(None, Some(&base), Some("c1"), None),
+            Some(FileStatus::Deleted)
+        );
+        assert_eq!(
+            classify(None, None, None, None),
+            Some(FileStatus::PendingPull)
+        );
+        assert_eq!(
+            classify(
+                Some("h1"),
+                Some(&base),
+                Some("c1"),
+                Some(&server("c1", true))
+            ),
+            None
+        );
+        assert_eq!(
+            classify(
+                Some("h2"),
+                Some(&base),
+                Some("c1"),
+                Some(&server("c1", false))
+            ),
+            Some(FileStatus::Modified)
+        );
+        assert_eq!(
+            classify(
+                Some("h1"),
+                Some(&base),
+                Some("c1"),
+                Some(&server("c2", false))
+            ),
+            Some(FileStatus::PendingPull)
+        );
+        assert_eq!(
+            classify(
+                Some("h2"),
+                Some(&base),
+                Some("c1"),
+                Some(&server("c2", false))
+            ),
+            Some(FileStatus::Conflicted)
+        );
+        // Both sides made the same change
+        assert_eq!(
+            classify(
+                Some("h2"),
+                Some(&base),
+                Some("c1"),
+                Some(&server("c2", true))
+            ),
+            None
+        );
+        // Offline, only local changes show
+        assert_eq!(
+            classify(Some("h2"), Some(&base), Some("c1"), None),
+            Some(FileStatus::Modified)
+        );
+        assert_eq!(classify(Some("h1"), Some(&base), Some("c1"), None), None);
+    }
+
+    #[test]
+    fn test_content_matches() {
+        assert!(content_matches(Path::new("a.txt"), b"hello\n", "hello"));
+        assert!(!content_matches(Path::new("a.txt"), b"hello", "world"));
+        assert!(content_matches(
+            Path::new("a.json"),
+            b"{\n  \"a\": 1\n}",
+            "{\"a\":1}"
+        ));
+    }
+
+    #[test]
+    fn test_untracked_and_ignored() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("notes")).unwrap();
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::write(root.join("notes/tracked.md"), "a").unwrap();
+        fs::write(root.join("notes/new.md"), "b").unwrap();
+        fs::write(root.join("build/out.txt"), "c").unwrap();
+        fs::write(root.join("photo.png"), "d").unwrap();
+        fs::write(root.join(".hidden.txt"), "e").unwrap();
+        fs::write(root.join(".commonplaceignore"), "build/\n").unwrap();
+
+        let mut rules = IgnoreRules::new(root, &ScanOptions::default());
+        let entries = untracked_and_ignored(
+            root,
+            &|path| path == "notes/tracked.md" || path == ".commonplaceignore",
+            &mut rules,
+        );
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.status))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("build/", FileStatus::Ignored),
+                ("notes/new.md", FileStatus::Untracked),
+                ("photo.png", FileStatus::Ignored),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schema_documents_follow_node_backed_dirs() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(
+            root.join(SCHEMA_FILENAME),
+            r#"{"version":1,"root":{"type":"dir","entries":{
+                "a.txt":{"type":"doc","node_id":"n-a"},
+                "inline":{"type":"dir","entries":{"b.txt":{"type":"doc"}}},
+                "sub":{"type":"dir","node_id":"n-sub"}}}}"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("sub").join(SCHEMA_FILENAME),
+            r#"{"version":1,"root":{"type":"dir","entries":{
+                "c.txt":{"type":"doc","node_id":"n-c"}}}}"#,
+        )
+        .unwrap();
+
+        let docs = schema_documents(root);
+        assert_eq!(docs.get("a.txt"), Some(&Some("n-a".to_string())));
+        assert_eq!(docs.get("inline/b.txt"), Some(&None));
+        assert_eq!(docs.get("sub/c.txt"), Some(&Some("n-c".to_string())));
+        assert_eq!(docs.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_keeps_files_not_yet_synced() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::write(root.join("pending.txt"), "still here").unwrap();
+        fs::write(root.join("synced.txt"), "synced").unwrap();
+
+        let mut state = SyncStateFile::new("http://server".to_string(), "root".to_string());
+        state.record_file(
+            "pending.txt",
+            "h-pending".to_string(),
+            Some("c-pending".to_string()),
+        );
+        state.record_file(
+            "deleted.txt",
+            "h-deleted".to_string(),
+            Some("c-deleted".to_string()),
+        );
+        let path = SyncStateFile::state_file_path(root);
+        state.save(&path).await.unwrap();
+
+        // Only synced.txt is being synced; pending.txt failed its initial sync
+        let mut sync_state = SyncState::new();
+        sync_state.last_written_cid = Some("c-synced".to_string());
+        sync_state.last_written_content = "synced".to_string();
+        let file_states = Arc::new(RwLock::new(HashMap::from([(
+            "synced.txt".to_string(),
+            FileSyncState {
+                relative_path: "synced.txt".to_string(),
+                identifier: "n-synced".to_string(),
+                state: Arc::new(RwLock::new(sync_state)),
+                task_handles: Vec::new(),
+                use_paths: false,
+                content_hash: None,
+            },
+        )])));
+
+        checkpoint_directory_state(root, "http://server", "root", &file_states)
+            .await
+            .unwrap();
+
+        let saved = SyncStateFile::load(&path).await.unwrap().unwrap();
+        assert_eq!(saved.files["pending.txt"].cid.as_deref(), Some("c-pending"));
+        assert_eq!(saved.files["synced.txt"].cid.as_deref(), Some("c-synced"));
+        assert!(!saved.files.contains_key("deleted.txt"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket_reports_in_flight() {
+        let temp = TempDir::new().unwrap();
+        let target = temp.path().join("notes.txt");
+        let socket = control_socket_path(&target);
+        assert_eq!(
+            socket.file_name().unwrap(),
+            ".notes.txt.commonplace-sync.sock"
+        );
+        assert!(query_control_socket(&socket).await.unwrap().is_none());
+
+        let state = Arc::new(RwLock::new(SyncState::new()));
+        let handle = serve_control_socket(
+            &socket,
+            ControlSource::File {
+                path: target.clone(),
+                state: state.clone(),
+            },
+        )
+        .await
+        .unwrap();
+
+        let status = query_control_socket(&socket).await.unwrap().unwrap();
+        assert_eq!(status.pid, std::process::id());
+        assert!(status.in_flight.is_empty());
+
+        let flag = state.read().await.upload_in_flight.clone();
+        let guard = crate::sync::state::UploadGuard::start(&flag);
+        let status = query_control_socket(&socket).await.unwrap().unwrap();
+        assert_eq!(
+            status.in_flight,
+            vec![InFlight {
+                path: "notes.txt".to_string(),
+                activity: Activity::Pushing,
+            }]
+        );
+        drop(guard);
+
+        // A second sync for the same target is refused
+        assert!(serve_control_socket(
+            &socket,
+            ControlSource::File {
+                path: target,
+                state,
+            },
+        )
+        .await
+        .is_err());
+        handle.abort();
+    }
+}
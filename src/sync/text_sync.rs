@@ -0,0 +1,258 @@
+//! Text file sync against a local Yjs document.
+//!
+//! The WebSocket and MQTT transports exchange raw Yjs updates rather than
+//! whole file contents, so the client keeps its own copy of the document.
+//! Local edits are diffed into it and sent as updates; remote updates are
+//! merged into it and the result written to the file.
+
+use crate::sync::{apply_text_diff, detect_from_path, FileEvent, SyncState, TEXT_ROOT_NAME};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, error, warn};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::{Doc, GetString, ReadTxn, StateVector, TextRef, Transact, Update};
+
+/// Whether `file_path` can sync as raw Yjs updates: its content must be text
+/// kept in the document's Y.Text, which rules out JSON, JSONL and binary
+/// files.
+pub fn is_text_syncable(file_path: &Path) -> bool {
+    let info = detect_from_path(file_path);
+    !info.is_binary
+        && info.mime_type != "application/json"
+        && info.mime_type != "application/x-ndjson"
+}
+
+/// The client's copy of a text document.
+pub(crate) struct TextDoc {
+    doc: Doc,
+    /// Whether the remote state has been applied at least once
+    pub(crate) synced: bool,
+}
+
+impl TextDoc {
+    pub(crate) fn new() -> Self {
+        Self {
+            doc: Doc::new(),
+            synced: false,
+        }
+    }
+
+    /// The shared text; kept out of the struct so tasks stay `Send`.
+    fn text(&self) -> TextRef {
+        self.doc.get_or_insert_text(TEXT_ROOT_NAME)
+    }
+
+    pub(crate) fn content(&self) -> String {
+        self.text().get_string(&self.doc.transact())
+    }
+
+    pub(crate) fn state_vector(&self) -> Vec<u8> {
+        self.doc.transact().state_vector().encode_v1()
+    }
+
+    /// The updates a peer with `state_vector` is missing.
+    pub(crate) fn diff(&self, state_vector: &[u8]) -> Option<Vec<u8>> {
+        let sv = StateVector::decode_v1(state_vector).ok()?;
+        Some(self.doc.transact().encode_state_as_update_v1(&sv))
+    }
+
+    pub(crate) fn apply(&mut self, update: &[u8]) -> bool {
+        match Update::decode_v1(update) {
+            Ok(update) => {
+                self.doc.transact_mut().apply_update(update);
+                true
+            }
+            Err(e) => {
+                warn!("Failed to decode Yjs update: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Edit the text to `content`, returning the update if anything changed.
+    pub(crate) fn edit(&mut self, content: &str) -> Option<Vec<u8>> {
+        let text = self.text();
+        let mut txn = self.doc.transact_mut();
+        let current = text.get_string(&txn);
+        if current == content {
+            return None;
+        }
+        apply_text_diff(&mut txn, &text, &current, content);
+        Some(txn.encode_update_v1())
+    }
+}
+
+/// Merge remote updates into the document and the file. Returns the local
+/// updates to send back.
+///
+/// The first merge marks the document synced; edits made to the file before
+/// it are picked up then. In pull-only mode local edits are never read; in
+/// push-only mode the merged text is never written.
+pub(crate) async fn merge_remote_updates(
+    file_path: &Path,
+    state: &Arc<RwLock<SyncState>>,
+    doc: &mut TextDoc,
+    updates: &[Vec<u8>],
+    pull_only: bool,
+    push_only: bool,
+) -> Vec<Vec<u8>> {
+    let mut outgoing = Vec::new();
+
+    // Record a pending local edit before the remote one lands, so it
+    // diffs against the text it was made on
+    if doc.synced && !pull_only {
+        if let Some(content) = read_text(file_path).await {
+            outgoing.extend(merge_local_edit(state, doc, content).await);
+        }
+    }
+
+    let applied = updates.iter().filter(|update| doc.apply(update)).count();
+    if applied == 0 && !updates.is_empty() {
+        return outgoing;
+    }
+
+    if !doc.synced {
+        doc.synced = true;
+        // Edits made before the remote state arrived count once it's in
+        if !pull_only {
+            if let Some(content) = read_text(file_path).await {
+                outgoing.extend(merge_local_edit(state, doc, content).await);
+            }
+        }
+    }
+
+    if push_only {
+        return outgoing;
+    }
+
+    let merged = doc.content();
+    let mut s = state.write().await;
+    if read_text(file_path).await.as_deref() != Some(merged.as_str()) {
+        if let Err(e) = tokio::fs::write(file_path, &merged).await {
+            error!("Failed to write {}: {}", file_path.display(), e);
+            return outgoing;
+        }
+        debug!("Wrote remote changes to {}", file_path.display());
+    }
+    s.last_written_content = merged;
+    outgoing
+}
+
+/// Merge the file's `content` into the document if it changed since it was
+/// last synced. Returns the update to send.
+pub(crate) async fn merge_local_edit(
+    state: &Arc<RwLock<SyncState>>,
+    doc: &mut TextDoc,
+    content: String,
+) -> Option<Vec<u8>> {
+    let mut s = state.write().await;
+    if content == s.last_written_content {
+        // Unchanged, or the echo of our own write
+        return None;
+    }
+    let update = doc.edit(&content);
+    s.last_written_content = content;
+    update
+}
+
+/// Wait for the next file event; never resolves without a watcher.
+pub(crate) async fn next_event(rx: &mut Option<mpsc::Receiver<FileEvent>>) -> Option<FileEvent> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn read_text(file_path: &Path) -> Option<String> {
+    let raw = tokio::fs::read(file_path).await.ok()?;
+    Some(String::from_utf8_lossy(&raw).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_text_syncable() {
+        assert!(is_text_syncable(Path::new("notes/todo.txt")));
+        assert!(is_text_syncable(Path::new("readme.md")));
+        assert!(!is_text_syncable(Path::new("data.json")));
+        assert!(!is_text_syncable(Path::new("log.jsonl")));
+        assert!(!is_text_syncable(Path::new("photo.png")));
+    }
+
+    #[test]
+    fn test_text_docs_converge() {
+        let mut ours = TextDoc::new();
+        let mut theirs = TextDoc::new();
+        let initial = theirs.edit("hello world").unwrap();
+        assert!(ours.apply(&initial));
+
+        // Concurrent edits at either end of the text
+        let local = ours.edit("hello brave world").unwrap();
+        let remote = theirs.edit("hello world!").unwrap();
+        assert!(ours.apply(&remote));
+        assert!(theirs.apply(&local));
+        assert_eq!(ours.content(), "hello brave world!");
+        assert_eq!(theirs.content(), "hello brave world!");
+
+        // Nothing to send once both sides agree
+        assert!(ours.edit("hello brave world!").is_none());
+        let missing = ours.diff(&theirs.state_vector()).unwrap();
+        assert!(theirs.apply(&missing));
+        assert_eq!(theirs.content(), "hello brave world!");
+    }
+
+    #[tokio::test]
+    async fn test_remote_update_keeps_local_edit() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let file_path = temp.path().join("note.txt");
+        let state = Arc::new(RwLock::new(SyncState::new()));
+
+        // First sync: the remote state is written to the file
+        let mut server = TextDoc::new();
+        let initial = server.edit("one\ntwo\n").unwrap();
+        let mut doc = TextDoc::new();
+        let sent =
+            merge_remote_updates(&file_path, &state, &mut doc, &[initial], false, false).await;
+        assert!(sent.is_empty());
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "one\ntwo\n");
+
+        // A local edit not yet sent is merged with the next remote update
+        std::fs::write(&file_path, "one\ntwo\nthree\n").unwrap();
+        let remote = server.edit("zero\none\ntwo\n").unwrap();
+        let sent =
+            merge_remote_updates(&file_path, &state, &mut doc, &[remote], false, false).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&file_path).unwrap(),
+            "zero\none\ntwo\nthree\n"
+        );
+        assert_eq!(
+            state.read().await.last_written_content,
+            "zero\none\ntwo\nthree\n"
+        );
+
+        // Our own write coming back from the watcher is not an edit
+        let echo = merge_local_edit(&state, &mut doc, "zero\none\ntwo\nthree\n".to_string()).await;
+        assert!(echo.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_first_sync_of_empty_document_pushes_file() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let file_path = temp.path().join("note.txt");
+        std::fs::write(&file_path, "local\n").unwrap();
+        let state = Arc::new(RwLock::new(SyncState::new()));
+
+        // No remote history at all still completes the first sync
+        let mut doc = TextDoc::new();
+        let sent = merge_remote_updates(&file_path, &state, &mut doc, &[], false, false).await;
+        assert!(doc.synced);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(doc.content(), "local\n");
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "local\n");
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! This module contains request/response types for the sync client API.
 
+use crate::sync::journal::JournalCommit;
 use crate::sync::SyncState;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -71,6 +72,26 @@ pub struct EditResponse {
     pub cid: String,
 }
 
+/// Request for POST /docs/:id/push (journaled offline edits)
+#[derive(Debug, Serialize)]
+pub struct PushRequest {
+    /// Server commit the chain starts from
+    pub base: String,
+    pub commits: Vec<JournalCommit>,
+}
+
+/// Response from POST /docs/:id/push
+#[derive(Debug, Deserialize)]
+pub struct PushResponse {
+    /// The document's new HEAD
+    pub cid: String,
+    /// Server CIDs of the pushed commits, in order
+    pub commits: Vec<String>,
+    /// Set when the server had moved on and the chain was merged with it
+    #[serde(default)]
+    pub merge_cid: Option<String>,
+}
+
 /// Response from POST /docs/:id/fork
 #[derive(Debug, Deserialize)]
 pub struct ForkResponse {
@@ -93,6 +114,8 @@ pub enum DirEvent {
     Created(std::path::PathBuf),
     Modified(std::path::PathBuf),
     Deleted(std::path::PathBuf),
+    /// A file or directory moved from the first path to the second
+    Renamed(std::path::PathBuf, std::path::PathBuf),
 }
 
 /// Sync state for a single file in directory mode.
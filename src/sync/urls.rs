@@ -80,6 +80,19 @@ pub fn build_sse_url(server: &str, path_or_id: &str, use_paths: bool) -> String
     }
 }
 
+/// Build URL for a document's WebSocket, switching the scheme to `ws` or
+/// `wss`
+pub fn build_ws_url(server: &str, node_id: &str) -> String {
+    let base = if let Some(rest) = server.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = server.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        server.to_string()
+    };
+    format!("{}/ws/docs/{}", base, encode_node_id(node_id))
+}
+
 /// Build URL for forking a document
 pub fn build_fork_url(server: &str, source_node: &str, at_commit: Option<&str>) -> String {
     let mut url = format!("{}/docs/{}/fork", server, encode_node_id(source_node));
@@ -226,6 +239,18 @@ mod tests {
             assert_eq!(url, "http://localhost:3000/sse/docs/abc-123");
         }
 
+        #[test]
+        fn test_build_ws_url() {
+            assert_eq!(
+                build_ws_url(SERVER, "abc-123"),
+                "ws://localhost:3000/ws/docs/abc-123"
+            );
+            assert_eq!(
+                build_ws_url("https://example.com", "a b"),
+                "wss://example.com/ws/docs/a%20b"
+            );
+        }
+
         #[test]
         fn test_build_urls_with_spaces_in_path() {
             let url = build_head_url(SERVER, "my notes/my file.txt", true);
@@ -2,12 +2,20 @@
 //!
 //! This module provides async tasks that watch files and directories for changes
 //! using the `notify` crate, with debouncing to handle rapid file modifications.
+//! Each task normally creates its own watcher; a process syncing many roots
+//! can have them all share one [`SharedWatcher`] instead.
 
+use crate::sync::conflict::is_conflict_copy;
+use crate::sync::ignore_file::{is_hidden_name, is_ignore_file, IgnoreRules};
+use crate::sync::state_file::{compute_content_hash, FileState, SyncStateFile};
 use crate::sync::{DirEvent, FileEvent, ScanOptions};
+use indexmap::IndexMap;
 use notify::event::{ModifyKind, RenameMode};
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
@@ -18,6 +26,260 @@ pub const FILE_DEBOUNCE_MS: u64 = 100;
 /// Default debounce duration for directory watcher (500ms)
 pub const DIR_DEBOUNCE_MS: u64 = 500;
 
+/// Raw `notify` events, as a watch task receives them.
+type NotifyReceiver = mpsc::Receiver<Result<Event, notify::Error>>;
+
+/// One `notify` watcher that many watch tasks subscribe to.
+///
+/// A subscription names a directory, watched recursively or not, and gets the
+/// events for paths under it, spelled relative to the directory as given. The
+/// watcher itself watches the smallest set of directories covering every
+/// subscription, so overlapping subscriptions don't undo each other's watches.
+///
+/// Each subscription has its own forwarding task, so a watch task that falls
+/// behind only delays its own events, never the other roots'.
+#[derive(Debug)]
+pub struct SharedWatcher {
+    watches: Mutex<Watches>,
+    subscribers: Arc<Mutex<HashMap<u64, Subscriber>>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Watches {
+    watcher: RecommendedWatcher,
+    /// Directories being watched, and whether recursively
+    active: BTreeMap<PathBuf, bool>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    /// The directory in canonical form, as events name it
+    root: PathBuf,
+    /// The directory as the subscriber named it
+    requested: PathBuf,
+    recursive: bool,
+    /// Queue drained by the subscription's forwarding task; never blocks the
+    /// `notify` thread
+    tx: mpsc::UnboundedSender<Result<Event, notify::Error>>,
+}
+
+impl Subscriber {
+    fn covers(&self, path: &Path) -> bool {
+        if self.recursive {
+            path.starts_with(&self.root)
+        } else {
+            path == self.root || path.parent() == Some(self.root.as_path())
+        }
+    }
+
+    /// `event` with its paths spelled the way the subscriber asked.
+    fn translate(&self, event: &Event) -> Event {
+        let mut event = event.clone();
+        for path in &mut event.paths {
+            if let Ok(rest) = path.strip_prefix(&self.root) {
+                *path = if rest.as_os_str().is_empty() {
+                    self.requested.clone()
+                } else {
+                    self.requested.join(rest)
+                };
+            }
+        }
+        event
+    }
+}
+
+/// A subscription to a [`SharedWatcher`]; dropping it stops the watch.
+pub struct Subscription {
+    watcher: Arc<SharedWatcher>,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.watcher.unsubscribe(self.id);
+    }
+}
+
+impl SharedWatcher {
+    pub fn new() -> notify::Result<Self> {
+        let subscribers: Arc<Mutex<HashMap<u64, Subscriber>>> = Arc::default();
+        let watcher = RecommendedWatcher::new(
+            {
+                let subscribers = subscribers.clone();
+                move |res| dispatch(&subscribers, res)
+            },
+            Config::default().with_poll_interval(Duration::from_millis(FILE_DEBOUNCE_MS)),
+        )?;
+        Ok(Self {
+            watches: Mutex::new(Watches {
+                watcher,
+                active: BTreeMap::new(),
+            }),
+            subscribers,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Receive the events for `path`, an existing directory.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        path: &Path,
+        mode: RecursiveMode,
+    ) -> notify::Result<(Subscription, NotifyReceiver)> {
+        let root = path.canonicalize().map_err(notify::Error::io)?;
+        let (tx, mut queue) = mpsc::unbounded_channel();
+        let (forward_tx, rx) = mpsc::channel(100);
+        // Ends once the subscriber is removed or its receiver dropped
+        tokio::spawn(async move {
+            while let Some(res) = queue.recv().await {
+                if forward_tx.send(res).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().insert(
+            id,
+            Subscriber {
+                root,
+                requested: path.to_path_buf(),
+                recursive: mode == RecursiveMode::Recursive,
+                tx,
+            },
+        );
+        // Dropped on failure, which takes the subscriber out again
+        let subscription = Subscription {
+            watcher: self.clone(),
+            id,
+        };
+        self.update_watches()?;
+        Ok((subscription, rx))
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.subscribers.lock().unwrap().remove(&id);
+        if let Err(e) = self.update_watches() {
+            warn!("Failed to update shared watcher: {}", e);
+        }
+    }
+
+    /// Bring the watched directories in line with the subscriptions.
+    fn update_watches(&self) -> notify::Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        let wanted = covering_watches(
+            self.subscribers
+                .lock()
+                .unwrap()
+                .values()
+                .map(|s| (s.root.clone(), s.recursive)),
+        );
+
+        // Unwatch first: dropping a recursive watch also drops the watches on
+        // the directories below it, which may be wanted on their own now
+        let stale: Vec<PathBuf> = watches
+            .active
+            .iter()
+            .filter(|(path, recursive)| wanted.get(*path) != Some(*recursive))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in stale {
+            watches.active.remove(&path);
+            if let Err(e) = watches.watcher.unwatch(&path) {
+                debug!("Failed to unwatch {}: {}", path.display(), e);
+            }
+        }
+        for (path, recursive) in wanted {
+            if watches.active.contains_key(&path) {
+                continue;
+            }
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            watches.watcher.watch(&path, mode)?;
+            watches.active.insert(path, recursive);
+        }
+        Ok(())
+    }
+}
+
+/// Hand a `notify` event to every subscriber it concerns.
+fn dispatch(subscribers: &Mutex<HashMap<u64, Subscriber>>, res: notify::Result<Event>) {
+    let event = match res {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("Shared watcher error: {}", e);
+            return;
+        }
+    };
+    let targets: Vec<_> = subscribers
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|s| event.paths.iter().any(|path| s.covers(path)))
+        .map(|s| (s.tx.clone(), s.translate(&event)))
+        .collect();
+    for (tx, event) in targets {
+        let _ = tx.send(Ok(event));
+    }
+}
+
+/// The fewest watches that cover every `(directory, recursive)` subscription:
+/// a recursive watch covers everything below its directory, and wins over a
+/// non-recursive one on the same directory.
+fn covering_watches(
+    subscriptions: impl IntoIterator<Item = (PathBuf, bool)>,
+) -> BTreeMap<PathBuf, bool> {
+    let mut wanted: BTreeMap<PathBuf, bool> = BTreeMap::new();
+    for (path, recursive) in subscriptions {
+        *wanted.entry(path).or_insert(false) |= recursive;
+    }
+    let recursive: Vec<PathBuf> = wanted
+        .iter()
+        .filter(|(_, recursive)| **recursive)
+        .map(|(path, _)| path.clone())
+        .collect();
+    wanted.retain(|path, _| {
+        !recursive
+            .iter()
+            .any(|root| path != root && path.starts_with(root))
+    });
+    wanted
+}
+
+/// Keeps a watch task's watch alive: its own watcher, or its subscription to
+/// the shared one.
+#[allow(dead_code)] // Held only to be dropped
+enum WatchGuard {
+    Own(RecommendedWatcher),
+    Shared(Subscription),
+}
+
+/// Watch `path`, through `shared` if given.
+fn watch_path(
+    path: &Path,
+    mode: RecursiveMode,
+    poll_interval: Duration,
+    shared: Option<&Arc<SharedWatcher>>,
+) -> notify::Result<(WatchGuard, NotifyReceiver)> {
+    if let Some(shared) = shared {
+        let (subscription, notify_rx) = shared.subscribe(path, mode)?;
+        return Ok((WatchGuard::Shared(subscription), notify_rx));
+    }
+
+    let (notify_tx, notify_rx) = mpsc::channel::<Result<Event, notify::Error>>(100);
+    let mut watcher = RecommendedWatcher::new(
+        move |res| {
+            let _ = notify_tx.blocking_send(res);
+        },
+        Config::default().with_poll_interval(poll_interval),
+    )?;
+    watcher.watch(path, mode)?;
+    Ok((WatchGuard::Own(watcher), notify_rx))
+}
+
 /// Check if a filename matches common temp file patterns used by atomic writes.
 ///
 /// Many applications write to a temp file first, then rename to the target.
@@ -51,6 +313,159 @@ fn is_temp_file(name: &str) -> bool {
     false
 }
 
+/// Whether directory events for `path` should be reported.
+fn is_watched(path: &Path, options: &ScanOptions, ignore: &mut IgnoreRules) -> bool {
+    let Some(name) = path.file_name() else {
+        return true;
+    };
+    let name = name.to_string_lossy();
+    // .commonplace.json is managed by the sync client itself; reporting it
+    // would cause feedback loops
+    if name == ".commonplace.json" {
+        debug!("Skipping schema file event: {}", path.display());
+        return false;
+    }
+    if !options.include_hidden && is_hidden_name(&name) {
+        return false;
+    }
+    // Temp files used by atomic writes are renamed to the target afterwards
+    if is_temp_file(&name) {
+        debug!("Skipping temp file event: {}", path.display());
+        return false;
+    }
+    // Conflict copies stay local rather than syncing to every peer
+    if is_conflict_copy(&name) {
+        debug!("Skipping conflict copy event: {}", path.display());
+        return false;
+    }
+    if ignore.is_ignored(path, path.is_dir()) {
+        debug!("Skipping ignored path event: {}", path.display());
+        return false;
+    }
+    true
+}
+
+/// Content hashes of the watched files under `start`, within the tree at
+/// `root`.
+///
+/// Hidden and ignored paths are skipped the same way as their events.
+/// Hashes in `recorded` (the state file's, keyed by path relative to
+/// `root`) are taken as they are; only files it doesn't know are read.
+fn hash_files(
+    root: &Path,
+    start: &Path,
+    options: &ScanOptions,
+    ignore: &mut IgnoreRules,
+    recorded: &HashMap<String, FileState>,
+) -> HashMap<PathBuf, String> {
+    let mut hashes = HashMap::new();
+    let mut dirs = vec![start.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !is_watched(&path, options, ignore) {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => dirs.push(path),
+                Ok(t) if t.is_file() => {
+                    let relative = path
+                        .strip_prefix(root)
+                        .map(|p| p.to_string_lossy().replace('\\', "/"))
+                        .unwrap_or_default();
+                    if let Some(state) = recorded.get(&relative) {
+                        hashes.insert(path, state.hash.clone());
+                    } else if let Ok(content) = std::fs::read(&path) {
+                        hashes.insert(path, compute_content_hash(&content));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    hashes
+}
+
+/// Turn a pending delete and create of a file with the same content into a
+/// rename, for platforms that don't pair rename events.
+fn pair_renames_by_hash(
+    pending: &mut IndexMap<PathBuf, DirEvent>,
+    known_hashes: &HashMap<PathBuf, String>,
+) {
+    let mut deleted: Vec<(PathBuf, String)> = pending
+        .values()
+        .filter_map(|event| match event {
+            DirEvent::Deleted(path) => known_hashes
+                .get(path)
+                .map(|hash| (path.clone(), hash.clone())),
+            _ => None,
+        })
+        .collect();
+    if deleted.is_empty() {
+        return;
+    }
+    let created: Vec<PathBuf> = pending
+        .values()
+        .filter_map(|event| match event {
+            DirEvent::Created(path) if path.is_file() => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+    for to in created {
+        let Ok(content) = std::fs::read(&to) else {
+            continue;
+        };
+        let hash = compute_content_hash(&content);
+        let Some(index) = deleted.iter().position(|(_, h)| *h == hash) else {
+            continue;
+        };
+        let (from, _) = deleted.swap_remove(index);
+        debug!("Rename by content: {} -> {}", from.display(), to.display());
+        pending.shift_remove(&from);
+        pending.insert(to.clone(), DirEvent::Renamed(from, to));
+    }
+}
+
+/// Keep `known_hashes` in step with a batch of events.
+fn update_known_hashes<'a>(
+    known_hashes: &mut HashMap<PathBuf, String>,
+    events: impl Iterator<Item = &'a DirEvent>,
+    root: &Path,
+    options: &ScanOptions,
+    ignore: &mut IgnoreRules,
+) {
+    for event in events {
+        match event {
+            DirEvent::Created(path) | DirEvent::Modified(path) => {
+                if path.is_dir() {
+                    known_hashes.extend(hash_files(root, path, options, ignore, &HashMap::new()));
+                } else if let Ok(content) = std::fs::read(path) {
+                    known_hashes.insert(path.clone(), compute_content_hash(&content));
+                }
+            }
+            DirEvent::Deleted(path) => {
+                known_hashes.retain(|known, _| !known.starts_with(path));
+            }
+            DirEvent::Renamed(from, to) => {
+                let moved: Vec<(PathBuf, String)> = known_hashes
+                    .iter()
+                    .filter(|(known, _)| known.starts_with(from))
+                    .map(|(known, hash)| (known.clone(), hash.clone()))
+                    .collect();
+                for (known, hash) in moved {
+                    known_hashes.remove(&known);
+                    if let Ok(rest) = known.strip_prefix(from) {
+                        known_hashes.insert(to.join(rest), hash);
+                    }
+                }
+            }
+        }
+    }
+}
+
 /// Task that watches a single file for modifications.
 ///
 /// This task sets up a file watcher using `notify` and sends [`FileEvent::Modified`]
@@ -62,6 +477,7 @@ fn is_temp_file(name: &str) -> bool {
 ///
 /// * `file_path` - Path to the file to watch
 /// * `tx` - Channel sender for file events
+/// * `shared` - Watcher to subscribe to instead of creating one
 ///
 /// # Behavior
 ///
@@ -77,7 +493,11 @@ fn is_temp_file(name: &str) -> bool {
 /// Many editors use atomic writes: write to temp file, then rename to target.
 /// This replaces the inode, so we watch the parent directory instead of the file
 /// itself to reliably detect these changes.
-pub async fn file_watcher_task(file_path: PathBuf, tx: mpsc::Sender<FileEvent>) {
+pub async fn file_watcher_task(
+    file_path: PathBuf,
+    tx: mpsc::Sender<FileEvent>,
+    shared: Option<Arc<SharedWatcher>>,
+) {
     // Get the parent directory - we watch this to catch atomic renames
     let parent_dir = match file_path.parent() {
         Some(p) if p.as_os_str().is_empty() => PathBuf::from("."),
@@ -100,34 +520,25 @@ pub async fn file_watcher_task(file_path: PathBuf, tx: mpsc::Sender<FileEvent>)
         }
     };
 
-    // Create a channel for notify events
-    let (notify_tx, mut notify_rx) = mpsc::channel::<Result<Event, notify::Error>>(100);
-
-    // Create watcher
-    let mut watcher = match RecommendedWatcher::new(
-        move |res| {
-            let _ = notify_tx.blocking_send(res);
-        },
-        Config::default().with_poll_interval(Duration::from_millis(FILE_DEBOUNCE_MS)),
+    // Watch the parent directory (non-recursive) to catch atomic renames
+    let (_watch, mut notify_rx) = match watch_path(
+        &parent_dir,
+        RecursiveMode::NonRecursive,
+        Duration::from_millis(FILE_DEBOUNCE_MS),
+        shared.as_ref(),
     ) {
-        Ok(w) => w,
+        Ok(watch) => watch,
         Err(e) => {
-            error!("Failed to create file watcher: {}", e);
+            error!(
+                "Failed to watch directory {} for file {}: {}",
+                parent_dir.display(),
+                file_path.display(),
+                e
+            );
             return;
         }
     };
 
-    // Watch the parent directory (non-recursive) to catch atomic renames
-    if let Err(e) = watcher.watch(&parent_dir, RecursiveMode::NonRecursive) {
-        error!(
-            "Failed to watch directory {} for file {}: {}",
-            parent_dir.display(),
-            file_path.display(),
-            e
-        );
-        return;
-    }
-
     info!(
         "Watching file: {} (via parent dir: {})",
         file_path.display(),
@@ -248,7 +659,9 @@ pub async fn file_watcher_task(file_path: PathBuf, tx: mpsc::Sender<FileEvent>)
 /// - File creation
 /// - File modification
 /// - File deletion
-/// - File renames (converted to delete + create events)
+/// - File and directory renames (reported as [`DirEvent::Renamed`] when the
+///   platform pairs them, or when a delete and a create in the same batch
+///   have the same content; otherwise as delete + create events)
 ///
 /// Events are debounced to consolidate rapid changes.
 ///
@@ -257,12 +670,15 @@ pub async fn file_watcher_task(file_path: PathBuf, tx: mpsc::Sender<FileEvent>)
 /// * `directory` - Root directory to watch recursively
 /// * `tx` - Channel sender for directory events
 /// * `options` - Scan options controlling hidden file handling and ignore patterns
+/// * `shared` - Watcher to subscribe to instead of creating one
 ///
 /// # Behavior
 ///
 /// - Watches recursively for all file system events
 /// - Respects `include_hidden` option from ScanOptions
-/// - Converts rename events to delete/create pairs
+/// - Skips paths excluded by ignore patterns or `.commonplaceignore` files,
+///   re-reading the rules when one of those files changes
+/// - Reports renames as [`DirEvent::Renamed`] where it can pair them
 /// - Debounces events with a 500ms delay
 /// - Logs errors but continues watching on watcher errors
 /// - Exits when the receiver is dropped
@@ -270,31 +686,50 @@ pub async fn directory_watcher_task(
     directory: PathBuf,
     tx: mpsc::Sender<DirEvent>,
     options: ScanOptions,
+    shared: Option<Arc<SharedWatcher>>,
 ) {
-    let (notify_tx, mut notify_rx) = mpsc::channel::<Result<Event, notify::Error>>(100);
-
-    let mut watcher = match RecommendedWatcher::new(
-        move |res| {
-            let _ = notify_tx.blocking_send(res);
-        },
-        Config::default().with_poll_interval(Duration::from_millis(DIR_DEBOUNCE_MS)),
+    let (_watch, mut notify_rx) = match watch_path(
+        &directory,
+        RecursiveMode::Recursive,
+        Duration::from_millis(DIR_DEBOUNCE_MS),
+        shared.as_ref(),
     ) {
-        Ok(w) => w,
+        Ok(watch) => watch,
         Err(e) => {
-            error!("Failed to create directory watcher: {}", e);
+            error!("Failed to watch directory {}: {}", directory.display(), e);
             return;
         }
     };
 
-    if let Err(e) = watcher.watch(&directory, RecursiveMode::Recursive) {
-        error!("Failed to watch directory {}: {}", directory.display(), e);
-        return;
-    }
-
     info!("Watching directory: {}", directory.display());
 
     let debounce_duration = Duration::from_millis(DIR_DEBOUNCE_MS);
-    let mut pending_events: HashMap<PathBuf, DirEvent> = HashMap::new();
+    // Keyed by path for coalescing, but kept in arrival order so a batch
+    // reports a directory's creation before the files created inside it
+    let mut pending_events: IndexMap<PathBuf, DirEvent> = IndexMap::new();
+    // Content hashes of files under the directory, for spotting renames the
+    // platform reports as unrelated delete + create events
+    let recorded = match SyncStateFile::load(&SyncStateFile::state_file_path(&directory)).await {
+        Ok(Some(state)) => state.files,
+        _ => HashMap::new(),
+    };
+    let (mut known_hashes, mut ignore) = {
+        let root = directory.clone();
+        let options = options.clone();
+        let hashed = tokio::task::spawn_blocking(move || {
+            let mut ignore = IgnoreRules::new(&root, &options);
+            let hashes = hash_files(&root, &root, &options, &mut ignore, &recorded);
+            (hashes, ignore)
+        })
+        .await;
+        match hashed {
+            Ok(hashed) => hashed,
+            Err(e) => {
+                error!("Failed to hash {}: {}", directory.display(), e);
+                return;
+            }
+        }
+    };
     let mut debounce_timer: Option<tokio::time::Instant> = None;
 
     loop {
@@ -302,26 +737,37 @@ pub async fn directory_watcher_task(
             Some(res) = notify_rx.recv() => {
                 match res {
                     Ok(event) => {
-                        for path in event.paths {
-                            // Skip .commonplace.json files to prevent feedback loops
-                            // These files are managed by the sync client itself
-                            if let Some(name) = path.file_name() {
-                                let name_str = name.to_string_lossy();
-                                if name_str == ".commonplace.json" {
-                                    debug!("Skipping schema file event: {}", path.display());
-                                    continue;
-                                }
-                                // Skip hidden files if not configured
-                                if !options.include_hidden && name_str.starts_with('.') {
-                                    continue;
-                                }
-                                // Skip temp files used by atomic write patterns
-                                // These are intermediate files that will be renamed to the target
-                                if is_temp_file(&name_str) {
-                                    debug!("Skipping temp file event: {}", path.display());
+                        if event.paths.iter().any(|path| is_ignore_file(path)) {
+                            ignore.reload();
+                        }
+
+                        // A rename the platform paired up (inotify): keep the
+                        // node_id by reporting it as one event
+                        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind {
+                            if let [from, to] = &event.paths[..] {
+                                if is_watched(from, &options, &mut ignore)
+                                    && is_watched(to, &options, &mut ignore)
+                                {
+                                    debug!("Rename: {} -> {}", from.display(), to.display());
+                                    // The From/To halves arrived first; fold them in
+                                    pending_events.shift_remove(to);
+                                    let from = match pending_events.shift_remove(from) {
+                                        Some(DirEvent::Renamed(origin, _)) => origin,
+                                        _ => from.clone(),
+                                    };
+                                    pending_events
+                                        .insert(to.clone(), DirEvent::Renamed(from, to.clone()));
+                                    debounce_timer =
+                                        Some(tokio::time::Instant::now() + debounce_duration);
                                     continue;
                                 }
                             }
+                        }
+
+                        for path in event.paths {
+                            if !is_watched(&path, &options, &mut ignore) {
+                                continue;
+                            }
 
                             // Handle rename events specially - treat as delete+create
                             // so that sync tasks are properly stopped/started
@@ -375,8 +821,11 @@ pub async fn directory_watcher_task(
                                 //    This handles temp file + rename patterns where a file is replaced
                                 // 3. All other cases -> replace with new event
                                 let coalesced_event = match (&evt, pending_events.get(&path)) {
-                                    (DirEvent::Modified(_), Some(DirEvent::Created(_))) => {
-                                        // Keep the existing Created event
+                                    (
+                                        DirEvent::Modified(_),
+                                        Some(DirEvent::Created(_) | DirEvent::Renamed(..)),
+                                    ) => {
+                                        // Keep the existing Created (or Renamed) event
                                         debug!("Preserving Created event (not overwriting with Modified)");
                                         None
                                     }
@@ -412,7 +861,31 @@ pub async fn directory_watcher_task(
                 }
             } => {
                 debounce_timer = None;
-                for (_, event) in pending_events.drain() {
+                // Reading the changed files blocks; hand everything the
+                // blocking task needs over and take it back afterwards
+                let mut batch = std::mem::take(&mut pending_events);
+                let root = directory.clone();
+                let batch_options = options.clone();
+                let mut hashes = std::mem::take(&mut known_hashes);
+                let mut rules = ignore;
+                let hashed = tokio::task::spawn_blocking(move || {
+                    pair_renames_by_hash(&mut batch, &hashes);
+                    update_known_hashes(&mut hashes, batch.values(), &root, &batch_options, &mut rules);
+                    (batch, hashes, rules)
+                })
+                .await;
+                let batch = match hashed {
+                    Ok((batch, hashes, rules)) => {
+                        known_hashes = hashes;
+                        ignore = rules;
+                        batch
+                    }
+                    Err(e) => {
+                        error!("Failed to hash changed files: {}", e);
+                        return;
+                    }
+                };
+                for (_, event) in batch {
                     if tx.send(event).await.is_err() {
                         return;
                     }
@@ -442,7 +915,7 @@ mod tests {
         // Start the watcher task
         let target_path_clone = target_path.clone();
         let watcher_handle = tokio::spawn(async move {
-            file_watcher_task(target_path_clone, tx).await;
+            file_watcher_task(target_path_clone, tx, None).await;
         });
 
         // Give the watcher time to start
@@ -483,7 +956,7 @@ mod tests {
         // Start the watcher task
         let target_path_clone = target_path.clone();
         let watcher_handle = tokio::spawn(async move {
-            file_watcher_task(target_path_clone, tx).await;
+            file_watcher_task(target_path_clone, tx, None).await;
         });
 
         // Give the watcher time to start
@@ -523,7 +996,7 @@ mod tests {
         // Start the watcher task
         let target_path_clone = target_path.clone();
         let watcher_handle = tokio::spawn(async move {
-            file_watcher_task(target_path_clone, tx).await;
+            file_watcher_task(target_path_clone, tx, None).await;
         });
 
         // Give the watcher time to start
@@ -575,7 +1048,7 @@ mod tests {
         // Start the watcher task
         let target_path_clone = target_path.clone();
         let watcher_handle = tokio::spawn(async move {
-            file_watcher_task(target_path_clone, tx).await;
+            file_watcher_task(target_path_clone, tx, None).await;
         });
 
         // Give the watcher time to start
@@ -614,12 +1087,13 @@ mod tests {
         let options = ScanOptions {
             include_hidden: false,
             ignore_patterns: vec![],
+            include_patterns: None,
         };
 
         // Start the watcher task
         let dir_clone = temp_dir.path().to_path_buf();
         let watcher_handle = tokio::spawn(async move {
-            directory_watcher_task(dir_clone, tx, options).await;
+            directory_watcher_task(dir_clone, tx, options, None).await;
         });
 
         // Give the watcher time to start
@@ -656,11 +1130,161 @@ mod tests {
                     path.display()
                 );
             }
+            Ok(Some(DirEvent::Renamed(from, to))) => {
+                panic!(
+                    "Expected Modified but got Renamed {} -> {}",
+                    from.display(),
+                    to.display()
+                );
+            }
             Ok(None) => panic!("Channel closed without receiving event"),
             Err(_) => panic!("Timeout waiting for directory event"),
         }
     }
 
+    /// Test that directory_watcher_task reports a rename as one event.
+    #[tokio::test]
+    async fn test_directory_watcher_reports_rename() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let from = temp_dir.path().join("before.txt");
+        fs::write(&from, "content").expect("Failed to write initial file");
+
+        let (tx, mut rx) = mpsc::channel::<DirEvent>(10);
+        let dir_clone = temp_dir.path().to_path_buf();
+        let watcher_handle = tokio::spawn(async move {
+            directory_watcher_task(dir_clone, tx, ScanOptions::default(), None).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let to = temp_dir.path().join("after.txt");
+        fs::rename(&from, &to).expect("Failed to rename");
+
+        let result = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await;
+        watcher_handle.abort();
+
+        match result {
+            Ok(Some(DirEvent::Renamed(old, new))) => {
+                assert_eq!(old.file_name(), from.file_name());
+                assert_eq!(new.file_name(), to.file_name());
+            }
+            other => panic!("Expected Renamed, got {:?}", other),
+        }
+    }
+
+    /// Test that a batch is reported in the order the changes happened.
+    #[tokio::test]
+    async fn test_directory_watcher_keeps_batch_order() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+
+        let (tx, mut rx) = mpsc::channel::<DirEvent>(64);
+        let dir_clone = temp_dir.path().to_path_buf();
+        let watcher_handle = tokio::spawn(async move {
+            directory_watcher_task(dir_clone, tx, ScanOptions::default(), None).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Enough files that an unordered batch would almost surely shuffle them
+        let names: Vec<String> = (0..16).rev().map(|i| format!("file{:02}.txt", i)).collect();
+        for name in &names {
+            fs::write(temp_dir.path().join(name), name).expect("Failed to write file");
+        }
+
+        let mut seen = Vec::new();
+        while seen.len() < names.len() {
+            match tokio::time::timeout(Duration::from_secs(2), rx.recv()).await {
+                Ok(Some(DirEvent::Created(path))) => {
+                    seen.push(path.file_name().unwrap().to_str().unwrap().to_string());
+                }
+                other => panic!("Expected Created, got {:?}", other),
+            }
+        }
+        watcher_handle.abort();
+
+        assert_eq!(seen, names);
+    }
+
+    /// Test that hashing skips what isn't watched and trusts the state file.
+    #[test]
+    fn test_hash_files_skips_ignored_and_reuses_recorded() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+        fs::write(root.join(".git/HEAD"), "ref").unwrap();
+        fs::write(root.join("node_modules/pkg/index.js"), "js").unwrap();
+        fs::write(root.join("known.txt"), "known").unwrap();
+        fs::write(root.join("new.txt"), "new").unwrap();
+
+        let options = ScanOptions {
+            ignore_patterns: vec!["node_modules/".to_string()],
+            ..Default::default()
+        };
+        let mut ignore = IgnoreRules::new(root, &options);
+        let recorded = HashMap::from([(
+            "known.txt".to_string(),
+            FileState {
+                hash: "recorded".to_string(),
+                last_modified: None,
+                cid: None,
+            },
+        )]);
+        let hashes = hash_files(root, root, &options, &mut ignore, &recorded);
+
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[&root.join("known.txt")], "recorded");
+        assert_eq!(hashes[&root.join("new.txt")], compute_content_hash(b"new"));
+    }
+
+    /// Test that conflict copies are never reported, even with no ignore patterns.
+    #[test]
+    fn test_conflict_copies_not_watched() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let root = temp_dir.path();
+        let options = ScanOptions::default();
+        let mut ignore = IgnoreRules::new(root, &options);
+
+        assert!(is_watched(&root.join("notes.txt"), &options, &mut ignore));
+        assert!(!is_watched(
+            &root.join("notes.conflict-alice-20250102T030405Z.txt"),
+            &options,
+            &mut ignore
+        ));
+    }
+
+    /// Test that an unpaired delete + create of the same content is a rename.
+    #[test]
+    fn test_pair_renames_by_hash() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let old = temp_dir.path().join("old.txt");
+        let moved = temp_dir.path().join("moved.txt");
+        let other = temp_dir.path().join("other.txt");
+        fs::write(&old, "same").unwrap();
+        let mut ignore = IgnoreRules::new(temp_dir.path(), &ScanOptions::default());
+        let known_hashes = hash_files(
+            temp_dir.path(),
+            temp_dir.path(),
+            &ScanOptions::default(),
+            &mut ignore,
+            &HashMap::new(),
+        );
+        fs::rename(&old, &moved).unwrap();
+        fs::write(&other, "different").unwrap();
+
+        let mut pending = IndexMap::from([
+            (old.clone(), DirEvent::Deleted(old.clone())),
+            (moved.clone(), DirEvent::Created(moved.clone())),
+            (other.clone(), DirEvent::Created(other.clone())),
+        ]);
+        pair_renames_by_hash(&mut pending, &known_hashes);
+
+        assert_eq!(pending.len(), 2);
+        assert!(matches!(
+            pending.get(&moved),
+            Some(DirEvent::Renamed(from, to)) if *from == old && *to == moved
+        ));
+        assert!(matches!(pending.get(&other), Some(DirEvent::Created(_))));
+    }
+
     /// Test that directory_watcher_task ignores temp files.
     #[tokio::test]
     async fn test_directory_watcher_ignores_temp_files() {
@@ -670,12 +1294,13 @@ mod tests {
         let options = ScanOptions {
             include_hidden: false,
             ignore_patterns: vec![],
+            include_patterns: None,
         };
 
         // Start the watcher task
         let dir_clone = temp_dir.path().to_path_buf();
         let watcher_handle = tokio::spawn(async move {
-            directory_watcher_task(dir_clone, tx, options).await;
+            directory_watcher_task(dir_clone, tx, options, None).await;
         });
 
         // Give the watcher time to start
@@ -707,7 +1332,10 @@ mod tests {
             Ok(Some(event)) => {
                 // Should only receive event for normal.txt
                 let path = match &event {
-                    DirEvent::Created(p) | DirEvent::Modified(p) | DirEvent::Deleted(p) => p,
+                    DirEvent::Created(p)
+                    | DirEvent::Modified(p)
+                    | DirEvent::Deleted(p)
+                    | DirEvent::Renamed(_, p) => p,
                 };
                 let name = path.file_name().unwrap().to_str().unwrap();
                 assert_eq!(
@@ -721,6 +1349,39 @@ mod tests {
         }
     }
 
+    /// Test that directory_watcher_task skips paths matched by .commonplaceignore.
+    #[tokio::test]
+    async fn test_directory_watcher_honors_commonplaceignore() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        fs::write(
+            temp_dir.path().join(".commonplaceignore"),
+            "*.log\nbuild/\n",
+        )
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::channel::<DirEvent>(10);
+        let dir_clone = temp_dir.path().to_path_buf();
+        let watcher_handle = tokio::spawn(async move {
+            directory_watcher_task(dir_clone, tx, ScanOptions::default(), None).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        fs::write(temp_dir.path().join("debug.log"), "ignored").unwrap();
+        fs::create_dir(temp_dir.path().join("build")).unwrap();
+        fs::write(temp_dir.path().join("build/out.txt"), "ignored").unwrap();
+        fs::write(temp_dir.path().join("normal.txt"), "synced").unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await;
+        watcher_handle.abort();
+
+        match result {
+            Ok(Some(DirEvent::Created(path))) | Ok(Some(DirEvent::Modified(path))) => {
+                assert_eq!(path.file_name().unwrap(), "normal.txt");
+            }
+            other => panic!("Expected event for normal.txt, got {:?}", other),
+        }
+    }
+
     /// Test is_temp_file function.
     #[test]
     fn test_is_temp_file_detection() {
@@ -739,4 +1400,100 @@ mod tests {
         assert!(!is_temp_file("template.txt"), "Contains 'tmp' but not temp");
         assert!(!is_temp_file("input.txt"), "Normal input file");
     }
+
+    #[test]
+    fn test_covering_watches() {
+        let wanted = covering_watches([
+            (PathBuf::from("/w"), false),
+            (PathBuf::from("/w/sub"), false),
+            (PathBuf::from("/w"), true),
+            (PathBuf::from("/other/file-dir"), false),
+            (PathBuf::from("/wide"), false),
+        ]);
+        // /w/sub is covered by the recursive watch on /w, which wins over the
+        // non-recursive one; /wide isn't below /w
+        assert_eq!(
+            wanted.into_iter().collect::<Vec<_>>(),
+            vec![
+                (PathBuf::from("/other/file-dir"), false),
+                (PathBuf::from("/w"), true),
+                (PathBuf::from("/wide"), false),
+            ]
+        );
+    }
+
+    /// Overlapping subscriptions each get their events, and dropping one
+    /// leaves the other watching.
+    #[tokio::test]
+    async fn test_shared_watcher_overlapping_subscriptions() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let sub_dir = temp_dir.path().join("sub");
+        fs::create_dir(&sub_dir).unwrap();
+
+        let shared = Arc::new(SharedWatcher::new().unwrap());
+        let (outer, mut outer_rx) = shared
+            .subscribe(temp_dir.path(), RecursiveMode::Recursive)
+            .unwrap();
+        let (_inner, mut inner_rx) = shared
+            .subscribe(&sub_dir, RecursiveMode::NonRecursive)
+            .unwrap();
+
+        let first = sub_dir.join("first.txt");
+        fs::write(&first, "one").unwrap();
+        for rx in [&mut outer_rx, &mut inner_rx] {
+            let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("Timeout waiting for event")
+                .unwrap()
+                .unwrap();
+            assert!(event.paths.contains(&first), "{:?}", event.paths);
+        }
+
+        drop(outer);
+        let second = sub_dir.join("second.txt");
+        fs::write(&second, "two").unwrap();
+        let found = tokio::time::timeout(Duration::from_secs(2), async {
+            while let Some(Ok(event)) = inner_rx.recv().await {
+                if event.paths.contains(&second) {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(found, Ok(true), "Inner subscription stopped watching");
+    }
+
+    /// Test that a subscriber that stops reading doesn't hold up the others.
+    #[tokio::test]
+    async fn test_shared_watcher_slow_subscriber_does_not_block_others() {
+        let slow_dir = TempDir::new().expect("Failed to create temp dir");
+        let fast_dir = TempDir::new().expect("Failed to create temp dir");
+
+        let shared = Arc::new(SharedWatcher::new().unwrap());
+        let (_slow, _slow_rx) = shared
+            .subscribe(slow_dir.path(), RecursiveMode::Recursive)
+            .unwrap();
+        let (_fast, mut fast_rx) = shared
+            .subscribe(fast_dir.path(), RecursiveMode::Recursive)
+            .unwrap();
+
+        // Far more events than the slow subscriber's channel holds
+        for i in 0..300 {
+            fs::write(slow_dir.path().join(format!("file{}.txt", i)), "x").unwrap();
+        }
+        let fast = fast_dir.path().join("fast.txt");
+        fs::write(&fast, "fast").unwrap();
+
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(event)) = fast_rx.recv().await {
+                if event.paths.contains(&fast) {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(found, Ok(true), "Fast subscription was held up");
+    }
 }
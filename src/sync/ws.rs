@@ -0,0 +1,232 @@
+//! WebSocket transport for the sync client.
+//!
+//! With `commonplace-sync --transport ws`, each text file syncs over one
+//! WebSocket per document instead of SSE subscriptions and HTTP edits. The
+//! connection speaks the `commonplace` subprotocol: Yjs sync step 1/2 when it
+//! opens, then incremental updates in both directions, plus a blue event
+//! naming each commit the server records. The client keeps its own Yjs
+//! document across reconnects, so edits made while disconnected merge in
+//! when the connection comes back.
+//!
+//! JSON, JSONL and binary files, path-addressed sync and `--force-push` keep
+//! using SSE and HTTP.
+
+use crate::sync::text_sync::{merge_local_edit, merge_remote_updates, next_event, TextDoc};
+use crate::sync::{build_ws_url, is_binary_content, FileEvent, SyncState};
+use crate::ws::protocol::{self, WsMessage, SUBPROTOCOL_COMMONPLACE};
+use futures::{SinkExt, StreamExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn};
+
+/// Delay before reconnecting a dropped WebSocket
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Syncing eligible files over WebSocket, once the server has been probed.
+#[derive(Debug, Clone, Default)]
+pub struct WsTransport {
+    /// Bearer token to connect with
+    pub token: Option<String>,
+}
+
+/// Errors from the WebSocket transport.
+#[derive(Debug, thiserror::Error)]
+pub enum WsTransportError {
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("server did not accept the {SUBPROTOCOL_COMMONPLACE} subprotocol")]
+    Subprotocol,
+    #[error("server does not serve WebSockets (e.g. commonplace-http)")]
+    Unsupported,
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Open a document's WebSocket with the `commonplace` subprotocol.
+pub async fn connect_ws(
+    server: &str,
+    node_id: &str,
+    token: Option<&str>,
+) -> Result<WsStream, WsTransportError> {
+    let mut request = build_ws_url(server, node_id).into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(SUBPROTOCOL_COMMONPLACE),
+    );
+    if let Some(token) = token.filter(|t| !t.is_empty()) {
+        if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", token)) {
+            headers.insert(AUTHORIZATION, value);
+        }
+    }
+
+    let (stream, response) = match tokio_tungstenite::connect_async(request).await {
+        Ok(connected) => connected,
+        Err(tokio_tungstenite::tungstenite::Error::Http(response))
+            if response.status() == StatusCode::NOT_IMPLEMENTED =>
+        {
+            return Err(WsTransportError::Unsupported)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let protocol = response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok());
+    if protocol != Some(SUBPROTOCOL_COMMONPLACE) {
+        return Err(WsTransportError::Subprotocol);
+    }
+    Ok(stream)
+}
+
+/// Check that the server accepts `commonplace` WebSockets for `node_id`.
+pub async fn probe_ws(
+    server: &str,
+    node_id: &str,
+    token: Option<&str>,
+) -> Result<(), WsTransportError> {
+    let mut stream = connect_ws(server, node_id, token).await?;
+    let _ = stream.close(None).await;
+    Ok(())
+}
+
+/// Task that syncs a single text file over its document's WebSocket.
+///
+/// This takes the place of the upload and SSE tasks. `rx` carries the file
+/// watcher's events and is `None` in pull-only mode; in push-only mode
+/// server changes are merged into the document but never written to the
+/// file.
+pub async fn ws_sync_task(
+    server: String,
+    identifier: String,
+    file_path: PathBuf,
+    state: Arc<RwLock<SyncState>>,
+    mut rx: Option<mpsc::Receiver<FileEvent>>,
+    push_only: bool,
+    transport: WsTransport,
+) {
+    let token = transport.token;
+    let mut doc = TextDoc::new();
+
+    loop {
+        info!("Connecting to WebSocket for {}", file_path.display());
+        match connect_ws(&server, &identifier, token.as_deref()).await {
+            Ok(stream) => {
+                info!("WebSocket connected: {}", identifier);
+                if let Err(e) =
+                    ws_session(stream, &file_path, &state, &mut rx, &mut doc, push_only).await
+                {
+                    warn!("WebSocket error for {}: {}", file_path.display(), e);
+                }
+            }
+            Err(e) => {
+                error!("WebSocket connection failed: {}", e);
+            }
+        }
+
+        info!("Reconnecting WebSocket in {:?}...", RECONNECT_DELAY);
+        // Local edits made meanwhile are picked up from the file once the
+        // server's state arrives again
+        let delay = sleep(RECONNECT_DELAY);
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                _ = &mut delay => break,
+                event = next_event(&mut rx) => {
+                    if event.is_none() {
+                        rx = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run one connection until it closes.
+async fn ws_session(
+    stream: WsStream,
+    file_path: &Path,
+    state: &Arc<RwLock<SyncState>>,
+    rx: &mut Option<mpsc::Receiver<FileEvent>>,
+    doc: &mut TextDoc,
+    push_only: bool,
+) -> Result<(), WsTransportError> {
+    let (mut sink, mut source) = stream.split();
+    let pull_only = rx.is_none();
+
+    // Ask for whatever changed on the server while we were away
+    sink.send(Message::Binary(protocol::encode_sync_step1(
+        &doc.state_vector(),
+    )))
+    .await?;
+
+    loop {
+        tokio::select! {
+            msg = source.next() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                };
+                let msg = match protocol::decode_message(&data) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("Failed to decode WebSocket message: {}", e);
+                        continue;
+                    }
+                };
+                match msg {
+                    // Send the server what it's missing, e.g. edits merged
+                    // while disconnected
+                    WsMessage::SyncStep1 { state_vector } if !pull_only => {
+                        if let Some(update) = doc.diff(&state_vector) {
+                            sink.send(Message::Binary(protocol::encode_sync_step2(&update)))
+                                .await?;
+                        }
+                    }
+                    WsMessage::SyncStep2 { update } | WsMessage::Update { update } => {
+                        for local in merge_remote_updates(file_path, state, doc, std::slice::from_ref(&update), pull_only, push_only).await {
+                            sink.send(Message::Binary(protocol::encode_update(&local))).await?;
+                        }
+                    }
+                    WsMessage::BlueEvent { commit_id, .. } => {
+                        debug!("Commit {} recorded for {}", commit_id, file_path.display());
+                        let mut s = state.write().await;
+                        s.last_written_cid = Some(commit_id);
+                        s.checkpoint(file_path).await;
+                    }
+                    _ => {}
+                }
+            }
+            event = next_event(rx) => {
+                let Some(FileEvent::Modified(raw)) = event else {
+                    *rx = None;
+                    continue;
+                };
+                // Until the server's state arrives there is nothing to diff
+                // against; the first sync picks the edit up from the file
+                if !doc.synced {
+                    continue;
+                }
+                if is_binary_content(&raw) {
+                    warn!("{} now holds binary content; not syncing it over WebSocket", file_path.display());
+                    continue;
+                }
+                let content = String::from_utf8_lossy(&raw).to_string();
+                if let Some(update) = merge_local_edit(state, doc, content).await {
+                    sink.send(Message::Binary(protocol::encode_update(&update))).await?;
+                }
+            }
+        }
+    }
+}
@@ -6,7 +6,7 @@ use base64::{engine::general_purpose::STANDARD, Engine};
 use yrs::any::Any;
 use yrs::types::ToJson;
 use yrs::updates::decoder::Decode;
-use yrs::{Array, Doc, Map, ReadTxn, Text, Transact, Update, WriteTxn};
+use yrs::{Array, Doc, Map, ReadTxn, Text, TextRef, Transact, TransactionMut, Update, WriteTxn};
 
 /// Name of the root text/map element in Yjs documents
 pub const TEXT_ROOT_NAME: &str = "content";
@@ -131,8 +131,6 @@ pub fn create_yjs_text_diff_update(
     old_content: &str,
     new_content: &str,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    use similar::{ChangeTag, TextDiff};
-
     // Decode base state
     let base_state_bytes = base64_decode(base_state_b64)?;
 
@@ -147,40 +145,51 @@ pub fn create_yjs_text_diff_update(
         txn.apply_update(update);
     }
 
-    // Compute character-level diff
-    let diff = TextDiff::from_chars(old_content, new_content);
-
-    // Apply diff operations
+    // Apply the character-level diff
     let update_bytes = {
         let mut txn = doc.transact_mut();
-        let mut pos = 0u32;
-
-        for change in diff.iter_all_changes() {
-            match change.tag() {
-                ChangeTag::Equal => {
-                    // Move position forward by the number of chars
-                    pos += change.value().chars().count() as u32;
-                }
-                ChangeTag::Delete => {
-                    // Delete characters at current position
-                    let len = change.value().chars().count() as u32;
-                    text.remove_range(&mut txn, pos, len);
-                    // Position stays the same after delete
-                }
-                ChangeTag::Insert => {
-                    // Insert characters at current position
-                    text.insert(&mut txn, pos, change.value());
-                    pos += change.value().chars().count() as u32;
-                }
-            }
-        }
-
+        apply_text_diff(&mut txn, &text, old_content, new_content);
         txn.encode_update_v1()
     };
 
     Ok(base64_encode(&update_bytes))
 }
 
+/// Edit `text` from `old_content` to `new_content` with a character-level
+/// diff, so concurrent edits elsewhere in the text merge cleanly.
+///
+/// Offsets are UTF-8 byte counts, the default for Yrs documents.
+pub fn apply_text_diff(
+    txn: &mut TransactionMut,
+    text: &TextRef,
+    old_content: &str,
+    new_content: &str,
+) {
+    use similar::{ChangeTag, TextDiff};
+
+    let diff = TextDiff::from_chars(old_content, new_content);
+    let mut pos = 0u32;
+
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => {
+                // Move position past the unchanged text
+                pos += change.value().len() as u32;
+            }
+            ChangeTag::Delete => {
+                // Delete at current position
+                text.remove_range(txn, pos, change.value().len() as u32);
+                // Position stays the same after delete
+            }
+            ChangeTag::Insert => {
+                // Insert at current position
+                text.insert(txn, pos, change.value());
+                pos += change.value().len() as u32;
+            }
+        }
+    }
+}
+
 /// Create a Yjs update for JSONL content (newline-delimited JSON).
 /// Each non-empty line is parsed as a JSON object and stored in a Y.Array.
 pub fn create_yjs_jsonl_update(
@@ -228,6 +237,48 @@ pub fn create_yjs_jsonl_update(
     Ok(base64_encode(&update))
 }
 
+/// Create a Yjs update that removes the lines at `remove` (indices into the
+/// current lines) from JSONL content and appends `append`, leaving the other
+/// lines untouched.
+pub fn create_yjs_jsonl_append_update(
+    base_state: Option<&str>,
+    remove: &[u32],
+    append: Vec<serde_json::Value>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let doc = Doc::with_client_id(1);
+
+    if let Some(state_b64) = base_state {
+        let state_bytes = base64_decode(state_b64)?;
+        if !state_bytes.is_empty() {
+            let update = Update::decode_v1(&state_bytes)?;
+            let mut txn = doc.transact_mut();
+            txn.apply_update(update);
+        }
+    }
+
+    let update = {
+        let mut txn = doc.transact_mut();
+        let array = txn.get_or_insert_array(TEXT_ROOT_NAME);
+
+        // Highest index first so earlier removals don't shift later ones
+        let mut remove = remove.to_vec();
+        remove.sort_unstable();
+        remove.dedup();
+        let len = array.len(&txn);
+        for index in remove.into_iter().rev().filter(|&i| i < len) {
+            array.remove(&mut txn, index);
+        }
+
+        for value in append {
+            array.push_back(&mut txn, json_value_to_any(value));
+        }
+
+        txn.encode_update_v1()
+    };
+
+    Ok(base64_encode(&update))
+}
+
 /// Convert Y.Array content to JSONL format (one JSON object per line).
 pub fn yjs_array_to_jsonl(state_b64: &str) -> Result<String, Box<dyn std::error::Error>> {
     let state_bytes = base64_decode(state_b64)?;
@@ -329,6 +380,18 @@ mod tests {
             assert!(base64_decode(&update).is_ok());
         }
 
+        #[test]
+        fn test_apply_text_diff_unicode() {
+            use yrs::GetString;
+
+            let doc = Doc::new();
+            let text = doc.get_or_insert_text(TEXT_ROOT_NAME);
+            let mut txn = doc.transact_mut();
+            text.push(&mut txn, "Hello 世界 🌍!");
+            apply_text_diff(&mut txn, &text, "Hello 世界 🌍!", "Hi 世界 and 🌍?");
+            assert_eq!(text.get_string(&txn), "Hi 世界 and 🌍?");
+        }
+
         #[test]
         fn test_create_yjs_json_update_object() {
             let result = create_yjs_json_update(r#"{"key": "value"}"#, None);
@@ -487,6 +550,36 @@ mod tests {
             assert!(result.is_err());
         }
 
+        #[test]
+        fn test_jsonl_append_update_edits_in_place() {
+            let base = create_yjs_jsonl_update("{\"a\":1}\n{\"b\":2}\n{\"c\":3}\n", None).unwrap();
+            let update = create_yjs_jsonl_append_update(
+                Some(&base),
+                &[0, 2],
+                vec![serde_json::json!({"d": 4})],
+            )
+            .unwrap();
+
+            let doc = Doc::new();
+            {
+                let mut txn = doc.transact_mut();
+                for state in [&base, &update] {
+                    let bytes = base64_decode(state).unwrap();
+                    txn.apply_update(Update::decode_v1(&bytes).unwrap());
+                }
+            }
+            let state = base64_encode(
+                &doc.transact()
+                    .encode_state_as_update_v1(&Default::default()),
+            );
+            assert_eq!(
+                yjs_array_to_jsonl(&state).unwrap(),
+                "{\"b\":2}\n{\"d\":4}\n"
+            );
+            // Only the change travels, not the whole log
+            assert!(base64_decode(&update).unwrap().len() < base64_decode(&base).unwrap().len());
+        }
+
         #[test]
         fn test_jsonl_roundtrip() {
             let content = r#"{"a":1}
@@ -5,18 +5,24 @@ use super::protocol::{
     self, ProtocolMode, WsMessage, SUBPROTOCOL_COMMONPLACE, SUBPROTOCOL_Y_WEBSOCKET,
 };
 use super::room::RoomManager;
+use crate::auth::{effective_author, Principal, WriteAccess};
+use crate::fs::OwnershipGuard;
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::{Path, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
+use axum::Extension;
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 use tracing::{debug, error, info, warn};
+use yrs::updates::encoder::Encode;
 
 /// WebSocket state shared across handlers.
 #[derive(Clone)]
 pub struct WsState {
     pub room_manager: Arc<RoomManager>,
+    /// Single-writer ownership checks for pushed updates
+    pub ownership: Option<Arc<OwnershipGuard>>,
 }
 
 /// Handle WebSocket upgrade request.
@@ -24,11 +30,22 @@ pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<WsState>,
     Path(doc_id): Path<String>,
+    principal: Option<Extension<Principal>>,
+    write_access: Option<Extension<WriteAccess>>,
     headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
+    // The auth middleware only requires read access for the upgrade, and
+    // records whether this connection may push updates too.
+    // Ownership is checked per update instead, since processes.json can
+    // change while the socket is open.
+    let can_write = principal.is_none() || write_access.is_some_and(|Extension(WriteAccess(w))| w);
+
     // Check if document exists
     let room = state.room_manager.get_or_create_room(&doc_id).await;
 
+    // Commits made through this connection are attributed to its principal
+    let author = effective_author(principal.as_deref(), None);
+
     // Negotiate subprotocol
     let protocol = negotiate_protocol(&headers);
 
@@ -41,7 +58,9 @@ pub async fn ws_handler(
     // Upgrade the connection
     Ok(ws
         .protocols([SUBPROTOCOL_Y_WEBSOCKET, SUBPROTOCOL_COMMONPLACE])
-        .on_upgrade(move |socket| handle_socket(socket, state, doc_id, protocol, room)))
+        .on_upgrade(move |socket| {
+            handle_socket(socket, state, doc_id, protocol, room, can_write, author)
+        }))
 }
 
 /// Negotiate the WebSocket subprotocol from headers.
@@ -72,6 +91,8 @@ async fn handle_socket(
     doc_id: String,
     protocol: ProtocolMode,
     room: Arc<super::room::Room>,
+    can_write: bool,
+    author: Option<String>,
 ) {
     // Create channel for outgoing messages
     let (tx, mut rx) = mpsc::channel::<OutgoingMessage>(256);
@@ -113,7 +134,7 @@ async fn handle_socket(
                 match msg {
                     Some(Ok(Message::Binary(data))) => {
                         conn.write().await.touch();
-                        if let Err(e) = handle_binary_message(&conn_id, &data, &room).await {
+                        if let Err(e) = handle_binary_message(&conn_id, &data, &room, can_write, &author, state.ownership.as_deref()).await {
                             warn!(conn_id = %conn_id, "Error handling message: {}", e);
                         }
                     }
@@ -131,7 +152,7 @@ async fn handle_socket(
                     Some(Ok(Message::Text(text))) => {
                         // y-websocket uses binary, but some clients might send text
                         conn.write().await.touch();
-                        if let Err(e) = handle_binary_message(&conn_id, text.as_bytes(), &room).await {
+                        if let Err(e) = handle_binary_message(&conn_id, text.as_bytes(), &room, can_write, &author, state.ownership.as_deref()).await {
                             warn!(conn_id = %conn_id, "Error handling text message: {}", e);
                         }
                     }
@@ -171,7 +192,8 @@ async fn send_initial_sync(
 
     // Send SyncStep2 with full state (so client gets everything)
     // Use empty state vector to get full document
-    let full_state = room.handle_sync_step1(&[]).await?;
+    let empty_sv = yrs::StateVector::default().encode_v1();
+    let full_state = room.handle_sync_step1(&empty_sv).await?;
     let _ = socket.send(Message::Binary(full_state)).await;
 
     Ok(())
@@ -182,9 +204,25 @@ async fn handle_binary_message(
     conn_id: &str,
     data: &[u8],
     room: &Arc<super::room::Room>,
+    can_write: bool,
+    author: &Option<String>,
+    ownership: Option<&OwnershipGuard>,
 ) -> Result<(), String> {
     let msg = protocol::decode_message(data).map_err(|e| e.to_string())?;
 
+    if matches!(msg, WsMessage::SyncStep2 { .. } | WsMessage::Update { .. }) {
+        if !can_write {
+            return Err("connection is read-only; update rejected".to_string());
+        }
+        // The author is the connection's principal, so it names the writer
+        if let Some(guard) = ownership {
+            guard
+                .check_doc(room.doc_id(), author.as_deref(), false)
+                .await
+                .map_err(|v| v.to_string())?;
+        }
+    }
+
     match msg {
         WsMessage::SyncStep1 { state_vector } => {
             // Client is asking what updates we have that they don't
@@ -193,20 +231,17 @@ async fn handle_binary_message(
                 .await
                 .map_err(|e| e.to_string())?;
 
-            // Send response to the specific connection
-            // Note: We need access to the connection here
-            // For now, broadcast to all (the sender will receive it too, which is fine)
-            room.broadcast_all(response).await;
+            room.send_to(conn_id, response).await;
         }
         WsMessage::SyncStep2 { update } => {
             // Client is sending us updates we're missing
-            room.handle_update(conn_id, &update)
+            room.handle_update(conn_id, &update, author.clone())
                 .await
                 .map_err(|e| e.to_string())?;
         }
         WsMessage::Update { update } => {
             // Incremental update from client
-            room.handle_update(conn_id, &update)
+            room.handle_update(conn_id, &update, author.clone())
                 .await
                 .map_err(|e| e.to_string())?;
         }
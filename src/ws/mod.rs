@@ -3,6 +3,9 @@
 //! Provides WebSocket endpoint at `/ws/docs/{id}` with subprotocol negotiation:
 //! - `y-websocket`: Standard Yjs sync protocol for browser tools (Tiptap, Monaco)
 //! - `commonplace`: Extended protocol with commit metadata and blue/red ports
+//!
+//! With commit storage, updates from either subprotocol are recorded as commits
+//! like HTTP edits; `commonplace-sync --transport ws` is a `commonplace` client.
 
 pub mod connection;
 pub mod handler;
@@ -11,6 +14,8 @@ pub mod room;
 
 use crate::document::DocumentStore;
 use crate::events::CommitBroadcaster;
+use crate::fs::OwnershipGuard;
+use crate::services::DocumentService;
 use crate::store::CommitStore;
 use axum::routing::get;
 use axum::Router;
@@ -24,11 +29,14 @@ pub fn router(
     commit_store: Option<Arc<CommitStore>>,
     broadcaster: Option<CommitBroadcaster>,
     _fs_root: Option<String>,
+    ownership: Option<Arc<OwnershipGuard>>,
+    service: Arc<DocumentService>,
 ) -> Router {
     let room_manager = Arc::new(RoomManager::new(
         doc_store,
         commit_store,
         broadcaster.clone(),
+        service,
     ));
 
     // Spawn background task to listen for commit notifications
@@ -39,10 +47,13 @@ pub fn router(
         });
     }
 
-    let state = WsState { room_manager };
+    let state = WsState {
+        room_manager,
+        ownership,
+    };
 
     Router::new()
-        .route("/ws/docs/{id}", get(handler::ws_handler))
+        .route("/ws/docs/:id", get(handler::ws_handler))
         .with_state(state)
 }
 
@@ -1,9 +1,10 @@
 //! Document room for coordinating multiple WebSocket connections.
 
 use super::connection::{ConnectionId, WsConnection};
-use super::protocol;
+use super::protocol::{self, ProtocolMode};
 use crate::document::DocumentStore;
 use crate::events::{CommitBroadcaster, CommitNotification};
+use crate::services::DocumentService;
 use crate::store::CommitStore;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -43,6 +44,9 @@ pub struct Room {
     /// Broadcaster for commit notifications (for Phase 2: commonplace extensions)
     #[allow(dead_code)]
     broadcaster: Option<CommitBroadcaster>,
+
+    /// Service that records updates as commits when persistence is enabled
+    service: Arc<DocumentService>,
 }
 
 impl Room {
@@ -52,6 +56,7 @@ impl Room {
         doc_store: Arc<DocumentStore>,
         commit_store: Option<Arc<CommitStore>>,
         broadcaster: Option<CommitBroadcaster>,
+        service: Arc<DocumentService>,
     ) -> Self {
         Self {
             doc_id,
@@ -59,6 +64,7 @@ impl Room {
             doc_store,
             commit_store,
             broadcaster,
+            service,
         }
     }
 
@@ -118,8 +124,30 @@ impl Room {
     }
 
     /// Handle an update from a client.
-    /// Applies to document store and broadcasts to other connections.
-    pub async fn handle_update(&self, from_conn_id: &str, update: &[u8]) -> Result<(), RoomError> {
+    ///
+    /// With persistence enabled the update becomes a commit, like an HTTP
+    /// edit, and reaches every connection through the commit listener.
+    /// Otherwise it is applied to the document store and broadcast to the
+    /// other connections directly.
+    pub async fn handle_update(
+        &self,
+        from_conn_id: &str,
+        update: &[u8],
+        author: Option<String>,
+    ) -> Result<(), RoomError> {
+        if update == yrs::Update::new().encode_v1().as_slice() {
+            // Nothing new, e.g. a SyncStep2 from an up-to-date client
+            return Ok(());
+        }
+
+        if self.commit_store.is_some() {
+            self.service
+                .edit_document(&self.doc_id, &crate::b64::encode(update), author, None)
+                .await
+                .map_err(|e| RoomError::ApplyError(format!("{:?}", e)))?;
+            return Ok(());
+        }
+
         // Apply to document store
         self.doc_store
             .apply_yjs_update(&self.doc_id, update)
@@ -145,6 +173,13 @@ impl Room {
         }
     }
 
+    /// Send a message to one connection.
+    pub async fn send_to(&self, conn_id: &str, message: Vec<u8>) {
+        if let Some(conn) = self.connections.read().await.get(conn_id) {
+            let _ = conn.read().await.try_send_binary(message);
+        }
+    }
+
     /// Broadcast a message to all connections.
     pub async fn broadcast_all(&self, message: Vec<u8>) {
         let connections = self.connections.read().await;
@@ -167,9 +202,22 @@ impl Room {
             if let Ok(commit) = store.get_commit(&notification.commit_id).await {
                 // Decode the update from base64
                 if let Ok(update_bytes) = crate::b64::decode(&commit.update) {
-                    // Broadcast to all WebSocket connections
+                    // Broadcast to all WebSocket connections; commonplace
+                    // clients also learn the commit that is now HEAD
                     let encoded = protocol::encode_update(&update_bytes);
-                    self.broadcast_all(encoded).await;
+                    let blue = protocol::encode_blue_event(
+                        &notification.doc_id,
+                        &notification.commit_id,
+                        notification.timestamp,
+                    );
+                    let connections = self.connections.read().await;
+                    for conn in connections.values() {
+                        let conn = conn.read().await;
+                        let _ = conn.try_send_binary(encoded.clone());
+                        if conn.protocol == ProtocolMode::Commonplace {
+                            let _ = conn.try_send_binary(blue.clone());
+                        }
+                    }
                 }
             }
         }
@@ -187,6 +235,7 @@ pub struct RoomManager {
     doc_store: Arc<DocumentStore>,
     commit_store: Option<Arc<CommitStore>>,
     broadcaster: Option<CommitBroadcaster>,
+    service: Arc<DocumentService>,
 }
 
 impl RoomManager {
@@ -195,12 +244,14 @@ impl RoomManager {
         doc_store: Arc<DocumentStore>,
         commit_store: Option<Arc<CommitStore>>,
         broadcaster: Option<CommitBroadcaster>,
+        service: Arc<DocumentService>,
     ) -> Self {
         Self {
             rooms: RwLock::new(HashMap::new()),
             doc_store,
             commit_store,
             broadcaster,
+            service,
         }
     }
 
@@ -227,6 +278,7 @@ impl RoomManager {
             self.doc_store.clone(),
             self.commit_store.clone(),
             self.broadcaster.clone(),
+            self.service.clone(),
         ));
 
         rooms.insert(doc_id.to_string(), room.clone());
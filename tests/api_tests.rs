@@ -942,3 +942,298 @@ async fn test_replace_text_document() {
     let after_body = body_to_string(get_after.into_body()).await;
     assert_eq!(after_body, new_content);
 }
+
+#[tokio::test]
+async fn test_replace_from_older_parent_merges_with_head() {
+    let (app, _dir) = create_app_with_commit_store();
+
+    async fn replace(app: &axum::Router, uri: String, content: &'static str) -> String {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header("content-type", "text/plain")
+                    .body(Body::from(content))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_to_string(response.into_body()).await;
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["cid"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    let create_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/docs")
+                .header("content-type", "text/plain")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let create_body = body_to_string(create_response.into_body()).await;
+    let json: serde_json::Value = serde_json::from_str(&create_body).unwrap();
+    let doc_id = json["id"].as_str().unwrap().to_string();
+
+    let base = replace(&app, format!("/docs/{}/replace", doc_id), "one\n").await;
+    // The server moves on...
+    replace(&app, format!("/docs/{}/replace", doc_id), "zero\none\n").await;
+    // ...while a client edits what it last saw
+    replace(
+        &app,
+        format!("/docs/{}/replace?parent_cid={}", doc_id, base),
+        "one\ntwo\n",
+    )
+    .await;
+
+    let get_after = app
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/docs/{}", doc_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let after_body = body_to_string(get_after.into_body()).await;
+    assert_eq!(after_body, "zero\none\ntwo\n");
+}
+
+#[tokio::test]
+async fn test_push_offline_commits_merges_with_head() {
+    let (app, _dir) = create_app_with_commit_store();
+
+    async fn send(
+        app: &axum::Router,
+        method: &str,
+        uri: String,
+        body: Body,
+    ) -> (StatusCode, String) {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("content-type", "text/plain")
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, body_to_string(response.into_body()).await)
+    }
+
+    let (_, body) = send(&app, "POST", "/docs".to_string(), Body::empty()).await;
+    let doc_id = serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (_, body) = send(
+        &app,
+        "POST",
+        format!("/docs/{}/replace", doc_id),
+        Body::from("one\n"),
+    )
+    .await;
+    let base = serde_json::from_str::<serde_json::Value>(&body).unwrap()["cid"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // The server moves on while the client is offline
+    let (status, _) = send(
+        &app,
+        "POST",
+        format!("/docs/{}/replace?parent_cid={}", doc_id, base),
+        Body::from("zero\none\n"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Two offline edits, pushed as a chain on top of the base
+    let push = serde_json::json!({
+        "base": base,
+        "commits": [
+            {"id": "local-1", "parent": base, "content": "one\ntwo\n", "timestamp": 1000},
+            {"id": "local-2", "parent": "local-1", "content": "one\ntwo\nthree\n", "timestamp": 2000},
+        ],
+    });
+    let push_request = |push: &serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/docs/{}/push", doc_id))
+            .header("content-type", "application/json")
+            .body(Body::from(push.to_string()))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(push_request(&push)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: serde_json::Value =
+        serde_json::from_str(&body_to_string(response.into_body()).await).unwrap();
+    let commits = result["commits"].as_array().unwrap();
+    assert_eq!(commits.len(), 2);
+    let merge_cid = result["merge_cid"].as_str().unwrap();
+    assert_eq!(result["cid"].as_str().unwrap(), merge_cid);
+
+    let (_, content) = send(&app, "GET", format!("/docs/{}", doc_id), Body::empty()).await;
+    assert_eq!(content, "zero\none\ntwo\nthree\n");
+
+    // Every offline state is in the history
+    let (_, body) = send(
+        &app,
+        "GET",
+        format!(
+            "/docs/{}/head?at_commit={}",
+            doc_id,
+            commits[0].as_str().unwrap()
+        ),
+        Body::empty(),
+    )
+    .await;
+    let head: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(head["content"], "one\ntwo\n");
+
+    // Pushing the same chain again changes nothing
+    let response = app.clone().oneshot(push_request(&push)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let retried: serde_json::Value =
+        serde_json::from_str(&body_to_string(response.into_body()).await).unwrap();
+    assert_eq!(retried["cid"].as_str().unwrap(), merge_cid);
+    assert!(retried.get("merge_cid").is_none());
+
+    // A chain that doesn't hang together is refused
+    let broken = serde_json::json!({
+        "base": base,
+        "commits": [{"id": "local-3", "parent": "local-1", "content": "x", "timestamp": 3000}],
+    });
+    let response = app.clone().oneshot(push_request(&broken)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_pushes_and_replaces_keep_head_and_content_in_step() {
+    let (app, _dir) = create_app_with_commit_store();
+
+    async fn send(
+        app: axum::Router,
+        method: &str,
+        uri: String,
+        content_type: &str,
+        body: String,
+    ) -> (StatusCode, String) {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("content-type", content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, body_to_string(response.into_body()).await)
+    }
+
+    let (_, body) = send(
+        app.clone(),
+        "POST",
+        "/docs".to_string(),
+        "text/plain",
+        String::new(),
+    )
+    .await;
+    let doc_id = serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (_, body) = send(
+        app.clone(),
+        "POST",
+        format!("/docs/{}/replace", doc_id),
+        "text/plain",
+        "base\n".to_string(),
+    )
+    .await;
+    let base = serde_json::from_str::<serde_json::Value>(&body).unwrap()["cid"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut tasks = Vec::new();
+    for i in 0..32 {
+        let push = serde_json::json!({
+            "base": base,
+            "commits": [{
+                "id": format!("local-{}", i),
+                "parent": base,
+                "content": format!("base\npushed {}\n", i),
+                "timestamp": 1000 + i,
+            }],
+        });
+        tasks.push(tokio::spawn(send(
+            app.clone(),
+            "POST",
+            format!("/docs/{}/push", doc_id),
+            "application/json",
+            push.to_string(),
+        )));
+        tasks.push(tokio::spawn(send(
+            app.clone(),
+            "POST",
+            format!("/docs/{}/replace", doc_id),
+            "text/plain",
+            format!("replaced {}\nbase\n", i),
+        )));
+    }
+    for task in tasks {
+        let (status, body) = task.await.unwrap();
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    // The live document is exactly what HEAD's history replays to
+    let (_, body) = send(
+        app.clone(),
+        "GET",
+        format!("/docs/{}/head", doc_id),
+        "text/plain",
+        String::new(),
+    )
+    .await;
+    let head: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let (_, body) = send(
+        app.clone(),
+        "GET",
+        format!(
+            "/docs/{}/head?at_commit={}",
+            doc_id,
+            head["cid"].as_str().unwrap()
+        ),
+        "text/plain",
+        String::new(),
+    )
+    .await;
+    let replayed: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let (_, content) = send(
+        app.clone(),
+        "GET",
+        format!("/docs/{}", doc_id),
+        "text/plain",
+        String::new(),
+    )
+    .await;
+    assert_eq!(replayed["content"].as_str().unwrap(), content);
+}
@@ -0,0 +1,668 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use commonplace_doc::auth::{Authenticator, Principal, StaticTokens};
+use commonplace_doc::fs::OwnershipPolicy;
+use commonplace_doc::sync::connect_ws;
+use commonplace_doc::ws::protocol::{self, WsMessage};
+use commonplace_doc::{create_router_with_config, RouterConfig};
+use futures::{SinkExt, StreamExt};
+use http_body_util::BodyExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use tower::util::ServiceExt;
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, Text, Transact, Update};
+
+// Helper to create an app requiring bearer tokens
+async fn create_app_with_auth() -> (axum::Router, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+
+    let mut tokens = StaticTokens::new();
+    tokens.insert(
+        "admin-token",
+        Principal {
+            name: "admin".to_string(),
+            read: vec![],
+            write: vec!["*".to_string()],
+            admin: true,
+        },
+    );
+    tokens.insert(
+        "reader-token",
+        Principal {
+            name: "reader".to_string(),
+            read: vec!["*".to_string()],
+            write: vec![],
+            admin: false,
+        },
+    );
+
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(store),
+        auth: Some(Arc::new(tokens) as Arc<dyn Authenticator>),
+        ..Default::default()
+    })
+    .await;
+
+    (app, dir)
+}
+
+async fn create_doc(app: &axum::Router, token: &str) -> String {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/docs")
+                .header("content-type", "text/plain")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    json["id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_health_is_open() {
+    let (app, _dir) = create_app_with_auth().await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/health")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_missing_token_is_unauthorized() {
+    let (app, _dir) = create_app_with_auth().await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/docs")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["www-authenticate"], "Bearer");
+}
+
+#[tokio::test]
+async fn test_invalid_token_is_unauthorized() {
+    let (app, _dir) = create_app_with_auth().await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/fs-root")
+                .header("authorization", "Bearer wrong")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_read_only_token_cannot_write() {
+    let (app, _dir) = create_app_with_auth().await;
+    let doc_id = create_doc(&app, "admin-token").await;
+
+    // Reading is allowed
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/docs/{}", doc_id))
+                .header("authorization", "Bearer reader-token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Writing is forbidden
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/docs/{}/replace", doc_id))
+                .header("authorization", "Bearer reader-token")
+                .body(Body::from("hello"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_access_token_query_param() {
+    let (app, _dir) = create_app_with_auth().await;
+    let doc_id = create_doc(&app, "admin-token").await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/docs/{}/replace?access_token=admin-token", doc_id))
+                .body(Body::from("hello"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+// Helper to create an app with an fs-root and enforced single-writer ownership
+async fn create_app_with_ownership() -> (axum::Router, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+
+    let mut tokens = StaticTokens::new();
+    for (token, name, admin) in [
+        ("admin-token", "admin", true),
+        ("alice-token", "alice", false),
+        ("counter-token", "process:examples/counter", false),
+    ] {
+        tokens.insert(
+            token,
+            Principal {
+                name: name.to_string(),
+                read: vec![],
+                write: vec!["*".to_string()],
+                admin,
+            },
+        );
+    }
+
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(store),
+        fs_root: Some("root".to_string()),
+        auth: Some(Arc::new(tokens) as Arc<dyn Authenticator>),
+        ownership: OwnershipPolicy::Enforce,
+        ..Default::default()
+    })
+    .await;
+
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{
+        "examples":{"type":"dir","entries":{
+            "processes.json":{"type":"doc","node_id":"procs"},
+            "counter.json":{"type":"doc","node_id":"counter"}}}}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/root/replace", "admin-token", schema).await,
+        StatusCode::OK
+    );
+    let processes =
+        r#"{"processes":{"counter":{"command":"python counter.py","owns":"counter.json"}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/procs/replace", "admin-token", processes).await,
+        StatusCode::OK
+    );
+
+    (app, dir)
+}
+
+async fn replace(app: &axum::Router, uri: &str, token: &str, body: &str) -> StatusCode {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn test_owned_file_rejects_other_writers() {
+    let (app, _dir) = create_app_with_ownership().await;
+
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace",
+            "alice-token",
+            r#"{"count":1}"#
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace",
+            "counter-token",
+            r#"{"count":1}"#
+        )
+        .await,
+        StatusCode::OK
+    );
+}
+
+async fn delete(app: &axum::Router, uri: &str, token: &str) -> StatusCode {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("DELETE")
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn test_owned_file_rejects_other_deleters() {
+    let (app, _dir) = create_app_with_ownership().await;
+
+    assert_eq!(
+        delete(&app, "/docs/counter", "alice-token").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        delete(&app, "/files/examples/counter.json", "alice-token").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        delete(&app, "/files/examples/counter.json", "counter-token").await,
+        StatusCode::NO_CONTENT
+    );
+}
+
+#[tokio::test]
+async fn test_ownership_follows_processes_json_edits() {
+    let (app, _dir) = create_app_with_ownership().await;
+
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace",
+            "alice-token",
+            r#"{"count":1}"#
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+    // Dropping the declaration frees the file
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/procs/replace",
+            "admin-token",
+            r#"{"processes":{}}"#
+        )
+        .await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace",
+            "alice-token",
+            r#"{"count":1}"#
+        )
+        .await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_admin_can_override_ownership() {
+    let (app, _dir) = create_app_with_ownership().await;
+
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace",
+            "admin-token",
+            r#"{"count":2}"#
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace?override_owner=true",
+            "admin-token",
+            r#"{"count":2}"#
+        )
+        .await,
+        StatusCode::OK
+    );
+
+    // Non-admins cannot override
+    assert_eq!(
+        replace(
+            &app,
+            "/docs/counter/replace?override_owner=true",
+            "alice-token",
+            r#"{"count":3}"#
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn test_path_scoped_token_can_write_over_ws() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+
+    let mut tokens = StaticTokens::new();
+    tokens.insert(
+        "admin-token",
+        Principal {
+            name: "admin".to_string(),
+            read: vec![],
+            write: vec!["*".to_string()],
+            admin: true,
+        },
+    );
+    tokens.insert(
+        "notes-token",
+        Principal {
+            name: "notes".to_string(),
+            read: vec![],
+            write: vec!["files/notes.txt".to_string()],
+            admin: false,
+        },
+    );
+
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(store),
+        fs_root: Some("root".to_string()),
+        auth: Some(Arc::new(tokens) as Arc<dyn Authenticator>),
+        ..Default::default()
+    })
+    .await;
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{"notes.txt":{"type":"doc","node_id":"notes"}}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/root/replace", "admin-token", schema).await,
+        StatusCode::OK
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    // The token only names the path, which the server resolves from the ID
+    let mut ws = connect_ws(&server, "notes", Some("notes-token"))
+        .await
+        .unwrap();
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text("content");
+    loop {
+        let Some(Ok(Message::Binary(data))) = ws.next().await else {
+            panic!("connection closed before the initial sync");
+        };
+        if let WsMessage::SyncStep2 { update } = protocol::decode_message(&data).unwrap() {
+            doc.transact_mut()
+                .apply_update(Update::decode_v1(&update).unwrap());
+            break;
+        }
+    }
+    let update = {
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, "written over ws");
+        txn.encode_update_v1()
+    };
+    ws.send(Message::Binary(protocol::encode_update(&update)))
+        .await
+        .unwrap();
+
+    let committed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let Some(Ok(Message::Binary(data))) = ws.next().await else {
+                panic!("connection closed before the commit");
+            };
+            if let WsMessage::BlueEvent { .. } = protocol::decode_message(&data).unwrap() {
+                return;
+            }
+        }
+    })
+    .await;
+    assert!(committed.is_ok(), "the update was never committed");
+}
+
+#[tokio::test]
+async fn test_open_ws_rechecks_ownership_per_update() {
+    let (app, _dir) = create_app_with_ownership().await;
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{
+        "examples":{"type":"dir","entries":{
+            "processes.json":{"type":"doc","node_id":"procs"},
+            "notes.txt":{"type":"doc","node_id":"notes"}}}}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/root/replace", "admin-token", schema).await,
+        StatusCode::OK
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    let served = app.clone();
+    tokio::spawn(async move {
+        axum::serve(listener, served).await.unwrap();
+    });
+
+    // Nothing owns notes.txt yet, so alice's socket opens write-capable
+    let mut ws = connect_ws(&server, "notes", Some("alice-token"))
+        .await
+        .unwrap();
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text("content");
+    loop {
+        let Some(Ok(Message::Binary(data))) = ws.next().await else {
+            panic!("connection closed before the initial sync");
+        };
+        if let WsMessage::SyncStep2 { update } = protocol::decode_message(&data).unwrap() {
+            doc.transact_mut()
+                .apply_update(Update::decode_v1(&update).unwrap());
+            break;
+        }
+    }
+
+    // The counter process claims the file while the socket stays open
+    let processes =
+        r#"{"processes":{"counter":{"command":"python counter.py","owns":"notes.txt"}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/procs/replace", "admin-token", processes).await,
+        StatusCode::OK
+    );
+
+    let before = head_cid(&app, "notes").await;
+    let update = {
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, "written after the claim");
+        txn.encode_update_v1()
+    };
+    ws.send(Message::Binary(protocol::encode_update(&update)))
+        .await
+        .unwrap();
+
+    let committed = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            let Some(Ok(Message::Binary(data))) = ws.next().await else {
+                return;
+            };
+            if let WsMessage::BlueEvent { .. } = protocol::decode_message(&data).unwrap() {
+                return;
+            }
+        }
+    })
+    .await;
+    assert!(committed.is_err(), "the owner's file took a foreign update");
+
+    assert_eq!(head_cid(&app, "notes").await, before);
+}
+
+async fn head_cid(app: &axum::Router, doc_id: &str) -> serde_json::Value {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/docs/{}/head", doc_id))
+                .header("authorization", "Bearer alice-token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let head: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    head["cid"].clone()
+}
+
+/// Wait for a file a managed process writes, and return its content.
+#[cfg(unix)]
+async fn read_when_written(path: &std::path::Path) -> String {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match std::fs::read_to_string(path) {
+                Ok(content) if !content.is_empty() => return content,
+                _ => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} was never written", path.display()))
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_managed_sync_process_can_write_with_minted_token() {
+    use commonplace_doc::auth::SigningKey;
+    use commonplace_doc::orchestrator::{
+        CredentialMinter, OrchestratorConfig, ProcessConfig, ProcessManager, RestartMode,
+        RestartPolicy,
+    };
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("auth.key");
+    std::fs::write(&key_file, "s3cret").unwrap();
+
+    // Stand-ins that record what the orchestrator gives them
+    let server_args = dir.path().join("server-args");
+    let server = dir.path().join("commonplace-server");
+    std::fs::write(
+        &server,
+        format!("#!/bin/sh\necho \"$@\" > {}\n", server_args.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&server, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let sync_token = dir.path().join("sync-token");
+
+    // The sync process keeps the path the shipped config gives it
+    let shipped = OrchestratorConfig::load(
+        &std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("commonplace.json"),
+    )
+    .unwrap();
+    let never = RestartPolicy {
+        policy: RestartMode::Never,
+        ..Default::default()
+    };
+    let mut processes = HashMap::new();
+    processes.insert(
+        "server".to_string(),
+        ProcessConfig {
+            command: server.display().to_string(),
+            args: vec![],
+            cwd: None,
+            restart: never.clone(),
+            depends_on: vec![],
+            path: None,
+        },
+    );
+    processes.insert(
+        "sync".to_string(),
+        ProcessConfig {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                format!(
+                    "printf %s \"$COMMONPLACE_TOKEN\" > {}",
+                    sync_token.display()
+                ),
+            ],
+            cwd: None,
+            restart: never,
+            depends_on: vec!["server".to_string()],
+            path: shipped.processes["sync"].path.clone(),
+        },
+    );
+    let mut manager = ProcessManager::new(
+        OrchestratorConfig {
+            processes,
+            ..shipped
+        },
+        None,
+        vec![],
+    );
+    manager.set_credentials(CredentialMinter::load(&key_file).unwrap());
+    manager.spawn_process("server").await.unwrap();
+    manager.spawn_process("sync").await.unwrap();
+
+    let args = read_when_written(&server_args).await;
+    assert!(
+        args.contains(&format!("--auth-key {}", key_file.display())),
+        "{}",
+        args
+    );
+    let token = read_when_written(&sync_token).await;
+    manager.shutdown().await;
+
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(store),
+        fs_root: Some("workspace".to_string()),
+        auth: Some(Arc::new(SigningKey::load(&key_file).unwrap()) as Arc<dyn Authenticator>),
+        ..Default::default()
+    })
+    .await;
+
+    // Sync pushes the schema and then file content
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{
+        "notes.json":{"type":"doc","node_id":"notes"}}}}"#;
+    assert_eq!(
+        replace(&app, "/docs/workspace/replace", &token, schema).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        replace(&app, "/files/notes.json/replace", &token, r#"{"todo":1}"#).await,
+        StatusCode::OK
+    );
+}
@@ -401,6 +401,9 @@ async fn test_router_with_fs_root() {
         fs_root: Some("my-filesystem".to_string()),
         mqtt: None,
         mqtt_subscribe: vec![],
+        auth: None,
+        ownership: Default::default(),
+        event_log: Default::default(),
     })
     .await;
 
@@ -0,0 +1,1769 @@
+//! Integration tests for the embedded MQTT broker.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use commonplace_doc::auth::{Authenticator, Principal, StaticTokens};
+use commonplace_doc::document::{ContentType, DocumentStore};
+use commonplace_doc::fs::FilesystemReconciler;
+use commonplace_doc::http_gateway::{self, HttpGateway};
+use commonplace_doc::mqtt::event_log::EventRecord;
+use commonplace_doc::mqtt::{
+    fetch_manifest, request_command, serve_commands, AllowAll, BrokerAcl, BrokerAction,
+    CommandManifest, CommandMessage, CommandReply, EmbeddedBroker, EventLogConfig, HaveFilter,
+    HeadMessage, MqttClient, MqttConfig, MqttError, MqttProtocol, MqttService, ReconnectPolicy,
+    StatusMessage, StoreCommit, SyncMessage, Topic, AUTHOR_PROPERTY, STORE_COMMITS,
+};
+use commonplace_doc::store::CommitStore;
+use commonplace_doc::sync::{mqtt_sync_task, FileEvent, SyncState};
+use commonplace_doc::{create_router_with_config, RouterConfig};
+use rumqttc::{v5, AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+async fn start_broker(acl: Arc<dyn BrokerAcl>) -> EmbeddedBroker {
+    EmbeddedBroker::start("127.0.0.1:0".parse().unwrap(), acl)
+        .await
+        .unwrap()
+}
+
+fn raw_client(broker: &EmbeddedBroker, id: &str) -> (AsyncClient, EventLoop) {
+    let addr = broker.local_addr();
+    let options = MqttOptions::new(id, addr.ip().to_string(), addr.port());
+    AsyncClient::new(options, 16)
+}
+
+/// Poll the event loop until a publish arrives.
+async fn next_publish(event_loop: &mut EventLoop) -> rumqttc::Publish {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Event::Incoming(Packet::Publish(publish)) = event_loop.poll().await.unwrap() {
+                return publish;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for publish")
+}
+
+/// Poll the event loop until the subscription is acknowledged.
+async fn wait_suback(event_loop: &mut EventLoop) -> rumqttc::SubAck {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Event::Incoming(Packet::SubAck(ack)) = event_loop.poll().await.unwrap() {
+                return ack;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for suback")
+}
+
+#[tokio::test]
+async fn test_wildcard_fanout_with_commonplace_client() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+
+    let subscriber = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "subscriber".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let mut messages = subscriber.subscribe_messages();
+    let loop_client = subscriber.clone();
+    tokio::spawn(async move { loop_client.run_event_loop().await });
+    subscriber
+        .subscribe("notes/todo.txt/#", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+
+    let (publisher, mut publisher_loop) = raw_client(&broker, "publisher");
+    tokio::spawn(async move { while publisher_loop.poll().await.is_ok() {} });
+
+    // Give the subscription time to reach the broker
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    publisher
+        .publish(
+            "notes/todo.txt/events/saved",
+            QoS::AtLeastOnce,
+            false,
+            b"{}".to_vec(),
+        )
+        .await
+        .unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), messages.recv())
+        .await
+        .expect("timed out waiting for message")
+        .unwrap();
+    assert_eq!(msg.topic, "notes/todo.txt/events/saved");
+    assert_eq!(msg.payload, b"{}");
+}
+
+#[tokio::test]
+async fn test_retained_message_delivered_on_subscribe() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+
+    let (publisher, mut publisher_loop) = raw_client(&broker, "publisher");
+    publisher
+        .publish(
+            "counter.json/head",
+            QoS::AtLeastOnce,
+            true,
+            b"cid-1".to_vec(),
+        )
+        .await
+        .unwrap();
+    // Drive the publisher until the broker acknowledges the publish
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Event::Incoming(Packet::PubAck(_)) = publisher_loop.poll().await.unwrap() {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let (subscriber, mut subscriber_loop) = raw_client(&broker, "late-subscriber");
+    subscriber
+        .subscribe("+/head", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut subscriber_loop).await;
+
+    let publish = next_publish(&mut subscriber_loop).await;
+    assert_eq!(publish.topic, "counter.json/head");
+    assert_eq!(&publish.payload[..], b"cid-1");
+    assert!(publish.retain);
+}
+
+struct ReadOnly;
+
+impl BrokerAcl for ReadOnly {
+    fn connect(&self, _client_id: &str, _username: Option<&str>, _password: Option<&str>) -> bool {
+        true
+    }
+
+    fn allow(&self, _client_id: &str, action: BrokerAction, topic: &str) -> bool {
+        action == BrokerAction::Subscribe && !topic.starts_with("secret")
+    }
+}
+
+#[tokio::test]
+async fn test_acl_rejects_subscription() {
+    let broker = start_broker(Arc::new(ReadOnly)).await;
+
+    let (client, mut event_loop) = raw_client(&broker, "reader");
+    client
+        .subscribe("secret.txt/edits", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    let ack = wait_suback(&mut event_loop).await;
+    assert_eq!(
+        ack.return_codes,
+        vec![rumqttc::SubscribeReasonCode::Failure]
+    );
+}
+
+#[tokio::test]
+async fn test_persistent_session_refused() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+
+    let addr = broker.local_addr();
+    let mut options = MqttOptions::new("durable", addr.ip().to_string(), addr.port());
+    options.set_clean_session(false);
+    let (_client, mut event_loop) = AsyncClient::new(options, 16);
+    let refused = tokio::time::timeout(Duration::from_secs(5), event_loop.poll())
+        .await
+        .expect("timed out waiting for connack");
+    assert!(
+        matches!(
+            refused,
+            Err(rumqttc::ConnectionError::ConnectionRefused(
+                rumqttc::ConnectReturnCode::ServiceUnavailable
+            ))
+        ),
+        "unexpected connect result: {:?}",
+        refused
+    );
+}
+
+#[tokio::test]
+async fn test_command_request_reply() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+
+    // A handler that answers `get` commands on its reply_to topic
+    let (handler, mut handler_loop) = raw_client(&broker, "counter");
+    handler
+        .subscribe("examples/counter.json/commands/#", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut handler_loop).await;
+    tokio::spawn(async move {
+        loop {
+            let publish = next_publish(&mut handler_loop).await;
+            let command: CommandMessage = serde_json::from_slice(&publish.payload).unwrap();
+            let reply = CommandReply {
+                req: command.req.unwrap(),
+                result: Some(serde_json::json!({ "value": 7 })),
+                error: None,
+                code: None,
+                source: Some("counter".to_string()),
+            };
+            handler
+                .publish(
+                    command.reply_to.unwrap(),
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_vec(&reply).unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "requester".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let loop_client = client.clone();
+    tokio::spawn(async move { loop_client.run_event_loop().await });
+
+    let message = CommandMessage {
+        payload: serde_json::json!({}),
+        source: Some("test".to_string()),
+        reply_to: None,
+        req: None,
+        token: None,
+    };
+    let reply = request_command(
+        &client,
+        "examples/counter.json",
+        "get",
+        message,
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reply.result, Some(serde_json::json!({ "value": 7 })));
+
+    // Nobody answers commands on other paths
+    let message = CommandMessage {
+        payload: serde_json::json!({}),
+        source: None,
+        reply_to: None,
+        req: None,
+        token: None,
+    };
+    let err = request_command(
+        &client,
+        "examples/other.json",
+        "get",
+        message,
+        Duration::from_millis(300),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, MqttError::Timeout(_)));
+}
+
+#[tokio::test]
+async fn test_store_validates_commands_against_manifest() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let path = "examples/counter.json";
+
+    // The owning node announces its commands
+    let (node, mut node_loop) = raw_client(&broker, "counter");
+    let manifest = serde_json::json!({
+        "commands": {
+            "increment": {
+                "description": "Add to the counter",
+                "schema": {"type": "object", "properties": {"amount": {"type": "integer"}}}
+            }
+        }
+    });
+    node.publish(
+        Topic::manifest(path).to_topic_string(),
+        QoS::AtLeastOnce,
+        true,
+        serde_json::to_vec(&manifest).unwrap(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(async move { while node_loop.poll().await.is_ok() {} });
+
+    let service = Arc::new(
+        MqttService::new(
+            MqttConfig {
+                broker_url: broker.url(),
+                client_id: "store".to_string(),
+                ..Default::default()
+            },
+            Arc::new(DocumentStore::new()),
+            None,
+        )
+        .await
+        .unwrap(),
+    );
+    service.subscribe_path(path).await.unwrap();
+    let runner = service.clone();
+    tokio::spawn(async move { runner.run().await });
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while service.commands_handler().manifest(path).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("store never saw the manifest");
+
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "requester".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let loop_client = client.clone();
+    tokio::spawn(async move { loop_client.run_event_loop().await });
+
+    let manifest = fetch_manifest(&client, path, Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("manifest is retained");
+    assert!(manifest.commands.contains_key("increment"));
+
+    let message = CommandMessage {
+        payload: serde_json::json!({"amount": "lots"}),
+        source: None,
+        reply_to: None,
+        req: None,
+        token: None,
+    };
+    let reply = request_command(&client, path, "increment", message, Duration::from_secs(5))
+        .await
+        .unwrap();
+    let error = reply.error.expect("invalid payload is rejected");
+    assert!(error.contains("/amount"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_node_never_handles_commands_its_manifest_rejects() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let path = "examples/counter.json";
+    let connect = |client_id: &str| {
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: client_id.to_string(),
+            ..Default::default()
+        })
+    };
+
+    let node = Arc::new(connect("counter").await.unwrap());
+    let loop_node = node.clone();
+    tokio::spawn(async move { loop_node.run_event_loop().await });
+    let manifest: CommandManifest = serde_json::from_value(serde_json::json!({
+        "commands": {
+            "increment": {
+                "schema": {"type": "object", "properties": {"amount": {"type": "integer"}}}
+            }
+        }
+    }))
+    .unwrap();
+    let (handled_tx, mut handled_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        serve_commands(&node, path, manifest, |verb, payload| {
+            let _ = handled_tx.send((verb, payload.clone()));
+            async move { Ok(payload) }
+        })
+        .await
+    });
+
+    let client = Arc::new(connect("requester").await.unwrap());
+    let loop_client = client.clone();
+    tokio::spawn(async move { loop_client.run_event_loop().await });
+    // The manifest is published once the node is subscribed
+    fetch_manifest(&client, path, Duration::from_secs(5))
+        .await
+        .unwrap()
+        .expect("manifest is retained");
+
+    let command = |payload| CommandMessage {
+        payload,
+        source: None,
+        reply_to: None,
+        req: None,
+        token: None,
+    };
+    let reply = request_command(
+        &client,
+        path,
+        "increment",
+        command(serde_json::json!({"amount": "lots"})),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert!(reply.error.unwrap().contains("/amount"));
+    assert_eq!(reply.code.as_deref(), Some("invalid_input"));
+    let reply = request_command(
+        &client,
+        path,
+        "explode",
+        command(serde_json::json!({})),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert!(reply.error.unwrap().contains("Unknown command"));
+
+    let reply = request_command(
+        &client,
+        path,
+        "increment",
+        command(serde_json::json!({"amount": 2})),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reply.result, Some(serde_json::json!({"amount": 2})));
+
+    // Only the valid command reached the handler
+    let (verb, payload) = handled_rx.recv().await.unwrap();
+    assert_eq!(verb, "increment");
+    assert_eq!(payload, serde_json::json!({"amount": 2}));
+    assert!(handled_rx.try_recv().is_err());
+}
+
+async fn replace(app: &axum::Router, uri: &str, body: &str) {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = http_body_util::BodyExt::collect(response.into_body())
+        .await
+        .unwrap()
+        .to_bytes();
+    assert_eq!(status, StatusCode::OK, "{} {:?}", uri, body);
+}
+
+#[tokio::test]
+async fn test_head_retained_after_http_commit() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"}}}}"#,
+    )
+    .await;
+    replace(&app, "/docs/notes/replace?author=alice", r#"{"todo":1}"#).await;
+
+    // The head is announced shortly after the commit...
+    let (watcher, mut watcher_loop) = raw_client(&broker, "watcher");
+    watcher
+        .subscribe("notes.json/head", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut watcher_loop).await;
+    let announced = next_publish(&mut watcher_loop).await;
+
+    // ...and a late subscriber sees it straight away
+    let (subscriber, mut subscriber_loop) = raw_client(&broker, "dashboard");
+    subscriber
+        .subscribe("notes.json/head", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut subscriber_loop).await;
+
+    let publish = next_publish(&mut subscriber_loop).await;
+    assert!(publish.retain);
+    assert_eq!(publish.payload, announced.payload);
+    let head: HeadMessage = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(head.author, "alice");
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/docs/notes/head")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = http_body_util::BodyExt::collect(response.into_body())
+        .await
+        .unwrap()
+        .to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["cid"], head.cid);
+}
+
+/// GET a path and return the body, or None unless it's 200.
+async fn get_body(app: &axum::Router, uri: &str) -> Option<String> {
+    let response = app
+        .clone()
+        .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+    let body = http_body_util::BodyExt::collect(response.into_body())
+        .await
+        .unwrap()
+        .to_bytes();
+    Some(String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_events_recorded_to_event_log() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        event_log: EventLogConfig {
+            paths: vec!["notes.json".to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"}}}}"#,
+    )
+    .await;
+
+    // Give the server a moment to finish subscribing before publishing
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (node, mut node_loop) = raw_client(&broker, "node");
+    tokio::spawn(async move { while node_loop.poll().await.is_ok() {} });
+    for n in 1..=2 {
+        let event = serde_json::json!({"payload": {"n": n}, "source": "ui"});
+        node.publish(
+            "notes.json/events/clicked",
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&event).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let log = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(body) = get_body(&app, "/files/notes.json.events.jsonl").await {
+                if body.lines().count() == 2 {
+                    return body;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for event log");
+
+    let records: Vec<EventRecord> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records[0].name, "clicked");
+    assert_eq!(records[0].source.as_deref(), Some("ui"));
+    assert_eq!(records[1].payload, serde_json::json!({"n": 2}));
+
+    // The log is mounted in the schema like any other file
+    let schema = get_body(&app, "/docs/root").await.unwrap();
+    assert!(schema.contains("notes.json.events.jsonl"));
+}
+
+/// Wait until `check` passes, polling every 20ms for up to 5s.
+async fn eventually<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !check().await {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+}
+
+#[tokio::test]
+async fn test_subscriptions_follow_reconciler() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let doc_store = Arc::new(DocumentStore::new());
+    doc_store
+        .get_or_create_with_id("root.json", ContentType::Json)
+        .await;
+    let reconciler = Arc::new(FilesystemReconciler::new(
+        "root.json".to_string(),
+        doc_store.clone(),
+    ));
+
+    let service = Arc::new(
+        MqttService::new(
+            MqttConfig {
+                broker_url: broker.url(),
+                client_id: "store".to_string(),
+                ..Default::default()
+            },
+            doc_store.clone(),
+            None,
+        )
+        .await
+        .unwrap(),
+    );
+    let runner = service.clone();
+    tokio::spawn(async move { runner.run().await });
+    service.clone().follow_reconciler(reconciler.clone()).await;
+    assert!(service.edits_handler().is_subscribed("root.json").await);
+
+    // A new file and a node-backed subdirectory appear
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{
+        "notes.json":{"type":"doc","node_id":"notes"},
+        "sub":{"type":"dir","node_id":"sub-doc"}}}}"#;
+    doc_store.set_content("root.json", schema).await.unwrap();
+    reconciler.reconcile(schema).await.unwrap();
+    doc_store
+        .set_content(
+            "sub-doc",
+            r#"{"version":1,"root":{"type":"dir","entries":{
+                "a.txt":{"type":"doc","node_id":"a","content_type":"text/plain"}}}}"#,
+        )
+        .await
+        .unwrap();
+    reconciler.reconcile(schema).await.unwrap();
+
+    let edits = service.edits_handler();
+    eventually("new paths", || async {
+        edits.is_subscribed("notes.json").await && edits.is_subscribed("sub/a.txt").await
+    })
+    .await;
+
+    // The file is removed again
+    let schema = r#"{"version":1,"root":{"type":"dir","entries":{
+        "sub":{"type":"dir","node_id":"sub-doc"}}}}"#;
+    doc_store.set_content("root.json", schema).await.unwrap();
+    reconciler.reconcile(schema).await.unwrap();
+    eventually("removed path", || async {
+        !edits.is_subscribed("notes.json").await
+    })
+    .await;
+    assert!(edits.is_subscribed("sub/a.txt").await);
+}
+
+#[tokio::test]
+async fn test_status_topic_announces_online_and_last_will() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "counter".to_string(),
+            status_topic: Some("nodes/counter/status".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let runner = client.clone();
+    let event_loop = tokio::spawn(async move { runner.run_event_loop().await });
+
+    let (watcher, mut watcher_loop) = raw_client(&broker, "watcher");
+    watcher
+        .subscribe("nodes/counter/status", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut watcher_loop).await;
+    let status: StatusMessage =
+        serde_json::from_slice(&next_publish(&mut watcher_loop).await.payload).unwrap();
+    assert!(status.online);
+    assert_eq!(status.client_id, "counter");
+
+    // Dropping the connection without a DISCONNECT triggers the last will
+    event_loop.abort();
+    let _ = event_loop.await;
+    drop(client);
+    let status: StatusMessage =
+        serde_json::from_slice(&next_publish(&mut watcher_loop).await.payload).unwrap();
+    assert!(!status.online);
+}
+
+#[tokio::test]
+async fn test_outbox_delivers_publishes_made_while_offline() {
+    // Reserve a port with nothing listening on it yet
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: format!("mqtt://{}", addr),
+            client_id: "editor".to_string(),
+            outbox: Some(dir.path().join("outbox.redb")),
+            reconnect: ReconnectPolicy {
+                initial_delay: Duration::from_millis(300),
+                max_delay: Duration::from_millis(300),
+                max_attempts: None,
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let runner = client.clone();
+    tokio::spawn(async move { runner.run_event_loop().await });
+
+    for n in 1..=3 {
+        client
+            .publish(
+                "notes.json/edits",
+                n.to_string().as_bytes(),
+                QoS::AtLeastOnce,
+            )
+            .await
+            .unwrap();
+    }
+
+    // The broker comes up; queued edits arrive in order once it reconnects
+    let broker = EmbeddedBroker::start(addr, Arc::new(AllowAll))
+        .await
+        .unwrap();
+    let (watcher, mut watcher_loop) = raw_client(&broker, "watcher");
+    watcher
+        .subscribe("notes.json/edits", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut watcher_loop).await;
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        received.push(next_publish(&mut watcher_loop).await.payload.to_vec());
+    }
+    assert_eq!(received, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+
+    // Rows are only dropped once the broker has acked them
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.outbox_len().unwrap() > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("outbox never emptied");
+}
+
+/// Send a request and return the status and body.
+async fn send(app: &axum::Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+    send_as(app, None, method, uri, body).await
+}
+
+/// Send a request with an optional bearer token and return the status and body.
+async fn send_as(
+    app: &axum::Router,
+    token: Option<&str>,
+    method: &str,
+    uri: &str,
+    body: &str,
+) -> (StatusCode, String) {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    let response = app
+        .clone()
+        .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = http_body_util::BodyExt::collect(response.into_body())
+        .await
+        .unwrap()
+        .to_bytes();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_http_gateway_matches_server_api() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let server = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "store".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+    replace(
+        &server,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"}}}}"#,
+    )
+    .await;
+
+    let gateway = HttpGateway::new(MqttConfig {
+        broker_url: broker.url(),
+        client_id: "gateway".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .with_request_timeout(Duration::from_millis(500));
+    let app = http_gateway::router(Arc::new(gateway), None);
+
+    eventually("store API", || async {
+        get_body(&app, "/fs-root").await.as_deref() == Some(r#"{"id":"root"}"#)
+    })
+    .await;
+
+    // Writes by path, reads by ID, through the store
+    let (status, body) = send(&app, "POST", "/files/notes.json/replace", r#"{"todo":1}"#).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let replaced: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        get_body(&app, "/docs/notes").await.as_deref(),
+        Some(r#"{"todo":1}"#)
+    );
+    assert_eq!(
+        get_body(&app, "/files/notes.json").await,
+        get_body(&server, "/docs/notes").await
+    );
+    let head: serde_json::Value =
+        serde_json::from_str(&get_body(&app, "/files/notes.json/head").await.unwrap()).unwrap();
+    assert_eq!(head["cid"], replaced["cid"]);
+
+    // Commits made offline are replayed through the store
+    let push = serde_json::json!({
+        "base": replaced["cid"],
+        "commits": [{
+            "id": "local-1",
+            "parent": replaced["cid"],
+            "content": r#"{"todo":3}"#,
+            "timestamp": 1,
+        }],
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/docs/notes/push")
+                .header("Content-Type", "application/json")
+                .body(Body::from(push.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        get_body(&server, "/docs/notes").await.as_deref(),
+        Some(r#"{"todo":3}"#)
+    );
+
+    // WebSocket sessions are the server's alone
+    let (status, _) = send(&app, "GET", "/ws/docs/notes", "").await;
+    assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+    let (status, body) = send(&app, "POST", "/docs/notes/fork", "").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let fork: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        get_body(&app, &format!("/docs/{}", fork["id"].as_str().unwrap())).await,
+        get_body(&server, "/docs/notes").await
+    );
+
+    let changes: serde_json::Value =
+        serde_json::from_str(&get_body(&app, "/documents/notes/changes").await.unwrap()).unwrap();
+    assert!(changes["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|change| change["commit_id"] == replaced["cid"]));
+
+    let (status, _) = send(&app, "GET", "/docs/missing/head", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&app, "GET", "/files/missing.json", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Commits made elsewhere stream to the gateway's SSE clients
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/sse/files/notes.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    replace(&server, "/docs/notes/replace?author=bob", r#"{"todo":2}"#).await;
+
+    let frame = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let frame = http_body_util::BodyExt::frame(&mut body)
+                .await
+                .unwrap()
+                .unwrap();
+            if let Ok(data) = frame.into_data() {
+                let text = String::from_utf8(data.to_vec()).unwrap();
+                if text.contains("event: edit") {
+                    return text;
+                }
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for edit event");
+    assert!(frame.contains(r#""author":"bob""#), "{}", frame);
+}
+
+#[tokio::test]
+async fn test_http_gateway_checks_tokens() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let mut tokens = StaticTokens::new();
+    tokens.insert(
+        "admin-token",
+        Principal {
+            name: "admin".to_string(),
+            read: vec![],
+            write: vec!["*".to_string()],
+            admin: true,
+        },
+    );
+    tokens.insert(
+        "notes-token",
+        Principal::for_path("notes-bot", "notes.json"),
+    );
+    let tokens: Arc<dyn Authenticator> = Arc::new(tokens);
+
+    let server = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "store".to_string(),
+            ..Default::default()
+        }),
+        auth: Some(tokens.clone()),
+        ..Default::default()
+    })
+    .await;
+    let (status, _) = send_as(
+        &server,
+        Some("admin-token"),
+        "POST",
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"},
+            "other.json":{"type":"doc","node_id":"other"}}}}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let gateway = HttpGateway::new(MqttConfig {
+        broker_url: broker.url(),
+        client_id: "gateway".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap()
+    .with_request_timeout(Duration::from_millis(500));
+    let app = http_gateway::router(Arc::new(gateway), Some(tokens));
+
+    eventually("store API", || async {
+        send_as(&app, Some("notes-token"), "GET", "/fs-root", "")
+            .await
+            .0
+            == StatusCode::OK
+    })
+    .await;
+
+    let (client, mut event_loop) = raw_client(&broker, "watcher");
+    client
+        .subscribe(STORE_COMMITS, QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut event_loop).await;
+
+    // No token, or a token without a scope for the document
+    let (status, _) = send(&app, "GET", "/docs/notes", "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send_as(
+        &app,
+        Some("notes-token"),
+        "POST",
+        "/files/other.json/replace",
+        "{}",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // The principal, not the claimed author, makes the commit
+    let (status, body) = send_as(
+        &app,
+        Some("notes-token"),
+        "POST",
+        "/files/notes.json/replace?author=mallory",
+        r#"{"todo":1}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let publish = next_publish(&mut event_loop).await;
+    let commit: StoreCommit = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(commit.doc_id, "notes");
+    assert_eq!(commit.author, "notes-bot");
+
+    // Path-scoped tokens can use the ID routes of their documents
+    let (status, body) = send_as(&app, Some("notes-token"), "GET", "/docs/notes", "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"{"todo":1}"#);
+    let (status, _) = send_as(&app, Some("notes-token"), "GET", "/docs/other", "").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Requests sent straight to the store need a token too
+    client
+        .subscribe("$store/replies/watcher/+", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut event_loop).await;
+    let command = CommandMessage {
+        payload: serde_json::json!({ "path": "notes.json", "content": "{}" }),
+        source: None,
+        reply_to: Some("$store/replies/watcher/r-1".to_string()),
+        req: Some("r-1".to_string()),
+        token: None,
+    };
+    client
+        .publish(
+            "$store/commands/replace",
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&command).unwrap(),
+        )
+        .await
+        .unwrap();
+    let reply = loop {
+        let publish = next_publish(&mut event_loop).await;
+        if publish.topic == "$store/replies/watcher/r-1" {
+            break serde_json::from_slice::<CommandReply>(&publish.payload).unwrap();
+        }
+    };
+    assert_eq!(reply.code.as_deref(), Some("unauthorized"));
+    assert_eq!(
+        get_body(&server, "/docs/notes?access_token=admin-token")
+            .await
+            .as_deref(),
+        Some(r#"{"todo":1}"#)
+    );
+}
+
+/// Send a sync request for `path` and collect the replies up to `done`.
+async fn sync_request(
+    client: &AsyncClient,
+    event_loop: &mut EventLoop,
+    path: &str,
+    request: SyncMessage,
+) -> Vec<SyncMessage> {
+    let topic = format!("{}/sync/tester", path);
+    client
+        .publish(
+            &topic,
+            QoS::AtMostOnce,
+            false,
+            serde_json::to_vec(&request).unwrap(),
+        )
+        .await
+        .unwrap();
+    let mut replies = Vec::new();
+    loop {
+        let publish = next_publish(event_loop).await;
+        let message: SyncMessage = serde_json::from_slice(&publish.payload).unwrap();
+        if message.is_request() || message.req() != request.req() {
+            continue;
+        }
+        let done = matches!(message, SyncMessage::Done { .. });
+        replies.push(message);
+        if done {
+            return replies;
+        }
+    }
+}
+
+fn commit_ids(replies: &[SyncMessage]) -> Vec<String> {
+    replies
+        .iter()
+        .filter_map(|m| match m {
+            SyncMessage::Commit { id, .. } => Some(id.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_sync_negotiate_streams_missing_commits() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"}}}}"#,
+    )
+    .await;
+    for n in 1..=3 {
+        replace(&app, "/docs/notes/replace", &format!(r#"{{"n":{}}}"#, n)).await;
+    }
+
+    let (client, mut event_loop) = raw_client(&broker, "tester");
+    client
+        .subscribe("notes.json/sync/tester", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut event_loop).await;
+
+    let negotiate = |req: &str, have: Vec<String>, bloom, snapshot_after| SyncMessage::Negotiate {
+        req: req.to_string(),
+        want: "HEAD".to_string(),
+        have,
+        bloom,
+        snapshot_after,
+    };
+
+    // Nothing held: the full history, parents first
+    let replies = sync_request(
+        &client,
+        &mut event_loop,
+        "notes.json",
+        negotiate("r-1", vec![], None, None),
+    )
+    .await;
+    let all = commit_ids(&replies);
+    assert!(all.len() >= 3, "{:?}", replies);
+    let mut seen = std::collections::HashSet::new();
+    for reply in &replies {
+        if let SyncMessage::Commit { id, parents, .. } = reply {
+            assert!(
+                parents.iter().all(|p| seen.contains(p)),
+                "{} before parent",
+                id
+            );
+            seen.insert(id.clone());
+        }
+    }
+    match replies.last() {
+        Some(SyncMessage::Done { commits, .. }) => assert_eq!(commits, &all),
+        other => panic!("Expected done, got {:?}", other),
+    }
+
+    // Everything but the tip held, half of it only via the Bloom filter
+    let (listed, filtered) = all[..all.len() - 1].split_at(1);
+    let bloom = HaveFilter::from_cids(filtered.iter().map(|s| s.as_str()), 1e-6);
+    let replies = sync_request(
+        &client,
+        &mut event_loop,
+        "notes.json",
+        negotiate("r-2", listed.to_vec(), Some(bloom), None),
+    )
+    .await;
+    assert_eq!(commit_ids(&replies), vec![all.last().unwrap().clone()]);
+
+    // A large gap is answered with a snapshot
+    let replies = sync_request(
+        &client,
+        &mut event_loop,
+        "notes.json",
+        negotiate("r-3", vec![], None, Some(1)),
+    )
+    .await;
+    match replies.as_slice() {
+        [SyncMessage::Snapshot {
+            commit, missing, ..
+        }, SyncMessage::Done { commits, .. }] => {
+            assert_eq!(commit, all.last().unwrap());
+            assert_eq!(*missing, all.len());
+            assert!(commits.is_empty());
+        }
+        other => panic!("Expected snapshot and done, got {:?}", other),
+    }
+}
+
+/// Poll an MQTT 5 event loop until a publish arrives.
+async fn next_publish_v5(event_loop: &mut v5::EventLoop) -> v5::mqttbytes::v5::Publish {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let v5::Event::Incoming(v5::Incoming::Publish(publish)) =
+                event_loop.poll().await.unwrap()
+            {
+                return publish;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for publish")
+}
+
+/// Poll an MQTT 5 event loop until the subscription is acknowledged.
+async fn wait_suback_v5(event_loop: &mut v5::EventLoop) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let v5::Event::Incoming(v5::Incoming::SubAck(_)) = event_loop.poll().await.unwrap() {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for suback")
+}
+
+fn request_properties(correlation: &str) -> v5::mqttbytes::v5::PublishProperties {
+    v5::mqttbytes::v5::PublishProperties {
+        response_topic: Some("tester/replies".to_string()),
+        correlation_data: Some(correlation.as_bytes().to_vec().into()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_mqtt5_replies_use_response_topic_and_correlation_data() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.json":{"type":"doc","node_id":"notes"}}}}"#,
+    )
+    .await;
+
+    let addr = broker.local_addr();
+    let (client, mut event_loop) = v5::AsyncClient::new(
+        v5::MqttOptions::new("tester", addr.ip().to_string(), addr.port()),
+        16,
+    );
+    for topic in ["tester/replies", STORE_COMMITS] {
+        client
+            .subscribe(topic, v5::mqttbytes::QoS::AtMostOnce)
+            .await
+            .unwrap();
+        wait_suback_v5(&mut event_loop).await;
+    }
+
+    // A store command with neither reply_to nor req in its payload
+    client
+        .publish_with_properties(
+            "$store/commands/fs-root",
+            v5::mqttbytes::QoS::AtLeastOnce,
+            false,
+            r#"{"payload":{}}"#,
+            request_properties("c-1"),
+        )
+        .await
+        .unwrap();
+    let publish = next_publish_v5(&mut event_loop).await;
+    assert_eq!(publish.topic, "tester/replies");
+    let reply: CommandReply = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(reply.req, "c-1");
+    assert_eq!(reply.result.unwrap()["id"], "root");
+    let reply_properties = publish.properties.unwrap();
+    assert_eq!(
+        reply_properties.correlation_data.as_deref(),
+        Some(&b"c-1"[..])
+    );
+
+    // Sync replies expire instead of queueing for a departed client
+    let head = SyncMessage::Head {
+        req: "h-1".to_string(),
+    };
+    client
+        .publish_with_properties(
+            "notes.json/sync/tester",
+            v5::mqttbytes::QoS::AtMostOnce,
+            false,
+            serde_json::to_vec(&head).unwrap(),
+            request_properties("h-1"),
+        )
+        .await
+        .unwrap();
+    let publish = next_publish_v5(&mut event_loop).await;
+    assert_eq!(publish.topic, "tester/replies");
+    let reply: SyncMessage = serde_json::from_slice(&publish.payload).unwrap();
+    assert!(
+        matches!(reply, SyncMessage::HeadResponse { .. }),
+        "{:?}",
+        reply
+    );
+    let reply_properties = publish.properties.unwrap();
+    assert_eq!(
+        reply_properties.correlation_data.as_deref(),
+        Some(&b"h-1"[..])
+    );
+    let expiry = reply_properties.message_expiry_interval.unwrap();
+    assert!(expiry > 0 && expiry <= 60, "{}", expiry);
+
+    // Commit announcements name their author as a user property
+    replace(&app, "/docs/notes/replace", r#"{"n":1}"#).await;
+    let publish = next_publish_v5(&mut event_loop).await;
+    assert_eq!(publish.topic, STORE_COMMITS);
+    let commit: StoreCommit = serde_json::from_slice(&publish.payload).unwrap();
+    let user_properties = publish.properties.unwrap().user_properties;
+    assert!(
+        user_properties.contains(&(AUTHOR_PROPERTY.to_string(), commit.author.clone())),
+        "{:?}",
+        user_properties
+    );
+}
+
+/// Forward connections to `broker`, refusing MQTT 5 CONNECTs the way a
+/// 3.1.1-only broker does.
+async fn v311_only_proxy(broker: &EmbeddedBroker) -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = broker.local_addr();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut first = vec![0u8; 256];
+                let n = client.read(&mut first).await.unwrap_or(0);
+                // Fixed header, a one-byte remaining length, "MQTT", level
+                if n > 8 && first[8] == 5 {
+                    // CONNACK: unacceptable protocol version
+                    let _ = client.write_all(&[0x20, 0x02, 0x00, 0x01]).await;
+                    return;
+                }
+                let mut server = tokio::net::TcpStream::connect(upstream).await.unwrap();
+                server.write_all(&first[..n]).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_mqtt5_falls_back_to_v311() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let proxy = v311_only_proxy(&broker).await;
+
+    let (publisher, mut publisher_loop) = raw_client(&broker, "publisher");
+    publisher
+        .publish("legacy/topic", QoS::AtLeastOnce, true, "hello")
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !matches!(
+            publisher_loop.poll().await.unwrap(),
+            Event::Incoming(Packet::PubAck(_))
+        ) {}
+    })
+    .await
+    .unwrap();
+
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: format!("mqtt://{}", proxy),
+            client_id: "legacy".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    assert_eq!(client.protocol(), MqttProtocol::V5);
+    let mut messages = client.subscribe_messages();
+    // Queued before the handshake, so it must survive the protocol switch
+    client
+        .subscribe("legacy/topic", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    let runner = client.clone();
+    tokio::spawn(async move { runner.run_event_loop().await });
+
+    let message = tokio::time::timeout(Duration::from_secs(10), messages.recv())
+        .await
+        .expect("timed out waiting for retained message")
+        .unwrap();
+    assert_eq!(message.topic, "legacy/topic");
+    assert_eq!(message.payload, b"hello");
+    assert_eq!(client.protocol(), MqttProtocol::V311);
+}
+
+#[tokio::test]
+async fn test_bridges_forward_events_and_mirror_edits() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "bridges.json":{"type":"doc","node_id":"bridges"},
+            "temp.json":{"type":"doc","node_id":"temp"},
+            "monitor.json":{"type":"doc","node_id":"monitor"},
+            "a.json":{"type":"doc","node_id":"a"},
+            "b.json":{"type":"doc","node_id":"b"}}}}"#,
+    )
+    .await;
+    replace(
+        &app,
+        "/docs/bridges/replace",
+        r#"{"bridges":[
+            {"from":"temp.json","port":"events","name":"reading",
+             "to":"monitor.json","as":"commands","rename":"check"},
+            {"from":"a.json","port":"edits","to":"b.json"}]}"#,
+    )
+    .await;
+
+    let (client, mut event_loop) = raw_client(&broker, "tester");
+    client
+        .subscribe("monitor.json/commands/#", QoS::AtMostOnce)
+        .await
+        .unwrap();
+    wait_suback(&mut event_loop).await;
+
+    // Bridges load asynchronously, so repeat the event until it crosses
+    let event = serde_json::json!({"payload": {"celsius": 41}, "source": "probe"}).to_string();
+    let command = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            client
+                .publish(
+                    "temp.json/events/reading",
+                    QoS::AtMostOnce,
+                    false,
+                    event.clone(),
+                )
+                .await
+                .unwrap();
+            let wait = tokio::time::timeout(Duration::from_millis(200), async {
+                loop {
+                    if let Event::Incoming(Packet::Publish(publish)) =
+                        event_loop.poll().await.unwrap()
+                    {
+                        return publish;
+                    }
+                }
+            });
+            if let Ok(publish) = wait.await {
+                return publish;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for bridged command");
+    assert_eq!(command.topic, "monitor.json/commands/check");
+    let command: CommandMessage = serde_json::from_slice(&command.payload).unwrap();
+    assert_eq!(command.payload["celsius"], 41);
+    assert_eq!(command.source.as_deref(), Some("bridge:temp.json"));
+
+    replace(&app, "/docs/a/replace", r#"{"mirrored":true}"#).await;
+    eventually("b.json to mirror a.json", || async {
+        get_body(&app, "/docs/b")
+            .await
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+            == Some(serde_json::json!({"mirrored": true}))
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_edits_bridges_keep_up_under_load() {
+    const BRIDGES: usize = 8;
+    const EDITS: usize = 50;
+
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+
+    let mut entries = vec![r#""bridges.json":{"type":"doc","node_id":"bridges"}"#.to_string()];
+    let mut bridges = Vec::new();
+    for i in 0..BRIDGES {
+        entries.push(format!(r#""a{i}.json":{{"type":"doc","node_id":"a{i}"}}"#));
+        entries.push(format!(r#""b{i}.json":{{"type":"doc","node_id":"b{i}"}}"#));
+        bridges.push(format!(
+            r#"{{"from":"a{i}.json","port":"edits","to":"b{i}.json"}}"#
+        ));
+    }
+    replace(
+        &app,
+        "/docs/root/replace",
+        &format!(
+            r#"{{"version":1,"root":{{"type":"dir","entries":{{{}}}}}}}"#,
+            entries.join(",")
+        ),
+    )
+    .await;
+    replace(
+        &app,
+        "/docs/bridges/replace",
+        &format!(r#"{{"bridges":[{}]}}"#, bridges.join(",")),
+    )
+    .await;
+
+    let mirrors = |i: usize, expected: serde_json::Value| {
+        let app = app.clone();
+        async move {
+            get_body(&app, &format!("/docs/b{}", i))
+                .await
+                .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+                == Some(expected)
+        }
+    };
+
+    // Bridges load asynchronously, so repeat an edit until it crosses
+    eventually("bridges to load", || async {
+        replace(&app, "/docs/a0/replace", r#"{"n":-1}"#).await;
+        mirrors(0, serde_json::json!({"n": -1})).await
+    })
+    .await;
+
+    // Edit every source concurrently, as fast as the store takes them
+    let writers = (0..BRIDGES).map(|i| {
+        let app = app.clone();
+        tokio::spawn(async move {
+            for n in 0..EDITS {
+                replace(
+                    &app,
+                    &format!("/docs/a{}/replace", i),
+                    &format!(r#"{{"n":{}}}"#, n),
+                )
+                .await;
+            }
+        })
+    });
+    for writer in writers.collect::<Vec<_>>() {
+        writer.await.unwrap();
+    }
+
+    // Every target ends up with its source's last edit
+    for i in 0..BRIDGES {
+        eventually(&format!("b{} to mirror a{}", i, i), || {
+            mirrors(i, serde_json::json!({"n": EDITS - 1}))
+        })
+        .await;
+    }
+}
+
+/// Wait until the store answers sync requests for `path`.
+async fn wait_for_sync(broker: &EmbeddedBroker, path: &str) {
+    let (client, mut event_loop) = raw_client(broker, "probe");
+    let topic = format!("{}/sync/probe", path);
+    client.subscribe(&topic, QoS::AtMostOnce).await.unwrap();
+    wait_suback(&mut event_loop).await;
+    let request = serde_json::to_vec(&SyncMessage::Head {
+        req: "probe".to_string(),
+    })
+    .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            client
+                .publish(&topic, QoS::AtMostOnce, false, request.clone())
+                .await
+                .unwrap();
+            let answered = tokio::time::timeout(Duration::from_millis(200), async {
+                loop {
+                    if let Event::Incoming(Packet::Publish(publish)) =
+                        event_loop.poll().await.unwrap()
+                    {
+                        let message: SyncMessage =
+                            serde_json::from_slice(&publish.payload).unwrap();
+                        if !message.is_request() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .await;
+            if answered.is_ok() {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the store to serve sync requests");
+}
+
+#[tokio::test]
+async fn test_mqtt_sync_task_syncs_file() {
+    let broker = start_broker(Arc::new(AllowAll)).await;
+    let dir = tempfile::tempdir().unwrap();
+    let app = create_router_with_config(RouterConfig {
+        commit_store: Some(CommitStore::new(dir.path().join("commits.redb")).unwrap()),
+        fs_root: Some("root".to_string()),
+        mqtt: Some(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "server".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await;
+    replace(
+        &app,
+        "/docs/root/replace",
+        r#"{"version":1,"root":{"type":"dir","entries":{
+            "notes.txt":{"type":"doc","node_id":"notes","content_type":"text/plain"}}}}"#,
+    )
+    .await;
+    replace(&app, "/docs/notes/replace", "hello\n").await;
+
+    wait_for_sync(&broker, "notes.txt").await;
+
+    // The sync client only talks to the broker
+    let client = Arc::new(
+        MqttClient::connect(MqttConfig {
+            broker_url: broker.url(),
+            client_id: "sync-client".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap(),
+    );
+    let client_for_loop = client.clone();
+    tokio::spawn(async move { client_for_loop.run_event_loop().await });
+
+    let temp = tempfile::tempdir().unwrap();
+    let file_path = temp.path().join("notes.txt");
+    let state = Arc::new(tokio::sync::RwLock::new(SyncState::new()));
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let task = tokio::spawn(mqtt_sync_task(
+        client,
+        "notes.txt".to_string(),
+        file_path.clone(),
+        state.clone(),
+        Some(rx),
+        false,
+        "bob".to_string(),
+    ));
+
+    let file_content = || std::fs::read_to_string(&file_path).unwrap_or_default();
+    let head = || async {
+        let body = get_body(&app, "/docs/notes/head").await.unwrap();
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()
+    };
+
+    // The history is written to the file
+    eventually("catch-up", || async { file_content() == "hello\n" }).await;
+
+    // Local edits become commits
+    std::fs::write(&file_path, "hello\nfrom mqtt\n").unwrap();
+    tx.send(FileEvent::Modified(b"hello\nfrom mqtt\n".to_vec()))
+        .await
+        .unwrap();
+    eventually("local edit", || async {
+        head().await["content"] == "hello\nfrom mqtt\n"
+    })
+    .await;
+
+    // Commits made elsewhere are fetched once their head is announced
+    replace(&app, "/docs/notes/replace", "hello\nfrom mqtt\nand http\n").await;
+    eventually("remote edit", || async {
+        file_content() == "hello\nfrom mqtt\nand http\n"
+    })
+    .await;
+    let cid = head().await["cid"].as_str().unwrap().to_string();
+    eventually("head recorded", || async {
+        state.read().await.last_written_cid.as_deref() == Some(cid.as_str())
+    })
+    .await;
+
+    task.abort();
+}
@@ -0,0 +1,396 @@
+//! Protocol conformance tests for the embedded MQTT broker.
+//!
+//! Each test speaks raw MQTT 3.1.1 packets over TCP and checks one of the
+//! specification's normative statements (cited as `[MQTT-x.y.z-n]`), so
+//! behaviour a client library would paper over is still exercised.
+
+use bytes::BytesMut;
+use commonplace_doc::mqtt::{AllowAll, EmbeddedBroker};
+use rumqttc::mqttbytes::v4::{
+    self, Connect, ConnectReturnCode, Disconnect, LastWill, Packet, PingReq, PubRel, Publish,
+    Subscribe, SubscribeFilter, SubscribeReasonCode, Unsubscribe,
+};
+use rumqttc::mqttbytes::{Error as PacketError, QoS};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start_broker() -> EmbeddedBroker {
+    EmbeddedBroker::start("127.0.0.1:0".parse().unwrap(), Arc::new(AllowAll))
+        .await
+        .unwrap()
+}
+
+/// A raw connection to the broker.
+struct Conn {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl Conn {
+    async fn open(broker: &EmbeddedBroker) -> Self {
+        Self {
+            stream: TcpStream::connect(broker.local_addr()).await.unwrap(),
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Open a connection and complete the CONNECT handshake.
+    async fn connect(broker: &EmbeddedBroker, connect: Connect) -> Self {
+        let mut conn = Self::open(broker).await;
+        conn.send(Packet::Connect(connect)).await;
+        match conn.recv().await {
+            Packet::ConnAck(ack) => assert_eq!(ack.code, ConnectReturnCode::Success),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+        conn
+    }
+
+    async fn send(&mut self, packet: Packet) {
+        let mut frame = BytesMut::new();
+        match packet {
+            Packet::Connect(p) => p.write(&mut frame),
+            Packet::Publish(p) => p.write(&mut frame),
+            Packet::PubRel(p) => p.write(&mut frame),
+            Packet::Subscribe(p) => p.write(&mut frame),
+            Packet::Unsubscribe(p) => p.write(&mut frame),
+            Packet::PingReq => PingReq.write(&mut frame),
+            Packet::Disconnect => Disconnect.write(&mut frame),
+            other => panic!("clients don't send {:?}", other),
+        }
+        .unwrap();
+        self.stream.write_all(&frame).await.unwrap();
+    }
+
+    /// Read the next packet, or `None` if the broker closed the connection.
+    async fn try_recv(&mut self, timeout: Duration) -> Option<Packet> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                match v4::read(&mut self.buf, 1024 * 1024) {
+                    Ok(packet) => return Some(packet),
+                    Err(PacketError::InsufficientBytes(_)) => {}
+                    Err(e) => panic!("broker sent a malformed packet: {}", e),
+                }
+                match self.stream.read_buf(&mut self.buf).await {
+                    Ok(0) | Err(_) => return None,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for the broker")
+    }
+
+    async fn recv(&mut self) -> Packet {
+        self.try_recv(Duration::from_secs(5))
+            .await
+            .expect("broker closed the connection")
+    }
+
+    async fn recv_publish(&mut self) -> Publish {
+        match self.recv().await {
+            Packet::Publish(publish) => publish,
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    /// Check the broker closes the connection without sending anything.
+    async fn assert_closed(&mut self) {
+        if let Some(packet) = self.try_recv(Duration::from_secs(5)).await {
+            panic!("expected the connection to close, got {:?}", packet);
+        }
+    }
+
+    /// Check nothing arrives for a moment.
+    async fn assert_silent(&mut self) {
+        let quiet = tokio::time::timeout(Duration::from_millis(300), async {
+            loop {
+                match v4::read(&mut self.buf, 1024 * 1024) {
+                    Ok(packet) => return packet,
+                    Err(_) => {
+                        let _ = self.stream.read_buf(&mut self.buf).await;
+                    }
+                }
+            }
+        })
+        .await;
+        if let Ok(packet) = quiet {
+            panic!("expected no packets, got {:?}", packet);
+        }
+    }
+
+    async fn subscribe(&mut self, pkid: u16, filters: &[(&str, QoS)]) -> Vec<SubscribeReasonCode> {
+        let mut subscribe = Subscribe::new_many(
+            filters
+                .iter()
+                .map(|(path, qos)| SubscribeFilter::new(path.to_string(), *qos)),
+        );
+        subscribe.pkid = pkid;
+        self.send(Packet::Subscribe(subscribe)).await;
+        match self.recv().await {
+            Packet::SubAck(ack) => {
+                // [MQTT-3.8.4-2] SUBACK has the SUBSCRIBE's packet identifier
+                assert_eq!(ack.pkid, pkid);
+                ack.return_codes
+            }
+            other => panic!("expected SUBACK, got {:?}", other),
+        }
+    }
+
+    async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: &str) {
+        let mut publish = Publish::new(topic, qos, payload);
+        publish.retain = retain;
+        if qos != QoS::AtMostOnce {
+            publish.pkid = 1;
+        }
+        self.send(Packet::Publish(publish)).await;
+        match qos {
+            QoS::AtMostOnce => {}
+            QoS::AtLeastOnce => assert!(matches!(self.recv().await, Packet::PubAck(_))),
+            QoS::ExactlyOnce => assert!(matches!(self.recv().await, Packet::PubRec(_))),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_first_packet_must_be_connect() {
+    // [MQTT-3.1.0-1]
+    let broker = start_broker().await;
+    let mut conn = Conn::open(&broker).await;
+    conn.send(Packet::PingReq).await;
+    conn.assert_closed().await;
+}
+
+#[tokio::test]
+async fn test_second_connect_is_a_protocol_violation() {
+    // [MQTT-3.1.0-2]
+    let broker = start_broker().await;
+    let mut conn = Conn::connect(&broker, Connect::new("twice")).await;
+    conn.send(Packet::Connect(Connect::new("twice"))).await;
+    conn.assert_closed().await;
+}
+
+#[tokio::test]
+async fn test_reused_client_id_takes_over() {
+    // [MQTT-3.1.4-2]
+    let broker = start_broker().await;
+    let mut first = Conn::connect(&broker, Connect::new("same")).await;
+    let mut second = Conn::connect(&broker, Connect::new("same")).await;
+    first.assert_closed().await;
+
+    second.send(Packet::PingReq).await;
+    assert!(matches!(second.recv().await, Packet::PingResp));
+}
+
+#[tokio::test]
+async fn test_ping_answered() {
+    // [MQTT-3.12.4-1]
+    let broker = start_broker().await;
+    let mut conn = Conn::connect(&broker, Connect::new("pinger")).await;
+    conn.send(Packet::PingReq).await;
+    assert!(matches!(conn.recv().await, Packet::PingResp));
+}
+
+#[tokio::test]
+async fn test_publish_acknowledged_with_its_packet_id() {
+    let broker = start_broker().await;
+    let mut conn = Conn::connect(&broker, Connect::new("acker")).await;
+
+    // [MQTT-4.3.2-4] QoS 1 is answered with PUBACK
+    let mut publish = Publish::new("acks/one", QoS::AtLeastOnce, "1");
+    publish.pkid = 7;
+    conn.send(Packet::Publish(publish)).await;
+    match conn.recv().await {
+        Packet::PubAck(ack) => assert_eq!(ack.pkid, 7),
+        other => panic!("expected PUBACK, got {:?}", other),
+    }
+
+    // [MQTT-4.3.3-9] [MQTT-4.3.3-11] QoS 2 is answered with PUBREC, then
+    // PUBREL with PUBCOMP
+    let mut publish = Publish::new("acks/two", QoS::ExactlyOnce, "2");
+    publish.pkid = 8;
+    conn.send(Packet::Publish(publish)).await;
+    match conn.recv().await {
+        Packet::PubRec(rec) => assert_eq!(rec.pkid, 8),
+        other => panic!("expected PUBREC, got {:?}", other),
+    }
+    conn.send(Packet::PubRel(PubRel::new(8))).await;
+    match conn.recv().await {
+        Packet::PubComp(comp) => assert_eq!(comp.pkid, 8),
+        other => panic!("expected PUBCOMP, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_suback_has_a_code_per_filter() {
+    // [MQTT-3.8.4-5] one return code per filter, in order; [MQTT-3.9.3-2]
+    // a malformed filter gets 0x80
+    let broker = start_broker().await;
+    let mut conn = Conn::connect(&broker, Connect::new("subscriber")).await;
+    let codes = conn
+        .subscribe(
+            3,
+            &[
+                ("a/+", QoS::AtLeastOnce),
+                ("b/#", QoS::AtMostOnce),
+                ("c/#/d", QoS::AtMostOnce),
+            ],
+        )
+        .await;
+    assert_eq!(
+        codes,
+        vec![
+            SubscribeReasonCode::Success(QoS::AtLeastOnce),
+            SubscribeReasonCode::Success(QoS::AtMostOnce),
+            SubscribeReasonCode::Failure,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_delivery_qos_is_the_lower_of_publish_and_subscription() {
+    // [MQTT-3.8.4-6]
+    let broker = start_broker().await;
+    let mut subscriber = Conn::connect(&broker, Connect::new("subscriber")).await;
+    subscriber
+        .subscribe(1, &[("q/0", QoS::AtMostOnce), ("q/1", QoS::AtLeastOnce)])
+        .await;
+    let mut publisher = Conn::connect(&broker, Connect::new("publisher")).await;
+
+    publisher.publish("q/0", QoS::AtLeastOnce, false, "a").await;
+    let delivered = subscriber.recv_publish().await;
+    assert_eq!(
+        (delivered.topic.as_str(), delivered.qos),
+        ("q/0", QoS::AtMostOnce)
+    );
+
+    publisher.publish("q/1", QoS::AtMostOnce, false, "b").await;
+    let delivered = subscriber.recv_publish().await;
+    assert_eq!(
+        (delivered.topic.as_str(), delivered.qos),
+        ("q/1", QoS::AtMostOnce)
+    );
+
+    // [MQTT-2.3.1-1] QoS 1 deliveries carry a non-zero packet identifier
+    publisher.publish("q/1", QoS::AtLeastOnce, false, "c").await;
+    let delivered = subscriber.recv_publish().await;
+    assert_eq!(delivered.qos, QoS::AtLeastOnce);
+    assert_ne!(delivered.pkid, 0);
+}
+
+#[tokio::test]
+async fn test_retained_messages() {
+    let broker = start_broker().await;
+    let mut publisher = Conn::connect(&broker, Connect::new("publisher")).await;
+    publisher
+        .publish("r/x", QoS::AtLeastOnce, true, "kept")
+        .await;
+
+    // [MQTT-3.3.1-6] [MQTT-3.3.1-8] a new subscription gets the retained
+    // message with RETAIN set
+    let mut subscriber = Conn::connect(&broker, Connect::new("subscriber")).await;
+    subscriber.subscribe(1, &[("r/+", QoS::AtLeastOnce)]).await;
+    let retained = subscriber.recv_publish().await;
+    assert!(retained.retain);
+    assert_eq!(&retained.payload[..], b"kept");
+
+    // [MQTT-3.3.1-9] live deliveries to existing subscribers have RETAIN unset
+    publisher
+        .publish("r/x", QoS::AtLeastOnce, true, "newer")
+        .await;
+    let live = subscriber.recv_publish().await;
+    assert!(!live.retain);
+    assert_eq!(&live.payload[..], b"newer");
+
+    // [MQTT-3.3.1-10] [MQTT-3.3.1-12] an empty retained message is delivered
+    // as usual and clears the retained message
+    publisher.publish("r/x", QoS::AtLeastOnce, true, "").await;
+    assert!(subscriber.recv_publish().await.payload.is_empty());
+    let mut late = Conn::connect(&broker, Connect::new("late")).await;
+    late.subscribe(1, &[("r/+", QoS::AtLeastOnce)]).await;
+    late.assert_silent().await;
+}
+
+#[tokio::test]
+async fn test_dollar_topics_not_matched_by_leading_wildcards() {
+    // [MQTT-4.7.2-1]
+    let broker = start_broker().await;
+    let mut wildcard = Conn::connect(&broker, Connect::new("wildcard")).await;
+    wildcard
+        .subscribe(1, &[("#", QoS::AtMostOnce), ("+/commits", QoS::AtMostOnce)])
+        .await;
+    let mut explicit = Conn::connect(&broker, Connect::new("explicit")).await;
+    explicit
+        .subscribe(1, &[("$store/#", QoS::AtMostOnce)])
+        .await;
+
+    let mut publisher = Conn::connect(&broker, Connect::new("publisher")).await;
+    publisher
+        .publish("$store/commits", QoS::AtMostOnce, false, "c")
+        .await;
+    assert_eq!(explicit.recv_publish().await.topic, "$store/commits");
+    wildcard.assert_silent().await;
+}
+
+#[tokio::test]
+async fn test_unsubscribe_stops_delivery() {
+    // [MQTT-3.10.4-4] [MQTT-3.10.4-5] UNSUBACK with the same packet
+    // identifier, and no more deliveries
+    let broker = start_broker().await;
+    let mut subscriber = Conn::connect(&broker, Connect::new("subscriber")).await;
+    subscriber.subscribe(1, &[("u/x", QoS::AtMostOnce)]).await;
+
+    let mut unsubscribe = Unsubscribe::new("u/x");
+    unsubscribe.pkid = 9;
+    subscriber.send(Packet::Unsubscribe(unsubscribe)).await;
+    match subscriber.recv().await {
+        Packet::UnsubAck(ack) => assert_eq!(ack.pkid, 9),
+        other => panic!("expected UNSUBACK, got {:?}", other),
+    }
+
+    let mut publisher = Conn::connect(&broker, Connect::new("publisher")).await;
+    publisher
+        .publish("u/x", QoS::AtMostOnce, false, "gone")
+        .await;
+    subscriber.assert_silent().await;
+}
+
+#[tokio::test]
+async fn test_will_published_only_on_abnormal_disconnect() {
+    let broker = start_broker().await;
+    let mut watcher = Conn::connect(&broker, Connect::new("watcher")).await;
+    watcher.subscribe(1, &[("wills/+", QoS::AtMostOnce)]).await;
+
+    let with_will = |id: &str| {
+        let mut connect = Connect::new(id);
+        connect.last_will = Some(LastWill::new(
+            format!("wills/{}", id),
+            "gone",
+            QoS::AtMostOnce,
+            false,
+        ));
+        connect
+    };
+
+    // [MQTT-3.1.2-10] dropped without DISCONNECT: the will is published
+    let crashed = Conn::connect(&broker, with_will("crashed")).await;
+    drop(crashed);
+    assert_eq!(watcher.recv_publish().await.topic, "wills/crashed");
+
+    // [MQTT-3.1.2-8] [MQTT-3.14.4-3] a DISCONNECT discards the will
+    let mut polite = Conn::connect(&broker, with_will("polite")).await;
+    polite.send(Packet::Disconnect).await;
+    polite.assert_closed().await;
+    watcher.assert_silent().await;
+}
+
+#[tokio::test]
+async fn test_idle_client_disconnected_after_keep_alive() {
+    // [MQTT-3.1.2-24] nothing within 1.5x the keep-alive closes the connection
+    let broker = start_broker().await;
+    let mut connect = Connect::new("idle");
+    connect.keep_alive = 1;
+    let mut conn = Conn::connect(&broker, connect).await;
+    conn.assert_closed().await;
+}
@@ -0,0 +1,294 @@
+//! Several sync roots in one commonplace-syncd process against a running server.
+//!
+//! Roots report that they are syncing over their control sockets, which are
+//! Unix sockets.
+#![cfg(unix)]
+
+use commonplace_doc::sync::state_file::SyncStateFile;
+use commonplace_doc::sync::{
+    build_uuid_map_recursive, control_socket_path, query_control_socket, CommitJournal, RootSpec,
+    RootState, SyncDaemon,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+async fn start_server() -> (String, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+    let app = commonplace_doc::create_router_with_store(Some(store));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (format!("http://{}", addr), dir)
+}
+
+/// A server whose fs-root is "root", for directory roots.
+async fn start_fs_server() -> (String, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+    let app = commonplace_doc::create_router_with_config(commonplace_doc::RouterConfig {
+        commit_store: Some(store),
+        fs_root: Some("root".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (format!("http://{}", addr), dir)
+}
+
+async fn create_text_doc(server: &str) -> String {
+    let response: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/docs", server))
+        .header("content-type", "text/plain")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    response["id"].as_str().unwrap().to_string()
+}
+
+async fn head_content(server: &str, doc_id: &str) -> String {
+    let head: serde_json::Value = reqwest::get(format!("{}/docs/{}/head", server, doc_id))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    head["content"].as_str().unwrap_or_default().to_string()
+}
+
+async fn wait_for_content(server: &str, doc_id: &str, expected: &str) {
+    let reached = timeout(Duration::from_secs(10), async {
+        while head_content(server, doc_id).await != expected {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(
+        reached.is_ok(),
+        "server never had {:?}, has {:?}",
+        expected,
+        head_content(server, doc_id).await
+    );
+}
+
+/// Wait until every root has finished its initial sync and answers on its
+/// control socket.
+async fn wait_until_syncing(daemon: &Arc<SyncDaemon>, count: usize) {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let roots = daemon.list().await;
+            if roots.len() == count && roots.iter().all(|root| root.sync.is_some()) {
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("roots never started syncing");
+}
+
+/// Content of a file in the fs-root, or "" before it has been created.
+async fn file_head(server: &str, path: &str) -> String {
+    let response = reqwest::get(format!("{}/files/{}/head", server, path))
+        .await
+        .unwrap();
+    match response.json::<serde_json::Value>().await {
+        Ok(head) => head["content"].as_str().unwrap_or_default().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+async fn wait_for_file(server: &str, path: &str, expected: &str) {
+    let reached = timeout(Duration::from_secs(10), async {
+        while file_head(server, path).await != expected {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(
+        reached.is_ok(),
+        "server never had {:?} in {}, has {:?}",
+        expected,
+        path,
+        file_head(server, path).await
+    );
+}
+
+async fn replace_file(server: &str, path: &str, content: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("{}/files/{}/replace", server, path))
+        .header("content-type", "text/plain")
+        .body(content.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+fn file_root(name: &str, node: &str, file: &Path) -> RootSpec {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "node": node,
+        "file": file,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_daemon_syncs_pauses_and_removes_roots() {
+    let (server, _server_dir) = start_server().await;
+    let doc_a = create_text_doc(&server).await;
+    let doc_b = create_text_doc(&server).await;
+
+    let local = tempfile::tempdir().unwrap();
+    let file_a = local.path().join("a.txt");
+    let file_b = local.path().join("b.txt");
+    std::fs::write(&file_a, "").unwrap();
+    std::fs::write(&file_b, "").unwrap();
+
+    let daemon = SyncDaemon::new(reqwest::Client::new(), server.clone(), "test".to_string());
+    daemon.add(file_root("a", &doc_a, &file_a)).unwrap();
+    daemon.add(file_root("b", &doc_b, &file_b)).unwrap();
+    wait_until_syncing(&daemon, 2).await;
+
+    // Both roots push through the shared watcher
+    std::fs::write(&file_a, "hello from a\n").unwrap();
+    std::fs::write(&file_b, "hello from b\n").unwrap();
+    wait_for_content(&server, &doc_a, "hello from a\n").await;
+    wait_for_content(&server, &doc_b, "hello from b\n").await;
+
+    // A paused root stops syncing and closes its control socket...
+    daemon.pause("b").await.unwrap();
+    let roots = daemon.list().await;
+    assert_eq!(roots[1].state, RootState::Paused);
+    assert!(query_control_socket(&control_socket_path(&file_b))
+        .await
+        .unwrap()
+        .is_none());
+    std::fs::write(&file_b, "edited while paused\n").unwrap();
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(head_content(&server, &doc_b).await, "hello from b\n");
+
+    // ...and picks up what changed when resumed
+    daemon.resume("b").unwrap();
+    wait_for_content(&server, &doc_b, "edited while paused\n").await;
+
+    // A removed root is gone, and the others carry on
+    daemon.remove("a").await.unwrap();
+    let roots = daemon.list().await;
+    assert_eq!(roots.len(), 1);
+    assert_eq!(roots[0].spec.name, "b");
+    assert!(!control_socket_path(&file_a).exists());
+    std::fs::write(&file_a, "not synced\n").unwrap();
+    wait_until_syncing(&daemon, 1).await;
+    std::fs::write(&file_b, "still syncing\n").unwrap();
+    wait_for_content(&server, &doc_b, "still syncing\n").await;
+    assert_eq!(head_content(&server, &doc_a).await, "hello from a\n");
+
+    daemon.shutdown().await;
+    assert!(daemon.list().await.is_empty());
+    assert!(!control_socket_path(&file_b).exists());
+}
+
+#[tokio::test]
+async fn test_directory_root_merges_offline_edits() {
+    let (server, _server_dir) = start_fs_server().await;
+    let local = tempfile::tempdir().unwrap();
+    let notes = local.path().join("notes");
+    std::fs::create_dir(&notes).unwrap();
+    std::fs::write(notes.join("a.txt"), "one\ntwo\n").unwrap();
+
+    let daemon = SyncDaemon::new(reqwest::Client::new(), server.clone(), "test".to_string());
+    let root = serde_json::from_value(serde_json::json!({
+        "name": "notes",
+        "node": "root",
+        "directory": notes,
+    }))
+    .unwrap();
+    daemon.add(root).unwrap();
+    wait_until_syncing(&daemon, 1).await;
+    wait_for_file(&server, "a.txt", "one\ntwo\n").await;
+    daemon.pause("notes").await.unwrap();
+
+    // Both sides edit the file while the root is paused...
+    std::fs::write(notes.join("a.txt"), "one\ntwo\nlocal\n").unwrap();
+    replace_file(&server, "a.txt", "server\none\ntwo\n").await;
+
+    // ...and resuming merges the two rather than pushing over the server's
+    daemon.resume("notes").unwrap();
+    wait_for_file(&server, "a.txt", "server\none\ntwo\nlocal\n").await;
+    wait_until_syncing(&daemon, 1).await;
+    assert_eq!(
+        std::fs::read_to_string(notes.join("a.txt")).unwrap(),
+        "server\none\ntwo\nlocal\n"
+    );
+
+    daemon.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_directory_root_pushes_journaled_edits() {
+    let (server, _server_dir) = start_fs_server().await;
+    let local = tempfile::tempdir().unwrap();
+    let notes = local.path().join("notes");
+    std::fs::create_dir(&notes).unwrap();
+    std::fs::write(notes.join("a.txt"), "one\ntwo\n").unwrap();
+
+    let daemon = SyncDaemon::new(reqwest::Client::new(), server.clone(), "test".to_string());
+    let root = serde_json::from_value(serde_json::json!({
+        "name": "notes",
+        "node": "root",
+        "directory": notes,
+    }))
+    .unwrap();
+    daemon.add(root).unwrap();
+    wait_until_syncing(&daemon, 1).await;
+    wait_for_file(&server, "a.txt", "one\ntwo\n").await;
+    daemon.pause("notes").await.unwrap();
+
+    // Two edits the server never saw wait in the directory's journal...
+    let uuid_map = build_uuid_map_recursive(&reqwest::Client::new(), &server, "root").await;
+    let node = &uuid_map["a.txt"];
+    let state = SyncStateFile::load(&SyncStateFile::state_file_path(&notes))
+        .await
+        .unwrap()
+        .unwrap();
+    let synced_cid = state.files["a.txt"].cid.clone().unwrap();
+    let journal_path = CommitJournal::journal_path(&notes);
+    {
+        let journal = CommitJournal::open(&journal_path).unwrap();
+        journal
+            .record(node, &synced_cid, "one\ntwo\nthree\n")
+            .unwrap();
+        journal
+            .record(node, &synced_cid, "one\ntwo\nthree\nfour\n")
+            .unwrap();
+    }
+    std::fs::write(notes.join("a.txt"), "one\ntwo\nthree\nfour\n").unwrap();
+    replace_file(&server, "a.txt", "server\none\ntwo\n").await;
+
+    // ...and go up as history on resume, merged with the server's edit
+    daemon.resume("notes").unwrap();
+    wait_for_file(&server, "a.txt", "server\none\ntwo\nthree\nfour\n").await;
+    wait_until_syncing(&daemon, 1).await;
+    assert_eq!(
+        std::fs::read_to_string(notes.join("a.txt")).unwrap(),
+        "server\none\ntwo\nthree\nfour\n"
+    );
+
+    daemon.shutdown().await;
+    assert!(!CommitJournal::open(&journal_path)
+        .unwrap()
+        .has_pending(node));
+}
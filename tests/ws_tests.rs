@@ -0,0 +1,177 @@
+//! WebSocket sync against a running server with commit storage.
+
+use commonplace_doc::sync::{connect_ws, ws_sync_task, FileEvent, SyncState, WsTransport};
+use commonplace_doc::ws::protocol::{self, WsMessage};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, GetString, Text, Transact, Update};
+
+async fn start_server() -> (String, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = commonplace_doc::store::CommitStore::new(dir.path().join("commits.redb")).unwrap();
+    let app = commonplace_doc::create_router_with_store(Some(store));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (format!("http://{}", addr), dir)
+}
+
+async fn create_text_doc(server: &str) -> String {
+    let response: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/docs", server))
+        .header("content-type", "text/plain")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    response["id"].as_str().unwrap().to_string()
+}
+
+async fn head(server: &str, doc_id: &str) -> serde_json::Value {
+    reqwest::get(format!("{}/docs/{}/head", server, doc_id))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_ws_update_becomes_commit() {
+    let (server, _dir) = start_server().await;
+    let doc_id = create_text_doc(&server).await;
+    let mut ws = connect_ws(&server, &doc_id, None).await.unwrap();
+
+    // The server opens with its state vector and full state
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text("content");
+    loop {
+        let Some(Ok(Message::Binary(data))) = ws.next().await else {
+            panic!("connection closed before the initial sync");
+        };
+        if let WsMessage::SyncStep2 { update } = protocol::decode_message(&data).unwrap() {
+            doc.transact_mut()
+                .apply_update(Update::decode_v1(&update).unwrap());
+            break;
+        }
+    }
+
+    let update = {
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, "hello over ws");
+        txn.encode_update_v1()
+    };
+    ws.send(Message::Binary(protocol::encode_update(&update)))
+        .await
+        .unwrap();
+
+    // The commit is announced with a blue event
+    let commit_id = timeout(Duration::from_secs(5), async {
+        loop {
+            let Some(Ok(Message::Binary(data))) = ws.next().await else {
+                panic!("connection closed before the commit");
+            };
+            if let WsMessage::BlueEvent {
+                doc_id: event_doc,
+                commit_id,
+                ..
+            } = protocol::decode_message(&data).unwrap()
+            {
+                assert_eq!(event_doc, doc_id);
+                return commit_id;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let head = head(&server, &doc_id).await;
+    assert_eq!(head["content"], "hello over ws");
+    assert_eq!(head["cid"], commit_id.as_str());
+}
+
+#[tokio::test]
+async fn test_ws_sync_task_syncs_file() {
+    let (server, _dir) = start_server().await;
+    let doc_id = create_text_doc(&server).await;
+    let temp = tempfile::tempdir().unwrap();
+    let file_path = temp.path().join("note.txt");
+    std::fs::write(&file_path, "").unwrap();
+
+    let state = Arc::new(RwLock::new(SyncState::new()));
+    let (tx, rx) = mpsc::channel(16);
+    let task = tokio::spawn(ws_sync_task(
+        server.clone(),
+        doc_id.clone(),
+        file_path.clone(),
+        state.clone(),
+        Some(rx),
+        false,
+        WsTransport::default(),
+    ));
+
+    // Local edits are pushed once the server's state is in
+    let mut pushed = false;
+    for _ in 0..50 {
+        sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file_path, "local edit\n").unwrap();
+        tx.send(FileEvent::Modified(b"local edit\n".to_vec()))
+            .await
+            .unwrap();
+        if head(&server, &doc_id).await["content"] == "local edit\n" {
+            pushed = true;
+            break;
+        }
+    }
+    assert!(pushed, "local edit never reached the server");
+
+    // Edits from another client are written to the file
+    let mut other = connect_ws(&server, &doc_id, None).await.unwrap();
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text("content");
+    loop {
+        let Some(Ok(Message::Binary(data))) = other.next().await else {
+            panic!("connection closed before the initial sync");
+        };
+        if let WsMessage::SyncStep2 { update } = protocol::decode_message(&data).unwrap() {
+            doc.transact_mut()
+                .apply_update(Update::decode_v1(&update).unwrap());
+            break;
+        }
+    }
+    let update = {
+        let mut txn = doc.transact_mut();
+        text.push(&mut txn, "remote edit\n");
+        txn.encode_update_v1()
+    };
+    assert_eq!(
+        text.get_string(&doc.transact()),
+        "local edit\nremote edit\n"
+    );
+    other
+        .send(Message::Binary(protocol::encode_update(&update)))
+        .await
+        .unwrap();
+
+    let mut written = String::new();
+    for _ in 0..50 {
+        sleep(Duration::from_millis(100)).await;
+        written = std::fs::read_to_string(&file_path).unwrap();
+        if written == "local edit\nremote edit\n" {
+            break;
+        }
+    }
+    assert_eq!(written, "local edit\nremote edit\n");
+    assert!(state.read().await.last_written_cid.is_some());
+
+    task.abort();
+}